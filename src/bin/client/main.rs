@@ -0,0 +1,733 @@
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::{
+    io::{self, stdout, BufReader, BufWriter, Read, Write},
+    net::TcpStream,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use aqua_db::{
+    format,
+    http::{self, PROTOCOL_VERSION, PROTOCOL_VERSION_HEADER, ROW_COUNT_TRAILER},
+    line::{self, Reply},
+};
+use rustyline::{error::ReadlineError, DefaultEditor};
+
+mod render;
+
+const ADDR: &str = "127.0.0.1:8080";
+const PROMPT: &str = "> ";
+// statementが;で終わらず、次の行に続いているとき
+const CONTINUATION_PROMPT: &str = "...> ";
+// home directoryに置く入力履歴
+const HISTORY_FILE: &str = ".aqua_history";
+
+const HELLO: &str = r"
+
+▄▀█ █▀█ █░█ ▄▀█   █▀▄ █▄▄
+█▀█ ▀▀█ █▄█ █▀█   █▄▀ █▄█
+
+";
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // --token <secret>か環境変数AQUA_DB_TOKENで、serverのauth tokenを渡す
+    let token = arg("--token")?.or_else(|| std::env::var("AQUA_DB_TOKEN").ok());
+    // --socket <path>なら、TCPの代わりにserverのunix domain socketにつなぐ
+    let socket = arg("--socket")?;
+    // --protocol lineなら、line protocolで1つの接続を使い続ける
+    let mut line_stream = match arg("--protocol")?.as_deref() {
+        None | Some("http") => None,
+        Some("line") => Some(connect_line(socket.as_deref(), token.as_deref())?),
+        Some(p) => return Err(format!("unknown protocol {}", p).into()),
+    };
+    // transactionは接続ごとなので、HTTPでも1つの接続を使い回す
+    // 行数のtrailerを読むため、HTTPは直接書く
+    let mut http_stream = None;
+    // 接続が切れたときに、transactionが失われたと伝えるため
+    let mut in_transaction = false;
+
+    // 上下で履歴をたどり、Ctrl-Rで履歴を検索できる
+    // --no-historyなら履歴をfileに残さない
+    let mut editor = DefaultEditor::new()?;
+    let history = if flag("--no-history") {
+        None
+    } else {
+        history_path()
+    };
+    if let Some(path) = &history {
+        // 初めて使うときはfileがない
+        let _ = editor.load_history(path);
+    }
+
+    output(HELLO)?;
+    let mut buffer = InputBuffer::default();
+    // \xで切り替える。trueなら1行の値を縦に並べる
+    let mut expanded = false;
+    'repl: loop {
+        let prompt = if buffer.is_empty() {
+            PROMPT
+        } else {
+            CONTINUATION_PROMPT
+        };
+        let line = match editor.readline(prompt) {
+            Ok(l) => l,
+            // Ctrl-Cは打ちかけのstatementを捨てるだけで、clientは閉じない
+            Err(ReadlineError::Interrupted) => {
+                buffer.clear();
+                continue;
+            }
+            // Ctrl-Dで終わる
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        };
+        for input in buffer.push(&line) {
+            editor.add_history_entry(input.trim_end())?;
+
+            let query = match dispatch(&input) {
+                Action::Send(query) => query,
+                Action::Print(message) => {
+                    output(&format!("{}\n", message))?;
+                    continue;
+                }
+                Action::ToggleExpanded => {
+                    expanded = !expanded;
+                    let state = if expanded { "on" } else { "off" };
+                    output(&format!("expanded display is {}\n", state))?;
+                    continue;
+                }
+                Action::Exit => break 'repl,
+            };
+
+            if let Some(stream) = line_stream.as_mut() {
+                // serverは;まで待ち続けるので、送る前に確かめる
+                if !query.trim_end().ends_with(';') {
+                    output("error: expect end with ;\n")?;
+                    continue;
+                }
+                match communicate_line(stream, &query)? {
+                    Reply::Ok(lines) if lines.is_empty() => output("OK\n")?,
+                    Reply::Ok(lines) => {
+                        for l in lines {
+                            let cells: Vec<String> = l.split('\t').map(line::unescape).collect();
+                            output(&format!("{}\n", cells.join(" | ")))?;
+                        }
+                    }
+                    Reply::Err(message) => output(&format!("error: {}\n", message))?,
+                }
+                continue;
+            }
+
+            // 表示にかかった時間は含めない
+            let started = Instant::now();
+            let response = match communicate(
+                &mut http_stream,
+                socket.as_deref(),
+                token.as_deref(),
+                &query,
+                in_transaction,
+            ) {
+                Ok(r) => r,
+                // 次のstatementは新しい接続で、transactionの外で実行される
+                Err(e) if e.is::<TransactionLost>() => {
+                    in_transaction = false;
+                    output(&format!("error: {}\n", e))?;
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            if response.status.starts_with('2') {
+                in_transaction = transaction_open(&query, in_transaction);
+            }
+            let csv = response.content_type.as_deref().is_some_and(is_csv);
+            if response.status.starts_with('2') && csv {
+                output(&rows_text(&response, expanded, started.elapsed()))?;
+            } else if response.status.starts_with('2') {
+                output(&format!("{}\n", response.body.trim_end_matches('\n')))?;
+            } else {
+                output(&format!("error ({}): {}\n", response.status, response.body))?;
+            }
+        }
+    }
+
+    if let Some(path) = &history {
+        if let Err(e) = editor.save_history(path) {
+            eprintln!("cannot save history to {}: {}", path.display(), e);
+        }
+    }
+    Ok(())
+}
+
+fn is_csv(content_type: &str) -> bool {
+    content_type.starts_with("text/csv")
+}
+
+// selectの結果はCSVで受け取り、列の幅をそろえて表示する。1行目は列名
+// 列の幅を決めるため、全ての行が届いてから表示する
+fn rows_text(response: &http::Response, expanded: bool, elapsed: Duration) -> String {
+    // 途中で失敗したときは行数のtrailerがつかず、最後の行に理由が届く
+    if response.trailer(ROW_COUNT_TRAILER).is_none() {
+        let reason = response
+            .body
+            .lines()
+            .last()
+            .filter(|l| l.starts_with("error: "))
+            .unwrap_or("error: result ended before all rows were sent");
+        return format!("{}\n", reason);
+    }
+
+    let body = &response.body;
+    let mut rows = format::parse_csv(body);
+    let columns = if rows.is_empty() {
+        Vec::new()
+    } else {
+        rows.remove(0)
+    };
+    let text = if expanded {
+        render::expanded(&columns, &rows)
+    } else {
+        render::table(&columns, &rows)
+    };
+    format!("{}{}", text, render::footer(rows.len(), elapsed))
+}
+
+fn history_path() -> Option<PathBuf> {
+    let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"))?;
+    Some(PathBuf::from(home).join(HISTORY_FILE))
+}
+
+// 入力された行をためて、;で終わったところで1つのstatementとして返す
+// 'の中の;や改行では区切らない。.から始まるコマンドとexit、quitは1行で終わる
+// serverは改行で区切らないので、行は前後の空白を除いて空白1つでつなぐ
+#[derive(Default)]
+struct InputBuffer {
+    text: String,
+}
+
+// ;を待たずにためた分を送る
+const SEND_COMMAND: &str = "\\g";
+// ためた分を送らずに捨てる
+const RESET_COMMAND: &str = "\\reset";
+// selectの結果を縦に並べるかを切り替える
+const EXPANDED_COMMAND: &str = "\\x";
+
+impl InputBuffer {
+    fn is_empty(&self) -> bool {
+        self.text.is_empty()
+    }
+
+    fn clear(&mut self) {
+        self.text.clear();
+    }
+
+    // 1行に;が2つあれば2つのstatementを返す。続きの行を待つときは空
+    fn push(&mut self, line: &str) -> Vec<String> {
+        let trimmed = line.trim();
+        if trimmed == RESET_COMMAND {
+            self.clear();
+            return Vec::new();
+        }
+        // 打ちかけのstatementはそのまま残す
+        if trimmed == EXPANDED_COMMAND {
+            return vec![format!("{}\n", EXPANDED_COMMAND)];
+        }
+        if self.is_empty() {
+            // 空行やcommentだけの行では、statementを始めない
+            if trimmed.is_empty() || trimmed.starts_with("--") {
+                return Vec::new();
+            }
+            if trimmed.starts_with('.') || matches!(trimmed, "exit" | "quit") {
+                return vec![format!("{}\n", line)];
+            }
+        }
+
+        // 'の外で行末に\gがあれば、そこまでを1つのstatementとして送る
+        let (trimmed, send) = match trimmed.strip_suffix(SEND_COMMAND) {
+            Some(rest) if !is_quoted(&format!("{} {}", self.text, rest)) => (rest.trim_end(), true),
+            _ => (trimmed, false),
+        };
+        if !self.is_empty() && !trimmed.is_empty() {
+            self.text.push(' ');
+        }
+        self.text.push_str(trimmed);
+
+        let mut statements = split_statements(&mut self.text);
+        if send && !self.is_empty() {
+            // serverは;で終わるstatementしか受け付けない
+            statements.push(format!("{};\n", std::mem::take(&mut self.text)));
+        }
+        statements
+    }
+}
+
+// 'の外の;ごとに切り出し、最後の;より後ろはtextに残す
+fn split_statements(text: &mut String) -> Vec<String> {
+    let mut statements = Vec::new();
+    let mut quoted = false;
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '\'' => quoted = !quoted,
+            ';' if !quoted => {
+                statements.push(format!("{}\n", text[start..=i].trim()));
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    *text = text[start..].trim_start().to_string();
+    statements
+}
+
+// 閉じていない'があるか。''は'そのものなので、数えるだけでよい
+fn is_quoted(text: &str) -> bool {
+    text.chars().filter(|&c| c == '\'').count() % 2 == 1
+}
+
+const DOT_HELP: &str = ".tables | .schema [table] | .exit";
+
+#[derive(Debug, PartialEq)]
+enum Action {
+    Send(String),
+    Print(String),
+    ToggleExpanded,
+    Exit,
+}
+
+// .から始まる入力はclient側で解釈し、SQLとしてはそのまま送らない
+fn dispatch(input: &str) -> Action {
+    let trimmed = input.trim();
+
+    // serverを止めるstatementはないので、exitやquitはclientを閉じるだけ
+    if matches!(trimmed, "exit" | "exit;" | "quit" | "quit;") {
+        return Action::Exit;
+    }
+
+    if trimmed == EXPANDED_COMMAND {
+        return Action::ToggleExpanded;
+    }
+
+    if !trimmed.starts_with('.') {
+        return Action::Send(input.to_string());
+    }
+
+    let tokens: Vec<&str> = trimmed.split_whitespace().collect();
+
+    match tokens[..] {
+        [".tables"] => Action::Send("show tables;\n".to_string()),
+        [".schema"] => Action::Send("show schema;\n".to_string()),
+        [".schema", table_name] => Action::Send(format!("show schema {};\n", table_name)),
+        [".exit"] => Action::Exit,
+        _ => Action::Print(format!("unknown command: {}\n{}", trimmed, DOT_HELP)),
+    }
+}
+
+fn output(message: &str) -> std::io::Result<()> {
+    let out = stdout();
+    let mut out = BufWriter::new(out.lock());
+    write!(out, "{}", message)?;
+    out.flush()?;
+
+    Ok(())
+}
+
+fn flag(name: &str) -> bool {
+    std::env::args().skip(1).any(|a| a == name)
+}
+
+fn arg(name: &str) -> Result<Option<String>, String> {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == name {
+            return args
+                .next()
+                .map(Some)
+                .ok_or_else(|| format!("{} requires a value", name));
+        }
+    }
+
+    Ok(None)
+}
+
+trait Stream: Read + Write {}
+
+impl<T: Read + Write> Stream for T {}
+
+fn connect(socket: Option<&str>) -> io::Result<Box<dyn Stream>> {
+    match socket {
+        #[cfg(unix)]
+        Some(path) => Ok(Box::new(UnixStream::connect(path)?)),
+        #[cfg(not(unix))]
+        Some(_) => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "--socket is not supported on this platform",
+        )),
+        None => {
+            let stream = TcpStream::connect(ADDR)?;
+            // 小さなrequestを待たせずに送る
+            stream.set_nodelay(true)?;
+            Ok(Box::new(stream))
+        }
+    }
+}
+
+fn connect_line(
+    socket: Option<&str>,
+    token: Option<&str>,
+) -> Result<BufReader<Box<dyn Stream>>, Box<dyn std::error::Error>> {
+    let mut stream = BufReader::new(connect(socket)?);
+    if let Some(token) = token {
+        if let Reply::Err(message) = communicate_line(&mut stream, &format!("auth {};", token))? {
+            return Err(message.into());
+        }
+    }
+
+    Ok(stream)
+}
+
+fn communicate_line(
+    stream: &mut BufReader<Box<dyn Stream>>,
+    input: &str,
+) -> Result<Reply, anyhow::Error> {
+    let input = input.trim_end();
+    writeln!(stream.get_mut(), "{}", input)?;
+    line::read_reply(stream)
+}
+
+// 接続が切れて、serverが開いていたtransactionを戻した
+#[derive(Debug)]
+struct TransactionLost(String);
+
+impl std::fmt::Display for TransactionLost {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "connection lost ({}); the open transaction was rolled back",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for TransactionLost {}
+
+// 成功したstatementから、serverでtransactionが開いているかを追う
+fn transaction_open(query: &str, open: bool) -> bool {
+    let query = query.trim_end().trim_end_matches(';').to_ascii_lowercase();
+    let words: Vec<&str> = query.split_whitespace().collect();
+    match words[..] {
+        ["begin"] | ["begin", "read", "only"] => true,
+        ["commit"] | ["rollback"] => false,
+        _ => open,
+    }
+}
+
+// transactionが続くよう接続を使い回す
+// 空いている間にserverが閉じていたら、つなぎ直して1度だけ送り直す
+// 送り直すのは、書き込みに失敗したか何も読めずに閉じられて、statementが届いていないときだけ
+// transactionの途中で切れたら、serverが戻しているので送り直さずにTransactionLostを返す
+// selectの結果は、値の区切りがはっきりするCSVで受け取る
+fn communicate(
+    stream: &mut Option<BufReader<Box<dyn Stream>>>,
+    socket: Option<&str>,
+    token: Option<&str>,
+    input: &str,
+    in_transaction: bool,
+) -> Result<http::Response, anyhow::Error> {
+    let mut request = format!(
+        "POST / HTTP/1.1\r\nHost: localhost\r\nConnection: keep-alive\r\nAccept: text/csv\r\n{}: {}\r\nContent-Length: {}\r\n",
+        PROTOCOL_VERSION_HEADER,
+        PROTOCOL_VERSION,
+        input.len()
+    );
+    if let Some(token) = token {
+        request.push_str(&format!("Authorization: Bearer {}\r\n", token));
+    }
+    request.push_str("\r\n");
+    request.push_str(input);
+
+    loop {
+        let reused = stream.is_some();
+        let reader = match stream {
+            Some(s) => s,
+            None => stream.insert(BufReader::new(connect(socket)?)),
+        };
+        let (e, unsent) = match reader.get_mut().write_all(request.as_bytes()) {
+            Err(e) => (e.into(), true),
+            Ok(()) => match http::read_response(reader) {
+                Ok(Some(r)) => return Ok(r),
+                Ok(None) => (anyhow::anyhow!("server closed the connection"), true),
+                // 応答の途中で切れたなら、statementは実行されたかもしれない
+                Err(e) => (e, false),
+            },
+        };
+        *stream = None;
+        if in_transaction {
+            return Err(TransactionLost(e.to_string()).into());
+        }
+        if !(reused && unsent) {
+            return Err(e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_dispatch() {
+        assert_eq!(
+            dispatch("select * from users;\n"),
+            Action::Send("select * from users;\n".to_string())
+        );
+        assert_eq!(
+            dispatch(".tables\n"),
+            Action::Send("show tables;\n".to_string())
+        );
+        assert_eq!(
+            dispatch(".schema\n"),
+            Action::Send("show schema;\n".to_string())
+        );
+        assert_eq!(
+            dispatch(".schema users\n"),
+            Action::Send("show schema users;\n".to_string())
+        );
+        assert_eq!(dispatch(".exit\n"), Action::Exit);
+        assert_eq!(dispatch("exit;\n"), Action::Exit);
+        assert_eq!(dispatch(" quit\n"), Action::Exit);
+        assert_eq!(dispatch("\\x\n"), Action::ToggleExpanded);
+
+        match dispatch(".dump\n") {
+            Action::Print(message) => assert!(message.contains(DOT_HELP)),
+            a => panic!("unexpected {:?}", a),
+        }
+    }
+
+    #[test]
+    fn client_continuation() {
+        let mut buffer = InputBuffer::default();
+
+        assert!(buffer.push("select *").is_empty());
+        assert!(!buffer.is_empty());
+        assert_eq!(
+            buffer.push("  from users;  "),
+            vec!["select * from users;\n".to_string()]
+        );
+        assert!(buffer.is_empty());
+
+        // 'の中の;では終わらない。''は'そのもの
+        assert!(buffer.push("insert into users ( name='a;").is_empty());
+        assert_eq!(
+            buffer.push("it''s' );"),
+            vec!["insert into users ( name='a; it''s' );\n".to_string()]
+        );
+        assert!(buffer
+            .push("insert into users ( name='it''s;' )")
+            .is_empty());
+        assert_eq!(
+            buffer.push(";"),
+            vec!["insert into users ( name='it''s;' ) ;\n".to_string()]
+        );
+
+        // 1行で終わる入力と、読み飛ばす行
+        assert!(buffer.push("").is_empty());
+        assert!(buffer.push("-- comment").is_empty());
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.push(".tables"), vec![".tables\n".to_string()]);
+        assert_eq!(buffer.push("exit"), vec!["exit\n".to_string()]);
+
+        // 続きの途中なら、.から始まる行もstatementの一部になる
+        assert!(buffer.push("select").is_empty());
+        assert!(buffer.push(".5").is_empty());
+        buffer.clear();
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.push("commit;"), vec!["commit;\n".to_string()]);
+    }
+
+    #[test]
+    fn client_send_commands() {
+        let mut buffer = InputBuffer::default();
+
+        // 1行に並んだstatementは別々に送り、;の後ろは次の行を待つ
+        assert_eq!(
+            buffer.push("insert into users ( name='a;b' ); select * from users; select"),
+            vec![
+                "insert into users ( name='a;b' );\n".to_string(),
+                "select * from users;\n".to_string()
+            ]
+        );
+        assert_eq!(
+            buffer.push("* from users;"),
+            vec!["select * from users;\n".to_string()]
+        );
+
+        // \gは;がなくても送る
+        assert!(buffer.push("select * from users").is_empty());
+        assert_eq!(
+            buffer.push("\\g"),
+            vec!["select * from users;\n".to_string()]
+        );
+        assert_eq!(
+            buffer.push("select * from users \\g"),
+            vec!["select * from users;\n".to_string()]
+        );
+        assert!(buffer.push("\\g").is_empty());
+
+        // 'の中の\gでは送らない
+        assert!(buffer.push("insert into users ( name='a\\g").is_empty());
+        assert_eq!(
+            buffer.push("' );"),
+            vec!["insert into users ( name='a\\g ' );\n".to_string()]
+        );
+
+        // \xは打ちかけのstatementを残したまま表示を切り替える
+        assert!(buffer.push("select * from").is_empty());
+        assert_eq!(buffer.push("\\x"), vec!["\\x\n".to_string()]);
+        assert_eq!(
+            buffer.push("users;"),
+            vec!["select * from users;\n".to_string()]
+        );
+
+        // \resetはためた分を捨てる
+        assert!(buffer.push("select * from").is_empty());
+        assert!(buffer.push("\\reset").is_empty());
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.push("commit;"), vec!["commit;\n".to_string()]);
+    }
+
+    #[test]
+    fn client_rows_text() {
+        let response = |body: &str, trailers: &[(&str, &str)]| http::Response {
+            status: "200 OK".to_string(),
+            content_type: Some("text/csv; charset=utf-8".to_string()),
+            body: body.to_string(),
+            trailers: trailers
+                .iter()
+                .map(|(n, v)| (n.to_string(), v.to_string()))
+                .collect(),
+        };
+
+        assert_eq!(
+            rows_text(
+                &response("id\n1\n", &[("X-Row-Count", "1")]),
+                false,
+                Duration::ZERO
+            ),
+            "+----+\n| id |\n+----+\n| 1  |\n+----+\n(1 row, 0.0 ms)\n"
+        );
+
+        // 行数のtrailerがあれば、値が error: で始まっていても失敗とは見なさない
+        assert_eq!(
+            rows_text(
+                &response("note\nerror: x\n", &[("x-row-count", "1")]),
+                true,
+                Duration::ZERO
+            ),
+            "-[ RECORD 1 ]-\nnote | error: x\n(1 row, 0.0 ms)\n"
+        );
+
+        // trailerがなければ途中で失敗している
+        assert_eq!(
+            rows_text(
+                &response("id\n1\nerror: query cancelled\n", &[]),
+                false,
+                Duration::ZERO
+            ),
+            "error: query cancelled\n"
+        );
+        assert_eq!(
+            rows_text(&response("id\n1\n", &[]), false, Duration::ZERO),
+            "error: result ended before all rows were sent\n"
+        );
+    }
+
+    #[test]
+    fn client_transaction_open() {
+        assert!(transaction_open("begin;\n", false));
+        assert!(transaction_open("BEGIN  READ ONLY ;\n", false));
+        assert!(transaction_open("select * from users;\n", true));
+        assert!(!transaction_open("select * from users;\n", false));
+        assert!(!transaction_open("commit;\n", true));
+        assert!(!transaction_open("rollback;\n", true));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn client_communicate_retry() {
+        use std::{io::BufRead, os::unix::net::UnixListener, sync::mpsc, thread};
+
+        let dir = std::env::temp_dir().join("aqua_db_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("client_communicate_retry.sock");
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        // 接続ごとに、requestを読むたびに応答を1つ返し、返し終えたら閉じる
+        let replies = [
+            vec!["HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\na"],
+            vec![
+                "HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\nb",
+                "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nbegin",
+            ],
+            vec![
+                "HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\nc",
+                // 応答の途中で閉じる
+                "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nd",
+            ],
+        ];
+        let (closed, wait_closed) = mpsc::channel();
+        let server = thread::spawn(move || {
+            let mut bodies = Vec::new();
+            for replies in replies {
+                let (conn, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(conn);
+                for reply in replies {
+                    let mut length = 0;
+                    loop {
+                        let mut line = String::new();
+                        reader.read_line(&mut line).unwrap();
+                        match line.trim_end().split_once(": ") {
+                            Some(("Content-Length", v)) => length = v.parse().unwrap(),
+                            Some(_) => {}
+                            None if line.trim_end().is_empty() => break,
+                            None => {}
+                        }
+                    }
+                    let mut body = vec![0_u8; length];
+                    reader.read_exact(&mut body).unwrap();
+                    bodies.push(String::from_utf8(body).unwrap());
+                    reader.get_mut().write_all(reply.as_bytes()).unwrap();
+                }
+                drop(reader);
+                closed.send(()).unwrap();
+            }
+            (bodies, listener)
+        });
+
+        let socket = path.to_str();
+        let mut stream = None;
+        let mut send = |input: &str, in_transaction| {
+            communicate(&mut stream, socket, None, input, in_transaction).map(|r| r.body)
+        };
+
+        assert_eq!(send("a", false).unwrap(), "a");
+        wait_closed.recv().unwrap();
+        // serverが閉じた接続には届いていないので、つなぎ直して送る
+        assert_eq!(send("b", false).unwrap(), "b");
+        assert_eq!(send("begin", false).unwrap(), "begin");
+        wait_closed.recv().unwrap();
+        // transactionの途中なら送り直さない
+        let e = send("insert", true).unwrap_err();
+        assert!(e.is::<TransactionLost>(), "{}", e);
+        assert_eq!(send("c", false).unwrap(), "c");
+        // 応答の途中で切れたら、実行されたかもしれないので送り直さない
+        assert!(send("d", false).is_err());
+        wait_closed.recv().unwrap();
+
+        let (bodies, listener) = server.join().unwrap();
+        assert_eq!(bodies, vec!["a", "b", "begin", "c", "d"]);
+        listener.set_nonblocking(true).unwrap();
+        assert!(listener.accept().is_err());
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,171 @@
+use std::time::Duration;
+
+use unicode_width::UnicodeWidthStr;
+
+// selectの結果を、列の幅をそろえた表にする
+pub fn table(columns: &[String], rows: &[Vec<String>]) -> String {
+    let mut widths: Vec<usize> = columns.iter().map(|c| width(c)).collect();
+    for row in rows {
+        for (w, cell) in widths.iter_mut().zip(row) {
+            *w = (*w).max(width(&display(cell)));
+        }
+    }
+
+    let border = format!(
+        "+{}+\n",
+        widths
+            .iter()
+            .map(|w| "-".repeat(w + 2))
+            .collect::<Vec<_>>()
+            .join("+")
+    );
+    // 列が足りない行は空の値で埋める
+    let line = |cells: &[String]| {
+        let cells: Vec<String> = widths
+            .iter()
+            .enumerate()
+            .map(|(i, &w)| pad(&display(cells.get(i).map_or("", |c| c)), w))
+            .collect();
+        format!("| {} |\n", cells.join(" | "))
+    };
+
+    let mut out = border.clone();
+    out.push_str(&line(columns));
+    out.push_str(&border);
+    for row in rows {
+        out.push_str(&line(row));
+    }
+    if !rows.is_empty() {
+        out.push_str(&border);
+    }
+    out
+}
+
+// 横に長い行を読むため、1行の値を列ごとに縦に並べる
+pub fn expanded(columns: &[String], rows: &[Vec<String>]) -> String {
+    let name_width = columns.iter().map(|c| width(c)).max().unwrap_or(0);
+
+    let mut out = String::new();
+    for (n, row) in rows.iter().enumerate() {
+        out.push_str(&format!("-[ RECORD {} ]-\n", n + 1));
+        for (i, name) in columns.iter().enumerate() {
+            let value = display(row.get(i).map_or("", |c| c));
+            // 空の値の後ろに空白を残さない
+            if value.is_empty() {
+                out.push_str(&format!("{} |\n", pad(name, name_width)));
+            } else {
+                out.push_str(&format!("{} | {}\n", pad(name, name_width), value));
+            }
+        }
+    }
+    out
+}
+
+// (42 rows, 3.1 ms)
+pub fn footer(rows: usize, elapsed: Duration) -> String {
+    format!(
+        "({} {}, {:.1} ms)\n",
+        rows,
+        if rows == 1 { "row" } else { "rows" },
+        elapsed.as_secs_f64() * 1000.0
+    )
+}
+
+// 改行やtabがあると表が崩れるので、見える形にする
+fn display(cell: &str) -> String {
+    cell.replace('\n', "\\n")
+        .replace('\r', "\\r")
+        .replace('\t', "\\t")
+}
+
+// 全角の文字は2つ分の幅で数える
+fn width(s: &str) -> usize {
+    UnicodeWidthStr::width(s)
+}
+
+fn pad(s: &str, to: usize) -> String {
+    format!("{}{}", s, " ".repeat(to.saturating_sub(width(s))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(cells: &[&str]) -> Vec<String> {
+        cells.iter().map(|c| c.to_string()).collect()
+    }
+
+    #[test]
+    fn render_table() {
+        let columns = strings(&["id", "name"]);
+        let rows = vec![
+            strings(&["1", "alice"]),
+            strings(&["1000", "two\nlines"]),
+            strings(&["2"]),
+        ];
+
+        assert_eq!(
+            table(&columns, &rows),
+            "\
++------+------------+
+| id   | name       |
++------+------------+
+| 1    | alice      |
+| 1000 | two\\nlines |
+| 2    |            |
++------+------------+
+"
+        );
+
+        // 行がなくても列名は出す
+        assert_eq!(
+            table(&columns, &[]),
+            "\
++----+------+
+| id | name |
++----+------+
+"
+        );
+
+        // 全角の文字は2つ分の幅を取る
+        assert_eq!(
+            table(&strings(&["名前"]), &[strings(&["あ"])]),
+            "\
++------+
+| 名前 |
++------+
+| あ   |
++------+
+"
+        );
+    }
+
+    #[test]
+    fn render_expanded() {
+        let columns = strings(&["id", "name"]);
+        let rows = vec![strings(&["1", "alice"]), strings(&["2", ""])];
+
+        assert_eq!(
+            expanded(&columns, &rows),
+            "\
+-[ RECORD 1 ]-
+id   | 1
+name | alice
+-[ RECORD 2 ]-
+id   | 2
+name |
+"
+        );
+        assert_eq!(expanded(&columns, &[]), "");
+    }
+
+    #[test]
+    fn render_footer() {
+        assert_eq!(
+            footer(42, Duration::from_micros(3140)),
+            "(42 rows, 3.1 ms)\n"
+        );
+        assert_eq!(footer(1, Duration::ZERO), "(1 row, 0.0 ms)\n");
+        assert_eq!(footer(0, Duration::from_millis(12)), "(0 rows, 12.0 ms)\n");
+    }
+}
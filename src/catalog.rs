@@ -1,8 +1,15 @@
 use crate::storage::tuple::*;
 use serde_derive::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    hash::{Hash, Hasher},
+    io::{BufReader, Read},
+    path::Path,
+};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Catalog {
     #[serde(rename = "schemas")]
     pub schemas: Vec<Schema>,
@@ -11,14 +18,73 @@ pub struct Catalog {
 }
 
 impl Catalog {
-    pub fn from_json(json: &str) -> Self {
-        let mut c: Catalog = serde_json::from_str(json).unwrap();
+    pub fn from_json(json: &str) -> Result<Self, anyhow::Error> {
+        Self::build(serde_json::from_str(json)?)
+    }
 
-        c.schemas.iter().enumerate().for_each(|(index, schema)| {
-            c.map.insert(schema.table.name.clone(), index);
-        });
+    pub fn from_reader<R: Read>(reader: R) -> Result<Self, anyhow::Error> {
+        Self::build(serde_json::from_reader(reader)?)
+    }
+
+    #[cfg(feature = "toml")]
+    pub fn from_toml(toml: &str) -> Result<Self, anyhow::Error> {
+        Self::build(toml::from_str(toml)?)
+    }
+
+    #[cfg(feature = "yaml")]
+    pub fn from_yaml(yaml: &str) -> Result<Self, anyhow::Error> {
+        Self::build(serde_yaml::from_str(yaml)?)
+    }
+
+    // 拡張子でformatを決める
+    pub fn from_path(path: &str) -> Result<Self, anyhow::Error> {
+        Self::build(read_file(Path::new(path))?)
+    }
+
+    // directory内のschema fileを全て読み、1つのcatalogにまとめる
+    pub fn from_dir(path: &str) -> Result<Self, anyhow::Error> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(path).map_err(|e| anyhow::anyhow!("{}: {}", path, e))? {
+            let p = entry?.path();
+            if p.is_file()
+                && p.extension()
+                    .and_then(|e| e.to_str())
+                    .is_some_and(|e| EXTENSIONS.contains(&e))
+            {
+                files.push(p);
+            }
+        }
+        // どのfileで重複したかを毎回同じ順で報告できるよう、名前順に読む
+        files.sort();
+
+        let mut schemas = Vec::new();
+        for f in files {
+            schemas.extend(read_file(&f)?.schemas);
+        }
 
-        c
+        Self::build(Catalog {
+            schemas,
+            map: HashMap::new(),
+        })
+    }
+
+    fn build(mut c: Catalog) -> Result<Self, anyhow::Error> {
+        for schema in &mut c.schemas {
+            schema.table.validate()?;
+            schema.table.tuple_size = schema.table.compute_tuple_size();
+        }
+
+        c.map.clear();
+        for (index, schema) in c.schemas.iter().enumerate() {
+            if c.map.insert(schema.table.name.clone(), index).is_some() {
+                return Err(anyhow::anyhow!(
+                    "table {} is defined more than once",
+                    schema.table.name
+                ));
+            }
+        }
+
+        Ok(c)
     }
 
     pub fn get_schema_by_table_name(&self, table_name: &str) -> Option<&Schema> {
@@ -27,45 +93,553 @@ impl Catalog {
     }
 
     pub fn exist_table(&self, table_name: &str) -> bool {
-        self.map.get(table_name).is_some()
+        self.map.contains_key(table_name)
+    }
+
+    // schemaに書かれた順のtable名
+    pub fn table_names(&self) -> impl Iterator<Item = &str> {
+        self.schemas.iter().map(|s| s.table.name.as_str())
+    }
+
+    pub fn columns_of(&self, table_name: &str) -> Option<&[Column]> {
+        Some(&self.get_schema_by_table_name(table_name)?.table.columns)
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+// from_dirが読み込む拡張子
+const EXTENSIONS: &[&str] = &[
+    "json",
+    #[cfg(feature = "toml")]
+    "toml",
+    #[cfg(feature = "yaml")]
+    "yaml",
+    #[cfg(feature = "yaml")]
+    "yml",
+];
+
+// mapはまだ組み立てていないcatalogを返す
+fn read_file(path: &Path) -> Result<Catalog, anyhow::Error> {
+    let with_path = |e: &dyn std::fmt::Display| anyhow::anyhow!("{}: {}", path.display(), e);
+    let reader = BufReader::new(File::open(path).map_err(|e| with_path(&e))?);
+
+    match path.extension().and_then(|e| e.to_str()) {
+        #[cfg(feature = "toml")]
+        Some("toml") => {
+            let raw = std::io::read_to_string(reader).map_err(|e| with_path(&e))?;
+            toml::from_str(&raw).map_err(|e| with_path(&e))
+        }
+        #[cfg(feature = "yaml")]
+        Some("yaml" | "yml") => serde_yaml::from_reader(reader).map_err(|e| with_path(&e)),
+        _ => serde_json::from_reader(reader).map_err(|e| with_path(&e)),
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Schema {
     pub table: Table,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Table {
     pub name: String,
-    pub columns: Vec<Column>,
+    // tuple_sizeと食い違わないよう、変えるときはset_columnsを通す
+    columns: Vec<Column>,
+    // 追記しかしないtable。insertは最後に書いたpageにだけ行う
+    #[serde(default)]
+    pub append_only: bool,
+    // 1pageに入れるtupleの数の上限。byte数から決まる数より小さくしたいときに使う
+    #[serde(default)]
+    pub max_tuples_per_page: Option<usize>,
+    // 複数の列を組み合わせて一意にする。空なら制約なし
+    #[serde(default)]
+    pub primary_key: Vec<String>,
+    // textの値の後ろを埋めるbyte。既存のfileと合わなくなるので、作った後は変えない
+    #[serde(default)]
+    pub text_padding: TextPadding,
+    // pageを読み書きするたびに使うので、Catalog::buildで一度だけ計算しておく
+    #[serde(skip)]
+    tuple_size: usize,
 }
 
 impl Table {
+    // TupleBodyは列の並びとcolumn名で値を引くので、名前の重複や空は許さない
+    fn validate(&self) -> Result<(), anyhow::Error> {
+        if self.max_tuples_per_page == Some(0) {
+            return Err(anyhow::anyhow!(
+                "{} max_tuples_per_page must be at least 1",
+                self.name
+            ));
+        }
+
+        let mut names = HashSet::new();
+
+        for c in &self.columns {
+            if c.types.starts_with("numeric") && ColumnType::parse(&c.types).is_none() {
+                return Err(anyhow::anyhow!(
+                    "{}.{}: {} must be numeric(precision,scale) with 1 <= precision <= {} and scale <= precision",
+                    self.name,
+                    c.name,
+                    c.types,
+                    MAX_NUMERIC_PRECISION
+                ));
+            }
+            // 知らない型は0byteと数えられ、tupleを読むときに初めて失敗する
+            if ColumnType::parse(&c.types).is_none() {
+                return Err(anyhow::anyhow!(
+                    "{}.{}: unknown type {} (expected int, text, blob or numeric(precision,scale))",
+                    self.name,
+                    c.name,
+                    c.types
+                ));
+            }
+            if c.name.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "{} has a column with empty name",
+                    self.name
+                ));
+            }
+            if !names.insert(c.name.as_str()) {
+                return Err(anyhow::anyhow!(
+                    "{} has duplicate column {}",
+                    self.name,
+                    c.name
+                ));
+            }
+        }
+
+        let mut key_columns = HashSet::new();
+        for k in &self.primary_key {
+            if !names.contains(k.as_str()) {
+                return Err(anyhow::anyhow!(
+                    "{} primary key column {} does not exist",
+                    self.name,
+                    k
+                ));
+            }
+            if !key_columns.insert(k.as_str()) {
+                return Err(anyhow::anyhow!(
+                    "{} primary key has duplicate column {}",
+                    self.name,
+                    k
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn columns(&self) -> &[Column] {
+        &self.columns
+    }
+
+    // 列を入れ替えてtuple_sizeを計算し直す。不正な列ならtableは変えない
+    pub fn set_columns(&mut self, columns: Vec<Column>) -> Result<(), anyhow::Error> {
+        let previous = std::mem::replace(&mut self.columns, columns);
+        if let Err(e) = self.validate() {
+            self.columns = previous;
+            return Err(e);
+        }
+        self.tuple_size = self.compute_tuple_size();
+        Ok(())
+    }
+
     pub fn tuple_size(&self) -> usize {
+        self.tuple_size
+    }
+
+    fn compute_tuple_size(&self) -> usize {
         TUPLE_HEADER_SIZE
             + self
                 .columns
                 .iter()
-                .fold(0, |acc, c| match c.types.as_str() {
-                    "int" => acc + 4,
-                    "text" => acc + 256,
-                    _ => acc,
-                })
+                .fold(0, |acc, c| acc + c.column_type().map_or(0, |t| t.size()))
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Column {
     pub types: String,
     pub name: String,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+impl Column {
+    pub fn column_type(&self) -> Option<ColumnType> {
+        ColumnType::parse(&self.types)
+    }
+}
+
+// selectで`*, updated_at`と書くと加わる疑似列。tupleを書き込んだ時刻をmillisecondで返す
+pub const UPDATED_AT_COLUMN: &str = "updated_at";
+
+pub fn updated_at_column() -> Column {
+    Column {
+        types: format!("numeric({},0)", MAX_NUMERIC_PRECISION),
+        name: UPDATED_AT_COLUMN.to_string(),
+    }
+}
+
+// i64に収まる桁数
+pub const MAX_NUMERIC_PRECISION: u32 = 18;
+
+// textの値の後ろを、255byteまで何で埋めるか
+// 値の長さは先頭の1byteが正。insertでも読むときと同じく値の後ろの埋め草を落とすので、
+// 長さの内側に埋め草が入るのは長さが壊れたときだけ
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TextPadding {
+    #[default]
+    Null,
+    // 固定長のfileとして他のtoolで読みやすい。SQLのCHARと同じく、値の後ろの空白はinsertで落ちる
+    Space,
+}
+
+impl TextPadding {
+    pub fn byte(&self) -> u8 {
+        match self {
+            TextPadding::Null => 0,
+            TextPadding::Space => b' ',
+        }
+    }
+
+    // 値の後ろの0と埋め草を落とす。書いた値と読み直した値が食い違わないよう、insertと読むときの両方で使う
+    pub fn trim<'a>(&self, s: &'a str) -> &'a str {
+        let pad = self.byte() as char;
+        s.trim_end_matches(['\0', pad])
+    }
+}
+
+// schemaのtypesを解釈したもの
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColumnType {
+    Int,
+    Text,
+    // utf-8か確かめずに持つbyte列。textと同じく255byteまで
+    Blob,
+    // numeric(precision,scale)。全体でprecision桁、そのうち小数点以下がscale桁
+    Numeric { precision: u32, scale: u32 },
+}
+
+impl ColumnType {
+    pub fn parse(types: &str) -> Option<Self> {
+        match types {
+            "int" => Some(ColumnType::Int),
+            "text" => Some(ColumnType::Text),
+            "blob" => Some(ColumnType::Blob),
+            t => {
+                let args = t.strip_prefix("numeric(")?.strip_suffix(')')?;
+                let (precision, scale) = args.split_once(',')?;
+                let precision: u32 = precision.trim().parse().ok()?;
+                let scale: u32 = scale.trim().parse().ok()?;
+                if precision == 0 || precision > MAX_NUMERIC_PRECISION || scale > precision {
+                    return None;
+                }
+                Some(ColumnType::Numeric { precision, scale })
+            }
+        }
+    }
+
+    // page上で使うbyte数
+    pub fn size(&self) -> usize {
+        match self {
+            ColumnType::Int => 4,
+            ColumnType::Text | ColumnType::Blob => 256,
+            // scaleはcatalogにあるので、値だけを8byteで持つ
+            ColumnType::Numeric { .. } => 8,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum AttributeType {
     Int(i32),
     Text(String),
+    Decimal(Decimal),
+    Blob(Vec<u8>),
+}
+
+// 10^scale倍した整数で持つ固定小数点数。19.99はscale 2なら1999
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Decimal {
+    pub unscaled: i64,
+    pub scale: u32,
+}
+
+impl Decimal {
+    // 小数点以下がscale桁より多い値は、丸めずに断る
+    pub fn parse(raw: &str, precision: u32, scale: u32) -> Result<Self, anyhow::Error> {
+        let invalid = || anyhow::anyhow!("{} is not a numeric", raw);
+
+        let (negative, digits) = match raw.strip_prefix('-') {
+            Some(d) => (true, d),
+            None => (false, raw),
+        };
+        let (int_part, frac_part) = digits.split_once('.').unwrap_or((digits, ""));
+        if int_part.is_empty()
+            || !int_part.bytes().all(|b| b.is_ascii_digit())
+            || !frac_part.bytes().all(|b| b.is_ascii_digit())
+            || (digits.contains('.') && frac_part.is_empty())
+        {
+            return Err(invalid());
+        }
+        if frac_part.len() > scale as usize {
+            return Err(anyhow::anyhow!(
+                "{} has more than {} digits after the decimal point",
+                raw,
+                scale
+            ));
+        }
+        let int_part = int_part.trim_start_matches('0');
+        if int_part.len() > (precision - scale) as usize {
+            return Err(anyhow::anyhow!(
+                "{} does not fit in numeric({},{})",
+                raw,
+                precision,
+                scale
+            ));
+        }
+
+        let padded = format!("{}{:0<width$}", int_part, frac_part, width = scale as usize);
+        let unscaled = if padded.is_empty() {
+            0
+        } else {
+            padded.parse::<i64>().map_err(|_| invalid())?
+        };
+
+        Ok(Self {
+            unscaled: if negative { -unscaled } else { unscaled },
+            scale,
+        })
+    }
+
+    // 同じscaleどうしだけを足す。桁あふれはNone
+    pub fn checked_add(&self, other: &Decimal) -> Option<Decimal> {
+        if self.scale != other.scale {
+            return None;
+        }
+        Some(Decimal {
+            unscaled: self.unscaled.checked_add(other.unscaled)?,
+            scale: self.scale,
+        })
+    }
+}
+
+impl std::fmt::Display for Decimal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let sign = if self.unscaled < 0 { "-" } else { "" };
+        let abs = self.unscaled.unsigned_abs();
+        if self.scale == 0 {
+            return write!(f, "{}{}", sign, abs);
+        }
+        let unit = 10_u64.pow(self.scale);
+        write!(
+            f,
+            "{}{}.{:0width$}",
+            sign,
+            abs / unit,
+            abs % unit,
+            width = self.scale as usize
+        )
+    }
+}
+
+// textはpage上で長さ1byteと255byteの領域に詰める
+pub const MAX_TEXT_LEN: usize = 255;
+
+impl AttributeType {
+    // queryに書かれた値を、columnの型に合わせて変換する
+    pub fn from_str_typed(type_name: &str, raw: &str) -> Result<Self, anyhow::Error> {
+        let column_type = ColumnType::parse(type_name)
+            .ok_or_else(|| anyhow::anyhow!("{} is not a known type", type_name))?;
+
+        match column_type {
+            ColumnType::Int => raw
+                .parse()
+                .map(AttributeType::Int)
+                .map_err(|_| anyhow::anyhow!("{} is not an int", raw)),
+            ColumnType::Text => {
+                let quoted = raw
+                    .strip_prefix('\'')
+                    .and_then(|s| s.strip_suffix('\''))
+                    .ok_or_else(|| anyhow::anyhow!("text must be quoted with ': {}", raw))?;
+
+                // 中の'は''と重ねて書く
+                if quoted.replace("''", "").contains('\'') {
+                    return Err(anyhow::anyhow!(
+                        "' inside text must be written as '': {}",
+                        raw
+                    ));
+                }
+                let s = quoted.replace("''", "'");
+
+                if s.len() > MAX_TEXT_LEN {
+                    return Err(anyhow::anyhow!(
+                        "text is {} bytes, longer than {}",
+                        s.len(),
+                        MAX_TEXT_LEN
+                    ));
+                }
+
+                Ok(AttributeType::Text(s))
+            }
+            ColumnType::Numeric { precision, scale } => {
+                Decimal::parse(raw, precision, scale).map(AttributeType::Decimal)
+            }
+            // x'deadbeef'のように16進数で書く
+            ColumnType::Blob => {
+                let hex = raw
+                    .strip_prefix("x'")
+                    .or_else(|| raw.strip_prefix("X'"))
+                    .and_then(|s| s.strip_suffix('\''))
+                    .ok_or_else(|| anyhow::anyhow!("blob must be written as x'<hex>': {}", raw))?;
+                parse_hex(hex).map(AttributeType::Blob)
+            }
+        }
+    }
+
+    // JSONの値を、columnの型に合わせて変換する
+    // 数値の列には文字列で書いた数値も、textの列には数値や真偽値も受け付ける
+    pub fn from_json_typed(
+        type_name: &str,
+        value: &serde_json::Value,
+    ) -> Result<Self, anyhow::Error> {
+        let column_type = ColumnType::parse(type_name)
+            .ok_or_else(|| anyhow::anyhow!("{} is not a known type", type_name))?;
+        let raw = match value {
+            serde_json::Value::String(s) => s.clone(),
+            serde_json::Value::Number(n) => n.to_string(),
+            serde_json::Value::Bool(b) if column_type == ColumnType::Text => b.to_string(),
+            v => return Err(anyhow::anyhow!("{} cannot be stored as {}", v, type_name)),
+        };
+
+        match column_type {
+            ColumnType::Text => {
+                if raw.len() > MAX_TEXT_LEN {
+                    return Err(anyhow::anyhow!(
+                        "text is {} bytes, longer than {}",
+                        raw.len(),
+                        MAX_TEXT_LEN
+                    ));
+                }
+                Ok(AttributeType::Text(raw))
+            }
+            // JSONでは"deadbeef"のように16進数だけを書く。返すときの"\\xdeadbeef"もそのまま受け付ける
+            ColumnType::Blob => {
+                parse_hex(raw.strip_prefix("\\x").unwrap_or(&raw)).map(AttributeType::Blob)
+            }
+            _ => Self::from_str_typed(type_name, &raw),
+        }
+    }
+
+    // clientに返すときの表記。from_str_typedとは違い、textを'で囲まない
+    pub fn to_display(&self) -> String {
+        match self {
+            AttributeType::Int(v) => v.to_string(),
+            AttributeType::Text(v) => v.clone(),
+            AttributeType::Decimal(v) => v.to_string(),
+            // PostgreSQLのbyteaと同じ表記
+            AttributeType::Blob(v) => {
+                let hex: String = v.iter().map(|b| format!("{:02x}", b)).collect();
+                format!("\\x{}", hex)
+            }
+        }
+    }
+}
+
+// 値で比べる。indexのkeyやorder byの並びに使う
+// - IntとDecimalは数として比べる。1と1.00は等しく、hashも同じになる
+// - 種類の違う値は、数 < Text < Blobの順に並べる
+// - TextとBlobはbyte列として辞書順に比べる
+// NULLは持てないので、NULLの位置は決めていない
+impl PartialEq for AttributeType {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for AttributeType {}
+
+impl PartialOrd for AttributeType {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for AttributeType {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (AttributeType::Text(a), AttributeType::Text(b)) => a.cmp(b),
+            (AttributeType::Blob(a), AttributeType::Blob(b)) => a.cmp(b),
+            _ => match (self.as_decimal(), other.as_decimal()) {
+                (Some(a), Some(b)) => {
+                    // scaleはMAX_NUMERIC_PRECISION以下なので、i128に揃えてもあふれない
+                    let scale = a.scale.max(b.scale);
+                    let widen = |d: Decimal| d.unscaled as i128 * 10_i128.pow(scale - d.scale);
+                    widen(a).cmp(&widen(b))
+                }
+                _ => self.rank().cmp(&other.rank()),
+            },
+        }
+    }
+}
+
+impl Hash for AttributeType {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.rank().hash(state);
+        match self {
+            // 等しい数が同じhashになるよう、末尾の0を落としてから使う
+            AttributeType::Int(_) | AttributeType::Decimal(_) => {
+                let mut d = self.as_decimal().unwrap();
+                while d.scale > 0 && d.unscaled % 10 == 0 {
+                    d.unscaled /= 10;
+                    d.scale -= 1;
+                }
+                d.hash(state);
+            }
+            AttributeType::Text(v) => v.hash(state),
+            AttributeType::Blob(v) => v.hash(state),
+        }
+    }
+}
+
+impl AttributeType {
+    fn as_decimal(&self) -> Option<Decimal> {
+        match self {
+            AttributeType::Int(v) => Some(Decimal {
+                unscaled: *v as i64,
+                scale: 0,
+            }),
+            AttributeType::Decimal(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    // 種類の違う値を比べるときの順
+    fn rank(&self) -> u8 {
+        match self {
+            AttributeType::Int(_) | AttributeType::Decimal(_) => 0,
+            AttributeType::Text(_) => 1,
+            AttributeType::Blob(_) => 2,
+        }
+    }
+}
+
+fn parse_hex(hex: &str) -> Result<Vec<u8>, anyhow::Error> {
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(anyhow::anyhow!("{} is not a hex string", hex));
+    }
+    let bytes: Vec<u8> = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..(i + 2)], 16).unwrap())
+        .collect();
+    if bytes.len() > MAX_TEXT_LEN {
+        return Err(anyhow::anyhow!(
+            "blob is {} bytes, longer than {}",
+            bytes.len(),
+            MAX_TEXT_LEN
+        ));
+    }
+
+    Ok(bytes)
 }
 
 #[cfg(test)]
@@ -95,7 +669,7 @@ mod tests {
 
     #[test]
     fn catalog_from_json() {
-        let c = Catalog::from_json(JSON);
+        let c = Catalog::from_json(JSON).unwrap();
 
         // assert table num
         assert_eq!(1, c.schemas.len());
@@ -113,12 +687,477 @@ mod tests {
         }
     }
 
+    #[test]
+    fn catalog_table_names() {
+        let c = Catalog::from_json(JSON).unwrap();
+        assert_eq!(c.table_names().collect::<Vec<_>>(), vec!["table1"]);
+        let columns: Vec<&str> = c
+            .columns_of("table1")
+            .unwrap()
+            .iter()
+            .map(|c| c.name.as_str())
+            .collect();
+        assert_eq!(columns, vec!["column_int", "column_text"]);
+        assert!(c.columns_of("nothing").is_none());
+
+        let c = Catalog::from_json(
+            r#"{"schemas": [
+                {"table": {"name": "b", "columns": [{"types": "int", "name": "id"}]}},
+                {"table": {"name": "a", "columns": [{"types": "text", "name": "id"}]}}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(c.table_names().collect::<Vec<_>>(), vec!["b", "a"]);
+        assert_eq!(c.columns_of("a").unwrap()[0].types, "text");
+    }
+
     #[test]
     fn catalog_tuple_size() {
-        let c = Catalog::from_json(JSON);
+        let c = Catalog::from_json(JSON).unwrap();
         let schema = c.get_schema_by_table_name("table1").unwrap();
         let tuple_size = schema.table.tuple_size();
 
-        assert_eq!(tuple_size, 268)
+        assert_eq!(tuple_size, 268);
+        assert_eq!(tuple_size, schema.table.compute_tuple_size());
+
+        // 列を足すと計算し直される
+        let mut table = schema.table.clone();
+        let mut columns = table.columns().to_vec();
+        columns.push(Column {
+            types: "int".to_string(),
+            name: "added".to_string(),
+        });
+        table.set_columns(columns.clone()).unwrap();
+        assert_eq!(table.tuple_size(), table.compute_tuple_size());
+        assert!(table.tuple_size() > tuple_size);
+
+        // 名前が重なる列は入れず、前の列とtuple_sizeのまま
+        let before = table.clone();
+        columns.push(columns[0].clone());
+        assert!(table.set_columns(columns).is_err());
+        assert_eq!(table, before);
+    }
+
+    #[test]
+    fn catalog_duplicate_column() {
+        let json = r#"{
+            "schemas": [
+                {
+                    "table": {
+                        "name": "table1",
+                        "columns": [
+                            { "types": "int", "name": "id" },
+                            { "types": "text", "name": "id" }
+                        ]
+                    }
+                }
+            ]
+        }"#;
+
+        let e = Catalog::from_json(json).unwrap_err();
+        assert!(e.to_string().contains("duplicate column id"));
+    }
+
+    #[test]
+    fn catalog_unknown_type() {
+        let json = r#"{
+            "schemas": [
+                {
+                    "table": {
+                        "name": "table1",
+                        "columns": [
+                            { "types": "int", "name": "id" },
+                            { "types": "float", "name": "score" }
+                        ]
+                    }
+                }
+            ]
+        }"#;
+
+        let e = Catalog::from_json(json).unwrap_err();
+        assert!(
+            e.to_string().contains("table1.score: unknown type float"),
+            "{}",
+            e
+        );
+    }
+
+    #[test]
+    fn catalog_primary_key() {
+        let json = |primary_key: &str| {
+            format!(
+                r#"{{
+                    "schemas": [
+                        {{
+                            "table": {{
+                                "name": "table1",
+                                "columns": [
+                                    {{ "types": "int", "name": "user_id" }},
+                                    {{ "types": "int", "name": "group_id" }}
+                                ],
+                                "primary_key": {}
+                            }}
+                        }}
+                    ]
+                }}"#,
+                primary_key
+            )
+        };
+
+        let catalog = Catalog::from_json(&json(r#"["user_id", "group_id"]"#)).unwrap();
+        assert_eq!(
+            catalog
+                .get_schema_by_table_name("table1")
+                .unwrap()
+                .table
+                .primary_key,
+            vec!["user_id".to_string(), "group_id".to_string()]
+        );
+
+        let e = Catalog::from_json(&json(r#"["user_id", "role"]"#)).unwrap_err();
+        assert!(e
+            .to_string()
+            .contains("primary key column role does not exist"));
+        let e = Catalog::from_json(&json(r#"["user_id", "user_id"]"#)).unwrap_err();
+        assert!(e
+            .to_string()
+            .contains("primary key has duplicate column user_id"));
+    }
+
+    #[test]
+    fn catalog_empty_column_name() {
+        let json = r#"{
+            "schemas": [
+                {
+                    "table": {
+                        "name": "table1",
+                        "columns": [
+                            { "types": "int", "name": "" }
+                        ]
+                    }
+                }
+            ]
+        }"#;
+
+        assert!(Catalog::from_json(json).is_err());
+    }
+
+    #[test]
+    fn catalog_text_padding() {
+        let json = |padding: &str| {
+            format!(
+                r#"{{
+                    "schemas": [
+                        {{
+                            "table": {{
+                                "name": "table1",
+                                "columns": [
+                                    {{ "types": "text", "name": "name" }}
+                                ]{}
+                            }}
+                        }}
+                    ]
+                }}"#,
+                padding
+            )
+        };
+
+        let padding = |c: Catalog| c.schemas[0].table.text_padding;
+        assert_eq!(
+            padding(Catalog::from_json(&json("")).unwrap()),
+            TextPadding::Null
+        );
+        assert_eq!(
+            padding(Catalog::from_json(&json(r#", "text_padding": "space""#)).unwrap()),
+            TextPadding::Space
+        );
+        assert!(Catalog::from_json(&json(r#", "text_padding": "tab""#)).is_err());
+    }
+
+    #[test]
+    fn catalog_zero_max_tuples_per_page() {
+        let json = r#"{
+            "schemas": [
+                {
+                    "table": {
+                        "name": "table1",
+                        "columns": [
+                            { "types": "int", "name": "id" }
+                        ],
+                        "max_tuples_per_page": 0
+                    }
+                }
+            ]
+        }"#;
+
+        assert!(Catalog::from_json(json).is_err());
+    }
+
+    #[test]
+    fn catalog_from_reader() {
+        let c = Catalog::from_reader(std::io::Cursor::new(JSON)).unwrap();
+
+        assert!(c.exist_table("table1"));
+        assert_eq!(
+            c.get_schema_by_table_name("table1")
+                .unwrap()
+                .table
+                .columns
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn catalog_from_path() {
+        let path = crate::test_util::temp_dir("catalog_from_path").join("schema.json");
+        std::fs::write(&path, JSON).unwrap();
+
+        let c = Catalog::from_path(path.to_str().unwrap()).unwrap();
+        assert!(c.exist_table("table1"));
+
+        assert!(Catalog::from_path("not_found.json").is_err());
+    }
+
+    #[test]
+    fn catalog_from_dir() {
+        let dir = crate::test_util::temp_dir("catalog_from_dir");
+        std::fs::write(dir.join("a.json"), JSON).unwrap();
+        std::fs::write(dir.join("b.json"), JSON.replace("table1", "table2")).unwrap();
+        std::fs::write(dir.join("note.txt"), "not a schema").unwrap();
+
+        let c = Catalog::from_dir(dir.to_str().unwrap()).unwrap();
+        assert_eq!(c.schemas.len(), 2);
+        assert!(c.exist_table("table1"));
+        assert!(c.exist_table("table2"));
+
+        std::fs::write(dir.join("c.json"), JSON).unwrap();
+        let e = Catalog::from_dir(dir.to_str().unwrap()).unwrap_err();
+        assert_eq!(e.to_string(), "table table1 is defined more than once");
+    }
+
+    #[test]
+    fn attribute_from_str_typed() {
+        assert_eq!(
+            AttributeType::from_str_typed("int", "-12").unwrap(),
+            AttributeType::Int(-12)
+        );
+        assert!(AttributeType::from_str_typed("int", "1.5").is_err());
+        assert!(AttributeType::from_str_typed("int", "'1'").is_err());
+        assert!(AttributeType::from_str_typed("int", "99999999999").is_err());
+
+        assert_eq!(
+            AttributeType::from_str_typed("text", "'hoge'").unwrap(),
+            AttributeType::Text("hoge".to_string())
+        );
+        assert_eq!(
+            AttributeType::from_str_typed("text", "''").unwrap(),
+            AttributeType::Text("".to_string())
+        );
+        assert!(AttributeType::from_str_typed("text", "hoge").is_err());
+        assert!(AttributeType::from_str_typed("text", "'").is_err());
+        let long = format!("'{}'", "a".repeat(MAX_TEXT_LEN + 1));
+        assert!(AttributeType::from_str_typed("text", &long).is_err());
+
+        assert!(AttributeType::from_str_typed("float", "1.0").is_err());
+    }
+
+    #[test]
+    fn attribute_numeric() {
+        let price = |raw| AttributeType::from_str_typed("numeric(10,2)", raw);
+        let decimal = |unscaled| AttributeType::Decimal(Decimal { unscaled, scale: 2 });
+
+        assert_eq!(price("19.99").unwrap(), decimal(1999));
+        assert_eq!(price("19.9").unwrap(), decimal(1990));
+        assert_eq!(price("-0.05").unwrap(), decimal(-5));
+        assert_eq!(price("12345678").unwrap(), decimal(1234567800));
+        assert!(price("19.999").is_err());
+        assert!(price("123456789").is_err());
+        assert!(price("1.").is_err());
+        assert!(price(".5").is_err());
+        assert!(price("'19.99'").is_err());
+
+        assert_eq!(price("19.99").unwrap().to_display(), "19.99");
+        assert_eq!(price("-0.05").unwrap().to_display(), "-0.05");
+        assert_eq!(
+            AttributeType::from_str_typed("numeric(4,0)", "42")
+                .unwrap()
+                .to_display(),
+            "42"
+        );
+
+        assert!(ColumnType::parse("numeric(19,2)").is_none());
+        assert!(ColumnType::parse("numeric(2,3)").is_none());
+        assert!(ColumnType::parse("numeric").is_none());
+    }
+
+    #[test]
+    fn attribute_blob() {
+        let blob = |raw| AttributeType::from_str_typed("blob", raw);
+
+        assert_eq!(
+            blob("x'DEADbeef'").unwrap(),
+            AttributeType::Blob(vec![0xde, 0xad, 0xbe, 0xef])
+        );
+        assert_eq!(blob("X''").unwrap(), AttributeType::Blob(Vec::new()));
+        assert!(blob("'deadbeef'").is_err());
+        assert!(blob("x'abc'").is_err());
+        assert!(blob("x'zz'").is_err());
+        assert!(blob(&format!("x'{}'", "00".repeat(MAX_TEXT_LEN + 1))).is_err());
+
+        assert_eq!(
+            AttributeType::from_json_typed("blob", &"ff00".into()).unwrap(),
+            AttributeType::Blob(vec![0xff, 0x00])
+        );
+        assert!(AttributeType::from_json_typed("blob", &1.into()).is_err());
+
+        // 読んだ値をそのままJSONで書き戻せる
+        let value = AttributeType::Blob(vec![0xde, 0xad, 0xbe, 0xef]);
+        let display = value.to_display();
+        assert_eq!(display, "\\xdeadbeef");
+        assert_eq!(
+            AttributeType::from_json_typed("blob", &display.into()).unwrap(),
+            value
+        );
+        assert!(AttributeType::from_json_typed("blob", &"\\x\\xff".into()).is_err());
+    }
+
+    #[test]
+    fn decimal_sum_exact() {
+        // 0.10を10回足すと、f64と違ってちょうど1.00になる
+        let dime = Decimal::parse("0.10", 10, 2).unwrap();
+        let total = (0..10).try_fold(
+            Decimal {
+                unscaled: 0,
+                scale: 2,
+            },
+            |acc, _| acc.checked_add(&dime),
+        );
+        assert_eq!(total, Some(Decimal::parse("1.00", 10, 2).unwrap()));
+        assert_eq!(total.unwrap().to_string(), "1.00");
+
+        let max = Decimal {
+            unscaled: i64::MAX,
+            scale: 2,
+        };
+        assert_eq!(max.checked_add(&dime), None);
+        assert_eq!(
+            dime.checked_add(&Decimal {
+                unscaled: 1,
+                scale: 3
+            }),
+            None
+        );
+    }
+
+    #[cfg(feature = "toml")]
+    const TOML: &str = r#"
+        [[schemas]]
+        [schemas.table]
+        name = "table1"
+
+        [[schemas.table.columns]]
+        types = "int"
+        name = "column_int"
+
+        [[schemas.table.columns]]
+        types = "text"
+        name = "column_text"
+    "#;
+
+    #[cfg(feature = "yaml")]
+    const YAML: &str = r#"
+schemas:
+  - table:
+      name: table1
+      columns:
+        - types: int
+          name: column_int
+        - types: text
+          name: column_text
+"#;
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn catalog_from_toml() {
+        let json = Catalog::from_json(JSON).unwrap();
+        let toml = Catalog::from_toml(TOML).unwrap();
+
+        assert_eq!(json, toml);
+
+        let path = crate::test_util::temp_dir("catalog_from_toml").join("schema.toml");
+        std::fs::write(&path, TOML).unwrap();
+        assert_eq!(json, Catalog::from_path(path.to_str().unwrap()).unwrap());
+    }
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn catalog_from_yaml() {
+        let json = Catalog::from_json(JSON).unwrap();
+        let yaml = Catalog::from_yaml(YAML).unwrap();
+
+        assert_eq!(json, yaml);
+
+        let path = crate::test_util::temp_dir("catalog_from_yaml").join("schema.yml");
+        std::fs::write(&path, YAML).unwrap();
+        assert_eq!(json, Catalog::from_path(path.to_str().unwrap()).unwrap());
+    }
+
+    #[test]
+    fn attribute_to_display() {
+        assert_eq!(AttributeType::Int(12).to_display(), "12");
+        assert_eq!(AttributeType::Int(-3).to_display(), "-3");
+        assert_eq!(AttributeType::Text("hoge".to_string()).to_display(), "hoge");
+        assert_eq!(AttributeType::Text("".to_string()).to_display(), "");
+    }
+
+    #[test]
+    fn catalog_attribute_order() {
+        let decimal = |unscaled, scale| AttributeType::Decimal(Decimal { unscaled, scale });
+        let mut values = vec![
+            AttributeType::Blob(vec![1]),
+            AttributeType::Text("b".to_string()),
+            AttributeType::Int(3),
+            decimal(250, 2),
+            AttributeType::Blob(vec![0, 9]),
+            AttributeType::Int(-1),
+            AttributeType::Text("a".to_string()),
+            decimal(-15, 1),
+        ];
+        values.sort();
+        assert_eq!(
+            values,
+            vec![
+                decimal(-15, 1),
+                AttributeType::Int(-1),
+                decimal(250, 2),
+                AttributeType::Int(3),
+                AttributeType::Text("a".to_string()),
+                AttributeType::Text("b".to_string()),
+                AttributeType::Blob(vec![0, 9]),
+                AttributeType::Blob(vec![1]),
+            ]
+        );
+
+        // 1と1.00は同じkeyになる
+        assert_eq!(AttributeType::Int(1), decimal(100, 2));
+        assert_eq!(decimal(10, 1), decimal(100, 2));
+        assert_ne!(AttributeType::Int(1), AttributeType::Text("1".to_string()));
+
+        let mut map = HashMap::new();
+        map.insert(AttributeType::Int(1), "int");
+        map.insert(decimal(100, 2), "decimal");
+        map.insert(AttributeType::Text("1".to_string()), "text");
+        assert_eq!(map.len(), 2);
+        assert_eq!(map[&decimal(10, 1)], "decimal");
+
+        let mut tree = std::collections::BTreeMap::new();
+        for (i, v) in values.into_iter().enumerate() {
+            tree.insert(v, i);
+        }
+        tree.insert(decimal(300, 2), 8);
+        assert_eq!(tree.len(), 8);
+        assert_eq!(tree[&AttributeType::Int(3)], 8);
+        assert_eq!(tree.keys().next(), Some(&decimal(-15, 1)));
     }
 }
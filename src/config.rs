@@ -0,0 +1,323 @@
+use std::time::Duration;
+
+use anyhow::anyhow;
+use log::LevelFilter;
+
+use crate::{
+    server::{Protocol, ServerOptions},
+    storage::wal::Durability,
+};
+
+pub const HELP: &str = "usage: aqua_db [options]
+
+options:
+  --listen <addr>        listenするaddress (AQUA_DB_LISTEN, default 127.0.0.1:8080)
+  --listen-unix <path>   TCPの代わりにunix domain socketでlistenする (AQUA_DB_LISTEN_UNIX)
+  --protocol <name>      http、1行ずつやりとりするline、psqlから使えるpostgresのどれか (AQUA_DB_PROTOCOL, default http)
+  --data-dir <dir>       table fileとwalを置くdirectory (AQUA_DB_DATA_DIR, default ./data)
+  --schema <path>        schemaのfileかdirectory (AQUA_DB_SCHEMA, default schema.json)
+  --pool-size <n>        buffer poolのpage数 (AQUA_DB_POOL_SIZE, default 10)
+  --durability <mode>    commit時にfsyncするならsync、OSに渡すだけならbuffered、まとめてfsyncするならgroup (AQUA_DB_DURABILITY, default sync)
+  --group-commit-ms <n>  groupで、fsyncせずに待つ最長の時間 (AQUA_DB_GROUP_COMMIT_MS, default 10)
+  --group-commit-size <n> groupで、これだけcommitがたまったらすぐにfsyncする (AQUA_DB_GROUP_COMMIT_SIZE, default 16)
+  --max-rows <n>         selectが返す行数の上限。0なら上限なし (AQUA_DB_MAX_ROWS, default 10000)
+  --query-cache <n>      selectの結果を覚えておく件数。0ならcacheしない (AQUA_DB_QUERY_CACHE, default 0)
+  --max-body-bytes <n>   受け付けるrequest bodyの上限 (AQUA_DB_MAX_BODY_BYTES, default 1048576)
+  --max-connections <n>  同時に処理する接続の上限。超えた分は断る (AQUA_DB_MAX_CONNECTIONS, default 64)
+  --max-concurrent-writes <n> 同時に実行する書き込みの上限。超えた分は待つ。0なら上限なし (AQUA_DB_MAX_CONCURRENT_WRITES, default 0)
+  --query-timeout-ms <n> 1つのstatementに許す実行時間。0なら上限なし (AQUA_DB_QUERY_TIMEOUT_MS, default 5000)
+  --checkpoint-interval <n> dirtyなpageを書き出す間隔の秒数。0なら定期的には書き出さない (AQUA_DB_CHECKPOINT_INTERVAL, default 60)
+  --init-file <path>     接続を受け付ける前に実行するSQLのfile (AQUA_DB_INIT_FILE)
+  --log-level <level>    off, error, warn, info, debug, traceのいずれか (AQUA_DB_LOG_LEVEL, default info)
+  --redact-literals      logに出すqueryの値を?に置き換える
+  --auth-token <secret>  Authorization: Bearer <secret>のないrequestを401で断る (AQUA_DB_AUTH_TOKEN)
+  --auth-health          /healthにもtokenを求める
+  --read-only            全ての書き込みを拒否する
+  -h, --help             このhelpを表示する";
+
+// serverの起動に使う設定。引数、環境変数、既定値の順に決まる
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub listen: String,
+    // あればTCPのportは開かない
+    pub listen_unix: Option<String>,
+    pub data_dir: String,
+    pub schema: String,
+    pub init_file: Option<String>,
+    pub pool_size: usize,
+    pub durability: Durability,
+    pub read_only: bool,
+    pub log_level: LevelFilter,
+    pub server: ServerOptions,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: "127.0.0.1:8080".to_string(),
+            listen_unix: None,
+            data_dir: "./data".to_string(),
+            schema: "schema.json".to_string(),
+            init_file: None,
+            pool_size: 10,
+            durability: Durability::Sync,
+            read_only: false,
+            log_level: LevelFilter::Info,
+            server: ServerOptions::default(),
+        }
+    }
+}
+
+impl Config {
+    // --helpが指定されたときはNoneを返す
+    pub fn parse(
+        args: impl IntoIterator<Item = String>,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Option<Self>, anyhow::Error> {
+        let mut values = Vec::new();
+        let mut read_only = false;
+        let mut redact_literals = false;
+        let mut auth_health = false;
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(None),
+                "--read-only" => read_only = true,
+                "--redact-literals" => redact_literals = true,
+                "--auth-health" => auth_health = true,
+                "--listen"
+                | "--listen-unix"
+                | "--data-dir"
+                | "--schema"
+                | "--pool-size"
+                | "--durability"
+                | "--max-rows"
+                | "--query-cache"
+                | "--log-level"
+                | "--max-body-bytes"
+                | "--max-connections"
+                | "--max-concurrent-writes"
+                | "--query-timeout-ms"
+                | "--checkpoint-interval"
+                | "--init-file"
+                | "--auth-token"
+                | "--group-commit-ms"
+                | "--group-commit-size"
+                | "--protocol" => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| anyhow!("{} requires a value", arg))?;
+                    values.push((arg, value));
+                }
+                _ => return Err(anyhow!("unknown argument {}\n\n{}", arg, HELP)),
+            }
+        }
+
+        // 同じ引数が複数あれば後のものを使う
+        let value = |name: &str, env_name: &str| {
+            values
+                .iter()
+                .rev()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| v.clone())
+                .or_else(|| env(env_name))
+        };
+        let number = |name: &str, env_name: &str| {
+            value(name, env_name)
+                .map(|v| {
+                    v.parse::<usize>()
+                        .map_err(|_| anyhow!("{} expects a number, got {}", name, v))
+                })
+                .transpose()
+        };
+
+        let mut config = Config {
+            read_only,
+            ..Default::default()
+        };
+        if let Some(v) = value("--listen", "AQUA_DB_LISTEN") {
+            config.listen = v;
+        }
+        config.listen_unix = value("--listen-unix", "AQUA_DB_LISTEN_UNIX");
+        if cfg!(not(unix)) && config.listen_unix.is_some() {
+            return Err(anyhow!("--listen-unix is not supported on this platform"));
+        }
+        if let Some(v) = value("--protocol", "AQUA_DB_PROTOCOL") {
+            config.server.protocol = Protocol::parse(&v)?;
+        }
+        if let Some(v) = value("--data-dir", "AQUA_DB_DATA_DIR") {
+            config.data_dir = v;
+        }
+        if let Some(v) = value("--schema", "AQUA_DB_SCHEMA") {
+            config.schema = v;
+        }
+        config.init_file = value("--init-file", "AQUA_DB_INIT_FILE");
+        if let Some(n) = number("--pool-size", "AQUA_DB_POOL_SIZE")? {
+            if n == 0 {
+                return Err(anyhow!("--pool-size must be at least 1"));
+            }
+            config.pool_size = n;
+        }
+        if let Some(v) = value("--durability", "AQUA_DB_DURABILITY") {
+            config.durability = Durability::parse(&v)?;
+        }
+        let group_commit_ms = number("--group-commit-ms", "AQUA_DB_GROUP_COMMIT_MS")?;
+        let group_commit_size = number("--group-commit-size", "AQUA_DB_GROUP_COMMIT_SIZE")?;
+        if let Durability::Group(group) = &mut config.durability {
+            if let Some(n) = group_commit_ms {
+                if n == 0 {
+                    return Err(anyhow!("--group-commit-ms must be at least 1"));
+                }
+                group.interval = Duration::from_millis(n as u64);
+            }
+            if let Some(n) = group_commit_size {
+                if n == 0 {
+                    return Err(anyhow!("--group-commit-size must be at least 1"));
+                }
+                group.max_commits = n;
+            }
+        } else if group_commit_ms.is_some() || group_commit_size.is_some() {
+            return Err(anyhow!(
+                "--group-commit-ms and --group-commit-size need --durability group"
+            ));
+        }
+        if let Some(n) = number("--max-rows", "AQUA_DB_MAX_ROWS")? {
+            config.server.max_rows = if n == 0 { None } else { Some(n) };
+        }
+        if let Some(n) = number("--query-cache", "AQUA_DB_QUERY_CACHE")? {
+            config.server.query_cache_size = n;
+        }
+        if let Some(n) = number("--max-body-bytes", "AQUA_DB_MAX_BODY_BYTES")? {
+            if n == 0 {
+                return Err(anyhow!("--max-body-bytes must be at least 1"));
+            }
+            config.server.max_body_bytes = n;
+        }
+        if let Some(n) = number("--max-connections", "AQUA_DB_MAX_CONNECTIONS")? {
+            if n == 0 {
+                return Err(anyhow!("--max-connections must be at least 1"));
+            }
+            config.server.max_connections = n;
+        }
+        if let Some(n) = number("--max-concurrent-writes", "AQUA_DB_MAX_CONCURRENT_WRITES")? {
+            config.server.max_concurrent_writes = (n > 0).then_some(n);
+        }
+        if let Some(n) = number("--query-timeout-ms", "AQUA_DB_QUERY_TIMEOUT_MS")? {
+            config.server.query_timeout = (n > 0).then(|| Duration::from_millis(n as u64));
+        }
+        if let Some(n) = number("--checkpoint-interval", "AQUA_DB_CHECKPOINT_INTERVAL")? {
+            config.server.checkpoint_interval = (n > 0).then(|| Duration::from_secs(n as u64));
+        }
+        if let Some(v) = value("--log-level", "AQUA_DB_LOG_LEVEL") {
+            config.log_level = v.parse().map_err(|_| anyhow!("unknown log level {}", v))?;
+        }
+        config.server.redact_literals = redact_literals;
+        config.server.auth_token = value("--auth-token", "AQUA_DB_AUTH_TOKEN");
+        if config.server.auth_token.as_deref() == Some("") {
+            return Err(anyhow!("--auth-token must not be empty"));
+        }
+        config.server.auth_health = auth_health;
+
+        Ok(Some(config))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{server::DEFAULT_MAX_ROWS, storage::wal::GroupCommit};
+
+    fn args(s: &str) -> Vec<String> {
+        s.split_whitespace().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn config_default() {
+        let config = Config::parse(args(""), |_| None).unwrap().unwrap();
+
+        assert_eq!(config, Config::default());
+        assert_eq!(config.server.max_rows, Some(DEFAULT_MAX_ROWS));
+        assert_eq!(Config::parse(args("--help"), |_| None).unwrap(), None);
+    }
+
+    #[test]
+    fn config_args_and_env() {
+        let env = |name: &str| match name {
+            "AQUA_DB_LISTEN" => Some("0.0.0.0:9000".to_string()),
+            "AQUA_DB_POOL_SIZE" => Some("32".to_string()),
+            "AQUA_DB_AUTH_TOKEN" => Some("secret".to_string()),
+            "AQUA_DB_PROTOCOL" => Some("line".to_string()),
+            "AQUA_DB_MAX_CONNECTIONS" => Some("8".to_string()),
+            _ => None,
+        };
+
+        let config = Config::parse(
+            args("--pool-size 64 --data-dir /tmp/aqua --durability buffered --max-rows 0 --read-only --log-level warn --redact-literals --max-body-bytes 64 --query-timeout-ms 0 --checkpoint-interval 0 --max-concurrent-writes 2"),
+            env,
+        )
+        .unwrap()
+        .unwrap();
+
+        // 引数が環境変数より優先される
+        assert_eq!(config.pool_size, 64);
+        assert_eq!(config.listen, "0.0.0.0:9000");
+        assert_eq!(config.data_dir, "/tmp/aqua");
+        assert_eq!(config.durability, Durability::Buffered);
+        assert_eq!(config.server.max_rows, None);
+        assert!(config.read_only);
+        assert_eq!(config.log_level, LevelFilter::Warn);
+        assert!(config.server.redact_literals);
+        assert_eq!(config.server.max_body_bytes, 64);
+        assert_eq!(config.server.query_timeout, None);
+        assert_eq!(config.server.checkpoint_interval, None);
+        assert_eq!(config.server.auth_token, Some("secret".to_string()));
+        assert_eq!(config.server.protocol, Protocol::Line);
+        assert_eq!(config.server.max_connections, 8);
+        assert_eq!(config.server.max_concurrent_writes, Some(2));
+        assert_eq!(config.listen_unix, None);
+
+        let config = Config::parse(args("--listen-unix /tmp/aqua.sock"), env)
+            .unwrap()
+            .unwrap();
+        assert_eq!(config.listen_unix, Some("/tmp/aqua.sock".to_string()));
+
+        let config = Config::parse(args("--protocol postgres"), env)
+            .unwrap()
+            .unwrap();
+        assert_eq!(config.server.protocol, Protocol::Postgres);
+
+        let config = Config::parse(
+            args("--durability group --group-commit-ms 20 --group-commit-size 4"),
+            |_| None,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            config.durability,
+            Durability::Group(GroupCommit {
+                interval: Duration::from_millis(20),
+                max_commits: 4,
+            })
+        );
+    }
+
+    #[test]
+    fn config_invalid() {
+        for a in [
+            "--pool-size 0",
+            "--pool-size ten",
+            "--durability always",
+            "--log-level loud",
+            "--max-body-bytes 0",
+            "--max-connections 0",
+            "--group-commit-ms 5",
+            "--durability group --group-commit-size 0",
+            "--listen",
+            "--port 8080",
+            "--protocol grpc",
+        ] {
+            assert!(Config::parse(args(a), |_| None).is_err(), "{}", a);
+        }
+    }
+}
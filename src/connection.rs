@@ -0,0 +1,237 @@
+use std::{
+    io,
+    net::{SocketAddr, TcpListener, TcpStream},
+    time::Duration,
+};
+
+#[cfg(unix)]
+use std::{
+    fs,
+    os::unix::{
+        fs::{FileTypeExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+};
+
+// serverが読み書きする接続。TCPでもunix domain socketでも同じhandlerで処理する
+// 読み書きは&streamで行うので、handlerでは`for<'s> &'s S: Read + Write`も求める
+pub trait Connection: Send {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    // logに出す接続元
+    fn peer(&self) -> String;
+}
+
+impl Connection for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+
+    fn peer(&self) -> String {
+        self.peer_addr().map_or("-".to_string(), |a| a.to_string())
+    }
+}
+
+#[cfg(unix)]
+impl Connection for UnixStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_write_timeout(self, timeout)
+    }
+
+    // clientのsocketには名前がないことがほとんど
+    fn peer(&self) -> String {
+        "unix".to_string()
+    }
+}
+
+// serverがlistenしている先。止めるときは自分に接続してacceptを起こす
+#[derive(Clone, Debug)]
+pub enum Endpoint {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl Endpoint {
+    pub fn wake(&self) -> io::Result<()> {
+        match self {
+            Endpoint::Tcp(addr) => TcpStream::connect(addr).map(|_| ()),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => UnixStream::connect(path).map(|_| ()),
+        }
+    }
+}
+
+impl From<SocketAddr> for Endpoint {
+    fn from(addr: SocketAddr) -> Self {
+        Endpoint::Tcp(addr)
+    }
+}
+
+pub trait Listener {
+    type Stream: Connection;
+
+    fn accept(&self) -> io::Result<Self::Stream>;
+    fn endpoint(&self) -> io::Result<Endpoint>;
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    fn accept(&self) -> io::Result<TcpStream> {
+        TcpListener::accept(self).map(|(s, _)| s)
+    }
+
+    fn endpoint(&self) -> io::Result<Endpoint> {
+        Ok(Endpoint::Tcp(self.local_addr()?))
+    }
+}
+
+// socket fileの持ち主だけが接続できるようにする
+#[cfg(unix)]
+const SOCKET_MODE: u32 = 0o600;
+
+// bindしたsocket fileは、listenerと一緒に消す
+#[cfg(unix)]
+pub struct UnixSocketListener {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+#[cfg(unix)]
+impl UnixSocketListener {
+    // 前のserverが落ちて残したsocket fileは消してからbindする
+    // まだ接続できるsocketや、socketでないfileは消さない
+    pub fn bind(path: &Path) -> Result<Self, anyhow::Error> {
+        match fs::symlink_metadata(path) {
+            Ok(m) if m.file_type().is_socket() => {
+                if UnixStream::connect(path).is_ok() {
+                    return Err(anyhow::anyhow!(
+                        "another server is listening on {}",
+                        path.display()
+                    ));
+                }
+                fs::remove_file(path)?;
+            }
+            Ok(_) => {
+                return Err(anyhow::anyhow!(
+                    "{} exists and is not a socket",
+                    path.display()
+                ))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(anyhow::anyhow!("{}: {}", path.display(), e)),
+        }
+
+        // bindした直後はumaskで決まる権限になるので、他のuserが入れないdirectoryの中でbindし、
+        // 権限を絞ってから置き場所へ移す
+        let private = private_dir(path)?;
+        let bound = private.join("socket");
+        let result = UnixListener::bind(&bound)
+            .map_err(|e| anyhow::anyhow!("cannot bind {}: {}", path.display(), e))
+            .and_then(|listener| {
+                fs::set_permissions(&bound, fs::Permissions::from_mode(SOCKET_MODE))?;
+                fs::rename(&bound, path)?;
+                Ok(listener)
+            });
+        let _ = fs::remove_file(&bound);
+        fs::remove_dir(&private)?;
+        let listener = result?;
+
+        Ok(Self {
+            listener,
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+// socket fileと同じdirectoryに、持ち主だけが入れる一時directoryを作る
+// 同じfilesystemなので、renameでsocket fileを移せる
+#[cfg(unix)]
+fn private_dir(path: &Path) -> Result<PathBuf, anyhow::Error> {
+    use std::os::unix::fs::DirBuilderExt;
+
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow::anyhow!("{} is not a file path", path.display()))?;
+    let dir = path.with_file_name(format!(
+        ".{}.{}.tmp",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+    fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&dir)
+        .map_err(|e| anyhow::anyhow!("cannot create {}: {}", dir.display(), e))?;
+
+    Ok(dir)
+}
+
+#[cfg(unix)]
+impl Listener for UnixSocketListener {
+    type Stream = UnixStream;
+
+    fn accept(&self) -> io::Result<UnixStream> {
+        self.listener.accept().map(|(s, _)| s)
+    }
+
+    fn endpoint(&self) -> io::Result<Endpoint> {
+        Ok(Endpoint::Unix(self.path.clone()))
+    }
+}
+
+#[cfg(unix)]
+impl Drop for UnixSocketListener {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            log::warn!("cannot remove {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    #[test]
+    fn connection_unix_socket_file() {
+        let path = temp_dir("connection_unix_socket_file").join("aqua.sock");
+
+        // 落ちたserverが残したsocket fileは消してbindし直す
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+        let listener = UnixSocketListener::bind(&path).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, SOCKET_MODE);
+        // bindに使った一時directoryは残さない
+        let entries: Vec<_> = fs::read_dir(path.parent().unwrap())
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(entries, vec!["aqua.sock"]);
+
+        // listenしているsocketは消さない
+        let e = UnixSocketListener::bind(&path).err().unwrap();
+        assert!(e.to_string().contains("another server"), "{}", e);
+
+        listener.endpoint().unwrap().wake().unwrap();
+        assert!(listener.accept().is_ok());
+        drop(listener);
+        assert!(!path.exists());
+
+        fs::write(&path, "data").unwrap();
+        let e = UnixSocketListener::bind(&path).err().unwrap();
+        assert!(e.to_string().contains("is not a socket"), "{}", e);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "data");
+    }
+}
@@ -0,0 +1,62 @@
+use std::{fmt, io};
+
+// clientに返すstatusを決めるためのerrorの分類
+// 分類の要らないstorageのerrorは今まで通りanyhowで返し、serverでは500として扱う
+#[derive(Debug)]
+pub enum DbError {
+    // queryが文法や型として正しくない
+    Parse(String),
+    TableNotFound(String),
+    // 今のtransactionの状態では実行できない
+    Transaction(String),
+    ReadOnly(String),
+    // 実行時間の上限を超えて打ち切った
+    Cancelled(String),
+    // primary keyなどの制約に反する
+    Conflict(String),
+    Io(io::Error),
+}
+
+impl DbError {
+    pub fn status(&self) -> &'static str {
+        match self {
+            DbError::Parse(_) | DbError::Transaction(_) => "400 Bad Request",
+            DbError::TableNotFound(_) => "404 Not Found",
+            DbError::ReadOnly(_) => "403 Forbidden",
+            DbError::Cancelled(_) => "503 Service Unavailable",
+            DbError::Conflict(_) => "409 Conflict",
+            DbError::Io(_) => "500 Internal Server Error",
+        }
+    }
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbError::Parse(s)
+            | DbError::Transaction(s)
+            | DbError::ReadOnly(s)
+            | DbError::Cancelled(s)
+            | DbError::Conflict(s) => {
+                write!(f, "{}", s)
+            }
+            DbError::TableNotFound(table_name) => write!(f, "{} not exist", table_name),
+            DbError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for DbError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DbError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for DbError {
+    fn from(e: io::Error) -> Self {
+        DbError::Io(e)
+    }
+}
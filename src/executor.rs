@@ -1,13 +1,19 @@
 use crate::{
-    catalog::AttributeType,
+    catalog::{AttributeType, Decimal, Schema, Table, UPDATED_AT_COLUMN},
+    error::DbError,
     storage::{
-        buffer_pool::Buffer, buffer_pool_manager::BufferPoolManager, page::PageID,
-        replacer::Replacer, tuple::Tuple,
+        buffer_pool::Buffer,
+        buffer_pool_manager::{BufferPoolManager, BufferPoolStats, PoolSettings},
+        page::PageID,
+        replacer::Replacer,
+        tuple::Tuple,
+        wal::TxnID,
     },
 };
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     sync::{Arc, RwLock},
+    time::{Duration, Instant},
 };
 
 pub struct Executor<T>
@@ -15,19 +21,259 @@ where
     T: Replacer,
 {
     buffer_pool_manager: BufferPoolManager<T>,
+    next_txn_id: TxnID,
+    active_txns: HashSet<TxnID>,
+    checkpoint_threshold: Option<u64>,
+    read_only: bool,
+    // append onlyなtableごとの、次にinsertするpage
+    // Noneは最後のpageが埋まっていて、次は新しいpageを割り当てることを表す
+    append_cursors: HashMap<String, Option<PageID>>,
+    // primary keyのあるtableごとの、生きている行のkey。最初にinsertするときに作る
+    primary_keys: HashMap<String, HashSet<Vec<AttributeType>>>,
+    // scanでtupleのbyte列から作ったmapの数
+    // pageを読んでもmapは作らないので、絞り込みに一致しないtupleの分は増えない
+    maps_built: u64,
+    // 1つのstatementに許す実行時間と、今のstatementの期限
+    query_timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    // closeで書き出し済みなら、dropでは何もしない
+    closed: bool,
+}
+
+// walがこの大きさを超えたら、commitの後に自動でcheckpointする
+pub const DEFAULT_CHECKPOINT_THRESHOLD: u64 = 16 * 1024 * 1024;
+
+// scan_pagesで読み進めた位置。lockを放している間に足された行は、まだ読んでいないpageにあれば読む
+#[derive(Debug)]
+pub struct ScanCursor {
+    next_page: usize,
+    emitted: usize,
+    limit: Option<usize>,
+    done: bool,
+    truncated: bool,
+    deadline: Option<Instant>,
+}
+
+impl ScanCursor {
+    // 最後のpageまで読んだか、limitで打ち切った
+    pub fn done(&self) -> bool {
+        self.done
+    }
+
+    pub fn truncated(&self) -> bool {
+        self.truncated
+    }
+
+    // これまでに渡した行の数
+    pub fn emitted(&self) -> usize {
+        self.emitted
+    }
+}
+
+// rollbackのために、transaction中に追加したtupleの位置と、書き換える前のtupleを覚えておく
+#[derive(Debug)]
+pub struct Transaction {
+    id: TxnID,
+    inserted: Vec<(String, PageID, u32)>,
+    updated: Vec<(String, PageID, u32, Vec<u8>)>,
+    read_only: bool,
+}
+
+impl Transaction {
+    pub fn id(&self) -> TxnID {
+        self.id
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
 }
 
 impl<T: Replacer> Executor<T> {
     pub fn new(buffer_pool_manager: BufferPoolManager<T>) -> Self {
         Self {
             buffer_pool_manager,
+            next_txn_id: 1,
+            active_txns: HashSet::new(),
+            checkpoint_threshold: Some(DEFAULT_CHECKPOINT_THRESHOLD),
+            read_only: false,
+            append_cursors: HashMap::new(),
+            primary_keys: HashMap::new(),
+            maps_built: 0,
+            query_timeout: None,
+            deadline: None,
+            closed: false,
+        }
+    }
+
+    // dirtyなpageを書き出して閉じる。失敗を扱いたいときはdropに任せずこちらを呼ぶ
+    pub fn close(mut self) -> Result<(), anyhow::Error> {
+        self.closed = true;
+        if self.read_only {
+            return Ok(());
+        }
+
+        self.all_flush()
+    }
+
+    // 全ての書き込みを拒否し、table fileも書き込み権限なしで開く
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+        self.buffer_pool_manager.set_read_only(read_only);
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    // Noneなら時間で打ち切らない
+    pub fn set_query_timeout(&mut self, timeout: Option<Duration>) {
+        self.query_timeout = timeout;
+    }
+
+    // statementを始めるたびに呼び、期限を決め直す
+    pub fn start_statement(&mut self) {
+        self.deadline = self.query_timeout.map(|t| Instant::now() + t);
+    }
+
+    // pinしたbufferを全て戻した後に呼ぶ
+    fn check_deadline(&self) -> Result<(), anyhow::Error> {
+        match (self.deadline, self.query_timeout) {
+            (Some(deadline), Some(timeout)) if Instant::now() >= deadline => {
+                Err(DbError::Cancelled(format!("query cancelled: exceeded {:?}", timeout)).into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Noneで自動checkpointを止める
+    pub fn set_checkpoint_threshold(&mut self, threshold: Option<u64>) {
+        self.checkpoint_threshold = threshold;
+    }
+
+    fn primary_key_columns(&self, table_name: &str) -> Result<Vec<String>, anyhow::Error> {
+        Ok(self
+            .buffer_pool_manager
+            .schema(table_name)?
+            .table
+            .primary_key
+            .clone())
+    }
+
+    // primary keyの列の値を、schemaに書かれた順に並べる
+    fn primary_key_of(
+        columns: &[String],
+        attributes: &HashMap<String, AttributeType>,
+    ) -> Result<Vec<AttributeType>, anyhow::Error> {
+        columns
+            .iter()
+            .map(|c| {
+                attributes.get(c).cloned().ok_or_else(|| {
+                    DbError::Parse(format!("primary key column {} is missing", c)).into()
+                })
+            })
+            .collect()
+    }
+
+    // page上のtupleから、primary keyの列だけを読む
+    fn tuple_key(
+        columns: &[String],
+        tuple: &Tuple,
+        table: &Table,
+    ) -> Result<Vec<AttributeType>, anyhow::Error> {
+        columns
+            .iter()
+            .map(|c| {
+                tuple
+                    .body
+                    .get(c, table.columns(), table.text_padding)?
+                    .ok_or_else(|| {
+                        DbError::Parse(format!("primary key column {} is missing", c)).into()
+                    })
+            })
+            .collect()
+    }
+
+    // まだ作っていなければ、tableを1度だけ全て読んでkeyを集める
+    // recoveryで戻した行やrollbackで消した行も、pageの内容どおりに反映される
+    fn load_primary_keys(
+        &mut self,
+        table_name: &str,
+        columns: &[String],
+    ) -> Result<(), anyhow::Error> {
+        if self.primary_keys.contains_key(table_name) {
+            return Ok(());
         }
+
+        let mut keys = HashSet::new();
+        if let Some(PageID(last)) = self.buffer_pool_manager.last_page_id(table_name)? {
+            for i in 0..=last {
+                self.check_deadline()?;
+
+                let b = self
+                    .buffer_pool_manager
+                    .fetch_buffer(PageID(i), table_name)?;
+                let table = &self.buffer_pool_manager.schema(table_name)?.table;
+                let page_keys = b
+                    .read()
+                    .unwrap()
+                    .page
+                    .live_tuples()
+                    .map(|t| Self::tuple_key(columns, t, table))
+                    .collect::<Result<Vec<_>, _>>();
+                self.buffer_pool_manager
+                    .unpin_buffer(PageID(i), table_name)?;
+                keys.extend(page_keys?);
+            }
+        }
+
+        self.primary_keys.insert(table_name.to_string(), keys);
+        Ok(())
+    }
+
+    // 同じprimary keyの行が既にあれば断る
+    // transaction中に自分で追加した行も含めて比べる
+    fn check_primary_key(
+        &mut self,
+        attributes: &HashMap<String, AttributeType>,
+        table_name: &str,
+    ) -> Result<Option<Vec<AttributeType>>, anyhow::Error> {
+        let columns = self.primary_key_columns(table_name)?;
+        if columns.is_empty() {
+            return Ok(None);
+        }
+
+        let key = Self::primary_key_of(&columns, attributes)?;
+        self.load_primary_keys(table_name, &columns)?;
+
+        if self.primary_keys[table_name].contains(&key) {
+            let values: Vec<String> = key.iter().map(|v| v.to_display()).collect();
+            return Err(DbError::Conflict(format!(
+                "duplicate primary key ({}) = ({}) in {}",
+                columns.join(", "),
+                values.join(", "),
+                table_name
+            ))
+            .into());
+        }
+
+        Ok(Some(key))
     }
 
     fn find_writable_buffer(
         &mut self,
         table_name: &str,
     ) -> Result<Arc<RwLock<Buffer>>, anyhow::Error> {
+        // append onlyなtableは、埋まったpageを読み直さずにcursorのpageへ書く
+        match self.append_cursors.get(table_name) {
+            Some(Some(p_id)) => {
+                let p_id = *p_id;
+                return self.buffer_pool_manager.fetch_buffer(p_id, table_name);
+            }
+            Some(None) => return self.buffer_pool_manager.new_buffer(table_name),
+            None => {}
+        }
+
         let b = match self.buffer_pool_manager.last_page_id(table_name)? {
             Some(p_id) => {
                 let b = self.buffer_pool_manager.fetch_buffer(p_id, table_name)?;
@@ -51,125 +297,1601 @@ impl<T: Replacer> Executor<T> {
         Ok(Arc::clone(&b))
     }
 
+    pub fn begin(&mut self) -> Transaction {
+        let read_only = self.read_only;
+        self.start_transaction(read_only)
+    }
+
+    pub fn begin_read_only(&mut self) -> Transaction {
+        self.start_transaction(true)
+    }
+
+    fn start_transaction(&mut self, read_only: bool) -> Transaction {
+        let id = self.next_txn_id;
+        self.next_txn_id += 1;
+        self.active_txns.insert(id);
+
+        Transaction {
+            id,
+            inserted: Vec::new(),
+            updated: Vec::new(),
+            read_only,
+        }
+    }
+
+    // buffer poolに触る前に、書き込みが許されているかを確かめる
+    fn check_writable(&self, txn: &Transaction) -> Result<(), anyhow::Error> {
+        if self.read_only {
+            return Err(DbError::ReadOnly("server is read-only".to_string()).into());
+        }
+        if txn.read_only {
+            return Err(DbError::ReadOnly("transaction is read-only".to_string()).into());
+        }
+
+        Ok(())
+    }
+
+    // autocommit
     pub fn insert(
         &mut self,
         attributes: &HashMap<String, AttributeType>,
         table_name: &str,
     ) -> Result<(), anyhow::Error> {
+        let mut txn = self.begin();
+        if let Err(e) = self.insert_in(&mut txn, attributes, table_name) {
+            self.rollback(txn)?;
+            return Err(e);
+        }
+        self.commit(txn)
+    }
+
+    pub fn insert_in(
+        &mut self,
+        txn: &mut Transaction,
+        attributes: &HashMap<String, AttributeType>,
+        table_name: &str,
+    ) -> Result<(), anyhow::Error> {
+        self.check_writable(txn)?;
+        // pageに書いた値と、読み直した値が同じになるようにしてから一意性を確かめる
+        let padding = self
+            .buffer_pool_manager
+            .schema(table_name)?
+            .table
+            .text_padding;
+        let attributes: HashMap<String, AttributeType> = attributes
+            .iter()
+            .map(|(column, types)| {
+                let types = match types {
+                    AttributeType::Text(s) => AttributeType::Text(padding.trim(s).to_string()),
+                    t => t.clone(),
+                };
+                (column.clone(), types)
+            })
+            .collect();
+        let key = self.check_primary_key(&attributes, table_name)?;
+
         let b = self.find_writable_buffer(table_name)?;
 
         {
             let mut b = b.write().unwrap();
             let mut t = Tuple::new();
 
-            for (column, types) in attributes.iter() {
-                t.add_attribute(column, types.clone());
+            for (column, types) in attributes {
+                t.add_attribute(&column, types);
+            }
+            t.header.touch();
+            // pageの他のtupleと同じく、mapではなくbyte列で持つ
+            let encoded = {
+                let table = &self.buffer_pool_manager.schema(table_name)?.table;
+                t.encode(table.columns(), table.text_padding)
+            };
+            if let Err(e) = encoded {
+                self.buffer_pool_manager
+                    .unpin_buffer(b.page.id, table_name)?;
+                return Err(e);
             }
 
+            let lsn = match self
+                .buffer_pool_manager
+                .log_insert(txn.id, &b.page, &t, table_name)
+            {
+                Ok(lsn) => lsn,
+                Err(e) => {
+                    self.buffer_pool_manager
+                        .unpin_buffer(b.page.id, table_name)?;
+                    return Err(e);
+                }
+            };
+
+            txn.inserted
+                .push((table_name.to_string(), b.page.id, b.page.header.tuple_count));
             b.page.add_tuple(t);
+            b.page.header.lsn = lsn;
             self.buffer_pool_manager.mark_dirty(b.id)?;
+
+            if self
+                .buffer_pool_manager
+                .schema(table_name)?
+                .table
+                .append_only
+            {
+                self.append_cursors.insert(
+                    table_name.to_string(),
+                    b.page.can_add_tuple().then_some(b.page.id),
+                );
+            }
+
             self.buffer_pool_manager
                 .unpin_buffer(b.page.id, table_name)
                 .unwrap();
         }
 
+        // 書き込めたときだけkeyを覚える
+        if let (Some(key), Some(keys)) = (key, self.primary_keys.get_mut(table_name)) {
+            keys.insert(key);
+        }
+
         Ok(())
     }
 
-    pub fn scan(
+    // autocommit。書き換えた行の数を返す
+    pub fn update(
         &mut self,
+        attributes: &HashMap<String, AttributeType>,
         table_name: &str,
-        records: &mut Vec<HashMap<String, AttributeType>>,
-    ) -> Result<(), anyhow::Error> {
-        let last = match self.buffer_pool_manager.last_page_id(table_name)? {
-            Some(PageID(n)) => n,
-            None => return Ok(()),
-        };
+        filter: Option<(&str, &AttributeType)>,
+    ) -> Result<usize, anyhow::Error> {
+        let mut txn = self.begin();
+        match self.update_in(&mut txn, attributes, table_name, filter) {
+            Ok(updated) => {
+                self.commit(txn)?;
+                Ok(updated)
+            }
+            Err(e) => {
+                self.rollback(txn)?;
+                Err(e)
+            }
+        }
+    }
 
-        for i in 0..=last {
-            let b = self
-                .buffer_pool_manager
-                .fetch_buffer(PageID(i), table_name)?;
+    // filterに一致する行の、attributesのcolumnだけをpageの上で書き換える
+    // tupleは作り直さず、Page::update_attributeで書き換えるcolumnのbyteだけを上書きする
+    pub fn update_in(
+        &mut self,
+        txn: &mut Transaction,
+        attributes: &HashMap<String, AttributeType>,
+        table_name: &str,
+        filter: Option<(&str, &AttributeType)>,
+    ) -> Result<usize, anyhow::Error> {
+        self.check_writable(txn)?;
+        let schema = self.buffer_pool_manager.schema(table_name)?.clone();
+        if schema.table.append_only {
+            return Err(DbError::ReadOnly(format!("{} is append only", table_name)).into());
+        }
+        // keyを書き換えると覚えているkeyと食い違うので、keyの列は書き換えない
+        if let Some(column) = attributes
+            .keys()
+            .find(|c| schema.table.primary_key.contains(c))
+        {
+            return Err(
+                DbError::Parse(format!("primary key column {} cannot be updated", column)).into(),
+            );
+        }
 
-            let b = b.read().unwrap();
-            for t in &b.page.body {
-                records.push(t.body.attributes.clone());
+        // insertと同じく、pageに書いた値と読み直した値が同じになるようにする
+        let padding = schema.table.text_padding;
+        let attributes: Vec<(String, AttributeType)> = attributes
+            .iter()
+            .map(|(column, types)| {
+                let types = match types {
+                    AttributeType::Text(s) => AttributeType::Text(padding.trim(s).to_string()),
+                    t => t.clone(),
+                };
+                (column.clone(), types)
+            })
+            .collect();
+
+        let mut updated = 0;
+        if let Some(PageID(last)) = self.buffer_pool_manager.last_page_id(table_name)? {
+            for i in 0..=last {
+                self.check_deadline()?;
+
+                let b = self
+                    .buffer_pool_manager
+                    .fetch_buffer(PageID(i), table_name)?;
+                let result = {
+                    let mut b = b.write().unwrap();
+                    self.update_page(txn, &mut b, &schema, &attributes, filter)
+                };
+                self.buffer_pool_manager
+                    .unpin_buffer(PageID(i), table_name)?;
+                updated += result?;
             }
-            self.buffer_pool_manager
-                .unpin_buffer(b.page.id, table_name)
-                .unwrap();
         }
 
-        Ok(())
+        Ok(updated)
     }
 
-    pub fn all_flush(&mut self) -> Result<(), anyhow::Error> {
-        for b in self.buffer_pool_manager.dirty_buffers() {
-            let (id, table_name) = {
-                let b = b.read().unwrap();
-                (b.page.id, b.page.table_name.clone())
+    // page中でfilterに一致する生きている行を書き換え、その数を返す
+    // 1行ごとに、書き換える前後のtupleをwalに残してからpageのlsnを進める
+    fn update_page(
+        &mut self,
+        txn: &mut Transaction,
+        b: &mut Buffer,
+        schema: &Schema,
+        attributes: &[(String, AttributeType)],
+        filter: Option<(&str, &AttributeType)>,
+    ) -> Result<usize, anyhow::Error> {
+        let table = &schema.table;
+        let (columns, padding) = (table.columns(), table.text_padding);
+        let slots: Vec<u32> = b
+            .page
+            .tuples()
+            .enumerate()
+            .filter(|(_, t)| {
+                t.header.deleted == 0
+                    && filter.is_none_or(|(c, v)| t.body.matches(c, v, columns, padding))
+            })
+            .map(|(slot, _)| slot as u32)
+            .collect();
+
+        for &slot in &slots {
+            let before = b.page.body[slot as usize].raw(columns, padding)?;
+            let lsn = attributes
+                .iter()
+                .try_for_each(|(column, value)| {
+                    b.page
+                        .update_attribute(slot, column, value.clone(), schema)
+                        .map(|_| ())
+                })
+                .and_then(|()| {
+                    let after = b.page.body[slot as usize].raw(columns, padding)?;
+                    self.buffer_pool_manager.log_update(
+                        txn.id,
+                        &b.page,
+                        slot,
+                        before.clone(),
+                        after,
+                        &table.name,
+                    )
+                });
+            let lsn = match lsn {
+                Ok(lsn) => lsn,
+                // walに残せなかった書き換えは、pageからも戻す
+                Err(e) => {
+                    b.page.body[slot as usize].fill(
+                        &before,
+                        columns,
+                        padding,
+                        table.tuple_size(),
+                    )?;
+                    return Err(e);
+                }
             };
-            self.buffer_pool_manager.flush_buffer(id, &table_name)?;
+
+            b.page.header.lsn = lsn;
+            self.buffer_pool_manager.mark_dirty(b.id)?;
+            txn.updated
+                .push((table.name.clone(), b.page.id, slot, before));
         }
-        Ok(())
+
+        Ok(slots.len())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::{collections::HashMap, env::temp_dir};
+    pub fn commit(&mut self, txn: Transaction) -> Result<(), anyhow::Error> {
+        self.active_txns.remove(&txn.id);
+        // read onlyなtransactionはwalに何も残さない
+        if txn.read_only {
+            return Ok(());
+        }
+        self.buffer_pool_manager.commit(txn.id)?;
+        self.checkpoint_if_due()?;
 
-    use crate::catalog::Catalog;
+        Ok(())
+    }
 
-    use super::*;
+    // 定期的に書き出すときに呼び、書き出したpageの数を返す
+    // 開いているtransactionがあればwalは残し、pageを書き出すだけにする
+    pub fn background_checkpoint(&mut self) -> Result<usize, anyhow::Error> {
+        let pages = self.dirty_pages();
+        if self.active_txns.is_empty() {
+            self.checkpoint()?;
+        } else {
+            self.all_flush()?;
+        }
 
-    const JSON: &str = r#"{
-        "schemas": [
+        Ok(pages)
+    }
+
+    // walがthresholdを超えていて、開いているtransactionがなければcheckpointする
+    // commitの後だけでなく、serverが定期的にも呼ぶ。transactionが途切れなかった間に伸びた分もここで切り詰める
+    pub fn checkpoint_if_due(&mut self) -> Result<bool, anyhow::Error> {
+        match self.checkpoint_threshold {
+            Some(threshold)
+                if !self.read_only
+                    && self.active_txns.is_empty()
+                    && self.buffer_pool_manager.wal_size()? > threshold =>
             {
-                "table": {
-                    "name": "executor_test",
-                    "columns": [
-                        {
-                            "types": "int",
-                            "name": "column_int"
-                        },
-                        {
-                            "types": "text",
-                            "name": "column_text"
-                        }
-                    ]
+                self.checkpoint()?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    // 書き換えたtupleを逆順に前の値へ戻してから、追加したtupleを逆順に削除済みにする
+    // 新しく確保したpageは残るが、中のtupleは見えなくなる
+    pub fn rollback(&mut self, txn: Transaction) -> Result<(), anyhow::Error> {
+        for (table_name, page_id, slot, before) in txn.updated.iter().rev() {
+            self.restore_tuple(txn.id, table_name, *page_id, *slot, before)?;
+        }
+        for (table_name, page_id, slot) in txn.inserted.iter().rev() {
+            self.delete_tuple(txn.id, table_name, *page_id, *slot)?;
+        }
+
+        self.active_txns.remove(&txn.id);
+        if txn.read_only {
+            return Ok(());
+        }
+        self.buffer_pool_manager.abort(txn.id)
+    }
+
+    // 戻すのもupdateとしてwalに残すので、abortの前に落ちてもredoで同じ値になる
+    fn restore_tuple(
+        &mut self,
+        txn_id: TxnID,
+        table_name: &str,
+        page_id: PageID,
+        slot: u32,
+        before: &[u8],
+    ) -> Result<(), anyhow::Error> {
+        let b = self.buffer_pool_manager.fetch_buffer(page_id, table_name)?;
+
+        let result = {
+            let mut b = b.write().unwrap();
+            let table = &self.buffer_pool_manager.schema(table_name)?.table;
+            let (columns, padding, size) =
+                (table.columns(), table.text_padding, table.tuple_size());
+            let mut tuple = Tuple::default();
+            tuple
+                .fill(before, columns, padding, size)
+                .and_then(|()| b.page.body[slot as usize].raw(columns, padding))
+                .and_then(|current| {
+                    self.buffer_pool_manager.log_update(
+                        txn_id,
+                        &b.page,
+                        slot,
+                        current,
+                        before.to_vec(),
+                        table_name,
+                    )
+                })
+                .and_then(|lsn| {
+                    b.page.body[slot as usize] = tuple;
+                    b.page.header.lsn = lsn;
+                    self.buffer_pool_manager.mark_dirty(b.id)
+                })
+        };
+
+        self.buffer_pool_manager.unpin_buffer(page_id, table_name)?;
+
+        result
+    }
+
+    fn delete_tuple(
+        &mut self,
+        txn_id: TxnID,
+        table_name: &str,
+        page_id: PageID,
+        slot: u32,
+    ) -> Result<(), anyhow::Error> {
+        let b = self.buffer_pool_manager.fetch_buffer(page_id, table_name)?;
+
+        let result = {
+            let mut b = b.write().unwrap();
+            let result = self
+                .buffer_pool_manager
+                .log_delete(txn_id, &b.page, slot, table_name);
+
+            if let Ok(lsn) = result {
+                let tuple = &mut b.page.body[slot as usize];
+                tuple.header.deleted = 1;
+                // 消した行のkeyは、もう一度insertできる
+                let key = self
+                    .buffer_pool_manager
+                    .schema(table_name)
+                    .ok()
+                    .and_then(|s| Self::tuple_key(&s.table.primary_key, tuple, &s.table).ok());
+                if let (Some(key), Some(keys)) = (key, self.primary_keys.get_mut(table_name)) {
+                    keys.remove(&key);
                 }
+                b.page.header.lsn = lsn;
+                self.buffer_pool_manager.mark_dirty(b.id)?;
             }
-        ]
-    }"#;
 
-    #[test]
-    fn executor_insert_scan() {
-        let temp_dir = temp_dir();
-        let catalog = Catalog::from_json(JSON);
-        let table_name = "executor_test";
-        let b_manager = BufferPoolManager::new(1, temp_dir.to_str().unwrap().to_string(), catalog);
-        let mut executor = Executor::new(b_manager);
+            result
+        };
 
-        let mut attributes = HashMap::new();
-        attributes.insert("column_int".to_string(), AttributeType::Int(12));
-        attributes.insert(
-            "column_text".to_string(),
-            AttributeType::Text("executor".to_string()),
-        );
+        self.buffer_pool_manager.unpin_buffer(page_id, table_name)?;
 
-        executor.insert(&attributes, table_name).unwrap();
+        result.map(|_| ())
+    }
 
-        let mut records = Vec::new();
+    pub fn scan(
+        &mut self,
+        table_name: &str,
+        records: &mut Vec<HashMap<String, AttributeType>>,
+    ) -> Result<(), anyhow::Error> {
+        self.scan_with_limit(table_name, records, None)?;
+        Ok(())
+    }
 
-        executor.scan(table_name, &mut records).unwrap();
+    // recordsがlimit件に達したらscanを打ち切り、打ち切ったかどうかを返す
+    pub fn scan_with_limit(
+        &mut self,
+        table_name: &str,
+        records: &mut Vec<HashMap<String, AttributeType>>,
+        limit: Option<usize>,
+    ) -> Result<bool, anyhow::Error> {
+        self.scan_filtered(table_name, limit, None, false, &mut |r| {
+            records.push(r);
+            Ok(())
+        })
+    }
 
-        assert_eq!(records.len(), 1);
+    // 1行ずつfに渡す。複製するのは1page分だけなので、全行を持たずに結果を書き出せる
+    // fが失敗したらそこでscanを止める。limitで打ち切ったかどうかを返す
+    pub fn scan_each(
+        &mut self,
+        table_name: &str,
+        limit: Option<usize>,
+        f: &mut dyn FnMut(HashMap<String, AttributeType>) -> Result<(), anyhow::Error>,
+    ) -> Result<bool, anyhow::Error> {
+        self.scan_filtered(table_name, limit, None, false, f)
+    }
 
-        assert_eq!(records[0]["column_int"], AttributeType::Int(12));
-        assert_eq!(
-            records[0]["column_text"],
-            AttributeType::Text("executor".to_string())
-        );
+    // scan_eachと同じだが、各行にtupleを書き込んだ時刻をupdated_atの疑似列として加える
+    pub fn scan_each_with_updated_at(
+        &mut self,
+        table_name: &str,
+        limit: Option<usize>,
+        f: &mut dyn FnMut(HashMap<String, AttributeType>) -> Result<(), anyhow::Error>,
+    ) -> Result<bool, anyhow::Error> {
+        self.scan_filtered(table_name, limit, None, true, f)
+    }
+
+    // column = valueのtupleだけを取り出す
+    // 比較はpage上のtupleのbyte列に対して行い、一致したものだけをmapにする
+    pub fn scan_eq(
+        &mut self,
+        table_name: &str,
+        column: &str,
+        value: &AttributeType,
+        records: &mut Vec<HashMap<String, AttributeType>>,
+    ) -> Result<(), anyhow::Error> {
+        self.scan_each_eq(table_name, None, Some((column, value)), &mut |r| {
+            records.push(r);
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    // scan_eachと同じだが、filterがあればcolumn = valueのtupleだけを渡す
+    pub fn scan_each_eq(
+        &mut self,
+        table_name: &str,
+        limit: Option<usize>,
+        filter: Option<(&str, &AttributeType)>,
+        f: &mut dyn FnMut(HashMap<String, AttributeType>) -> Result<(), anyhow::Error>,
+    ) -> Result<bool, anyhow::Error> {
+        if let Some((column, _)) = filter {
+            let columns = self
+                .buffer_pool_manager
+                .catalog()
+                .columns_of(table_name)
+                .ok_or_else(|| DbError::TableNotFound(table_name.to_string()))?;
+            if !columns.iter().any(|c| c.name == column) {
+                return Err(
+                    DbError::Parse(format!("{} has no column {}", table_name, column)).into(),
+                );
+            }
+        }
+
+        self.scan_filtered(table_name, limit, filter, false, f)
+    }
+
+    pub fn maps_built(&self) -> u64 {
+        self.maps_built
+    }
+
+    fn scan_filtered(
+        &mut self,
+        table_name: &str,
+        limit: Option<usize>,
+        filter: Option<(&str, &AttributeType)>,
+        updated_at: bool,
+        f: &mut dyn FnMut(HashMap<String, AttributeType>) -> Result<(), anyhow::Error>,
+    ) -> Result<bool, anyhow::Error> {
+        let mut cursor = self.cursor(limit);
+        self.scan_pages(&mut cursor, table_name, None, filter, updated_at, f)?;
+        Ok(cursor.truncated)
+    }
+
+    // 今のstatementの期限を引き継いで、scanを途中から続けるための位置を作る
+    // limit件を渡したところでscanを打ち切る
+    pub fn cursor(&self, limit: Option<usize>) -> ScanCursor {
+        ScanCursor {
+            next_page: 0,
+            emitted: 0,
+            limit,
+            done: false,
+            truncated: false,
+            deadline: self.deadline,
+        }
+    }
+
+    // cursorの位置からpagesページ分を読む。Noneなら最後まで読む
+    // 間に他のstatementが走っても、期限はcursorを作ったときのものを使う
+    // pageごとに一致したtupleだけをmapにしてunpinしてから、fに渡す
+    // fが失敗したら(clientが切断したなど)、次のpageは読まずに返す
+    pub fn scan_pages(
+        &mut self,
+        cursor: &mut ScanCursor,
+        table_name: &str,
+        pages: Option<usize>,
+        filter: Option<(&str, &AttributeType)>,
+        updated_at: bool,
+        f: &mut dyn FnMut(HashMap<String, AttributeType>) -> Result<(), anyhow::Error>,
+    ) -> Result<(), anyhow::Error> {
+        self.deadline = cursor.deadline;
+        let last = match self.buffer_pool_manager.last_page_id(table_name)? {
+            Some(PageID(n)) => n,
+            None => {
+                cursor.done = true;
+                return Ok(());
+            }
+        };
+        let end = pages.map_or(last, |n| (cursor.next_page + n.max(1) - 1).min(last));
+
+        for i in cursor.next_page..=end {
+            self.check_deadline()?;
+
+            let b = self
+                .buffer_pool_manager
+                .fetch_buffer(PageID(i), table_name)?;
+
+            let mut rows = Vec::new();
+            let decoded = {
+                let b = b.read().unwrap();
+                let table = &self.buffer_pool_manager.schema(table_name)?.table;
+                let (columns, padding) = (table.columns(), table.text_padding);
+                let tuples = b
+                    .page
+                    .live_tuples()
+                    .filter(|t| filter.is_none_or(|(c, v)| t.body.matches(c, v, columns, padding)));
+                let mut decoded = Ok(());
+                for t in tuples {
+                    if cursor
+                        .limit
+                        .is_some_and(|l| cursor.emitted + rows.len() >= l)
+                    {
+                        cursor.truncated = true;
+                        break;
+                    }
+                    let mut row = match t.body.attributes(columns, padding) {
+                        Ok(row) => row,
+                        Err(e) => {
+                            decoded = Err(e);
+                            break;
+                        }
+                    };
+                    if updated_at {
+                        row.insert(
+                            UPDATED_AT_COLUMN.to_string(),
+                            AttributeType::Decimal(Decimal {
+                                unscaled: t.header.updated_at as i64,
+                                scale: 0,
+                            }),
+                        );
+                    }
+                    rows.push(row);
+                }
+                decoded
+            };
+            self.buffer_pool_manager
+                .unpin_buffer(PageID(i), table_name)
+                .unwrap();
+            decoded?;
+
+            self.maps_built += rows.len() as u64;
+            cursor.emitted += rows.len();
+            cursor.next_page = i + 1;
+            for r in rows {
+                f(r)?;
+            }
+
+            if cursor.truncated {
+                cursor.done = true;
+                return Ok(());
+            }
+        }
+
+        cursor.done = cursor.next_page > last;
+        Ok(())
+    }
+
+    pub fn buffer_pool_stats(&self) -> BufferPoolStats {
+        self.buffer_pool_manager.stats()
+    }
+
+    pub fn sync_wal_if_due(&mut self) -> Result<bool, anyhow::Error> {
+        self.buffer_pool_manager.sync_wal_if_due()
+    }
+
+    pub fn pool_settings(&self) -> PoolSettings {
+        self.buffer_pool_manager.settings()
+    }
+
+    pub fn dirty_pages(&self) -> usize {
+        self.buffer_pool_manager.dirty_buffers().len()
+    }
+
+    pub fn row_estimate(&mut self, table_name: &str) -> Result<usize, anyhow::Error> {
+        self.buffer_pool_manager.row_estimate(table_name)
+    }
+
+    pub fn all_flush(&mut self) -> Result<(), anyhow::Error> {
+        self.buffer_pool_manager.flush_all()
+    }
+
+    // dirtyなpageを全て書き出してからwalを切り詰める
+    // 開いているtransactionのundoに必要なlogは消せないので、その間は行わない
+    pub fn checkpoint(&mut self) -> Result<(), anyhow::Error> {
+        if self.read_only {
+            return Err(DbError::ReadOnly("server is read-only".to_string()).into());
+        }
+        if !self.active_txns.is_empty() {
+            return Err(DbError::Transaction(
+                "cannot checkpoint while a transaction is in progress".to_string(),
+            )
+            .into());
+        }
+
+        self.all_flush()?;
+        self.buffer_pool_manager.truncate_wal()?;
+        log::info!("checkpoint {:?}", self.buffer_pool_manager.stats());
+
+        Ok(())
+    }
+
+    // 末尾から続く、生きているtupleのないpageをtable fileから切り落とし、切り落としたpage数を返す
+    // rollbackで確保したまま空になったpageなどが対象
+    // tupleを詰め直すvacuumではないので、後ろに生きているpageがある空のpageは残る
+    // 切り落とすpageをwalが参照しないよう、先にcheckpointする
+    pub fn truncate_dead_tail(&mut self, table_name: &str) -> Result<usize, anyhow::Error> {
+        self.checkpoint()?;
+
+        let page_count = match self.buffer_pool_manager.last_page_id(table_name)? {
+            Some(PageID(n)) => n + 1,
+            None => return Ok(0),
+        };
+
+        let mut keep = page_count;
+        while keep > 0 {
+            let p_id = PageID(keep - 1);
+            let b = self.buffer_pool_manager.fetch_buffer(p_id, table_name)?;
+            let live = b.read().unwrap().page.live_tuples().next().is_some();
+            self.buffer_pool_manager.unpin_buffer(p_id, table_name)?;
+            if live {
+                break;
+            }
+            keep -= 1;
+        }
+
+        if keep < page_count {
+            self.buffer_pool_manager.truncate_table(table_name, keep)?;
+            // cursorのpageが切り落とされているかもしれないので、次のinsertで探し直す
+            self.append_cursors.remove(table_name);
+        }
+
+        Ok(page_count - keep)
+    }
+}
+
+// closeを呼ばずに捨てられても、dirtyなpageを失わないよう書き出す
+// dropでは失敗を返せないのでlogに出す
+impl<T: Replacer> Drop for Executor<T> {
+    fn drop(&mut self) {
+        if self.closed || self.read_only {
+            return;
+        }
+        if let Err(e) = self.all_flush() {
+            log::error!("failed to flush dirty pages on drop: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::{
+        catalog::Catalog,
+        storage::{disk_manager::DiskManager, page::PAGE_SIZE, replacer::LruReplacer},
+        test_util::temp_dir,
+    };
+
+    use super::*;
+
+    const JSON: &str = r#"{
+        "schemas": [
+            {
+                "table": {
+                    "name": "executor_test",
+                    "columns": [
+                        {
+                            "types": "int",
+                            "name": "column_int"
+                        },
+                        {
+                            "types": "text",
+                            "name": "column_text"
+                        }
+                    ]
+                }
+            }
+        ]
+    }"#;
+
+    #[test]
+    fn executor_insert_scan() {
+        let temp_dir = temp_dir("executor_insert_scan");
+        let catalog = Catalog::from_json(JSON).unwrap();
+        let table_name = "executor_test";
+        let b_manager = BufferPoolManager::new(1, temp_dir.to_str().unwrap().to_string(), catalog);
+        let mut executor = Executor::new(b_manager);
+
+        let mut attributes = HashMap::new();
+        attributes.insert("column_int".to_string(), AttributeType::Int(12));
+        attributes.insert(
+            "column_text".to_string(),
+            AttributeType::Text("executor".to_string()),
+        );
+
+        executor.insert(&attributes, table_name).unwrap();
+
+        let mut records = Vec::new();
+
+        executor.scan(table_name, &mut records).unwrap();
+
+        assert_eq!(records.len(), 1);
+
+        assert_eq!(records[0]["column_int"], AttributeType::Int(12));
+        assert_eq!(
+            records[0]["column_text"],
+            AttributeType::Text("executor".to_string())
+        );
+    }
+
+    #[test]
+    fn executor_transaction_rollback() {
+        let temp_dir = temp_dir("executor_transaction_rollback");
+        let catalog = Catalog::from_json(JSON).unwrap();
+        let table_name = "executor_test";
+        let b_manager = BufferPoolManager::new(2, temp_dir.to_str().unwrap().to_string(), catalog);
+        let mut executor = Executor::new(b_manager);
+
+        let mut attributes = HashMap::new();
+        attributes.insert("column_int".to_string(), AttributeType::Int(1));
+        attributes.insert(
+            "column_text".to_string(),
+            AttributeType::Text("committed".to_string()),
+        );
+
+        let mut txn = executor.begin();
+        executor
+            .insert_in(&mut txn, &attributes, table_name)
+            .unwrap();
+        executor.commit(txn).unwrap();
+
+        // 1pageに入りきらない数を入れて、新しいpageの確保もrollbackさせる
+        let mut txn = executor.begin();
+        attributes.insert(
+            "column_text".to_string(),
+            AttributeType::Text("rolled back".to_string()),
+        );
+        for _ in 0..20 {
+            executor
+                .insert_in(&mut txn, &attributes, table_name)
+                .unwrap();
+        }
+        assert_ne!(
+            executor
+                .buffer_pool_manager
+                .last_page_id(table_name)
+                .unwrap(),
+            Some(PageID(0))
+        );
+        executor.rollback(txn).unwrap();
+
+        let mut records = Vec::new();
+        executor.scan(table_name, &mut records).unwrap();
+
+        assert_eq!(records.len(), 1);
+        assert_eq!(
+            records[0]["column_text"],
+            AttributeType::Text("committed".to_string())
+        );
+    }
+
+    #[test]
+    fn executor_update() {
+        let temp_dir = temp_dir("executor_update");
+        let base_path = temp_dir.to_str().unwrap().to_string();
+        let catalog = Catalog::from_json(JSON).unwrap();
+        let table_name = "executor_test";
+        let b_manager = BufferPoolManager::new(2, base_path.clone(), catalog.clone());
+        let mut executor = Executor::new(b_manager);
+
+        // 2page以上にまたがるようにする
+        insert_rows(&mut executor, 20);
+        let texts = |executor: &mut Executor<LruReplacer>| {
+            let mut records = Vec::new();
+            executor.scan(table_name, &mut records).unwrap();
+            records
+                .iter()
+                .map(|r| r["column_text"].to_display())
+                .collect::<Vec<_>>()
+        };
+        let set = |text: &str| {
+            let mut attributes = HashMap::new();
+            attributes.insert(
+                "column_text".to_string(),
+                AttributeType::Text(text.to_string()),
+            );
+            attributes
+        };
+
+        let updated = executor
+            .update(
+                &set("updated"),
+                table_name,
+                Some(("column_int", &AttributeType::Int(17))),
+            )
+            .unwrap();
+        assert_eq!(updated, 1);
+        // 行は追加されず、その場で書き換わる
+        let mut expected = vec!["checkpoint".to_string(); 20];
+        expected[17] = "updated".to_string();
+        assert_eq!(texts(&mut executor), expected);
+
+        // rollbackすると、同じtransactionのinsertより先に書き換えを戻す
+        let mut txn = executor.begin();
+        let mut attributes = set("inserted");
+        attributes.insert("column_int".to_string(), AttributeType::Int(20));
+        executor
+            .insert_in(&mut txn, &attributes, table_name)
+            .unwrap();
+        assert_eq!(
+            executor
+                .update_in(&mut txn, &set("all"), table_name, None)
+                .unwrap(),
+            21
+        );
+        executor.rollback(txn).unwrap();
+        assert_eq!(texts(&mut executor), expected);
+
+        // 型の合わない値があれば、先に書き換えたcolumnも戻る
+        let mut attributes = set("broken");
+        attributes.insert(
+            "column_int".to_string(),
+            AttributeType::Text("1".to_string()),
+        );
+        assert!(executor.update(&attributes, table_name, None).is_err());
+        assert!(executor.update(&set("a"), "nothing", None).is_err());
+        assert_eq!(texts(&mut executor), expected);
+
+        // 書き出した後も書き換えた値が残る
+        executor.close().unwrap();
+        let b_manager = BufferPoolManager::new(2, base_path, catalog);
+        let mut executor = Executor::new(b_manager);
+        assert_eq!(texts(&mut executor), expected);
+
+        let mut txn = executor.begin_read_only();
+        let e = executor
+            .update_in(&mut txn, &set("a"), table_name, None)
+            .unwrap_err();
+        assert_eq!(e.to_string(), "transaction is read-only");
+    }
+
+    #[test]
+    fn executor_update_rejects_keys_and_append_only() {
+        const UPDATE_JSON: &str = r#"{
+            "schemas": [
+                {
+                    "table": {
+                        "name": "keyed",
+                        "columns": [
+                            { "types": "int", "name": "id" },
+                            { "types": "text", "name": "name" }
+                        ],
+                        "primary_key": ["id"]
+                    }
+                },
+                {
+                    "table": {
+                        "name": "appended",
+                        "columns": [
+                            { "types": "int", "name": "id" }
+                        ],
+                        "append_only": true
+                    }
+                }
+            ]
+        }"#;
+
+        let temp_dir = temp_dir("executor_update_rejects_keys_and_append_only");
+        let catalog = Catalog::from_json(UPDATE_JSON).unwrap();
+        let b_manager = BufferPoolManager::new(2, temp_dir.to_str().unwrap().to_string(), catalog);
+        let mut executor = Executor::new(b_manager);
+
+        let mut attributes = HashMap::new();
+        attributes.insert("id".to_string(), AttributeType::Int(1));
+        attributes.insert("name".to_string(), AttributeType::Text("a".to_string()));
+        executor.insert(&attributes, "keyed").unwrap();
+
+        let e = executor.update(&attributes, "keyed", None).unwrap_err();
+        assert_eq!(e.to_string(), "primary key column id cannot be updated");
+        attributes.remove("id");
+        assert_eq!(executor.update(&attributes, "keyed", None).unwrap(), 1);
+
+        let mut attributes = HashMap::new();
+        attributes.insert("id".to_string(), AttributeType::Int(1));
+        executor.insert(&attributes, "appended").unwrap();
+        let e = executor.update(&attributes, "appended", None).unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<DbError>().unwrap().status(),
+            "403 Forbidden"
+        );
+    }
+
+    fn insert_rows(executor: &mut Executor<LruReplacer>, n: i32) {
+        for i in 0..n {
+            let mut attributes = HashMap::new();
+            attributes.insert("column_int".to_string(), AttributeType::Int(i));
+            attributes.insert(
+                "column_text".to_string(),
+                AttributeType::Text("checkpoint".to_string()),
+            );
+            executor.insert(&attributes, "executor_test").unwrap();
+        }
+    }
+
+    #[test]
+    fn executor_scan_eq() {
+        let temp_dir = temp_dir("executor_scan_eq");
+        let catalog = Catalog::from_json(JSON).unwrap();
+        let b_manager = BufferPoolManager::new(2, temp_dir.to_str().unwrap().to_string(), catalog);
+        let mut executor = Executor::new(b_manager);
+
+        for i in 0..100 {
+            let mut attributes = HashMap::new();
+            attributes.insert("column_int".to_string(), AttributeType::Int(i % 10));
+            attributes.insert(
+                "column_text".to_string(),
+                AttributeType::Text(format!("t{}", i)),
+            );
+            executor.insert(&attributes, "executor_test").unwrap();
+        }
+
+        let mut records = Vec::new();
+        executor
+            .scan_eq(
+                "executor_test",
+                "column_int",
+                &AttributeType::Int(3),
+                &mut records,
+            )
+            .unwrap();
+        assert_eq!(records.len(), 10);
+        assert!(records
+            .iter()
+            .all(|r| r["column_int"] == AttributeType::Int(3)));
+        // 100件のbyte列と比べても、mapになるのは一致した10件だけ
+        assert_eq!(executor.maps_built(), 10);
+
+        // 絞り込みがなければ100件すべてがmapになる
+        let mut records = Vec::new();
+        executor.scan("executor_test", &mut records).unwrap();
+        assert_eq!(executor.maps_built(), 110);
+
+        assert!(executor
+            .scan_eq(
+                "executor_test",
+                "nothing",
+                &AttributeType::Int(3),
+                &mut records
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn executor_query_timeout() {
+        let temp_dir = temp_dir("executor_query_timeout");
+        let catalog = Catalog::from_json(JSON).unwrap();
+        let b_manager = BufferPoolManager::new(2, temp_dir.to_str().unwrap().to_string(), catalog);
+        let mut executor = Executor::new(b_manager);
+
+        // 複数pageにまたがるtable
+        insert_rows(&mut executor, 100);
+
+        executor.set_query_timeout(Some(Duration::from_nanos(1)));
+        executor.start_statement();
+        std::thread::sleep(Duration::from_millis(1));
+
+        let mut records = Vec::new();
+        let e = executor.scan("executor_test", &mut records).unwrap_err();
+        assert_eq!(e.to_string(), "query cancelled: exceeded 1ns");
+        assert_eq!(
+            e.downcast_ref::<DbError>().unwrap().status(),
+            "503 Service Unavailable"
+        );
+        // pinしたbufferは残っていない
+        executor.buffer_pool_manager.clear().unwrap();
+
+        // 次のstatementでは期限が決め直される
+        executor.set_query_timeout(Some(Duration::from_secs(60)));
+        executor.start_statement();
+        executor.scan("executor_test", &mut records).unwrap();
+        assert_eq!(records.len(), 100);
+    }
+
+    #[test]
+    fn executor_checkpoint() {
+        let temp_dir = temp_dir("executor_checkpoint");
+        let catalog = Catalog::from_json(JSON).unwrap();
+        let b_manager = BufferPoolManager::new(2, temp_dir.to_str().unwrap().to_string(), catalog);
+        let mut executor = Executor::new(b_manager);
+
+        insert_rows(&mut executor, 5);
+        let before = executor.buffer_pool_manager.wal_size().unwrap();
+
+        let txn = executor.begin();
+        assert!(executor.checkpoint().is_err());
+        executor.commit(txn).unwrap();
+
+        executor.checkpoint().unwrap();
+        let after = executor.buffer_pool_manager.wal_size().unwrap();
+
+        assert!(after < before);
+
+        let mut records = Vec::new();
+        executor.scan("executor_test", &mut records).unwrap();
+        assert_eq!(records.len(), 5);
+    }
+
+    #[test]
+    fn executor_truncate_dead_tail() {
+        let temp_dir = temp_dir("executor_truncate_dead_tail");
+        let catalog = Catalog::from_json(JSON).unwrap();
+        let b_manager = BufferPoolManager::new(2, temp_dir.to_str().unwrap().to_string(), catalog);
+        let mut executor = Executor::new(b_manager);
+        let file_len = || {
+            std::fs::metadata(temp_dir.join("executor_test"))
+                .unwrap()
+                .len()
+        };
+
+        insert_rows(&mut executor, 3);
+
+        // rollbackしたinsertのpageは、空のまま残る
+        let mut txn = executor.begin();
+        let mut attributes = HashMap::new();
+        attributes.insert("column_int".to_string(), AttributeType::Int(0));
+        attributes.insert(
+            "column_text".to_string(),
+            AttributeType::Text("rolled back".to_string()),
+        );
+        for _ in 0..100 {
+            executor
+                .insert_in(&mut txn, &attributes, "executor_test")
+                .unwrap();
+        }
+        assert!(executor.truncate_dead_tail("executor_test").is_err());
+        executor.rollback(txn).unwrap();
+
+        let before = file_len();
+        let freed = executor.truncate_dead_tail("executor_test").unwrap();
+        assert!(freed > 0);
+        assert_eq!(file_len(), before - (freed * PAGE_SIZE) as u64);
+        assert_eq!(executor.truncate_dead_tail("executor_test").unwrap(), 0);
+
+        let mut records = Vec::new();
+        executor.scan("executor_test", &mut records).unwrap();
+        assert_eq!(records.len(), 3);
+
+        // 切り詰めた後も続けて書ける
+        insert_rows(&mut executor, 2);
+        let mut records = Vec::new();
+        executor.scan("executor_test", &mut records).unwrap();
+        assert_eq!(records.len(), 5);
+    }
+
+    #[test]
+    fn executor_drop_flushes() {
+        let temp_dir = temp_dir("executor_drop_flushes");
+        let base_path = temp_dir.to_str().unwrap().to_string();
+        let catalog = Catalog::from_json(JSON).unwrap();
+        let tuple_count = || {
+            let mut disk_manager = DiskManager::new(base_path.clone(), catalog.clone());
+            disk_manager
+                .read(PageID(0), "executor_test")
+                .unwrap()
+                .header
+                .tuple_count
+        };
+
+        // walをredoしなくても、table fileから読める
+        {
+            let b_manager = BufferPoolManager::new(2, base_path.clone(), catalog.clone());
+            let mut executor = Executor::new(b_manager);
+            insert_rows(&mut executor, 3);
+        }
+        assert_eq!(tuple_count(), 3);
+
+        let b_manager = BufferPoolManager::new(2, base_path.clone(), catalog.clone());
+        let mut executor = Executor::new(b_manager);
+        insert_rows(&mut executor, 2);
+        executor.close().unwrap();
+        assert_eq!(tuple_count(), 5);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn executor_read_only_table_file() {
+        let temp_dir = temp_dir("executor_read_only_table_file");
+        let base_path = temp_dir.to_str().unwrap().to_string();
+        let catalog = Catalog::from_json(JSON).unwrap();
+        let path = temp_dir.join("executor_test");
+        {
+            let b_manager = BufferPoolManager::new(2, base_path.clone(), catalog.clone());
+            let mut executor = Executor::new(b_manager);
+            insert_rows(&mut executor, 1);
+            executor.close().unwrap();
+        }
+
+        // rootでは権限を変えても開けてしまうので、開くところで権限のerrorを返す
+        let denied = |_: &std::fs::OpenOptions, _: &str| -> std::io::Result<std::fs::File> {
+            Err(std::io::ErrorKind::PermissionDenied.into())
+        };
+        let io_message = |e: anyhow::Error| match e.downcast_ref::<DbError>() {
+            Some(DbError::Io(e)) => e.to_string(),
+            _ => panic!("expected DbError::Io, but {}", e),
+        };
+
+        let mut b_manager = BufferPoolManager::new(2, base_path.clone(), catalog.clone());
+        b_manager.set_opener(denied);
+        let mut executor = Executor::new(b_manager);
+        let mut attributes = HashMap::new();
+        attributes.insert("column_int".to_string(), AttributeType::Int(1));
+        attributes.insert(
+            "column_text".to_string(),
+            AttributeType::Text("denied".to_string()),
+        );
+        let message = io_message(executor.insert(&attributes, "executor_test").unwrap_err());
+        assert!(
+            message.contains(&format!("cannot open table file {}", path.display()))
+                && message.contains("for writing")
+                && message.contains("start with --read-only"),
+            "{}",
+            message
+        );
+        std::mem::forget(executor);
+
+        // read onlyで開けないときは、--read-onlyを勧めない
+        let mut b_manager = BufferPoolManager::new(2, base_path, catalog);
+        b_manager.set_opener(denied);
+        let mut executor = Executor::new(b_manager);
+        executor.set_read_only(true);
+        let mut records = Vec::new();
+        let message = io_message(executor.scan("executor_test", &mut records).unwrap_err());
+        assert!(
+            message.contains(&format!("cannot open table file {}", path.display()))
+                && message.contains("for reading")
+                && !message.contains("--read-only"),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn executor_auto_checkpoint() {
+        let temp_dir = temp_dir("executor_auto_checkpoint");
+        let catalog = Catalog::from_json(JSON).unwrap();
+        let b_manager = BufferPoolManager::new(2, temp_dir.to_str().unwrap().to_string(), catalog);
+        let mut executor = Executor::new(b_manager);
+
+        // insert 1件分のrecordは300byte程度
+        executor.set_checkpoint_threshold(Some(1000));
+        insert_rows(&mut executor, 10);
+
+        assert!(executor.buffer_pool_manager.wal_size().unwrap() <= 1000);
+    }
+
+    #[test]
+    fn executor_scan_with_limit() {
+        let temp_dir = temp_dir("executor_scan_with_limit");
+        let catalog = Catalog::from_json(JSON).unwrap();
+        let b_manager = BufferPoolManager::new(2, temp_dir.to_str().unwrap().to_string(), catalog);
+        let mut executor = Executor::new(b_manager);
+
+        insert_rows(&mut executor, 5);
+
+        let mut records = Vec::new();
+        let truncated = executor
+            .scan_with_limit("executor_test", &mut records, Some(3))
+            .unwrap();
+        assert!(truncated);
+        assert_eq!(records.len(), 3);
+
+        let mut records = Vec::new();
+        let truncated = executor
+            .scan_with_limit("executor_test", &mut records, Some(5))
+            .unwrap();
+        assert!(!truncated);
+        assert_eq!(records.len(), 5);
+    }
+
+    #[test]
+    fn executor_read_only_transaction() {
+        let temp_dir = temp_dir("executor_read_only_transaction");
+        let catalog = Catalog::from_json(JSON).unwrap();
+        let b_manager = BufferPoolManager::new(2, temp_dir.to_str().unwrap().to_string(), catalog);
+        let mut executor = Executor::new(b_manager);
+
+        insert_rows(&mut executor, 1);
+        let wal_size = executor.buffer_pool_manager.wal_size().unwrap();
+
+        let mut txn = executor.begin_read_only();
+        let mut attributes = HashMap::new();
+        attributes.insert("column_int".to_string(), AttributeType::Int(1));
+        attributes.insert(
+            "column_text".to_string(),
+            AttributeType::Text("text".to_string()),
+        );
+        let err = executor
+            .insert_in(&mut txn, &attributes, "executor_test")
+            .unwrap_err();
+        assert_eq!(err.to_string(), "transaction is read-only");
+
+        let mut records = Vec::new();
+        executor.scan("executor_test", &mut records).unwrap();
+        assert_eq!(records.len(), 1);
+
+        executor.commit(txn).unwrap();
+        assert_eq!(executor.buffer_pool_manager.wal_size().unwrap(), wal_size);
+
+        // 終わった後は普通に書ける
+        insert_rows(&mut executor, 1);
+    }
+
+    #[test]
+    fn executor_read_only_server() {
+        let temp_dir = temp_dir("executor_read_only_server");
+        let base_path = temp_dir.to_str().unwrap().to_string();
+        let catalog = Catalog::from_json(JSON).unwrap();
+
+        {
+            let b_manager = BufferPoolManager::new(2, base_path.clone(), catalog.clone());
+            let mut executor = Executor::new(b_manager);
+            insert_rows(&mut executor, 3);
+            executor.checkpoint().unwrap();
+        }
+
+        let b_manager = BufferPoolManager::new(2, base_path, catalog);
+        let mut executor = Executor::new(b_manager);
+        executor.set_read_only(true);
+
+        let mut records = Vec::new();
+        executor.scan("executor_test", &mut records).unwrap();
+        assert_eq!(records.len(), 3);
+
+        let mut attributes = HashMap::new();
+        attributes.insert("column_int".to_string(), AttributeType::Int(1));
+        attributes.insert(
+            "column_text".to_string(),
+            AttributeType::Text("text".to_string()),
+        );
+        let err = executor.insert(&attributes, "executor_test").unwrap_err();
+        assert_eq!(err.to_string(), "server is read-only");
+
+        let mut txn = executor.begin();
+        assert!(executor
+            .insert_in(&mut txn, &attributes, "executor_test")
+            .is_err());
+        executor.rollback(txn).unwrap();
+
+        assert!(executor.checkpoint().is_err());
+    }
+
+    #[test]
+    fn executor_primary_key() {
+        const KEYED_JSON: &str = r#"{
+            "schemas": [
+                {
+                    "table": {
+                        "name": "members",
+                        "columns": [
+                            { "types": "int", "name": "user_id" },
+                            { "types": "int", "name": "group_id" },
+                            { "types": "text", "name": "role" }
+                        ],
+                        "primary_key": ["user_id", "group_id"]
+                    }
+                }
+            ]
+        }"#;
+
+        let temp_dir = temp_dir("executor_primary_key");
+        let catalog = Catalog::from_json(KEYED_JSON).unwrap();
+        let b_manager = BufferPoolManager::new(2, temp_dir.to_str().unwrap().to_string(), catalog);
+        let mut executor = Executor::new(b_manager);
+        let member = |user_id, group_id| {
+            let mut attributes = HashMap::new();
+            attributes.insert("user_id".to_string(), AttributeType::Int(user_id));
+            attributes.insert("group_id".to_string(), AttributeType::Int(group_id));
+            attributes.insert("role".to_string(), AttributeType::Text("admin".to_string()));
+            attributes
+        };
+
+        // 片方の列だけが同じなら入る
+        executor.insert(&member(1, 1), "members").unwrap();
+        executor.insert(&member(1, 2), "members").unwrap();
+        executor.insert(&member(2, 1), "members").unwrap();
+
+        let err = executor.insert(&member(1, 2), "members").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "duplicate primary key (user_id, group_id) = (1, 2) in members"
+        );
+        assert_eq!(
+            err.downcast_ref::<DbError>().unwrap().status(),
+            "409 Conflict"
+        );
+
+        // 同じtransactionで入れた行とも比べ、rollbackした行とは比べない
+        let mut txn = executor.begin();
+        executor
+            .insert_in(&mut txn, &member(3, 1), "members")
+            .unwrap();
+        assert!(executor
+            .insert_in(&mut txn, &member(3, 1), "members")
+            .is_err());
+        executor.rollback(txn).unwrap();
+        executor.insert(&member(3, 1), "members").unwrap();
+
+        let mut records = Vec::new();
+        executor.scan("members", &mut records).unwrap();
+        assert_eq!(records.len(), 4);
+
+        // keyは覚えているので、insertのたびにtableを読み直さない
+        for user_id in 10..50 {
+            executor.insert(&member(user_id, 1), "members").unwrap();
+        }
+        assert!(executor.row_estimate("members").unwrap() > 40);
+        let fetches = |e: &Executor<LruReplacer>| {
+            let stats = e.buffer_pool_stats();
+            stats.hits + stats.misses
+        };
+        let before = fetches(&executor);
+        executor.insert(&member(50, 1), "members").unwrap();
+        assert!(executor.insert(&member(10, 1), "members").is_err());
+        assert_eq!(fetches(&executor) - before, 1);
+
+        // 開き直したときは、pageからkeyを集め直す
+        executor.close().unwrap();
+        let catalog = Catalog::from_json(KEYED_JSON).unwrap();
+        let b_manager = BufferPoolManager::new(2, temp_dir.to_str().unwrap().to_string(), catalog);
+        let mut executor = Executor::new(b_manager);
+        assert!(executor.insert(&member(1, 2), "members").is_err());
+        assert!(executor.insert(&member(3, 1), "members").is_err());
+        executor.insert(&member(3, 2), "members").unwrap();
+    }
+
+    #[test]
+    fn executor_space_padding() {
+        const PADDED_JSON: &str = r#"{
+            "schemas": [
+                {
+                    "table": {
+                        "name": "padded_test",
+                        "columns": [
+                            { "types": "text", "name": "code" }
+                        ],
+                        "primary_key": ["code"],
+                        "text_padding": "space"
+                    }
+                }
+            ]
+        }"#;
+
+        let temp_dir = temp_dir("executor_space_padding");
+        let open = || {
+            let catalog = Catalog::from_json(PADDED_JSON).unwrap();
+            let b_manager =
+                BufferPoolManager::new(2, temp_dir.to_str().unwrap().to_string(), catalog);
+            Executor::new(b_manager)
+        };
+        let code = |s: &str| {
+            let mut attributes = HashMap::new();
+            attributes.insert("code".to_string(), AttributeType::Text(s.to_string()));
+            attributes
+        };
+        let codes = |executor: &mut Executor<LruReplacer>| {
+            let mut records = Vec::new();
+            executor.scan("padded_test", &mut records).unwrap();
+            records
+                .iter()
+                .map(|r| r["code"].clone())
+                .collect::<Vec<_>>()
+        };
+
+        // 値の後ろの空白はinsertで落ちるので、空白だけが違う値は同じkeyになる
+        let mut executor = open();
+        executor.insert(&code("a "), "padded_test").unwrap();
+        assert!(executor.insert(&code("a"), "padded_test").is_err());
+        assert_eq!(
+            codes(&mut executor),
+            vec![AttributeType::Text("a".to_string())]
+        );
+
+        // pageを読み直しても同じ値が返る
+        executor.close().unwrap();
+        let mut executor = open();
+        assert_eq!(
+            codes(&mut executor),
+            vec![AttributeType::Text("a".to_string())]
+        );
+        assert!(executor.insert(&code("a  "), "padded_test").is_err());
+    }
+
+    #[test]
+    fn executor_max_tuples_per_page() {
+        const CAPPED_JSON: &str = r#"{
+            "schemas": [
+                {
+                    "table": {
+                        "name": "capped_test",
+                        "columns": [
+                            {
+                                "types": "int",
+                                "name": "column_int"
+                            }
+                        ],
+                        "max_tuples_per_page": 2
+                    }
+                }
+            ]
+        }"#;
+
+        let temp_dir = temp_dir("executor_max_tuples_per_page");
+        let catalog = Catalog::from_json(CAPPED_JSON).unwrap();
+        let b_manager = BufferPoolManager::new(2, temp_dir.to_str().unwrap().to_string(), catalog);
+        let mut executor = Executor::new(b_manager);
+
+        let mut attributes = HashMap::new();
+        attributes.insert("column_int".to_string(), AttributeType::Int(1));
+
+        for _ in 0..2 {
+            executor.insert(&attributes, "capped_test").unwrap();
+        }
+        assert_eq!(
+            executor
+                .buffer_pool_manager
+                .last_page_id("capped_test")
+                .unwrap(),
+            Some(PageID(0))
+        );
+
+        // 3つ目は新しいpageに入る
+        executor.insert(&attributes, "capped_test").unwrap();
+        assert_eq!(
+            executor
+                .buffer_pool_manager
+                .last_page_id("capped_test")
+                .unwrap(),
+            Some(PageID(1))
+        );
+        assert_eq!(executor.row_estimate("capped_test").unwrap(), 3);
+    }
+
+    #[test]
+    fn executor_append_only() {
+        const APPEND_JSON: &str = r#"{
+            "schemas": [
+                {
+                    "table": {
+                        "name": "append_test",
+                        "columns": [
+                            {
+                                "types": "int",
+                                "name": "column_int"
+                            },
+                            {
+                                "types": "text",
+                                "name": "column_text"
+                            }
+                        ],
+                        "append_only": true
+                    }
+                },
+                {
+                    "table": {
+                        "name": "heap_test",
+                        "columns": [
+                            {
+                                "types": "int",
+                                "name": "column_int"
+                            },
+                            {
+                                "types": "text",
+                                "name": "column_text"
+                            }
+                        ]
+                    }
+                }
+            ]
+        }"#;
+
+        let temp_dir = temp_dir("executor_append_only");
+        let catalog = Catalog::from_json(APPEND_JSON).unwrap();
+        let b_manager = BufferPoolManager::new(4, temp_dir.to_str().unwrap().to_string(), catalog);
+        let mut executor = Executor::new(b_manager);
+
+        let rows = 100;
+        let fetches = |e: &Executor<LruReplacer>| {
+            let stats = e.buffer_pool_stats();
+            stats.hits + stats.misses
+        };
+
+        let mut counts = Vec::new();
+        let mut lookups = Vec::new();
+        for table_name in ["append_test", "heap_test"] {
+            let before = fetches(&executor);
+            let lookups_before = executor.buffer_pool_manager.last_page_lookups();
+            for i in 0..rows {
+                let mut attributes = HashMap::new();
+                attributes.insert("column_int".to_string(), AttributeType::Int(i));
+                attributes.insert(
+                    "column_text".to_string(),
+                    AttributeType::Text("append".to_string()),
+                );
+                executor.insert(&attributes, table_name).unwrap();
+            }
+            counts.push(fetches(&executor) - before);
+            lookups.push(executor.buffer_pool_manager.last_page_lookups() - lookups_before);
+
+            let mut records = Vec::new();
+            executor.scan(table_name, &mut records).unwrap();
+            assert_eq!(records.len(), rows as usize);
+        }
+
+        // 新しいpageへのinsert以外は、cursorのpageを1回fetchするだけ
+        // 埋まったpageを読み直さない分、通常のtableより少なくなる
+        let pages = executor
+            .buffer_pool_manager
+            .last_page_id("append_test")
+            .unwrap()
+            .unwrap()
+            .value()
+            + 1;
+        assert!(pages > 1);
+        assert_eq!(counts[0], rows as u64 - pages as u64);
+        assert_eq!(counts[1], counts[0] + pages as u64 - 1);
+
+        // 通常のtableはinsertのたびにfileを開いて最後のpageを探すが、
+        // append onlyなtableは最初のinsertで探した後はcursorを使う
+        assert_eq!(lookups[0], 1);
+        assert_eq!(lookups[1], rows as u64);
     }
 }
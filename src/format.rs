@@ -0,0 +1,151 @@
+use std::borrow::Cow;
+
+// selectの結果を返す形式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    // 列を | で区切り、最後にtotalの行をつける
+    Text,
+    // RFC 4180。1行目は列名
+    Csv,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "text" => Some(Format::Text),
+            "csv" => Some(Format::Csv),
+            _ => None,
+        }
+    }
+
+    // Acceptに並んだmedia typeのうち、知っているものを使う。なければText
+    pub fn from_accept(accept: &str) -> Self {
+        accept
+            .split(',')
+            .filter_map(|t| t.split(';').next())
+            .find_map(|t| match t.trim() {
+                "text/csv" => Some(Format::Csv),
+                "text/plain" => Some(Format::Text),
+                _ => None,
+            })
+            .unwrap_or(Format::Text)
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Text => "text/plain; charset=utf-8",
+            Format::Csv => "text/csv; charset=utf-8",
+        }
+    }
+}
+
+// , " 改行を含む値だけを"で囲み、中の"は""と重ねる
+pub fn csv_field(value: &str) -> Cow<'_, str> {
+    if value.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", value.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+// 行末の改行はつけない
+pub fn csv_row(cells: &[String]) -> String {
+    cells
+        .iter()
+        .map(|c| csv_field(c))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+// csv_rowで書いた行を読み戻す。"の中の,や改行は値の一部
+// 最後の行の後ろの改行では、空の行を増やさない
+pub fn parse_csv(text: &str) -> Vec<Vec<String>> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => row.push(std::mem::take(&mut field)),
+            '\r' if !quoted && chars.peek() == Some(&'\n') => {}
+            '\n' if !quoted => {
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+            }
+            c => field.push(c),
+        }
+    }
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+
+    rows
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_csv_escape() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field("-12"), "-12");
+        assert_eq!(csv_field(""), "");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+        assert_eq!(csv_field("cr\r"), "\"cr\r\"");
+        assert_eq!(csv_field("O'Brien"), "O'Brien");
+
+        let cells = vec!["1".to_string(), "a,\"b\"".to_string(), String::new()];
+        assert_eq!(csv_row(&cells), "1,\"a,\"\"b\"\"\",");
+    }
+
+    #[test]
+    fn format_parse_csv() {
+        let cells = vec![
+            "1".to_string(),
+            "a,\"b\"".to_string(),
+            "two\nlines".to_string(),
+            String::new(),
+        ];
+        let text = format!("id,name,note,empty\n{}\n", csv_row(&cells));
+        assert_eq!(
+            parse_csv(&text),
+            vec![
+                vec!["id", "name", "note", "empty"]
+                    .into_iter()
+                    .map(String::from)
+                    .collect::<Vec<_>>(),
+                cells
+            ]
+        );
+
+        assert_eq!(parse_csv("a\r\n1\r\n"), vec![vec!["a"], vec!["1"]]);
+        // 列が1つなら、空の値は空の行になる
+        assert_eq!(parse_csv("a\n\n"), vec![vec!["a"], vec![""]]);
+        assert_eq!(parse_csv(""), Vec::<Vec<String>>::new());
+    }
+
+    #[test]
+    fn format_negotiate() {
+        assert_eq!(Format::parse("csv"), Some(Format::Csv));
+        assert_eq!(Format::parse("json"), None);
+
+        assert_eq!(Format::from_accept("text/csv"), Format::Csv);
+        assert_eq!(
+            Format::from_accept("application/json, text/csv;q=0.9"),
+            Format::Csv
+        );
+        assert_eq!(Format::from_accept("text/plain, text/csv"), Format::Text);
+        assert_eq!(Format::from_accept("*/*"), Format::Text);
+    }
+}
@@ -0,0 +1,698 @@
+use std::{
+    fmt,
+    io::{self, BufRead, ErrorKind, Read, Write},
+};
+
+// これより大きいbodyは読まずに413を返す
+pub const MAX_BODY_SIZE: usize = 1024 * 1024;
+// clientとserverのやりとりの形式の版。互換性のない変更をしたら上げる
+pub const PROTOCOL_VERSION: u32 = 1;
+// requestではclientの版を、応答ではserverの版を載せる。requestにないときは1とみなす
+pub const PROTOCOL_VERSION_HEADER: &str = "Aqua-Protocol-Version";
+
+#[derive(Debug, PartialEq)]
+pub struct Request {
+    pub request_line: String,
+    pub body: String,
+    // read_request_headではまだbodyを読まず、この長さだけ覚えておく
+    pub content_length: usize,
+    // Connection: keep-aliveが指定されたときだけ、応答の後も接続を閉じない
+    pub keep_alive: bool,
+    // Authorization: Bearer <token>のtoken
+    pub bearer_token: Option<String>,
+    pub protocol_version: u32,
+    // Upgrade: websocketのときの、Sec-WebSocket-Key
+    pub websocket_key: Option<String>,
+    // selectの結果の形式を選ぶのに使う
+    pub accept: Option<String>,
+}
+
+// 200以外のstatusで返すべきrequestの誤り
+#[derive(Debug, PartialEq)]
+pub struct HttpError {
+    pub status: &'static str,
+    pub message: String,
+}
+
+impl HttpError {
+    pub fn bad_request(message: String) -> Self {
+        Self {
+            status: "400 Bad Request",
+            message,
+        }
+    }
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+pub fn read_request<R: BufRead>(reader: &mut R) -> Result<Request, anyhow::Error> {
+    read_request_with_limit(reader, MAX_BODY_SIZE)
+}
+
+// max_bodyを超えるContent-Lengthは、bufferを確保する前に断る
+pub fn read_request_with_limit<R: BufRead>(
+    reader: &mut R,
+    max_body: usize,
+) -> Result<Request, anyhow::Error> {
+    let mut request = read_request_head(reader, max_body)?;
+    read_body(reader, &mut request)?;
+    Ok(request)
+}
+
+// headerの空行までを読む。bodyは読む前に断れるよう、read_bodyで別に読む
+pub fn read_request_head<R: BufRead>(
+    reader: &mut R,
+    max_body: usize,
+) -> Result<Request, anyhow::Error> {
+    let mut length = None;
+    let mut keep_alive = false;
+    let mut bearer_token = None;
+    let mut protocol_version = 1;
+    let mut upgrade_websocket = false;
+    let mut websocket_key = None;
+    let mut accept = None;
+    let mut request_line = String::new();
+
+    for x in reader.by_ref().lines() {
+        let x = x?;
+        if x.is_empty() {
+            break;
+        }
+
+        if request_line.is_empty() {
+            request_line = x;
+            continue;
+        }
+
+        let (name, value) = x
+            .split_once(':')
+            .ok_or_else(|| HttpError::bad_request(format!("malformed header: {}", x)))?;
+
+        // header名は大文字小文字を区別しない
+        if name.trim().eq_ignore_ascii_case("content-length") {
+            let n = value.trim().parse::<usize>().map_err(|_| {
+                HttpError::bad_request(format!("invalid content-length: {}", value.trim()))
+            })?;
+            length = Some(n);
+        }
+        if name.trim().eq_ignore_ascii_case("authorization") {
+            bearer_token = value
+                .trim()
+                .strip_prefix("Bearer ")
+                .map(|t| t.trim().to_string());
+        }
+        if name.trim().eq_ignore_ascii_case(PROTOCOL_VERSION_HEADER) {
+            protocol_version = value.trim().parse::<u32>().map_err(|_| {
+                HttpError::bad_request(format!(
+                    "invalid {}: {}",
+                    PROTOCOL_VERSION_HEADER,
+                    value.trim()
+                ))
+            })?;
+        }
+        if name.trim().eq_ignore_ascii_case("upgrade") {
+            upgrade_websocket = value.trim().eq_ignore_ascii_case("websocket");
+        }
+        if name.trim().eq_ignore_ascii_case("sec-websocket-key") {
+            websocket_key = Some(value.trim().to_string());
+        }
+        if name.trim().eq_ignore_ascii_case("accept") {
+            accept = Some(value.trim().to_string());
+        }
+        if name.trim().eq_ignore_ascii_case("connection") {
+            keep_alive = value
+                .split(',')
+                .any(|v| v.trim().eq_ignore_ascii_case("keep-alive"));
+        }
+    }
+
+    if request_line.is_empty() {
+        return Err(HttpError::bad_request("empty request".to_string()).into());
+    }
+
+    // bodyが要るかはpathによるので、ここでは空でも受け取る
+    let length = length.unwrap_or(0);
+
+    if length > max_body {
+        return Err(HttpError {
+            status: "413 Payload Too Large",
+            message: format!(
+                "request body of {} bytes exceeds {} bytes",
+                length, max_body
+            ),
+        }
+        .into());
+    }
+
+    Ok(Request {
+        request_line,
+        body: String::new(),
+        content_length: length,
+        keep_alive,
+        bearer_token,
+        protocol_version,
+        websocket_key: websocket_key.filter(|_| upgrade_websocket),
+        accept,
+    })
+}
+
+// read_request_headで読んだrequestの、Content-Lengthの分のbodyを読む
+pub fn read_body<R: BufRead>(reader: &mut R, request: &mut Request) -> Result<(), anyhow::Error> {
+    let mut buf = vec![0_u8; request.content_length];
+
+    // 1回のreadでbody全体が届くとは限らないので、read_exactで埋まるまで読む
+    reader.read_exact(&mut buf).map_err(|e| match e.kind() {
+        ErrorKind::UnexpectedEof => anyhow::anyhow!(
+            "connection closed before {} bytes of body were received",
+            buf.len()
+        ),
+        _ => e.into(),
+    })?;
+
+    request.body = String::from_utf8(buf)?;
+    Ok(())
+}
+
+// 断ったrequestのbodyを、bufferに溜めずに読み捨てる
+pub fn skip_body<R: BufRead>(reader: &mut R, request: &Request) -> io::Result<u64> {
+    io::copy(
+        &mut (&mut *reader).take(request.content_length as u64),
+        &mut io::sink(),
+    )
+}
+
+// clientがHTTPの応答を読むときに使う
+#[derive(Debug, PartialEq)]
+pub struct Response {
+    pub status: String,
+    // selectの結果がCSVで届いたかを見分けるのに使う
+    pub content_type: Option<String>,
+    pub body: String,
+    // chunkedの最後に届いたtrailerの名前と値
+    pub trailers: Vec<(String, String)>,
+}
+
+impl Response {
+    // 名前の大文字と小文字は区別しない
+    pub fn trailer(&self, name: &str) -> Option<&str> {
+        self.trailers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+// 何も読まずに閉じられたらNone。keep-aliveの接続をserverが先に閉じていたときに起きる
+pub fn read_response<R: BufRead>(reader: &mut R) -> Result<Option<Response>, anyhow::Error> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    let status = line
+        .trim_end()
+        .split_once(' ')
+        .map(|(_, s)| s.to_string())
+        .ok_or_else(|| anyhow::anyhow!("malformed status line: {}", line.trim_end()))?;
+
+    let mut length = None;
+    let mut chunked = false;
+    let mut content_type = None;
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+            if name.trim().eq_ignore_ascii_case("transfer-encoding") {
+                chunked = value.trim().eq_ignore_ascii_case("chunked");
+            }
+            if name.trim().eq_ignore_ascii_case("content-type") {
+                content_type = Some(value.trim().to_string());
+            }
+        }
+    }
+
+    let mut body = Vec::new();
+    let mut trailers = Vec::new();
+    if chunked {
+        loop {
+            let mut size = String::new();
+            reader.read_line(&mut size)?;
+            let size = usize::from_str_radix(size.trim_end(), 16)
+                .map_err(|_| anyhow::anyhow!("invalid chunk size: {}", size.trim_end()))?;
+            if size == 0 {
+                // trailerは空行まで続く
+                loop {
+                    let mut trailer = String::new();
+                    if reader.read_line(&mut trailer)? == 0 || trailer.trim_end().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = trailer.split_once(':') {
+                        trailers.push((name.trim().to_string(), value.trim().to_string()));
+                    }
+                }
+                break;
+            }
+            let start = body.len();
+            body.resize(start + size, 0);
+            reader.read_exact(&mut body[start..])?;
+            let mut crlf = [0_u8; 2];
+            reader.read_exact(&mut crlf)?;
+        }
+    } else if let Some(length) = length {
+        body.resize(length, 0);
+        reader.read_exact(&mut body)?;
+    } else {
+        reader.read_to_end(&mut body)?;
+    }
+
+    Ok(Some(Response {
+        status,
+        content_type,
+        body: String::from_utf8(body)?,
+        trailers,
+    }))
+}
+
+// 応答をTransfer-Encoding: chunkedで少しずつ書く
+// chunk_lines行たまるまではheaderも送らないので、それまでに起きたerrorは通常の応答で返せる
+pub struct ChunkedWriter<W: Write> {
+    writer: W,
+    keep_alive: bool,
+    content_type: &'static str,
+    chunk_lines: usize,
+    buf: String,
+    lines: usize,
+    started: bool,
+    finished: bool,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    pub fn new(writer: W, keep_alive: bool, chunk_lines: usize) -> Self {
+        Self {
+            writer,
+            keep_alive,
+            content_type: "text/plain; charset=utf-8",
+            chunk_lines,
+            buf: String::new(),
+            lines: 0,
+            started: false,
+            finished: false,
+        }
+    }
+
+    // headerを送る前に呼ぶ
+    pub fn set_content_type(&mut self, content_type: &'static str) {
+        self.content_type = content_type;
+    }
+
+    // headerを送ったか。送った後はstatusを変えられない
+    pub fn started(&self) -> bool {
+        self.started
+    }
+
+    pub fn finished(&self) -> bool {
+        self.finished
+    }
+
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        self.buf.push_str(line);
+        self.buf.push('\n');
+        self.lines += 1;
+        if self.lines >= self.chunk_lines {
+            self.flush_chunk()?;
+        }
+        Ok(())
+    }
+
+    // 残りを書き、trailerをつけて終える
+    pub fn finish(&mut self, last: &str, trailers: &[(&str, String)]) -> io::Result<()> {
+        self.buf.push_str(last);
+        // 最後のchunkと終わりは1度に送る。分けるとNagleとdelayed ACKで40msほど待つ
+        self.write_chunk()?;
+
+        write!(self.writer, "0\r\n")?;
+        for (name, value) in trailers {
+            write!(self.writer, "{}: {}\r\n", name, value)?;
+        }
+        write!(self.writer, "\r\n")?;
+        self.writer.flush()?;
+        self.finished = true;
+        Ok(())
+    }
+
+    // 途中で失敗したら、error: から始まる行を書いてtrailerなしで終える
+    // clientは行数のtrailerがないことでも失敗を見分けられる
+    pub fn abort(&mut self, message: &str) -> io::Result<()> {
+        self.buf.clear();
+        let line = format!("error: {}\n", message);
+        write!(self.writer, "{:x}\r\n{}\r\n0\r\n\r\n", line.len(), line)?;
+        self.writer.flush()?;
+        self.finished = true;
+        Ok(())
+    }
+
+    fn flush_chunk(&mut self) -> io::Result<()> {
+        self.write_chunk()?;
+        self.writer.flush()
+    }
+
+    fn write_chunk(&mut self) -> io::Result<()> {
+        if !self.started {
+            write!(
+                self.writer,
+                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nTransfer-Encoding: chunked\r\nTrailer: {}, {}\r\n{}: {}\r\nConnection: {}\r\n\r\n",
+                self.content_type,
+                ROW_COUNT_TRAILER,
+                ROW_TRUNCATED_TRAILER,
+                PROTOCOL_VERSION_HEADER,
+                PROTOCOL_VERSION,
+                if self.keep_alive { "keep-alive" } else { "close" },
+            )?;
+            self.started = true;
+        }
+
+        if !self.buf.is_empty() {
+            write!(self.writer, "{:x}\r\n{}\r\n", self.buf.len(), self.buf)?;
+            self.buf.clear();
+        }
+        self.lines = 0;
+        Ok(())
+    }
+}
+
+// streamで返したselectの行数を載せるtrailer
+pub const ROW_COUNT_TRAILER: &str = "X-Row-Count";
+// max_rowsで打ち切ったときだけ、trueで載せる
+pub const ROW_TRUNCATED_TRAILER: &str = "X-Row-Truncated";
+
+// `a=1&q=...`のようなquery stringから、nameの値をdecodeして返す
+pub fn query_param(query_string: &str, name: &str) -> Result<Option<String>, HttpError> {
+    Ok(query_params(query_string)?
+        .into_iter()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value))
+}
+
+// query stringの全てのkeyと値を、並んでいる順にdecodeして返す
+pub fn query_params(query_string: &str) -> Result<Vec<(String, String)>, HttpError> {
+    query_string
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            Ok((url_decode(key)?, url_decode(value)?))
+        })
+        .collect()
+}
+
+// `+`は空白、`%xx`はそのbyteに戻す。decodeした結果はutf-8でなければならない
+pub fn url_decode(s: &str) -> Result<String, HttpError> {
+    let malformed = || HttpError::bad_request(format!("malformed url encoding: {}", s));
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+
+    while let Some((&b, tail)) = rest.split_first() {
+        rest = tail;
+        match b {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex = rest
+                    .get(..2)
+                    .filter(|h| h.iter().all(u8::is_ascii_hexdigit))
+                    .ok_or_else(malformed)?;
+                let hex = std::str::from_utf8(hex).map_err(|_| malformed())?;
+                bytes.push(u8::from_str_radix(hex, 16).map_err(|_| malformed())?);
+                rest = &rest[2..];
+            }
+            b => bytes.push(b),
+        }
+    }
+
+    String::from_utf8(bytes).map_err(|_| malformed())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, Read};
+
+    use super::*;
+
+    #[test]
+    fn read_request_body_in_two_chunks() {
+        let head = "POST / HTTP/1.1\r\ncontent-length: 21\r\n\r\nselect * ";
+        let tail = "from users;\n";
+
+        // chainは1回のreadで前半しか返さない
+        let stream = head.as_bytes().chain(tail.as_bytes());
+        let mut reader = BufReader::new(stream);
+
+        let request = read_request(&mut reader).unwrap();
+
+        assert_eq!(request.request_line, "POST / HTTP/1.1");
+        assert_eq!(request.body, "select * from users;\n");
+    }
+
+    #[test]
+    fn read_request_body_at_segment_boundary() {
+        // 末尾に改行のない、1segment(1460byte)ちょうどのbody
+        let body = format!("select * from {};", "t".repeat(1460 - 15));
+        assert_eq!(body.len(), 1460);
+
+        let raw = format!("POST / HTTP/1.1\r\ncontent-length: 1460\r\n\r\n{}", body);
+        let (head, tail) = raw.split_at(raw.len() - 1);
+        let stream = head.as_bytes().chain(tail.as_bytes());
+        let mut reader = BufReader::new(stream);
+
+        let request = read_request(&mut reader).unwrap();
+
+        assert_eq!(request.body, body);
+    }
+
+    #[test]
+    fn read_request_stream_ends_early() {
+        let raw = "POST / HTTP/1.1\r\ncontent-length: 21\r\n\r\nselect";
+        let mut reader = BufReader::new(raw.as_bytes());
+
+        assert!(read_request(&mut reader).is_err());
+    }
+
+    fn status(raw: &str) -> &'static str {
+        let mut reader = BufReader::new(raw.as_bytes());
+        let e = read_request(&mut reader).unwrap_err();
+        e.downcast_ref::<HttpError>().unwrap().status
+    }
+
+    #[test]
+    fn read_request_header_case() {
+        // curlはContent-Lengthのように大文字で送ってくる
+        let raw = "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nexit;";
+        let mut reader = BufReader::new(raw.as_bytes());
+
+        let request = read_request(&mut reader).unwrap();
+
+        assert_eq!(request.body, "exit;");
+        assert_eq!(request.bearer_token, None);
+
+        let raw =
+            "POST / HTTP/1.1\r\nauthorization: Bearer secret\r\nContent-Length: 5\r\n\r\nexit;";
+        let mut reader = BufReader::new(raw.as_bytes());
+        let request = read_request(&mut reader).unwrap();
+        assert_eq!(request.bearer_token, Some("secret".to_string()));
+        assert_eq!(request.protocol_version, 1);
+
+        let raw = "POST / HTTP/1.1\r\naqua-protocol-version: 2\r\nContent-Length: 5\r\n\r\nexit;";
+        let mut reader = BufReader::new(raw.as_bytes());
+        assert_eq!(read_request(&mut reader).unwrap().protocol_version, 2);
+        assert_eq!(
+            status(
+                "POST / HTTP/1.1\r\nAqua-Protocol-Version: v1\r\nContent-Length: 5\r\n\r\nexit;"
+            ),
+            "400 Bad Request"
+        );
+        assert_eq!(request.websocket_key, None);
+
+        let raw = "GET /ws HTTP/1.1\r\nupgrade: WebSocket\r\nSec-WebSocket-Key: abc==\r\n\r\n";
+        let mut reader = BufReader::new(raw.as_bytes());
+        let request = read_request(&mut reader).unwrap();
+        assert_eq!(request.websocket_key, Some("abc==".to_string()));
+        assert_eq!(request.accept, None);
+
+        let raw = "POST / HTTP/1.1\r\nAccept: text/csv\r\nContent-Length: 5\r\n\r\nshow;";
+        let mut reader = BufReader::new(raw.as_bytes());
+        let request = read_request(&mut reader).unwrap();
+        assert_eq!(request.accept, Some("text/csv".to_string()));
+    }
+
+    #[test]
+    fn read_request_without_body() {
+        assert_eq!(
+            status("POST / HTTP/1.1\r\ncontent-length: abc\r\n\r\n"),
+            "400 Bad Request"
+        );
+        assert_eq!(status(""), "400 Bad Request");
+
+        let mut reader = BufReader::new("GET /metrics HTTP/1.1\r\n\r\n".as_bytes());
+        let request = read_request(&mut reader).unwrap();
+        assert_eq!(request.body, "");
+
+        // 空のbodyを断るかはserverがpathを見て決める
+        let mut reader = BufReader::new("POST /admin/shutdown HTTP/1.1\r\n\r\n".as_bytes());
+        assert_eq!(read_request(&mut reader).unwrap().body, "");
+    }
+
+    #[test]
+    fn read_request_too_large() {
+        let raw = format!(
+            "POST / HTTP/1.1\r\ncontent-length: {}\r\n\r\n",
+            MAX_BODY_SIZE + 1
+        );
+
+        assert_eq!(status(&raw), "413 Payload Too Large");
+    }
+
+    #[test]
+    fn chunked_writer() {
+        let mut out = Vec::new();
+        let mut w = ChunkedWriter::new(&mut out, false, 2);
+        w.write_line("a").unwrap();
+        assert!(!w.started());
+        w.write_line("b").unwrap();
+        assert!(w.started());
+        w.write_line("c").unwrap();
+        w.finish("total: 3", &[(ROW_COUNT_TRAILER, "3".to_string())])
+            .unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        let (head, body) = out.split_once("\r\n\r\n").unwrap();
+        assert!(head.contains("Transfer-Encoding: chunked"));
+        assert!(head.contains("Connection: close"));
+        assert_eq!(
+            body,
+            "4\r\na\nb\n\r\na\r\nc\ntotal: 3\r\n0\r\nX-Row-Count: 3\r\n\r\n"
+        );
+
+        let mut out = Vec::new();
+        let mut w = ChunkedWriter::new(&mut out, true, 1);
+        w.write_line("a").unwrap();
+        w.write_line("b").unwrap();
+        w.abort("query cancelled").unwrap();
+        assert!(w.finished());
+
+        let out = String::from_utf8(out).unwrap();
+        assert!(
+            out.ends_with("2\r\nb\n\r\n17\r\nerror: query cancelled\n\r\n0\r\n\r\n"),
+            "{}",
+            out
+        );
+    }
+
+    #[test]
+    fn read_response_bodies() {
+        let mut out = Vec::new();
+        let mut w = ChunkedWriter::new(&mut out, true, 1);
+        w.write_line("a").unwrap();
+        w.write_line("b").unwrap();
+        w.finish("total: 2", &[(ROW_COUNT_TRAILER, "2".to_string())])
+            .unwrap();
+        out.extend_from_slice(b"HTTP/1.1 400 Bad Request\r\ncontent-length: 4\r\n\r\noops");
+
+        // keep-aliveで続けて届いた応答も読める
+        let mut reader = out.as_slice();
+        assert_eq!(
+            read_response(&mut reader).unwrap(),
+            Some(Response {
+                status: "200 OK".to_string(),
+                content_type: Some("text/plain; charset=utf-8".to_string()),
+                body: "a\nb\ntotal: 2".to_string(),
+                trailers: vec![(ROW_COUNT_TRAILER.to_string(), "2".to_string())],
+            })
+        );
+        assert_eq!(
+            read_response(&mut reader).unwrap(),
+            Some(Response {
+                status: "400 Bad Request".to_string(),
+                content_type: None,
+                body: "oops".to_string(),
+                trailers: Vec::new(),
+            })
+        );
+        assert_eq!(read_response(&mut reader).unwrap(), None);
+
+        let mut reader = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n".as_bytes();
+        assert!(read_response(&mut reader).is_err());
+    }
+
+    #[test]
+    fn url_decode_escapes() {
+        assert_eq!(
+            url_decode("select+*+from%20users%3B").unwrap(),
+            "select * from users;"
+        );
+        assert_eq!(url_decode("%27O%27%27Brien%27").unwrap(), "'O''Brien'");
+        assert_eq!(url_decode("%E3%81%82").unwrap(), "あ");
+        for s in ["%", "%2", "%zz", "%+1", "%FF"] {
+            assert_eq!(url_decode(s).unwrap_err().status, "400 Bad Request");
+        }
+
+        assert_eq!(
+            query_param("x=1&q=show+tables%3B", "q").unwrap(),
+            Some("show tables;".to_string())
+        );
+        assert_eq!(query_param("x=1", "q").unwrap(), None);
+        assert_eq!(query_param("q", "q").unwrap(), Some(String::new()));
+        assert_eq!(query_params("").unwrap(), vec![]);
+        assert_eq!(
+            query_params("id=5&name=O%27Brien").unwrap(),
+            vec![
+                ("id".to_string(), "5".to_string()),
+                ("name".to_string(), "O'Brien".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn read_request_pipelined() {
+        // 2つのrequestが続けて届いても、1つ目のbodyの後ろは読まない
+        let raw = "POST / HTTP/1.1\r\nConnection: keep-alive\r\ncontent-length: 7\r\n\r\ncommit;POST / HTTP/1.1\r\ncontent-length: 5\r\n\r\nexit;";
+        let mut reader = BufReader::new(raw.as_bytes());
+
+        let first = read_request(&mut reader).unwrap();
+        assert_eq!(first.body, "commit;");
+        assert!(first.keep_alive);
+
+        let second = read_request(&mut reader).unwrap();
+        assert_eq!(second.body, "exit;");
+        assert!(!second.keep_alive);
+    }
+
+    #[test]
+    fn read_request_head_then_body() {
+        let raw = "POST / HTTP/1.1\r\ncontent-length: 7\r\n\r\ncommit;POST / HTTP/1.1\r\ncontent-length: 5\r\n\r\nshow;GET /health HTTP/1.1\r\n\r\n";
+        let mut reader = BufReader::new(raw.as_bytes());
+
+        // headerだけではbodyを読まない
+        let mut first = read_request_head(&mut reader, MAX_BODY_SIZE).unwrap();
+        assert_eq!(first.content_length, 7);
+        assert_eq!(first.body, "");
+        read_body(&mut reader, &mut first).unwrap();
+        assert_eq!(first.body, "commit;");
+
+        // 読み捨てても、次のrequestはその後ろから読める
+        let second = read_request_head(&mut reader, MAX_BODY_SIZE).unwrap();
+        assert_eq!(skip_body(&mut reader, &second).unwrap(), 5);
+        let third = read_request(&mut reader).unwrap();
+        assert_eq!(third.request_line, "GET /health HTTP/1.1");
+    }
+}
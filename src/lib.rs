@@ -1,4 +1,18 @@
 pub mod catalog;
+pub mod config;
+pub mod connection;
+pub mod error;
 pub mod executor;
+pub mod format;
+pub mod http;
+pub mod line;
+pub mod metrics;
+pub mod pgwire;
 pub mod query;
+pub mod query_cache;
+pub mod server;
 pub mod storage;
+pub mod websocket;
+
+#[cfg(test)]
+mod test_util;
@@ -0,0 +1,192 @@
+use std::io::{self, BufRead, Write};
+
+use anyhow::anyhow;
+
+// 1行ずつやりとりする簡単なprotocol
+// clientは;で終わる行までを1つのstatementとして送る
+// serverは`OK <n>`か`ERR <message>`の1行に続けてn行を返し、最後に空行を書く
+// 行の中の値はtabで区切り、値の中のtab、改行、\は\t、\n、\\と書く
+
+// ;で終わる行まで読み、行をつないだstatementを返す。何も読まずに閉じられたらNone
+pub fn read_statement<R: BufRead>(
+    reader: &mut R,
+    max_len: usize,
+) -> Result<Option<String>, anyhow::Error> {
+    let mut statement = String::new();
+
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            if statement.trim().is_empty() {
+                return Ok(None);
+            }
+            return Err(anyhow!("connection closed in the middle of a statement"));
+        }
+
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if !statement.is_empty() {
+            statement.push(' ');
+        }
+        statement.push_str(line);
+
+        if statement.len() > max_len {
+            return Err(anyhow!("statement exceeds {} bytes before ;", max_len));
+        }
+        if statement.ends_with(';') {
+            return Ok(Some(statement));
+        }
+    }
+}
+
+pub fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub fn unescape(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => unescaped.push('\t'),
+            Some('n') => unescaped.push('\n'),
+            Some(c) => unescaped.push(c),
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+// 値をescapeしてtabでつなぐ
+pub fn row(cells: &[String]) -> String {
+    cells
+        .iter()
+        .map(|c| escape(c))
+        .collect::<Vec<_>>()
+        .join("\t")
+}
+
+pub fn write_ok<W: Write>(writer: &mut W, lines: &[String]) -> io::Result<()> {
+    writeln!(writer, "OK {}", lines.len())?;
+    for line in lines {
+        writeln!(writer, "{}", line)?;
+    }
+    writeln!(writer)?;
+    writer.flush()
+}
+
+pub fn write_err<W: Write>(writer: &mut W, message: &str) -> io::Result<()> {
+    write!(writer, "ERR {}\n\n", message.replace('\n', " "))?;
+    writer.flush()
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Reply {
+    Ok(Vec<String>),
+    Err(String),
+}
+
+// 応答を1つ読む。clientとtestで使う
+pub fn read_reply<R: BufRead>(reader: &mut R) -> Result<Reply, anyhow::Error> {
+    let mut status = String::new();
+    if reader.read_line(&mut status)? == 0 {
+        return Err(anyhow!("connection closed"));
+    }
+    let status = status.trim_end_matches('\n');
+
+    let reply = if let Some(n) = status.strip_prefix("OK ") {
+        let n: usize = n
+            .parse()
+            .map_err(|_| anyhow!("invalid status {}", status))?;
+        let mut lines = Vec::with_capacity(n);
+        for _ in 0..n {
+            let mut line = String::new();
+            reader.read_line(&mut line)?;
+            lines.push(line.trim_end_matches('\n').to_string());
+        }
+        Reply::Ok(lines)
+    } else if let Some(message) = status.strip_prefix("ERR ") {
+        Reply::Err(message.to_string())
+    } else {
+        return Err(anyhow!("invalid status {}", status));
+    };
+
+    let mut blank = String::new();
+    reader.read_line(&mut blank)?;
+    if blank != "\n" {
+        return Err(anyhow!("expected a blank line after the reply"));
+    }
+
+    Ok(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::BufReader;
+
+    use super::*;
+
+    #[test]
+    fn line_read_statement() {
+        let raw = "select * from users;\ninsert into users\n  ( id=1 );\n\nbegin";
+        let mut reader = BufReader::new(raw.as_bytes());
+
+        assert_eq!(
+            read_statement(&mut reader, 1024).unwrap(),
+            Some("select * from users;".to_string())
+        );
+        assert_eq!(
+            read_statement(&mut reader, 1024).unwrap(),
+            Some("insert into users ( id=1 );".to_string())
+        );
+        assert!(read_statement(&mut reader, 1024).is_err());
+        assert_eq!(read_statement(&mut reader, 1024).unwrap(), None);
+
+        let mut reader = BufReader::new("select * from users;\n".as_bytes());
+        assert!(read_statement(&mut reader, 8).is_err());
+    }
+
+    #[test]
+    fn line_reply() {
+        let cells = vec!["1".to_string(), "a\tb\\c\n".to_string(), String::new()];
+        assert_eq!(row(&cells), "1\ta\\tb\\\\c\\n\t");
+        assert_eq!(unescape("a\\tb\\\\c\\n"), "a\tb\\c\n");
+
+        let mut out = Vec::new();
+        write_ok(&mut out, &["id\tname".to_string(), String::new()]).unwrap();
+        write_err(&mut out, "users not exist").unwrap();
+        write_ok(&mut out, &[]).unwrap();
+        assert_eq!(
+            String::from_utf8(out.clone()).unwrap(),
+            "OK 2\nid\tname\n\n\nERR users not exist\n\nOK 0\n\n"
+        );
+
+        // 空の行があっても、行数で区切りを見分ける
+        let mut reader = BufReader::new(out.as_slice());
+        assert_eq!(
+            read_reply(&mut reader).unwrap(),
+            Reply::Ok(vec!["id\tname".to_string(), String::new()])
+        );
+        assert_eq!(
+            read_reply(&mut reader).unwrap(),
+            Reply::Err("users not exist".to_string())
+        );
+        assert_eq!(read_reply(&mut reader).unwrap(), Reply::Ok(vec![]));
+        assert!(read_reply(&mut reader).is_err());
+    }
+}
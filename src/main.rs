@@ -1,110 +1,88 @@
-use std::{
-    fs::File,
-    io::{BufRead, BufReader, BufWriter, Read, Write},
-    net::{TcpListener, TcpStream},
-    vec,
-};
+use std::{fs, net::TcpListener, path::Path};
 
+#[cfg(unix)]
+use aqua_db::connection::UnixSocketListener;
 use aqua_db::{
     catalog::Catalog,
+    config::{Config, HELP},
+    connection::Listener,
     executor::Executor,
-    query::{ExecuteType, InsertInput, Parser, SelectInput},
-    storage::{buffer_pool_manager::BufferPoolManager, replacer::LruReplacer},
+    server::Server,
+    storage::{buffer_pool_manager::BufferPoolManager, disk_manager::DiskManager, recovery},
 };
 
 fn main() -> Result<(), anyhow::Error> {
-    let mut json_file = File::open("schema.json").unwrap();
-    let mut buf = Vec::new();
-    json_file.read_to_end(&mut buf).unwrap();
-    let json = String::from_utf8(buf).unwrap();
-    let catalog = Catalog::from_json(&json);
-
-    let parser = Parser::new(&catalog);
-    let manager = BufferPoolManager::new(10, "./data".to_string(), catalog.clone());
-    let mut executor = Executor::new(manager);
-
-    let listener = TcpListener::bind("127.0.0.1:8080")?;
-
-    for stream in listener.incoming() {
-        let read = stream?;
-        let write = read.try_clone()?;
-
-        let mut writer = BufWriter::new(&write);
-
-        let response_text = match read_handler(&read, &mut executor, &parser) {
-            Ok(s) => s,
-            Err(e) => format!("{}", e),
-        };
-
-        let response = format!("HTTP/1.1 200 OK\r\n\r\n{}", response_text);
-        writer.write_all(response.as_bytes())?;
-
-        if response_text == "exit" {
-            exit_handler(&mut executor)?;
-            break;
+    let config = match Config::parse(std::env::args().skip(1), |name| std::env::var(name).ok())? {
+        Some(config) => config,
+        None => {
+            println!("{}", HELP);
+            return Ok(());
         }
-    }
+    };
 
-    Ok(())
-}
+    env_logger::Builder::new()
+        .filter_level(config.log_level)
+        .init();
 
-fn read_handler(
-    stream: &TcpStream,
-    executor: &mut Executor<LruReplacer>,
-    parser: &Parser,
-) -> Result<String, anyhow::Error> {
-    let mut reader = BufReader::new(stream);
+    // schemaはfileでもdirectoryでも指定できる
+    let catalog = if Path::new(&config.schema).is_dir() {
+        Catalog::from_dir(&config.schema)?
+    } else {
+        Catalog::from_path(&config.schema)?
+    };
 
-    let mut length = 0;
+    if !config.read_only {
+        fs::create_dir_all(&config.data_dir)
+            .map_err(|e| anyhow::anyhow!("cannot create data dir {}: {}", config.data_dir, e))?;
+    }
 
-    for x in reader.by_ref().lines() {
-        let x = x?;
-        if x.is_empty() {
-            break;
+    // listenする前にwalをredoしておく
+    // read onlyではtable fileを書き換えられないので、redoが必要なら起動しない
+    let mut disk_manager = DiskManager::new(config.data_dir.clone(), catalog.clone());
+    if config.read_only {
+        if recovery::pending(&disk_manager)? > 0 {
+            return Err(anyhow::anyhow!(
+                "wal has records to recover; start once without --read-only"
+            ));
         }
+    } else {
+        recovery::run(&mut disk_manager, &catalog)?;
+    }
 
-        if x.starts_with("POST") {
-            continue;
-        }
+    let mut manager =
+        BufferPoolManager::new(config.pool_size, config.data_dir.clone(), catalog.clone());
+    manager.set_write_batching(true);
+    manager.set_durability(config.durability);
+    let mut executor = Executor::new(manager);
+    executor.set_read_only(config.read_only);
 
-        let header = x.split(':').collect::<Vec<&str>>();
+    let server = Server::with_config(&catalog, executor, config.clone());
 
-        if header[0] == "content-length" {
-            length = header[1].trim().parse::<u32>()?;
-        }
+    // unix domain socketなら、socket fileはrunを抜けるときに消える
+    #[cfg(unix)]
+    if let Some(path) = &config.listen_unix {
+        let listener = UnixSocketListener::bind(Path::new(path))?;
+        return serve(&server, &config, listener);
     }
+    let listener = TcpListener::bind(&config.listen)?;
+    serve(&server, &config, listener)
+}
 
-    let mut buf = vec![0_u8; (length - 1) as usize];
-    let _ = reader.read(&mut buf[..])?;
-
-    let query = std::str::from_utf8(&buf)?;
+fn serve<L: Listener>(server: &Server, config: &Config, listener: L) -> Result<(), anyhow::Error>
+where
+    for<'s> &'s L::Stream: std::io::Read + std::io::Write,
+{
+    if let Some(path) = &config.init_file {
+        server.run_init_file(path)?;
+    }
 
-    let response_text = match parser.parse(query)? {
-        ExecuteType::Select(SelectInput { table_name }) => {
-            let mut records = Vec::new();
-            executor.scan(&table_name, &mut records)?;
-            let mut s = String::new();
-            let len = records.len();
-            for r in records {
-                s.push_str(format!("{:?}\n", r).as_str());
-            }
-            s.push_str(format!("total: {}", len).as_str());
-            s
-        }
-        ExecuteType::Insert(InsertInput {
-            attributes,
-            table_name,
-        }) => {
-            executor.insert(&attributes, &table_name)?;
-            "success".to_string()
+    // Ctrl-CやSIGTERMでも/admin/shutdownと同じく、処理中の接続を待ってからcheckpointして終了する
+    let shutdown = server.shutdown_handle(listener.endpoint()?);
+    ctrlc::set_handler(move || {
+        if let Err(e) = shutdown.trigger() {
+            eprintln!("failed to stop the server: {}", e);
         }
-        ExecuteType::Exit => "exit".to_string(),
-    };
-
-    Ok(response_text)
-}
+    })?;
 
-fn exit_handler(executor: &mut Executor<LruReplacer>) -> Result<(), anyhow::Error> {
-    executor.all_flush()?;
-    Ok(())
+    server.run(listener)
 }
@@ -0,0 +1,269 @@
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::storage::buffer_pool_manager::BufferPoolStats;
+
+#[derive(Default, Debug)]
+pub struct Metrics {
+    // QueryKind::ALLの順に数える
+    queries: [AtomicU64; QueryKind::ALL.len()],
+    errors: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryKind {
+    Select,
+    Insert,
+    Update,
+    Begin,
+    Commit,
+    Rollback,
+    Checkpoint,
+    Show,
+}
+
+impl QueryKind {
+    const ALL: [QueryKind; 8] = [
+        QueryKind::Select,
+        QueryKind::Insert,
+        QueryKind::Update,
+        QueryKind::Begin,
+        QueryKind::Commit,
+        QueryKind::Rollback,
+        QueryKind::Checkpoint,
+        QueryKind::Show,
+    ];
+
+    fn label(&self) -> &'static str {
+        match self {
+            QueryKind::Select => "select",
+            QueryKind::Insert => "insert",
+            QueryKind::Update => "update",
+            QueryKind::Begin => "begin",
+            QueryKind::Commit => "commit",
+            QueryKind::Rollback => "rollback",
+            QueryKind::Checkpoint => "checkpoint",
+            QueryKind::Show => "show",
+        }
+    }
+}
+
+// scrapeのたびにstorageから集める値
+#[derive(Debug, Default)]
+pub struct StorageGauges {
+    pub dirty_pages: usize,
+    // tableごとの行数の見積もり
+    pub table_rows: Vec<(String, usize)>,
+}
+
+// 今処理している接続と書き込みの数と、その上限
+#[derive(Debug, Default)]
+pub struct ConcurrencyGauges {
+    pub connections: usize,
+    pub max_connections: usize,
+    pub writes: usize,
+    // Noneなら上限なし
+    pub max_concurrent_writes: Option<usize>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_query(&self, kind: QueryKind) {
+        self.queries[kind as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Prometheusのtext exposition formatで出力する
+    pub fn render(
+        &self,
+        pool: &BufferPoolStats,
+        gauges: &StorageGauges,
+        concurrency: &ConcurrencyGauges,
+    ) -> String {
+        let mut s = String::new();
+
+        write_help(
+            &mut s,
+            "aqua_queries_total",
+            "Queries executed by statement type.",
+            "counter",
+        );
+        for kind in QueryKind::ALL {
+            writeln!(
+                s,
+                "aqua_queries_total{{type=\"{}\"}} {}",
+                kind.label(),
+                self.queries[kind as usize].load(Ordering::Relaxed)
+            )
+            .unwrap();
+        }
+
+        write_counter(
+            &mut s,
+            "aqua_query_errors_total",
+            "Queries that returned an error.",
+            self.errors.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut s,
+            "aqua_buffer_pool_hits_total",
+            "Page fetches served from the buffer pool.",
+            pool.hits,
+        );
+        write_counter(
+            &mut s,
+            "aqua_buffer_pool_misses_total",
+            "Page fetches that had to read from disk.",
+            pool.misses,
+        );
+        write_counter(
+            &mut s,
+            "aqua_buffer_pool_evictions_total",
+            "Pages evicted from the buffer pool.",
+            pool.evictions,
+        );
+        write_counter(
+            &mut s,
+            "aqua_buffer_pool_writes_total",
+            "Pages written back to disk.",
+            pool.writes,
+        );
+
+        write_help(
+            &mut s,
+            "aqua_buffer_pool_dirty_pages",
+            "Pages in the buffer pool not yet written to disk.",
+            "gauge",
+        );
+        writeln!(s, "aqua_buffer_pool_dirty_pages {}", gauges.dirty_pages).unwrap();
+
+        write_help(
+            &mut s,
+            "aqua_table_rows",
+            "Estimated rows per table, assuming every page but the last is full.",
+            "gauge",
+        );
+        for (table_name, rows) in &gauges.table_rows {
+            writeln!(s, "aqua_table_rows{{table=\"{}\"}} {}", table_name, rows).unwrap();
+        }
+
+        write_gauge(
+            &mut s,
+            "aqua_connections",
+            "Connections being handled.",
+            concurrency.connections,
+        );
+        write_gauge(
+            &mut s,
+            "aqua_max_connections",
+            "Connections handled at once before new ones are refused.",
+            concurrency.max_connections,
+        );
+        write_gauge(
+            &mut s,
+            "aqua_writes_in_progress",
+            "Write statements running or waiting for the database.",
+            concurrency.writes,
+        );
+        // 上限がなければ出さない
+        if let Some(max) = concurrency.max_concurrent_writes {
+            write_gauge(
+                &mut s,
+                "aqua_max_concurrent_writes",
+                "Write statements allowed at once before others wait.",
+                max,
+            );
+        }
+
+        s
+    }
+}
+
+fn write_help(s: &mut String, name: &str, help: &str, metric_type: &str) {
+    writeln!(s, "# HELP {} {}", name, help).unwrap();
+    writeln!(s, "# TYPE {} {}", name, metric_type).unwrap();
+}
+
+fn write_counter(s: &mut String, name: &str, help: &str, value: u64) {
+    write_help(s, name, help, "counter");
+    writeln!(s, "{} {}", name, value).unwrap();
+}
+
+fn write_gauge(s: &mut String, name: &str, help: &str, value: usize) {
+    write_help(s, name, help, "gauge");
+    writeln!(s, "{} {}", name, value).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_render() {
+        let metrics = Metrics::new();
+        metrics.record_query(QueryKind::Select);
+        metrics.record_query(QueryKind::Select);
+        metrics.record_query(QueryKind::Insert);
+        metrics.record_error();
+
+        let pool = BufferPoolStats {
+            hits: 5,
+            misses: 2,
+            evictions: 1,
+            writes: 3,
+        };
+        let gauges = StorageGauges {
+            dirty_pages: 4,
+            table_rows: vec![("users".to_string(), 30)],
+        };
+        let concurrency = ConcurrencyGauges {
+            connections: 2,
+            max_connections: 64,
+            writes: 1,
+            max_concurrent_writes: Some(4),
+        };
+        let text = metrics.render(&pool, &gauges, &concurrency);
+
+        for line in text.lines() {
+            if line.starts_with('#') {
+                let parts: Vec<&str> = line.splitn(4, ' ').collect();
+                assert!(parts[1] == "HELP" || parts[1] == "TYPE", "{}", line);
+                continue;
+            }
+
+            // name{labels} value
+            let (name, value) = line.rsplit_once(' ').unwrap();
+            assert!(value.parse::<u64>().is_ok(), "{}", line);
+            let name = name.split('{').next().unwrap();
+            assert!(
+                name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'),
+                "{}",
+                line
+            );
+        }
+
+        assert!(text.contains("aqua_queries_total{type=\"select\"} 2\n"));
+        assert!(text.contains("aqua_queries_total{type=\"insert\"} 1\n"));
+        assert!(text.contains("aqua_query_errors_total 1\n"));
+        assert!(text.contains("aqua_buffer_pool_hits_total 5\n"));
+        assert!(text.contains("aqua_buffer_pool_writes_total 3\n"));
+        assert!(text.contains("aqua_queries_total{type=\"commit\"} 0\n"));
+        assert!(text.contains("aqua_buffer_pool_dirty_pages 4\n"));
+        assert!(text.contains("aqua_table_rows{table=\"users\"} 30\n"));
+        assert!(text.contains("aqua_connections 2\n"));
+        assert!(text.contains("aqua_writes_in_progress 1\n"));
+        assert!(text.contains("aqua_max_concurrent_writes 4\n"));
+
+        let concurrency = ConcurrencyGauges::default();
+        let text = metrics.render(&pool, &gauges, &concurrency);
+        assert!(!text.contains("aqua_max_concurrent_writes"));
+    }
+}
@@ -0,0 +1,246 @@
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+};
+
+use anyhow::anyhow;
+
+// PostgreSQLのfrontend/backend protocol(v3)のうち、simple queryに要る分だけ
+// messageは1byteの種類、自身を含む4byteの長さ、本体の順に並ぶ
+// startupのmessageだけは種類のbyteがない
+
+pub const PROTOCOL_VERSION: u32 = 196608; // 3.0
+const SSL_REQUEST: u32 = 80877103;
+const GSSENC_REQUEST: u32 = 80877104;
+const CANCEL_REQUEST: u32 = 80877102;
+
+// startupやmessageの長さの上限。これより長いものは壊れているとみなす
+const MAX_STARTUP_LEN: usize = 10_000;
+
+pub const AUTH_OK: u32 = 0;
+pub const AUTH_CLEARTEXT_PASSWORD: u32 = 3;
+
+pub const BYTEA_OID: u32 = 17;
+pub const INT4_OID: u32 = 23;
+pub const TEXT_OID: u32 = 25;
+pub const NUMERIC_OID: u32 = 1700;
+
+// SQLSTATE
+pub const SYNTAX_ERROR: &str = "42601";
+pub const UNDEFINED_TABLE: &str = "42P01";
+pub const FEATURE_NOT_SUPPORTED: &str = "0A000";
+pub const INVALID_TRANSACTION_STATE: &str = "25000";
+pub const READ_ONLY_TRANSACTION: &str = "25006";
+pub const QUERY_CANCELED: &str = "57014";
+pub const UNIQUE_VIOLATION: &str = "23505";
+pub const INVALID_PASSWORD: &str = "28P01";
+pub const TOO_MANY_CONNECTIONS: &str = "53300";
+pub const PROTOCOL_VIOLATION: &str = "08P01";
+pub const INTERNAL_ERROR: &str = "XX000";
+
+#[derive(Debug, PartialEq)]
+pub enum Startup {
+    // 暗号化の申し込み。Nを返して断ると、clientは平文でstartupを送り直す
+    Encryption,
+    Cancel,
+    Params {
+        version: u32,
+        params: HashMap<String, String>,
+    },
+}
+
+pub fn read_startup<R: Read>(reader: &mut R) -> Result<Startup, anyhow::Error> {
+    let body = read_body(reader, MAX_STARTUP_LEN)?;
+    if body.len() < 4 {
+        return Err(anyhow!("startup message too short"));
+    }
+    let code = u32::from_be_bytes([body[0], body[1], body[2], body[3]]);
+
+    match code {
+        SSL_REQUEST | GSSENC_REQUEST => Ok(Startup::Encryption),
+        CANCEL_REQUEST => Ok(Startup::Cancel),
+        version => {
+            // name、valueの順にNUL終端の文字列が並び、最後に空の文字列が来る
+            let mut strings = body[4..]
+                .split(|&b| b == 0)
+                .map(|s| String::from_utf8_lossy(s).into_owned());
+            let mut params = HashMap::new();
+            while let Some(name) = strings.next().filter(|n| !n.is_empty()) {
+                params.insert(name, strings.next().unwrap_or_default());
+            }
+            Ok(Startup::Params { version, params })
+        }
+    }
+}
+
+// 種類と本体を返す。messageの前で閉じられたらNone
+pub fn read_message<R: Read>(
+    reader: &mut R,
+    max_len: usize,
+) -> Result<Option<(u8, Vec<u8>)>, anyhow::Error> {
+    let mut tag = [0_u8; 1];
+    if reader.read(&mut tag)? == 0 {
+        return Ok(None);
+    }
+    let body = read_body(reader, max_len)?;
+
+    Ok(Some((tag[0], body)))
+}
+
+fn read_body<R: Read>(reader: &mut R, max_len: usize) -> Result<Vec<u8>, anyhow::Error> {
+    let mut len = [0_u8; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len < 4 || len - 4 > max_len {
+        return Err(anyhow!("invalid message length {}", len));
+    }
+    let mut body = vec![0_u8; len - 4];
+    reader.read_exact(&mut body)?;
+
+    Ok(body)
+}
+
+// QueryやPasswordMessageの本体はNUL終端の文字列1つ
+pub fn c_string(body: &[u8]) -> String {
+    let end = body.iter().position(|&b| b == 0).unwrap_or(body.len());
+    String::from_utf8_lossy(&body[..end]).into_owned()
+}
+
+fn write_message<W: Write>(writer: &mut W, tag: u8, body: &[u8]) -> io::Result<()> {
+    writer.write_all(&[tag])?;
+    writer.write_all(&(body.len() as u32 + 4).to_be_bytes())?;
+    writer.write_all(body)
+}
+
+fn push_c_string(body: &mut Vec<u8>, s: &str) {
+    body.extend_from_slice(s.as_bytes());
+    body.push(0);
+}
+
+pub fn authentication<W: Write>(writer: &mut W, code: u32) -> io::Result<()> {
+    write_message(writer, b'R', &code.to_be_bytes())
+}
+
+pub fn parameter_status<W: Write>(writer: &mut W, name: &str, value: &str) -> io::Result<()> {
+    let mut body = Vec::new();
+    push_c_string(&mut body, name);
+    push_c_string(&mut body, value);
+    write_message(writer, b'S', &body)
+}
+
+// Iはtransactionの外、Tはtransactionの中
+pub fn ready_for_query<W: Write>(writer: &mut W, status: u8) -> io::Result<()> {
+    write_message(writer, b'Z', &[status])?;
+    writer.flush()
+}
+
+// 列名と型のOID。値は全てtext形式で送る
+pub fn row_description<W: Write>(writer: &mut W, fields: &[(String, u32)]) -> io::Result<()> {
+    let mut body = (fields.len() as u16).to_be_bytes().to_vec();
+    for (name, oid) in fields {
+        push_c_string(&mut body, name);
+        body.extend_from_slice(&0_u32.to_be_bytes()); // table oid
+        body.extend_from_slice(&0_u16.to_be_bytes()); // column number
+        body.extend_from_slice(&oid.to_be_bytes());
+        body.extend_from_slice(&(-1_i16).to_be_bytes()); // type size
+        body.extend_from_slice(&(-1_i32).to_be_bytes()); // type modifier
+        body.extend_from_slice(&0_u16.to_be_bytes()); // text format
+    }
+    write_message(writer, b'T', &body)
+}
+
+pub fn data_row<W: Write>(writer: &mut W, values: &[String]) -> io::Result<()> {
+    let mut body = (values.len() as u16).to_be_bytes().to_vec();
+    for v in values {
+        body.extend_from_slice(&(v.len() as u32).to_be_bytes());
+        body.extend_from_slice(v.as_bytes());
+    }
+    write_message(writer, b'D', &body)
+}
+
+pub fn command_complete<W: Write>(writer: &mut W, tag: &str) -> io::Result<()> {
+    let mut body = Vec::new();
+    push_c_string(&mut body, tag);
+    write_message(writer, b'C', &body)
+}
+
+pub fn empty_query_response<W: Write>(writer: &mut W) -> io::Result<()> {
+    write_message(writer, b'I', &[])
+}
+
+pub fn error_response<W: Write>(writer: &mut W, code: &str, message: &str) -> io::Result<()> {
+    notice(writer, b'E', "ERROR", code, message)?;
+    writer.flush()
+}
+
+pub fn notice_response<W: Write>(writer: &mut W, message: &str) -> io::Result<()> {
+    notice(writer, b'N', "NOTICE", "01000", message)
+}
+
+fn notice<W: Write>(
+    writer: &mut W,
+    tag: u8,
+    severity: &str,
+    code: &str,
+    message: &str,
+) -> io::Result<()> {
+    let mut body = Vec::new();
+    for (field, value) in [
+        (b'S', severity),
+        (b'V', severity),
+        (b'C', code),
+        (b'M', message),
+    ] {
+        body.push(field);
+        push_c_string(&mut body, value);
+    }
+    body.push(0);
+    write_message(writer, tag, &body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pgwire_messages() {
+        let mut startup = Vec::new();
+        let params = b"user\0aqua\0database\0db\0\0";
+        startup.extend_from_slice(&(8 + params.len() as u32).to_be_bytes());
+        startup.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+        startup.extend_from_slice(params);
+        match read_startup(&mut startup.as_slice()).unwrap() {
+            Startup::Params { version, params } => {
+                assert_eq!(version, PROTOCOL_VERSION);
+                assert_eq!(params["user"], "aqua");
+                assert_eq!(params["database"], "db");
+            }
+            s => panic!("unexpected {:?}", s),
+        }
+
+        let ssl = [0, 0, 0, 8, 4, 210, 22, 47];
+        assert_eq!(read_startup(&mut &ssl[..]).unwrap(), Startup::Encryption);
+
+        let mut out = Vec::new();
+        row_description(&mut out, &[("id".to_string(), INT4_OID)]).unwrap();
+        data_row(&mut out, &["1".to_string()]).unwrap();
+        command_complete(&mut out, "SELECT 1").unwrap();
+
+        let mut reader = out.as_slice();
+        let (tag, body) = read_message(&mut reader, 1024).unwrap().unwrap();
+        assert_eq!(tag, b'T');
+        assert_eq!(&body[..2], &[0, 1]);
+        assert_eq!(c_string(&body[2..]), "id");
+        assert_eq!(&body[11..15], &INT4_OID.to_be_bytes());
+        assert_eq!(
+            read_message(&mut reader, 1024).unwrap(),
+            Some((b'D', vec![0, 1, 0, 0, 0, 1, b'1']))
+        );
+        let (tag, body) = read_message(&mut reader, 1024).unwrap().unwrap();
+        assert_eq!((tag, c_string(&body).as_str()), (b'C', "SELECT 1"));
+        assert_eq!(read_message(&mut reader, 1024).unwrap(), None);
+
+        let mut broken: &[u8] = &[b'Q', 0, 0, 0, 2];
+        assert!(read_message(&mut broken, 1024).is_err());
+    }
+}
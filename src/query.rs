@@ -1,6 +1,12 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+};
 
-use crate::catalog::{AttributeType, Catalog, Column};
+use crate::{
+    catalog::{AttributeType, Catalog, Column, Table, UPDATED_AT_COLUMN},
+    error::DbError,
+};
 
 pub struct Parser<'a> {
     catalog: &'a Catalog,
@@ -10,12 +16,36 @@ pub struct Parser<'a> {
 pub enum ExecuteType {
     Select(SelectInput),
     Insert(InsertInput),
-    Exit,
+    Update(UpdateInput),
+    Begin,
+    BeginReadOnly,
+    Commit,
+    Rollback,
+    Checkpoint,
+    ShowTables,
+    ShowSchema(Option<String>),
 }
 
 #[derive(PartialEq, Debug)]
 pub struct SelectInput {
     pub table_name: String,
+    // 空なら*として、schemaの全ての列を返す
+    pub columns: Vec<SelectColumn>,
+    // updated_atの疑似列も返す
+    pub updated_at: bool,
+}
+
+// select number as n, ... の1列。aliasがあれば結果の列名をそれにする
+#[derive(PartialEq, Debug)]
+pub struct SelectColumn {
+    pub name: String,
+    pub alias: Option<String>,
+}
+
+impl SelectColumn {
+    pub fn output_name(&self) -> &str {
+        self.alias.as_deref().unwrap_or(&self.name)
+    }
 }
 
 #[derive(PartialEq, Debug)]
@@ -24,113 +54,609 @@ pub struct InsertInput {
     pub attributes: HashMap<String, AttributeType>,
 }
 
+// update users set ( name='hoge' ) where id=1;
+// filterがなければ全ての行を書き換える
+#[derive(PartialEq, Debug)]
+pub struct UpdateInput {
+    pub table_name: String,
+    pub attributes: HashMap<String, AttributeType>,
+    pub filter: Option<(String, AttributeType)>,
+}
+
+// 複数のstatementを;で区切り、それぞれが始まる行番号と組にする
+// 'の中の;では区切らない。--から始まる行はcommentとして読み飛ばす
+// 改行や連続した空白は、parseできるように1つの空白に詰める
+pub fn split_statements(text: &str) -> Vec<(usize, String)> {
+    let mut statements = Vec::new();
+    let mut current = String::new();
+    let mut start_line = 0;
+    let mut quoted = false;
+
+    for (i, line) in text.lines().enumerate() {
+        if !quoted && line.trim_start().starts_with("--") {
+            continue;
+        }
+
+        for c in line.chars() {
+            if current.trim().is_empty() && !c.is_whitespace() {
+                start_line = i + 1;
+            }
+            current.push(c);
+
+            if c == '\'' {
+                quoted = !quoted;
+            } else if c == ';' && !quoted {
+                let statement = current.split_whitespace().collect::<Vec<_>>().join(" ");
+                statements.push((start_line, statement));
+                current.clear();
+            }
+        }
+        current.push('\n');
+    }
+
+    // ;で終わっていない末尾もparseさせてerrorにする
+    if !current.trim().is_empty() {
+        let statement = current.split_whitespace().collect::<Vec<_>>().join(" ");
+        statements.push((start_line, statement));
+    }
+
+    statements
+}
+
+// 空白で区切ったtokenと、それぞれがqueryの何byte目から始まるか
+struct Tokens<'q> {
+    words: Vec<&'q str>,
+    offsets: Vec<usize>,
+    // ;を除いたqueryの長さ。足りないtokenの位置として使う
+    end: usize,
+}
+
+impl<'q> Tokens<'q> {
+    // 空白で区切る。'で囲まれた中の空白では区切らない
+    // ''は'そのものを表すので、閉じてすぐ開いたものとして同じtokenに含める
+    fn new(query: &'q str) -> Result<Self, ParseError> {
+        let mut words = Vec::new();
+        let mut offsets = Vec::new();
+        let mut start = 0;
+        let mut quote_start = None;
+
+        for (i, c) in query.char_indices() {
+            match c {
+                '\'' => quote_start = quote_start.xor(Some(i)),
+                ' ' if quote_start.is_none() => {
+                    words.push(&query[start..i]);
+                    offsets.push(start);
+                    start = i + 1;
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(position) = quote_start {
+            return Err(ParseError::new(
+                ParseErrorKind::UnterminatedText,
+                "unterminated quoted text".to_string(),
+                position,
+            ));
+        }
+        words.push(&query[start..]);
+        offsets.push(start);
+
+        Ok(Self {
+            words,
+            offsets,
+            end: query.len(),
+        })
+    }
+
+    fn at(&self, i: usize) -> usize {
+        self.offsets.get(i).copied().unwrap_or(self.end)
+    }
+}
+
+// column_name=valueのcolumn名ごとに、まだ型をつけていない値とその位置
+type RawAttributes<'q> = HashMap<&'q str, (&'q str, usize)>;
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum ParseErrorKind {
+    // ;で終わっていない、tokenが足りないなど、queryが途中で終わっている
+    UnexpectedEnd,
+    UnexpectedToken,
+    UnknownTable(String),
+    // column_name=valueの形や値の型が正しくない
+    InvalidAttribute,
+    UnterminatedText,
+}
+
+// parseできなかった理由と、原因になったtokenがqueryの何byte目から始まるか
+#[derive(PartialEq, Debug, Clone)]
+pub struct ParseError {
+    pub message: String,
+    pub position: usize,
+    pub kind: ParseErrorKind,
+}
+
+impl ParseError {
+    fn new(kind: ParseErrorKind, message: String, position: usize) -> Self {
+        Self {
+            message,
+            position,
+            kind,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl From<ParseError> for DbError {
+    fn from(e: ParseError) -> Self {
+        match e.kind {
+            ParseErrorKind::UnknownTable(table_name) => DbError::TableNotFound(table_name),
+            _ => DbError::Parse(e.message),
+        }
+    }
+}
+
+impl ExecuteType {
+    // logに出すstatementの種類
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ExecuteType::Select(_) => "select",
+            ExecuteType::Insert(_) => "insert",
+            ExecuteType::Update(_) => "update",
+            ExecuteType::Begin => "begin",
+            ExecuteType::BeginReadOnly => "begin read only",
+            ExecuteType::Commit => "commit",
+            ExecuteType::Rollback => "rollback",
+            ExecuteType::Checkpoint => "checkpoint",
+            ExecuteType::ShowTables | ExecuteType::ShowSchema(_) => "show",
+        }
+    }
+}
+
 impl<'a> Parser<'a> {
     pub fn new(catalog: &'a Catalog) -> Self {
         Self { catalog }
     }
 
-    pub fn parse(&self, query: &str) -> Result<ExecuteType, anyhow::Error> {
-        if !query.ends_with(';') {
-            return Err(anyhow::anyhow!("expect end with ;"));
+    pub fn catalog(&self) -> &Catalog {
+        self.catalog
+    }
+
+    pub fn parse(&self, query: &str) -> Result<ExecuteType, DbError> {
+        Ok(self.parse_detailed(query)?)
+    }
+
+    // POST /tables/<table_name>/rowsのJSON objectを、insertとして読む
+    // keyをcolumn名として、値はcolumnの型に合わせて変換する
+    pub fn parse_json_row(&self, table_name: &str, body: &str) -> Result<ExecuteType, DbError> {
+        let table = &self
+            .catalog
+            .get_schema_by_table_name(table_name)
+            .ok_or_else(|| DbError::TableNotFound(table_name.to_string()))?
+            .table;
+
+        let row: serde_json::Map<String, serde_json::Value> = serde_json::from_str(body)
+            .map_err(|e| DbError::Parse(format!("body must be a JSON object: {}", e)))?;
+        if let Some(key) = row
+            .keys()
+            .find(|k| !table.columns().iter().any(|c| &c.name == *k))
+        {
+            return Err(DbError::Parse(format!(
+                "{} is not a column of {}",
+                key, table_name
+            )));
         }
 
+        let mut attributes = HashMap::new();
+        for Column { name, types } in table.columns() {
+            let value = row
+                .get(name)
+                .ok_or_else(|| DbError::Parse(format!("{} is not found", name)))?;
+            let t = AttributeType::from_json_typed(types, value)
+                .map_err(|e| DbError::Parse(format!("{}: {}", name, e)))?;
+            attributes.insert(name.clone(), t);
+        }
+
+        Ok(ExecuteType::Insert(InsertInput {
+            table_name: table_name.to_string(),
+            attributes,
+        }))
+    }
+
+    // parseと同じだが、失敗した位置と分類をParseErrorで返す
+    pub fn parse_detailed(&self, query: &str) -> Result<ExecuteType, ParseError> {
         // remove ;
-        let mut query = query.to_string();
-        query.pop();
+        let query = query.strip_suffix(';').ok_or_else(|| {
+            ParseError::new(
+                ParseErrorKind::UnexpectedEnd,
+                "expect end with ;".to_string(),
+                query.len(),
+            )
+        })?;
+
+        let tokens = Tokens::new(query)?;
 
-        let splitted: Vec<&str> = query.split(' ').collect();
+        match tokens.words[0] {
+            "select" => self.parse_select(&tokens),
+            "insert" => self.parse_insert(&tokens),
+            "update" => self.parse_update(&tokens),
+            "begin" => match tokens.words[1..] {
+                [] => Ok(ExecuteType::Begin),
+                ["read", "only"] => Ok(ExecuteType::BeginReadOnly),
+                _ => Err(ParseError::new(
+                    ParseErrorKind::UnexpectedToken,
+                    "begin query something wrong".to_string(),
+                    tokens.at(1),
+                )),
+            },
+            "commit" => Ok(ExecuteType::Commit),
+            "rollback" => Ok(ExecuteType::Rollback),
+            "checkpoint" => Ok(ExecuteType::Checkpoint),
+            "show" => self.parse_show(&tokens),
+            t => Err(ParseError::new(
+                ParseErrorKind::UnexpectedToken,
+                format!("not expected {}", t),
+                0,
+            )),
+        }
+    }
+
+    fn table_not_found(table_name: &str, position: usize) -> ParseError {
+        ParseError::new(
+            ParseErrorKind::UnknownTable(table_name.to_string()),
+            DbError::TableNotFound(table_name.to_string()).to_string(),
+            position,
+        )
+    }
+
+    fn parse_select(&self, tokens: &Tokens) -> Result<ExecuteType, ParseError> {
+        if tokens.words.len() < 4 {
+            return Err(ParseError::new(
+                ParseErrorKind::UnexpectedEnd,
+                "select query something wrong".to_string(),
+                tokens.end,
+            ));
+        }
+
+        // select *, updated_at from <table_name>; なら疑似列をつける
+        // select a, b as c from <table_name>; なら列を選ぶ
+        let pseudo = format!("*,{}", UPDATED_AT_COLUMN);
+        let from = tokens.words.iter().position(|&w| w == "from");
+        let (projection, updated_at, table_at) = match from {
+            Some(i) if tokens.words[1..i].concat() == pseudo => (None, true, i + 1),
+            Some(i) if i > 1 && tokens.words[1..i] != ["*"] => (Some(1..i), false, i + 1),
+            _ => (None, false, 3),
+        };
+        let table_name = match tokens.words.get(table_at) {
+            Some(name) => name.to_string(),
+            None => {
+                return Err(ParseError::new(
+                    ParseErrorKind::UnexpectedEnd,
+                    "select query something wrong".to_string(),
+                    tokens.end,
+                ))
+            }
+        };
 
-        match splitted[0] {
-            "select" => self.parse_select(&splitted),
-            "insert" => self.parse_insert(&splitted),
-            "exit" => Ok(ExecuteType::Exit),
-            t => Err(anyhow::anyhow!("not expected {}", t)),
+        let schema = self
+            .catalog
+            .get_schema_by_table_name(&table_name)
+            .ok_or_else(|| Self::table_not_found(&table_name, tokens.at(table_at)))?;
+        if updated_at
+            && schema
+                .table
+                .columns()
+                .iter()
+                .any(|c| c.name == UPDATED_AT_COLUMN)
+        {
+            return Err(ParseError::new(
+                ParseErrorKind::InvalidAttribute,
+                format!("{} already has a column {}", table_name, UPDATED_AT_COLUMN),
+                tokens.at(1),
+            ));
         }
+
+        let columns = match projection {
+            Some(range) => Self::parse_projection(tokens, range, &schema.table)?,
+            None => Vec::new(),
+        };
+
+        Ok(ExecuteType::Select(SelectInput {
+            table_name,
+            columns,
+            updated_at,
+        }))
     }
 
-    fn parse_select(&self, tokens: &[&str]) -> Result<ExecuteType, anyhow::Error> {
-        if tokens.len() < 4 {
-            return Err(anyhow::anyhow!("select query something wrong"));
+    // selectとfromの間を,で区切り、column か column as alias として読む
+    // 結果の列名が重なるとどの列か分からなくなるのでerrorにする
+    fn parse_projection(
+        tokens: &Tokens,
+        range: std::ops::Range<usize>,
+        table: &Table,
+    ) -> Result<Vec<SelectColumn>, ParseError> {
+        // ,の前後に空白があってもなくても同じに読めるよう、tokenをさらに,で分ける
+        let mut groups = vec![(tokens.at(range.start), Vec::new())];
+        for i in range {
+            let mut offset = tokens.at(i);
+            for (j, part) in tokens.words[i].split(',').enumerate() {
+                if j > 0 {
+                    groups.push((offset, Vec::new()));
+                }
+                if !part.is_empty() {
+                    groups.last_mut().unwrap().1.push((part, offset));
+                }
+                offset += part.len() + 1;
+            }
         }
 
-        let table_name = tokens[3].to_string();
+        let mut columns = Vec::new();
+        let mut names = HashSet::new();
+        for (start, group) in groups {
+            let (column, position) = match group[..] {
+                [(name, p)] => (
+                    SelectColumn {
+                        name: name.to_string(),
+                        alias: None,
+                    },
+                    p,
+                ),
+                [(name, p), ("as", _), (alias, _)] => (
+                    SelectColumn {
+                        name: name.to_string(),
+                        alias: Some(alias.to_string()),
+                    },
+                    p,
+                ),
+                [] => {
+                    return Err(ParseError::new(
+                        ParseErrorKind::UnexpectedToken,
+                        "select column is empty".to_string(),
+                        start,
+                    ))
+                }
+                [.., (_, p)] => {
+                    return Err(ParseError::new(
+                        ParseErrorKind::UnexpectedToken,
+                        "select column must be column or column as alias".to_string(),
+                        p,
+                    ))
+                }
+            };
 
-        if !self.catalog.exist_table(&table_name) {
-            return Err(anyhow::anyhow!("{} not exist", table_name));
+            if !table.columns().iter().any(|c| c.name == column.name) {
+                return Err(ParseError::new(
+                    ParseErrorKind::InvalidAttribute,
+                    format!("{} is not a column of {}", column.name, table.name),
+                    position,
+                ));
+            }
+            if !names.insert(column.output_name().to_string()) {
+                return Err(ParseError::new(
+                    ParseErrorKind::InvalidAttribute,
+                    format!(
+                        "column name {} is used twice in select",
+                        column.output_name()
+                    ),
+                    position,
+                ));
+            }
+            columns.push(column);
         }
 
-        Ok(ExecuteType::Select(SelectInput { table_name }))
+        Ok(columns)
     }
 
-    fn parse_insert(&self, tokens: &[&str]) -> Result<ExecuteType, anyhow::Error> {
-        if tokens.len() < 6 {
-            return Err(anyhow::anyhow!("insert query something wrong"));
+    // show tables; / show schema [table_name];
+    fn parse_show(&self, tokens: &Tokens) -> Result<ExecuteType, ParseError> {
+        match tokens.words[..] {
+            [_, "tables"] => Ok(ExecuteType::ShowTables),
+            [_, "schema"] => Ok(ExecuteType::ShowSchema(None)),
+            [_, "schema", table_name] => {
+                if !self.catalog.exist_table(table_name) {
+                    return Err(Self::table_not_found(table_name, tokens.at(2)));
+                }
+                Ok(ExecuteType::ShowSchema(Some(table_name.to_string())))
+            }
+            _ => Err(ParseError::new(
+                ParseErrorKind::UnexpectedToken,
+                "show query something wrong".to_string(),
+                tokens.at(1),
+            )),
         }
+    }
 
-        let table_name = tokens[2].to_string();
+    fn parse_insert(&self, tokens: &Tokens) -> Result<ExecuteType, ParseError> {
+        if tokens.words.len() < 6 {
+            return Err(ParseError::new(
+                ParseErrorKind::UnexpectedEnd,
+                "insert query something wrong".to_string(),
+                tokens.end,
+            ));
+        }
+
+        let table_name = tokens.words[2].to_string();
 
         let table = &self
             .catalog
             .get_schema_by_table_name(&table_name)
-            .ok_or_else(|| anyhow::anyhow!("{} not exist", table_name))?
+            .ok_or_else(|| Self::table_not_found(&table_name, tokens.at(2)))?
             .table;
 
-        let mut raw_attributes = HashMap::new();
         let mut attributes = HashMap::new();
-
-        // gather attribute
-        'o: for (i, &token) in tokens.iter().enumerate() {
-            if token != "(" {
-                continue;
+        // column名ごとに、値とその位置を持つ
+        // columnが足りないときに指す位置。)があればそこ
+        let (raw_attributes, close) = match tokens.words.iter().position(|&w| w == "(") {
+            Some(open) => {
+                let (raw_attributes, close) = Self::gather_attributes(tokens, open)?;
+                (raw_attributes, tokens.at(close))
             }
+            None => (HashMap::new(), tokens.end),
+        };
 
-            for &x in &tokens[i + 1..] {
-                if x == ")" {
-                    break 'o;
-                }
+        for Column { name, types } in table.columns() {
+            let &(value, position) = raw_attributes.get(name.as_str()).ok_or_else(|| {
+                ParseError::new(
+                    ParseErrorKind::InvalidAttribute,
+                    format!("{} is not found", name),
+                    close,
+                )
+            })?;
 
-                // insert into users ( id=1 name='hoge' );
+            let t = AttributeType::from_str_typed(types, value).map_err(|e| {
+                ParseError::new(
+                    ParseErrorKind::InvalidAttribute,
+                    format!("{}: {}", name, e),
+                    position,
+                )
+            })?;
 
-                let v: Vec<&str> = x.split('=').collect();
+            attributes.insert(name.clone(), t);
+        }
 
-                if v.len() != 2 {
-                    return Err(anyhow::anyhow!(
-                        "Specify an attribute like column_name=value"
-                    ));
-                }
+        Ok(ExecuteType::Insert(InsertInput {
+            table_name,
+            attributes,
+        }))
+    }
 
-                let c_name = v[0];
-                let value = v[1];
+    // openの(から)までのcolumn_name=valueを集め、column名ごとの値とその位置、)のtokenの番号を返す
+    // insert into users ( id=1 name='hoge' );
+    fn gather_attributes<'q>(
+        tokens: &Tokens<'q>,
+        open: usize,
+    ) -> Result<(RawAttributes<'q>, usize), ParseError> {
+        let mut raw_attributes = HashMap::new();
 
-                raw_attributes.insert(c_name, value);
+        for (j, &x) in tokens.words.iter().enumerate().skip(open + 1) {
+            if x == ")" {
+                return Ok((raw_attributes, j));
             }
 
-            return Err(anyhow::anyhow!("not found )"));
+            // textの中の=では分けない
+            let (c_name, value) = x.split_once('=').ok_or_else(|| {
+                ParseError::new(
+                    ParseErrorKind::InvalidAttribute,
+                    "Specify an attribute like column_name=value".to_string(),
+                    tokens.at(j),
+                )
+            })?;
+
+            raw_attributes.insert(c_name, (value, tokens.at(j) + c_name.len() + 1));
         }
 
-        for Column { name, types } in &table.columns {
-            let &value = raw_attributes
-                .get(name.as_str())
-                .ok_or_else(|| anyhow::anyhow!("{} is not found", name))?;
+        Err(ParseError::new(
+            ParseErrorKind::UnexpectedEnd,
+            "not found )".to_string(),
+            tokens.end,
+        ))
+    }
 
-            let t = match types.as_str() {
-                "int" => Ok(AttributeType::Int(value.parse().unwrap())),
-                "text" => {
-                    let mut s = value.to_string();
-                    // remove '
-                    s.remove(0);
-                    s.pop();
-                    Ok(AttributeType::Text(s))
-                }
-                _ => Err(anyhow::anyhow!("not found )")),
-            }?;
+    // column_name=valueの値を、tableのcolumnの型で読む。positionはcolumn名の位置
+    fn typed_attribute(
+        table: &Table,
+        name: &str,
+        value: &str,
+        position: usize,
+    ) -> Result<AttributeType, ParseError> {
+        let column = table
+            .columns()
+            .iter()
+            .find(|c| c.name == name)
+            .ok_or_else(|| {
+                ParseError::new(
+                    ParseErrorKind::InvalidAttribute,
+                    format!("{} is not a column of {}", name, table.name),
+                    position,
+                )
+            })?;
 
-            attributes.insert(name.clone(), t);
+        AttributeType::from_str_typed(&column.types, value).map_err(|e| {
+            ParseError::new(
+                ParseErrorKind::InvalidAttribute,
+                format!("{}: {}", name, e),
+                position + name.len() + 1,
+            )
+        })
+    }
+
+    // update <table_name> set ( column_name=value ... ) [where column_name=value];
+    // 書き換えるcolumnだけを書く
+    fn parse_update(&self, tokens: &Tokens) -> Result<ExecuteType, ParseError> {
+        if tokens.words.len() < 5 {
+            return Err(ParseError::new(
+                ParseErrorKind::UnexpectedEnd,
+                "update query something wrong".to_string(),
+                tokens.end,
+            ));
         }
 
-        Ok(ExecuteType::Insert(InsertInput {
+        let table_name = tokens.words[1].to_string();
+        let table = &self
+            .catalog
+            .get_schema_by_table_name(&table_name)
+            .ok_or_else(|| Self::table_not_found(&table_name, tokens.at(1)))?
+            .table;
+
+        if tokens.words[2..4] != ["set", "("] {
+            return Err(ParseError::new(
+                ParseErrorKind::UnexpectedToken,
+                "expect set ( after the table name".to_string(),
+                tokens.at(2),
+            ));
+        }
+        let (raw_attributes, close) = Self::gather_attributes(tokens, 3)?;
+        if raw_attributes.is_empty() {
+            return Err(ParseError::new(
+                ParseErrorKind::InvalidAttribute,
+                "update needs at least one column_name=value".to_string(),
+                tokens.at(close),
+            ));
+        }
+
+        // 書いた順に確かめ、最初に読めなかったcolumnの位置を返す
+        let mut raw_attributes: Vec<_> = raw_attributes.into_iter().collect();
+        raw_attributes.sort_by_key(|(_, (_, position))| *position);
+        let mut attributes = HashMap::new();
+        for (name, (value, position)) in raw_attributes {
+            let t = Self::typed_attribute(table, name, value, position - name.len() - 1)?;
+            attributes.insert(name.to_string(), t);
+        }
+
+        let filter = match tokens.words[(close + 1)..] {
+            [] => None,
+            ["where", condition] => {
+                let position = tokens.at(close + 2);
+                let (name, value) = condition.split_once('=').ok_or_else(|| {
+                    ParseError::new(
+                        ParseErrorKind::InvalidAttribute,
+                        "Specify a condition like column_name=value".to_string(),
+                        position,
+                    )
+                })?;
+                let t = Self::typed_attribute(table, name, value, position)?;
+                Some((name.to_string(), t))
+            }
+            _ => {
+                return Err(ParseError::new(
+                    ParseErrorKind::UnexpectedToken,
+                    "expect where column_name=value after )".to_string(),
+                    tokens.at(close + 1),
+                ))
+            }
+        };
+
+        Ok(ExecuteType::Update(UpdateInput {
             table_name,
             attributes,
+            filter,
         }))
     }
 }
@@ -161,7 +687,7 @@ mod tests {
 
     #[test]
     fn query_parse_select() {
-        let catalog = Catalog::from_json(JSON);
+        let catalog = Catalog::from_json(JSON).unwrap();
         let p = Parser::new(&catalog);
         let query = "select * from query_test;";
 
@@ -170,14 +696,87 @@ mod tests {
         assert_eq!(
             e_type,
             ExecuteType::Select(SelectInput {
-                table_name: "query_test".to_string()
+                table_name: "query_test".to_string(),
+                columns: Vec::new(),
+                updated_at: false,
             })
         );
+
+        for query in [
+            "select *, updated_at from query_test;",
+            "select * , updated_at from query_test;",
+        ] {
+            assert_eq!(
+                p.parse(query).unwrap(),
+                ExecuteType::Select(SelectInput {
+                    table_name: "query_test".to_string(),
+                    columns: Vec::new(),
+                    updated_at: true,
+                })
+            );
+        }
+        assert!(p.parse("select *, updated_at from nothing;").is_err());
+        assert!(p.parse("select *, updated_at from").is_err());
+    }
+
+    #[test]
+    fn query_parse_select_alias() {
+        let catalog = Catalog::from_json(JSON).unwrap();
+        let p = Parser::new(&catalog);
+
+        let column = |name: &str, alias: Option<&str>| SelectColumn {
+            name: name.to_string(),
+            alias: alias.map(str::to_string),
+        };
+        for query in [
+            "select text, number as n from query_test;",
+            "select text,number as n from query_test;",
+            "select text , number as n from query_test;",
+        ] {
+            assert_eq!(
+                p.parse(query).unwrap(),
+                ExecuteType::Select(SelectInput {
+                    table_name: "query_test".to_string(),
+                    columns: vec![column("text", None), column("number", Some("n"))],
+                    updated_at: false,
+                }),
+                "{}",
+                query
+            );
+        }
+
+        // 元の列名と同じaliasは重ならなければよい
+        assert!(p
+            .parse("select number as text, text as number from query_test;")
+            .is_ok());
+
+        let e = p
+            .parse_detailed("select number as n, text as n from query_test;")
+            .unwrap_err();
+        assert_eq!(e.kind, ParseErrorKind::InvalidAttribute);
+        assert_eq!(e.message, "column name n is used twice in select");
+        assert_eq!(e.position, 20);
+        let e = p
+            .parse_detailed("select number as text, text from query_test;")
+            .unwrap_err();
+        assert_eq!(e.message, "column name text is used twice in select");
+
+        let e = p
+            .parse_detailed("select number, nothing from query_test;")
+            .unwrap_err();
+        assert_eq!(e.message, "nothing is not a column of query_test");
+        assert_eq!(e.position, 15);
+        let e = p
+            .parse_detailed("select number, from query_test;")
+            .unwrap_err();
+        assert_eq!(e.message, "select column is empty");
+        assert!(p.parse("select number as from query_test;").is_err());
+        assert!(p.parse("select number n from query_test;").is_err());
     }
 
     #[test]
     fn query_parse_insert() {
-        let catalog = Catalog::from_json(JSON);
+        let catalog = Catalog::from_json(JSON).unwrap();
         let p = Parser::new(&catalog);
         let query = "insert into query_test ( number=1 text='hoge' );";
 
@@ -196,20 +795,109 @@ mod tests {
         );
     }
 
+    #[test]
+    fn query_parse_json_row() {
+        let catalog = Catalog::from_json(JSON).unwrap();
+        let p = Parser::new(&catalog);
+
+        let mut attributes = HashMap::new();
+        attributes.insert("number".to_string(), AttributeType::Int(1));
+        attributes.insert("text".to_string(), AttributeType::Text("2".to_string()));
+        let expected = ExecuteType::Insert(InsertInput {
+            table_name: "query_test".to_string(),
+            attributes,
+        });
+        // 数値の文字列はintに、数値はtextに変換する
+        assert_eq!(
+            p.parse_json_row("query_test", r#"{"number":1,"text":"2"}"#)
+                .unwrap(),
+            expected
+        );
+        assert_eq!(
+            p.parse_json_row("query_test", r#"{"number":"1","text":2}"#)
+                .unwrap(),
+            expected
+        );
+
+        let error =
+            |table: &str, body: &str| p.parse_json_row(table, body).unwrap_err().to_string();
+        assert_eq!(error("nothing", "{}"), "nothing not exist");
+        assert_eq!(error("query_test", r#"{"number":1}"#), "text is not found");
+        assert_eq!(
+            error("query_test", r#"{"number":1,"text":"a","x":1}"#),
+            "x is not a column of query_test"
+        );
+        assert_eq!(
+            error("query_test", r#"{"number":1.5,"text":"a"}"#),
+            "number: 1.5 is not an int"
+        );
+        assert_eq!(
+            error("query_test", r#"{"number":null,"text":"a"}"#),
+            "number: null cannot be stored as int"
+        );
+        assert!(error("query_test", "[1]").starts_with("body must be a JSON object"));
+    }
+
+    #[test]
+    fn query_parse_insert_blob() {
+        let catalog = Catalog::from_json(
+            r#"{"schemas": [{"table": {"name": "files", "columns": [
+                {"types": "int", "name": "id"},
+                {"types": "blob", "name": "data"}
+            ]}}]}"#,
+        )
+        .unwrap();
+        let p = Parser::new(&catalog);
+
+        match p
+            .parse("insert into files ( id=1 data=x'deadbeef00ff' );")
+            .unwrap()
+        {
+            ExecuteType::Insert(InsertInput { attributes, .. }) => assert_eq!(
+                attributes["data"],
+                AttributeType::Blob(vec![0xde, 0xad, 0xbe, 0xef, 0x00, 0xff])
+            ),
+            e => panic!("unexpected {:?}", e),
+        }
+        assert!(p
+            .parse("insert into files ( id=1 data='deadbeef' );")
+            .is_err());
+    }
+
     #[test]
     fn query_parse_exit() {
-        let catalog = Catalog::from_json(JSON);
+        let catalog = Catalog::from_json(JSON).unwrap();
         let p = Parser::new(&catalog);
-        let query = "exit;";
+        // serverは/admin/shutdownで止めるので、statementとしては読まない
+        assert!(p.parse("exit;").is_err());
+    }
 
-        let e_type = p.parse(query).unwrap();
+    #[test]
+    fn query_parse_transaction() {
+        let catalog = Catalog::from_json(JSON).unwrap();
+        let p = Parser::new(&catalog);
 
-        assert_eq!(e_type, ExecuteType::Exit);
+        assert_eq!(p.parse("begin;").unwrap(), ExecuteType::Begin);
+        assert_eq!(p.parse("commit;").unwrap(), ExecuteType::Commit);
+        assert_eq!(p.parse("rollback;").unwrap(), ExecuteType::Rollback);
+        assert_eq!(
+            p.parse("begin read only;").unwrap(),
+            ExecuteType::BeginReadOnly
+        );
+        assert!(p.parse("begin read;").is_err());
+    }
+
+    #[test]
+    fn query_parse_checkpoint() {
+        let catalog = Catalog::from_json(JSON).unwrap();
+        let p = Parser::new(&catalog);
+
+        assert_eq!(p.parse("checkpoint;").unwrap(), ExecuteType::Checkpoint);
     }
 
     #[test]
     fn query_parse_end_with_semicolon() {
-        let catalog = Catalog::from_json(JSON);
+        let catalog = Catalog::from_json(JSON).unwrap();
         let p = Parser::new(&catalog);
         let query = "select id, name from users";
 
@@ -218,10 +906,210 @@ mod tests {
 
     #[test]
     fn query_parse_not_support_type() {
-        let catalog = Catalog::from_json(JSON);
+        let catalog = Catalog::from_json(JSON).unwrap();
         let p = Parser::new(&catalog);
-        let query = "update users";
+        let query = "delete from users;";
 
         assert!(p.parse(query).is_err());
     }
+
+    #[test]
+    fn query_parse_update() {
+        let catalog = Catalog::from_json(JSON).unwrap();
+        let p = Parser::new(&catalog);
+
+        let mut attributes = HashMap::new();
+        attributes.insert("text".to_string(), AttributeType::Text("a b=c".to_string()));
+        assert_eq!(
+            p.parse("update query_test set ( text='a b=c' ) where number=1;")
+                .unwrap(),
+            ExecuteType::Update(UpdateInput {
+                table_name: "query_test".to_string(),
+                attributes,
+                filter: Some(("number".to_string(), AttributeType::Int(1))),
+            })
+        );
+
+        // whereがなければ全ての行を書き換える
+        match p.parse("update query_test set ( number=2 );").unwrap() {
+            ExecuteType::Update(UpdateInput {
+                attributes, filter, ..
+            }) => {
+                assert_eq!(attributes["number"], AttributeType::Int(2));
+                assert_eq!(attributes.len(), 1);
+                assert_eq!(filter, None);
+            }
+            e => panic!("expected update, but {:?}", e),
+        }
+
+        let error = |query: &str| {
+            let e = p.parse_detailed(query).unwrap_err();
+            (e.kind, e.position, e.message)
+        };
+        assert_eq!(
+            error("update query_test set ( nothing=1 );").2,
+            "nothing is not a column of query_test"
+        );
+        assert_eq!(
+            error("update query_test set ( number=1 text=2 );"),
+            (
+                ParseErrorKind::InvalidAttribute,
+                38,
+                "text: text must be quoted with ': 2".to_string()
+            )
+        );
+        assert_eq!(
+            error("update query_test set ( );").0,
+            ParseErrorKind::InvalidAttribute
+        );
+        assert_eq!(
+            error("update query_test ( number=1 );").0,
+            ParseErrorKind::UnexpectedToken
+        );
+        assert_eq!(
+            error("update query_test set ( number=1 ) where number;").0,
+            ParseErrorKind::InvalidAttribute
+        );
+        assert_eq!(
+            error("update query_test set ( number=1 ) number=1;").0,
+            ParseErrorKind::UnexpectedToken
+        );
+        assert_eq!(
+            error("update users set ( number=1 );").0,
+            ParseErrorKind::UnknownTable("users".to_string())
+        );
+    }
+
+    #[test]
+    fn query_parse_show() {
+        let catalog = Catalog::from_json(JSON).unwrap();
+        let p = Parser::new(&catalog);
+
+        assert_eq!(p.parse("show tables;").unwrap(), ExecuteType::ShowTables);
+        assert_eq!(
+            p.parse("show schema;").unwrap(),
+            ExecuteType::ShowSchema(None)
+        );
+        assert_eq!(
+            p.parse("show schema query_test;").unwrap(),
+            ExecuteType::ShowSchema(Some("query_test".to_string()))
+        );
+        assert!(p.parse("show schema unknown;").is_err());
+        assert!(p.parse("show indexes;").is_err());
+    }
+
+    #[test]
+    fn query_split_statements() {
+        let text = "-- seed\nbegin;\ninsert into users\n  ( id=1 name='a;b' );\ncommit; select * from users;\nselect";
+
+        assert_eq!(
+            split_statements(text),
+            vec![
+                (2, "begin;".to_string()),
+                (3, "insert into users ( id=1 name='a;b' );".to_string()),
+                (5, "commit;".to_string()),
+                (5, "select * from users;".to_string()),
+                (6, "select".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn query_parse_insert_quoted_text() {
+        let catalog = Catalog::from_json(JSON).unwrap();
+        let p = Parser::new(&catalog);
+
+        let e_type = p
+            .parse("insert into query_test ( number=1 text='O''Brien said a=b ''hi''' );")
+            .unwrap();
+
+        let mut attributes = HashMap::new();
+        attributes.insert("number".to_string(), AttributeType::Int(1));
+        attributes.insert(
+            "text".to_string(),
+            AttributeType::Text("O'Brien said a=b 'hi'".to_string()),
+        );
+        assert_eq!(
+            e_type,
+            ExecuteType::Insert(InsertInput {
+                table_name: "query_test".to_string(),
+                attributes
+            })
+        );
+
+        // \'でのescapeは受け付けない
+        assert!(p
+            .parse("insert into query_test ( number=1 text='it\\'s' );")
+            .is_err());
+        assert!(p
+            .parse("insert into query_test ( number=1 text='open );")
+            .is_err());
+    }
+
+    #[test]
+    fn query_parse_error_position() {
+        let catalog = Catalog::from_json(JSON).unwrap();
+        let p = Parser::new(&catalog);
+        let error = |q: &str| {
+            let e = p.parse_detailed(q).unwrap_err();
+            (e.kind, e.position)
+        };
+
+        assert_eq!(
+            error("insert into query_test ( number=abc text='hoge' );"),
+            (ParseErrorKind::InvalidAttribute, 32)
+        );
+        assert_eq!(
+            error("insert into query_test ( number=1 text );"),
+            (ParseErrorKind::InvalidAttribute, 34)
+        );
+        assert_eq!(
+            error("insert into query_test ( number=1 );"),
+            (ParseErrorKind::InvalidAttribute, 34)
+        );
+        assert_eq!(
+            error("insert into users ( id=1 );"),
+            (ParseErrorKind::UnknownTable("users".to_string()), 12)
+        );
+        assert_eq!(
+            error("insert into query_test ( number=1 text='a';"),
+            (ParseErrorKind::UnexpectedEnd, 42)
+        );
+        assert_eq!(
+            error("insert into query_test ( number=1 text='a );"),
+            (ParseErrorKind::UnterminatedText, 39)
+        );
+        assert_eq!(error("select * from"), (ParseErrorKind::UnexpectedEnd, 13));
+        assert_eq!(error("select *;"), (ParseErrorKind::UnexpectedEnd, 8));
+        assert_eq!(error("begin write;"), (ParseErrorKind::UnexpectedToken, 6));
+        assert_eq!(error("delete users;"), (ParseErrorKind::UnexpectedToken, 0));
+        assert_eq!(error("update users;"), (ParseErrorKind::UnexpectedEnd, 12));
+
+        // parseは今まで通りDbErrorに変換して返す
+        assert!(matches!(
+            p.parse("select * from users;"),
+            Err(DbError::TableNotFound(t)) if t == "users"
+        ));
+        assert_eq!(
+            p.parse_detailed("select * from users;")
+                .unwrap_err()
+                .to_string(),
+            "users not exist"
+        );
+    }
+
+    #[test]
+    fn query_parse_insert_invalid_value() {
+        let catalog = Catalog::from_json(JSON).unwrap();
+        let p = Parser::new(&catalog);
+
+        let e = p
+            .parse("insert into query_test ( number=abc text='hoge' );")
+            .unwrap_err();
+        assert_eq!(e.to_string(), "number: abc is not an int");
+
+        assert!(p
+            .parse("insert into query_test ( number=1 text=hoge );")
+            .is_err());
+    }
 }
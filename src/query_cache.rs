@@ -0,0 +1,129 @@
+use lru::LruCache;
+
+use crate::catalog::Column;
+
+// selectの結果を、空白を詰めたquery文字列ごとに覚えておく
+// tableに書き込みがあれば、そのtableを参照するentryは全て捨てる
+pub struct QueryCache {
+    entries: LruCache<String, Entry>,
+    hits: u64,
+    misses: u64,
+}
+
+struct Entry {
+    table_name: String,
+    rows: CachedRows,
+}
+
+// 返す形式によらず、ここから応答を作り直す
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CachedRows {
+    pub columns: Vec<Column>,
+    pub rows: Vec<Vec<String>>,
+    pub truncated: bool,
+}
+
+impl QueryCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: LruCache::new(capacity),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn normalize(query: &str) -> String {
+        query.split_whitespace().collect::<Vec<_>>().join(" ")
+    }
+
+    pub fn get(&mut self, key: &str) -> Option<CachedRows> {
+        match self.entries.get(key) {
+            Some(e) => {
+                self.hits += 1;
+                Some(e.rows.clone())
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub fn put(&mut self, key: String, table_name: &str, rows: CachedRows) {
+        self.entries.put(
+            key,
+            Entry {
+                table_name: table_name.to_string(),
+                rows,
+            },
+        );
+    }
+
+    pub fn invalidate_table(&mut self, table_name: &str) {
+        let keys: Vec<String> = self
+            .entries
+            .iter()
+            .filter(|(_, e)| e.table_name == table_name)
+            .map(|(k, _)| k.clone())
+            .collect();
+
+        for k in keys {
+            self.entries.pop(&k);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rows(values: &[&str]) -> CachedRows {
+        CachedRows {
+            rows: values.iter().map(|v| vec![v.to_string()]).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn query_cache_invalidate() {
+        let mut cache = QueryCache::new(2);
+
+        let key = QueryCache::normalize("select  * from users;");
+        assert_eq!(key, "select * from users;");
+
+        assert_eq!(cache.get(&key), None);
+        cache.put(key.clone(), "users", rows(&[]));
+        cache.put("select * from items;".to_string(), "items", rows(&["1"]));
+
+        assert_eq!(cache.get(&key), Some(rows(&[])));
+        assert_eq!(cache.hits(), 1);
+        assert_eq!(cache.misses(), 1);
+
+        cache.invalidate_table("users");
+        assert_eq!(cache.get(&key), None);
+        assert_eq!(cache.get("select * from items;"), Some(rows(&["1"])));
+    }
+
+    #[test]
+    fn query_cache_capacity() {
+        let mut cache = QueryCache::new(1);
+
+        cache.put("a".to_string(), "users", rows(&["1"]));
+        cache.put("b".to_string(), "users", rows(&["2"]));
+
+        assert_eq!(cache.get("a"), None);
+        assert_eq!(cache.get("b"), Some(rows(&["2"])));
+    }
+}
@@ -0,0 +1,3964 @@
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, BufReader, BufWriter, ErrorKind, Read, Write},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    catalog::{updated_at_column, AttributeType, Catalog, Column, ColumnType},
+    config::Config,
+    connection::{Connection, Endpoint, Listener},
+    error::DbError,
+    executor::{Executor, ScanCursor, Transaction},
+    format::{self, Format},
+    http::{
+        query_param, query_params, read_body, read_request_head, skip_body, url_decode,
+        ChunkedWriter, HttpError, Request, MAX_BODY_SIZE, PROTOCOL_VERSION,
+        PROTOCOL_VERSION_HEADER, ROW_COUNT_TRAILER, ROW_TRUNCATED_TRAILER,
+    },
+    line,
+    metrics::{ConcurrencyGauges, Metrics, QueryKind, StorageGauges},
+    pgwire::{self, Startup},
+    query::{
+        split_statements, ExecuteType, InsertInput, Parser, SelectColumn, SelectInput, UpdateInput,
+    },
+    query_cache::{CachedRows, QueryCache},
+    storage::{page::PAGE_SIZE, replacer::LruReplacer, wal::Durability},
+    websocket::{self, Message, MessageReader, WebSocketError},
+};
+
+// selectが1回で返す行数の上限
+pub const DEFAULT_MAX_ROWS: usize = 10_000;
+// 同時に処理する接続の上限
+pub const DEFAULT_MAX_CONNECTIONS: usize = 64;
+// 1つのstatementに許す実行時間
+pub const DEFAULT_QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+// walの大きさにかかわらず、dirtyなpageを書き出す間隔
+pub const DEFAULT_CHECKPOINT_INTERVAL: Duration = Duration::from_secs(60);
+// 1つの接続がrequestの読み書きで待つ上限
+// 終了時に処理中のworkerを待つ時間もこれで抑えられる
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+// 401で断ったrequestのbodyを読み捨てるときに待つ上限
+const REJECTED_BODY_TIMEOUT: Duration = Duration::from_millis(500);
+// keep-aliveの接続で次のrequestを待つ上限
+const KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(5);
+// logに出すqueryの最大文字数
+const MAX_LOGGED_QUERY_LEN: usize = 200;
+// 人が打ち込むことの多いline、postgres protocolと、transaction中のHTTPでは次のstatementを長く待つ
+const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+// websocketで何も届かないとき、proxyなどに切られないようこの間隔でpingを送る
+const WEBSOCKET_PING_INTERVAL: Duration = Duration::from_secs(30);
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(200);
+// walが伸びすぎていないかを確かめる間隔
+const CHECKPOINT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+// selectの結果をstreamで返すとき、この行数ごとにchunkとして書き出す
+const STREAM_CHUNK_ROWS: usize = 100;
+// 出力先に渡すselectで、databaseのlockを1回取る間に読むpageの数
+const SCAN_CHUNK_PAGES: usize = 8;
+// postgres protocolでstartupの後に送る設定。psqlはserver_versionを見て使う機能を決める
+const PG_PARAMETERS: [(&str, &str); 5] = [
+    ("server_version", "14.0"),
+    ("server_encoding", "UTF8"),
+    ("client_encoding", "UTF8"),
+    ("DateStyle", "ISO, MDY"),
+    ("standard_conforming_strings", "on"),
+];
+const MAX_PASSWORD_LEN: usize = 1024;
+
+type StreamBody<'s> = ChunkedWriter<BufWriter<&'s mut dyn Write>>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerOptions {
+    pub max_rows: Option<usize>,
+    pub max_connections: usize,
+    // 同時に実行する書き込みの上限。超えた分は空くまで待つ。Noneなら上限なし
+    pub max_concurrent_writes: Option<usize>,
+    // selectの応答を覚えておく数。0ならcacheしない
+    pub query_cache_size: usize,
+    // logに出すqueryから値を伏せる
+    pub redact_literals: bool,
+    pub max_body_bytes: usize,
+    // Noneなら時間で打ち切らない
+    pub query_timeout: Option<Duration>,
+    // Noneなら定期的には書き出さず、walの大きさでだけcheckpointする
+    pub checkpoint_interval: Option<Duration>,
+    // 指定すると、Authorization: Bearer <token>のないrequestに401を返す
+    pub auth_token: Option<String>,
+    // /healthにもtokenを求める
+    pub auth_health: bool,
+    pub protocol: Protocol,
+}
+
+// clientとのやりとりの形式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    Http,
+    // 1行ずつやりとりする。ncやtelnetからも使える
+    Line,
+    // PostgreSQLのprotocolのsimple query。psqlなどから使える
+    Postgres,
+}
+
+impl Protocol {
+    pub fn parse(s: &str) -> Result<Self, anyhow::Error> {
+        match s {
+            "http" => Ok(Protocol::Http),
+            "line" => Ok(Protocol::Line),
+            "postgres" => Ok(Protocol::Postgres),
+            _ => Err(anyhow::anyhow!(
+                "unknown protocol {} (expected http, line or postgres)",
+                s
+            )),
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Protocol::Http => "http",
+            Protocol::Line => "line",
+            Protocol::Postgres => "postgres",
+        }
+    }
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        Self {
+            max_rows: Some(DEFAULT_MAX_ROWS),
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            max_concurrent_writes: None,
+            query_cache_size: 0,
+            redact_literals: false,
+            max_body_bytes: MAX_BODY_SIZE,
+            query_timeout: Some(DEFAULT_QUERY_TIMEOUT),
+            checkpoint_interval: Some(DEFAULT_CHECKPOINT_INTERVAL),
+            auth_token: None,
+            auth_health: false,
+            protocol: Protocol::Http,
+        }
+    }
+}
+
+// executorとquery cacheは全ての接続で共有し、1つのlockでまとめて守る
+struct Database {
+    executor: Executor<LruReplacer>,
+    cache: Option<QueryCache>,
+}
+
+impl Database {
+    // どのtableのentryが取り消したinsertを含んでいるか分からないので、cacheは全て捨てる
+    // 他の接続が読んだcommit前の行を、戻した後まで返さないようにする
+    fn rollback(&mut self, txn: Transaction) -> Result<(), anyhow::Error> {
+        if let Some(c) = self.cache.as_mut() {
+            c.clear();
+        }
+        self.executor.rollback(txn)
+    }
+}
+
+// 1つの接続の間だけ続く状態。keep-aliveのHTTP、line、postgresのどれでも1つの接続が1つのsession
+// 接続が閉じるか失敗してdropされたら、開いたままのtransactionを戻す
+struct Session<'s> {
+    database: &'s Mutex<Database>,
+    transaction: Option<Transaction>,
+    // line protocolで`auth <token>;`を受け取ったか、tokenが要らない
+    authorized: bool,
+}
+
+impl Drop for Session<'_> {
+    fn drop(&mut self) {
+        let txn = match self.transaction.take() {
+            Some(txn) => txn,
+            None => return,
+        };
+        // 他のworkerがpanicしていても、戻せるだけ戻しておく
+        let mut database = self.database.lock().unwrap_or_else(|e| e.into_inner());
+        log::info!("rolling back transaction {} of a closed session", txn.id());
+        if let Err(e) = database.rollback(txn) {
+            log::warn!("failed to roll back a closed session: {}", e);
+        }
+    }
+}
+
+// 1つのstatementについてlogに出す内容。executeの中で埋める
+#[derive(Default)]
+struct Statement {
+    kind: &'static str,
+    // selectが返した行数、insertが書いた行数。cacheから返したselectはNone
+    rows: Option<usize>,
+}
+
+// selectの結果を、全て作らずに1行ずつ受け取る出力先
+trait RowWriter {
+    // 値の前に1回呼ぶ。既定では列名を1行目として渡す
+    fn columns(&mut self, columns: &[Column]) -> Result<(), anyhow::Error> {
+        let names: Vec<String> = columns.iter().map(|c| c.name.clone()).collect();
+        self.row(&names)
+    }
+    // 値をschemaの列順で渡す
+    fn row(&mut self, cells: &[String]) -> Result<(), anyhow::Error>;
+    // 全ての行を渡した後に1回呼ぶ
+    fn end(&mut self, rows: usize, truncated: bool) -> Result<(), anyhow::Error>;
+}
+
+impl RowWriter for StreamBody<'_> {
+    fn row(&mut self, cells: &[String]) -> Result<(), anyhow::Error> {
+        Ok(self.write_line(&cells.join(" | "))?)
+    }
+
+    fn end(&mut self, rows: usize, truncated: bool) -> Result<(), anyhow::Error> {
+        if truncated {
+            self.write_line(&format!("result truncated at {} rows", rows))?;
+        }
+        self.finish(&format!("total: {}", rows), &row_trailers(rows, truncated))?;
+        Ok(())
+    }
+}
+
+// CSVでは値の行だけを返す。行数と打ち切ったかはtrailerで伝える
+struct CsvRows<'b, 's>(&'b mut StreamBody<'s>);
+
+impl RowWriter for CsvRows<'_, '_> {
+    fn row(&mut self, cells: &[String]) -> Result<(), anyhow::Error> {
+        Ok(self.0.write_line(&format::csv_row(cells))?)
+    }
+
+    fn end(&mut self, rows: usize, truncated: bool) -> Result<(), anyhow::Error> {
+        self.0.finish("", &row_trailers(rows, truncated))?;
+        Ok(())
+    }
+}
+
+fn row_trailers(rows: usize, truncated: bool) -> Vec<(&'static str, String)> {
+    let mut trailers = vec![(ROW_COUNT_TRAILER, rows.to_string())];
+    if truncated {
+        trailers.push((ROW_TRUNCATED_TRAILER, "true".to_string()));
+    }
+    trailers
+}
+
+// query cacheに入れるため、読んだ行を全て覚えておく
+impl RowWriter for CachedRows {
+    fn columns(&mut self, columns: &[Column]) -> Result<(), anyhow::Error> {
+        self.columns = columns.to_vec();
+        Ok(())
+    }
+
+    fn row(&mut self, cells: &[String]) -> Result<(), anyhow::Error> {
+        self.rows.push(cells.to_vec());
+        Ok(())
+    }
+
+    fn end(&mut self, _rows: usize, truncated: bool) -> Result<(), anyhow::Error> {
+        self.truncated = truncated;
+        Ok(())
+    }
+}
+
+// cacheした結果を、scanしたときと同じ順に出力先へ渡す
+fn replay(cached: &CachedRows, out: &mut dyn RowWriter) -> Result<(), anyhow::Error> {
+    out.columns(&cached.columns)?;
+    for row in &cached.rows {
+        out.row(row)?;
+    }
+    out.end(cached.rows.len(), cached.truncated)
+}
+
+// 出力先がないとき(init fileなど)に、selectの結果を全て文字列にする
+#[derive(Default)]
+struct ResponseText(String);
+
+impl RowWriter for ResponseText {
+    fn row(&mut self, cells: &[String]) -> Result<(), anyhow::Error> {
+        self.0.push_str(&cells.join(" | "));
+        self.0.push('\n');
+        Ok(())
+    }
+
+    fn end(&mut self, rows: usize, truncated: bool) -> Result<(), anyhow::Error> {
+        if truncated {
+            self.0
+                .push_str(&format!("result truncated at {} rows\n", rows));
+        }
+        self.0.push_str(&format!("total: {}", rows));
+        Ok(())
+    }
+}
+
+// line protocolでは行数を先に書くので、全ての行をためてから返す
+#[derive(Default)]
+struct LineRows {
+    lines: Vec<String>,
+    truncated: bool,
+}
+
+impl RowWriter for LineRows {
+    fn row(&mut self, cells: &[String]) -> Result<(), anyhow::Error> {
+        self.lines.push(line::row(cells));
+        Ok(())
+    }
+
+    fn end(&mut self, _rows: usize, truncated: bool) -> Result<(), anyhow::Error> {
+        self.truncated = truncated;
+        Ok(())
+    }
+}
+
+// postgres protocolでは、読んだ行をそのままDataRowにして送る
+struct PgRows<'w, W: Write> {
+    writer: &'w mut W,
+}
+
+impl<W: Write> RowWriter for PgRows<'_, W> {
+    fn columns(&mut self, columns: &[Column]) -> Result<(), anyhow::Error> {
+        let fields: Vec<(String, u32)> = columns
+            .iter()
+            .map(|c| {
+                let oid = match c.column_type() {
+                    Some(ColumnType::Int) => pgwire::INT4_OID,
+                    Some(ColumnType::Numeric { .. }) => pgwire::NUMERIC_OID,
+                    Some(ColumnType::Blob) => pgwire::BYTEA_OID,
+                    _ => pgwire::TEXT_OID,
+                };
+                (c.name.clone(), oid)
+            })
+            .collect();
+        Ok(pgwire::row_description(self.writer, &fields)?)
+    }
+
+    fn row(&mut self, cells: &[String]) -> Result<(), anyhow::Error> {
+        Ok(pgwire::data_row(self.writer, cells)?)
+    }
+
+    fn end(&mut self, rows: usize, truncated: bool) -> Result<(), anyhow::Error> {
+        if truncated {
+            pgwire::notice_response(self.writer, &format!("result truncated at {} rows", rows))?;
+        }
+        Ok(())
+    }
+}
+
+// websocketでは、selectの結果をJSONの1つのmessageにまとめて返す
+#[derive(Default)]
+struct JsonRows {
+    columns: Vec<String>,
+    rows: Vec<Vec<String>>,
+    truncated: bool,
+}
+
+impl RowWriter for JsonRows {
+    fn columns(&mut self, columns: &[Column]) -> Result<(), anyhow::Error> {
+        self.columns = columns.iter().map(|c| c.name.clone()).collect();
+        Ok(())
+    }
+
+    fn row(&mut self, cells: &[String]) -> Result<(), anyhow::Error> {
+        self.rows.push(cells.to_vec());
+        Ok(())
+    }
+
+    fn end(&mut self, _rows: usize, truncated: bool) -> Result<(), anyhow::Error> {
+        self.truncated = truncated;
+        Ok(())
+    }
+}
+
+// selectがexecutorを使う間のlockの持ち方
+enum ScanLock<'e, 'd> {
+    // 呼び出し側がlockを持ったまま、全てを読む。cacheに入れる結果に途中でinsertが混ざらないようにする
+    Held(&'e mut Executor<LruReplacer>),
+    // SCAN_CHUNK_PAGESごとにlockを取り直し、読んだ行は放してから出力先に渡す
+    // clientが読むのを待つ間に、他の接続を止めないようにする
+    Chunked(&'d Mutex<Database>),
+}
+
+// wait_for_inputで待った結果
+#[derive(Debug, PartialEq)]
+enum Wait {
+    Input,
+    // idle timeoutまで何も届かなかった
+    Idle,
+    // clientが閉じたか、serverを止めている
+    Closed,
+}
+
+pub struct Server<'a> {
+    parser: Parser<'a>,
+    metrics: Metrics,
+    database: Mutex<Database>,
+    options: ServerOptions,
+    connections: AtomicUsize,
+    writes: WriteLimit,
+    shutdown: Arc<AtomicBool>,
+    started: Instant,
+    // GET /configで返す起動時の設定。testなどで渡されなければpathは分からない
+    config: Option<Config>,
+}
+
+// 書き込みのstatementに数の上限をかけるsemaphore
+// 大量のinsertが来ても、databaseのlockを待つ書き込みはmaxまでに抑え、selectが割り込めるようにする
+struct WriteLimit {
+    max: Option<usize>,
+    in_progress: Mutex<usize>,
+    released: Condvar,
+}
+
+struct WriteSlot<'a>(&'a WriteLimit);
+
+impl WriteLimit {
+    fn new(max: Option<usize>) -> Self {
+        Self {
+            max,
+            in_progress: Mutex::new(0),
+            released: Condvar::new(),
+        }
+    }
+
+    // 空くまで待つ。timeoutまでに空かなければNone
+    fn acquire(&self, timeout: Option<Duration>) -> Option<WriteSlot<'_>> {
+        let full = |n: &mut usize| self.max.is_some_and(|max| *n >= max);
+        let guard = self.in_progress.lock().unwrap();
+        let mut in_progress = match timeout {
+            Some(timeout) => {
+                let (guard, result) = self
+                    .released
+                    .wait_timeout_while(guard, timeout, full)
+                    .unwrap();
+                if result.timed_out() {
+                    return None;
+                }
+                guard
+            }
+            None => self.released.wait_while(guard, full).unwrap(),
+        };
+        *in_progress += 1;
+
+        Some(WriteSlot(self))
+    }
+
+    fn in_progress(&self) -> usize {
+        *self.in_progress.lock().unwrap()
+    }
+}
+
+impl Drop for WriteSlot<'_> {
+    fn drop(&mut self) {
+        *self.0.in_progress.lock().unwrap() -= 1;
+        self.0.released.notify_one();
+    }
+}
+
+// 別threadからserverを止めるためのhandle。signal handlerと/admin/shutdownの両方で使う
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    flag: Arc<AtomicBool>,
+    endpoint: Endpoint,
+}
+
+impl ShutdownHandle {
+    // 新しい接続を受け付けないようにして、acceptで止まっているloopを起こす
+    pub fn trigger(&self) -> Result<(), anyhow::Error> {
+        if !self.flag.swap(true, Ordering::SeqCst) {
+            self.endpoint.wake()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a> Server<'a> {
+    pub fn new(
+        catalog: &'a Catalog,
+        mut executor: Executor<LruReplacer>,
+        options: ServerOptions,
+    ) -> Self {
+        executor.set_query_timeout(options.query_timeout);
+
+        Self {
+            parser: Parser::new(catalog),
+            metrics: Metrics::new(),
+            database: Mutex::new(Database {
+                executor,
+                cache: (options.query_cache_size > 0)
+                    .then(|| QueryCache::new(options.query_cache_size)),
+            }),
+            writes: WriteLimit::new(options.max_concurrent_writes),
+            options,
+            connections: AtomicUsize::new(0),
+            shutdown: Arc::new(AtomicBool::new(false)),
+            started: Instant::now(),
+            config: None,
+        }
+    }
+
+    fn session(&self) -> Session<'_> {
+        Session {
+            database: &self.database,
+            transaction: None,
+            authorized: self.options.auth_token.is_none(),
+        }
+    }
+
+    // 起動時の設定から作る。optionsはconfig.serverを使うので、GET /configと食い違わない
+    pub fn with_config(
+        catalog: &'a Catalog,
+        executor: Executor<LruReplacer>,
+        config: Config,
+    ) -> Self {
+        let mut server = Self::new(catalog, executor, config.server.clone());
+        server.config = Some(config);
+        server
+    }
+
+    // endpointはrunに渡すlistenerのaddressかsocket file
+    pub fn shutdown_handle(&self, endpoint: impl Into<Endpoint>) -> ShutdownHandle {
+        ShutdownHandle {
+            flag: Arc::clone(&self.shutdown),
+            endpoint: endpoint.into(),
+        }
+    }
+
+    // ShutdownHandleで止められるまで、接続ごとにthreadを立てて処理する
+    // 処理中のworkerを全て待ってからcheckpointする。開いていたtransactionはsessionと一緒に戻される
+    // TCPでもunix domain socketでも、同じprotocolで応答する
+    pub fn run<L: Listener>(&self, listener: L) -> Result<(), anyhow::Error>
+    where
+        for<'s> &'s L::Stream: Read + Write,
+    {
+        let endpoint = &listener.endpoint()?;
+
+        let (durability, read_only) = {
+            let database = self.database.lock().unwrap();
+            (
+                database.executor.pool_settings().durability,
+                database.executor.read_only(),
+            )
+        };
+
+        thread::scope(|scope| {
+            // group commitでは、次のcommitが来なくてもintervalごとにfsyncする
+            if let Durability::Group(group) = durability {
+                scope.spawn(move || self.sync_wal_periodically(group.interval));
+            }
+            let checkpointer = (!read_only).then(|| {
+                scope.spawn(move || self.checkpoint_periodically(self.options.checkpoint_interval))
+            });
+
+            loop {
+                let stream = listener.accept();
+                if self.shutdown.load(Ordering::SeqCst) {
+                    break;
+                }
+
+                let stream = match stream {
+                    Ok(s) => s,
+                    Err(_) => continue,
+                };
+
+                let _ = stream.set_read_timeout(Some(IO_TIMEOUT));
+                let _ = stream.set_write_timeout(Some(IO_TIMEOUT));
+
+                if !self.acquire_connection() {
+                    let _ = match self.options.protocol {
+                        Protocol::Http => respond(
+                            &stream,
+                            "503 Service Unavailable",
+                            "too many connections",
+                            false,
+                        ),
+                        Protocol::Line => line::write_err(&mut &stream, "too many connections")
+                            .map_err(Into::into),
+                        Protocol::Postgres => pgwire::error_response(
+                            &mut &stream,
+                            pgwire::TOO_MANY_CONNECTIONS,
+                            "too many connections",
+                        )
+                        .map_err(Into::into),
+                    };
+                    continue;
+                }
+
+                scope.spawn(move || {
+                    // 1つの接続の失敗でserverは止めない
+                    let _ = match self.options.protocol {
+                        Protocol::Http => self.handle(stream, endpoint),
+                        Protocol::Line => self.handle_line(stream),
+                        Protocol::Postgres => self.handle_postgres(stream),
+                    };
+                    self.connections.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+
+            // 待っている間隔を最後まで待たずに終わらせる
+            if let Some(checkpointer) = checkpointer {
+                checkpointer.thread().unpark();
+            }
+        });
+
+        self.exit_handler()
+    }
+
+    fn sync_wal_periodically(&self, interval: Duration) {
+        while !self.shutdown.load(Ordering::SeqCst) {
+            thread::sleep(interval);
+            if let Err(e) = self.database.lock().unwrap().executor.sync_wal_if_due() {
+                log::warn!("failed to sync wal: {}", e);
+            }
+        }
+    }
+
+    // transactionが重なり続けてcommitの後にcheckpointできなかった分を、空いたときに切り詰める
+    // intervalごとにはwalの大きさにかかわらず書き出す。最後のcheckpointはexit_handlerが行う
+    // 止めるときはrunがunparkして起こす
+    fn checkpoint_periodically(&self, interval: Option<Duration>) {
+        let wait = interval.map_or(CHECKPOINT_CHECK_INTERVAL, |i| {
+            i.min(CHECKPOINT_CHECK_INTERVAL)
+        });
+        let mut last = Instant::now();
+        loop {
+            thread::park_timeout(wait);
+            if self.shutdown.load(Ordering::SeqCst) {
+                return;
+            }
+
+            // 書き込み中のstatementとは、databaseのlockで順番になる
+            let executor = &mut self.database.lock().unwrap().executor;
+            let result = match interval {
+                Some(interval) if last.elapsed() >= interval => {
+                    last = Instant::now();
+                    let started = Instant::now();
+                    executor.background_checkpoint().map(|pages| {
+                        log::info!(
+                            "background checkpoint wrote {} pages in {:?}",
+                            pages,
+                            started.elapsed()
+                        );
+                    })
+                }
+                _ => executor.checkpoint_if_due().map(|_| ()),
+            };
+            if let Err(e) = result {
+                log::warn!("failed to checkpoint: {}", e);
+            }
+        }
+    }
+
+    fn acquire_connection(&self) -> bool {
+        let max = self.options.max_connections;
+        self.connections
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n < max).then_some(n + 1)
+            })
+            .is_ok()
+    }
+
+    // keep-aliveのrequestには、clientが閉じるかidle timeoutまで同じ接続で応答し続ける
+    // readerは接続の間使い回し、先に届いている次のrequestのbyteを捨てない
+    fn handle<S: Connection>(&self, stream: S, endpoint: &Endpoint) -> Result<(), anyhow::Error>
+    where
+        for<'s> &'s S: Read + Write,
+    {
+        let mut reader = BufReader::new(&stream);
+        let mut session = self.session();
+
+        loop {
+            let result = read_request_head(&mut reader, self.options.max_body_bytes);
+            let mut request = match result {
+                // tokenのないclientのbodyは、bufferに読み込む前に断る
+                // websocketはupgradeの後で`auth`を受け付けるので、ここでは断らない
+                Ok(r)
+                    if !r.request_line.starts_with("GET /ws ")
+                        && !self.authorized(&r.request_line, r.bearer_token.as_deref()) =>
+                {
+                    return self.reject_unauthorized(&stream, &mut reader, &r);
+                }
+                Ok(r) => r,
+                Err(e) => return self.reject_invalid(&stream, e),
+            };
+            if let Err(e) = read_body(&mut reader, &mut request) {
+                return self.reject_invalid(&stream, e);
+            }
+            if request.request_line.starts_with("GET /ws ") {
+                return self.upgrade_websocket(&stream, reader, request, session);
+            }
+            let keep_alive = request.keep_alive;
+            let query = self.log_text(&request.body);
+
+            let started = Instant::now();
+            let mut statement = Statement::default();
+            let mut out = &stream;
+            let mut body = ChunkedWriter::new(
+                BufWriter::new(&mut out as &mut dyn Write),
+                keep_alive && !self.shutdown.load(Ordering::SeqCst),
+                STREAM_CHUNK_ROWS,
+            );
+            let result = self.execute(request, endpoint, &mut session, &mut statement, &mut body);
+            // 途中でclientが閉じたら、scanはその時点で止めてある。応答も届かないので閉じる
+            if result.as_ref().is_err_and(disconnected) {
+                log::info!(
+                    "peer={} kind={} client disconnected after {} us",
+                    stream.peer(),
+                    statement.kind,
+                    started.elapsed().as_micros()
+                );
+                return Ok(());
+            }
+            self.log_statement(&stream, &query, &statement, started, &result);
+
+            let (status, response_text) = match result {
+                Ok(s) => ("200 OK", s),
+                Err(e) => (error_status(&e), format!("{}", e)),
+            };
+
+            let keep_alive = keep_alive && !self.shutdown.load(Ordering::SeqCst);
+            if body.started() && !body.finished() {
+                // headerはもう200で送ってあるので、errorの行を書いて接続を閉じる
+                body.abort(&response_text)?;
+                return Ok(());
+            }
+            if !body.finished() {
+                respond(&stream, status, &response_text, keep_alive)?;
+            }
+
+            if !keep_alive {
+                return Ok(());
+            }
+
+            // 次のrequestの先頭が届くまで待つ。閉じられたかtimeoutしたら終わる
+            // transactionの途中なら、人がclientで打ち込むのを待てるよう長く待つ
+            let idle = if session.transaction.is_some() {
+                SESSION_IDLE_TIMEOUT
+            } else {
+                KEEP_ALIVE_TIMEOUT
+            };
+            if self.wait_for_input(&stream, &mut reader, idle)? != Wait::Input {
+                return Ok(());
+            }
+            stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        }
+    }
+
+    // line protocolでは、clientが閉じるかidle timeoutまで1つずつstatementを受け付ける
+    // auth tokenがあるときは、最初に`auth <token>;`を送らせる
+    fn handle_line<S: Connection>(&self, stream: S) -> Result<(), anyhow::Error>
+    where
+        for<'s> &'s S: Read + Write,
+    {
+        let mut reader = BufReader::new(&stream);
+        let mut writer = BufWriter::new(&stream);
+        let mut session = self.session();
+
+        loop {
+            if self.wait_for_input(&stream, &mut reader, SESSION_IDLE_TIMEOUT)? != Wait::Input {
+                return Ok(());
+            }
+            let query = match line::read_statement(&mut reader, self.options.max_body_bytes) {
+                Ok(Some(q)) => q,
+                Ok(None) => return Ok(()),
+                Err(e) => {
+                    self.metrics.record_error();
+                    let _ = line::write_err(&mut writer, &e.to_string());
+                    return Ok(());
+                }
+            };
+
+            if !session.authorized {
+                let token = query
+                    .strip_prefix("auth ")
+                    .and_then(|t| t.strip_suffix(';'));
+                if !self.authorized("", token) {
+                    self.metrics.record_error();
+                    line::write_err(&mut writer, "send auth <token>; first")?;
+                    return Ok(());
+                }
+                session.authorized = true;
+                line::write_ok(&mut writer, &[])?;
+                continue;
+            }
+
+            let started = Instant::now();
+            let mut statement = Statement::default();
+            let mut rows = LineRows::default();
+            let result = self.run_query(&mut session, &query, &mut statement, Some(&mut rows));
+            self.log_statement(
+                &stream,
+                &self.log_text(&query),
+                &statement,
+                started,
+                &result,
+            );
+
+            match result {
+                Ok(text) => {
+                    let mut lines = match statement.kind {
+                        "select" => rows.lines,
+                        "show" => text.lines().map(line::escape).collect(),
+                        _ => Vec::new(),
+                    };
+                    if rows.truncated {
+                        lines.push(format!(
+                            "result truncated at {} rows",
+                            statement.rows.unwrap_or(0)
+                        ));
+                    }
+                    line::write_ok(&mut writer, &lines)?;
+                }
+                Err(e) => line::write_err(&mut writer, &e.to_string())?,
+            }
+
+            if self.shutdown.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+        }
+    }
+
+    // GET /wsをwebsocketに切り替える。版とtokenはHTTPと同じく確かめる
+    // Authorizationがないときは、line protocolと同じく最初のmessageで`auth <token>;`を送らせる
+    fn upgrade_websocket<S: Connection>(
+        &self,
+        stream: &S,
+        reader: BufReader<&S>,
+        request: Request,
+        mut session: Session,
+    ) -> Result<(), anyhow::Error>
+    where
+        for<'s> &'s S: Read + Write,
+    {
+        let started = Instant::now();
+        let mut statement = Statement::default();
+        let result =
+            check_protocol_version(request.protocol_version, &mut statement).and_then(|_| {
+                statement.kind = "websocket";
+                let key = request.websocket_key.as_deref().ok_or_else(|| {
+                    HttpError::bad_request("expected a websocket upgrade".to_string())
+                })?;
+                let token = request.bearer_token.as_deref();
+                session.authorized = self.authorized(&request.request_line, token);
+                if token.is_some() && !session.authorized {
+                    return Err(unauthorized(&mut statement));
+                }
+                Ok(websocket::accept_key(key))
+            });
+        self.log_statement(stream, "GET /ws", &statement, started, &result);
+
+        let accept = match result {
+            Ok(a) => a,
+            Err(e) => return respond(stream, error_status(&e), &format!("{}", e), false),
+        };
+        let response = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n{}: {}\r\n\r\n",
+            accept, PROTOCOL_VERSION_HEADER, PROTOCOL_VERSION
+        );
+        (&mut &*stream).write_all(response.as_bytes())?;
+
+        self.handle_websocket(stream, reader, session)
+    }
+
+    // text messageを1つのstatementとして実行し、結果をJSONのtext messageで返す
+    // 何も届かない間はpingを送り、SESSION_IDLE_TIMEOUTまでstatementが来なければ閉じる
+    // 閉じるとsessionが捨てられ、開いたままのtransactionは取り消される
+    fn handle_websocket<S: Connection>(
+        &self,
+        stream: &S,
+        mut reader: BufReader<&S>,
+        mut session: Session,
+    ) -> Result<(), anyhow::Error>
+    where
+        for<'s> &'s S: Read + Write,
+    {
+        let mut writer = BufWriter::new(stream);
+        let mut messages = MessageReader::new(self.options.max_body_bytes);
+        let mut idle = Instant::now();
+
+        loop {
+            match self.wait_for_input(stream, &mut reader, WEBSOCKET_PING_INTERVAL)? {
+                Wait::Input => {}
+                _ if self.shutdown.load(Ordering::SeqCst) => {
+                    websocket::close(
+                        &mut writer,
+                        websocket::CLOSE_GOING_AWAY,
+                        "server is shutting down",
+                    )?;
+                    return Ok(());
+                }
+                Wait::Idle if idle.elapsed() < SESSION_IDLE_TIMEOUT => {
+                    websocket::ping(&mut writer)?;
+                    continue;
+                }
+                Wait::Idle => {
+                    websocket::close(&mut writer, websocket::CLOSE_NORMAL, "idle timeout")?;
+                    return Ok(());
+                }
+                Wait::Closed => return Ok(()),
+            }
+            stream.set_read_timeout(Some(IO_TIMEOUT))?;
+
+            let query = match messages.read(&mut reader) {
+                Ok(Some(Message::Text(q))) => q,
+                Ok(Some(Message::Ping(payload))) => {
+                    websocket::pong(&mut writer, &payload)?;
+                    continue;
+                }
+                Ok(Some(Message::Pong)) => continue,
+                Ok(Some(Message::Binary(_))) => {
+                    self.metrics.record_error();
+                    websocket::close(
+                        &mut writer,
+                        websocket::CLOSE_UNSUPPORTED_DATA,
+                        "send statements as text",
+                    )?;
+                    return Ok(());
+                }
+                Ok(Some(Message::Close(_))) => {
+                    websocket::close(&mut writer, websocket::CLOSE_NORMAL, "")?;
+                    return Ok(());
+                }
+                Ok(None) => return Ok(()),
+                Err(e) => {
+                    // frameの誤りでなければ、接続がもう使えない
+                    let code = match e.downcast_ref::<WebSocketError>().map(|w| w.code) {
+                        Some(c) => c,
+                        None => return Err(e),
+                    };
+                    self.metrics.record_error();
+                    let _ = websocket::close(&mut writer, code, "invalid frame");
+                    return Ok(());
+                }
+            };
+            idle = Instant::now();
+
+            if !session.authorized {
+                let token = query
+                    .trim_end()
+                    .strip_prefix("auth ")
+                    .and_then(|t| t.strip_suffix(';'));
+                if !self.authorized("", token) {
+                    self.metrics.record_error();
+                    websocket::close(
+                        &mut writer,
+                        websocket::CLOSE_POLICY_VIOLATION,
+                        "send auth <token>; first",
+                    )?;
+                    return Ok(());
+                }
+                session.authorized = true;
+                let reply = serde_json::json!({ "kind": "auth", "result": "ok" });
+                websocket::text(&mut writer, &reply.to_string())?;
+                continue;
+            }
+
+            let started = Instant::now();
+            let mut statement = Statement::default();
+            let mut rows = JsonRows::default();
+            let query = query.trim_end();
+            let result = self.run_query(&mut session, query, &mut statement, Some(&mut rows));
+            self.log_statement(stream, &self.log_text(query), &statement, started, &result);
+
+            let reply = match result {
+                Ok(_) if statement.kind == "select" => serde_json::json!({
+                    "kind": statement.kind,
+                    "columns": rows.columns,
+                    "rows": rows.rows,
+                    "total": statement.rows.unwrap_or(0),
+                    "truncated": rows.truncated,
+                }),
+                Ok(text) => serde_json::json!({ "kind": statement.kind, "result": text }),
+                Err(e) => {
+                    let status = error_status(&e);
+                    let code = status
+                        .split_whitespace()
+                        .next()
+                        .and_then(|c| c.parse::<u16>().ok());
+                    serde_json::json!({
+                        "kind": statement.kind,
+                        "status": code,
+                        "error": e.to_string(),
+                    })
+                }
+            };
+            websocket::text(&mut writer, &reply.to_string())?;
+
+            if self.shutdown.load(Ordering::SeqCst) {
+                websocket::close(
+                    &mut writer,
+                    websocket::CLOSE_GOING_AWAY,
+                    "server is shutting down",
+                )?;
+                return Ok(());
+            }
+        }
+    }
+
+    // startupの後、clientがTerminateを送るか閉じるまでQueryを1つずつ処理する
+    // auth tokenがあるときは、passwordとしてtokenを求める
+    fn handle_postgres<S: Connection>(&self, stream: S) -> Result<(), anyhow::Error>
+    where
+        for<'s> &'s S: Read + Write,
+    {
+        let mut reader = BufReader::new(&stream);
+        let mut writer = BufWriter::new(&stream);
+        let mut session = self.session();
+
+        // 暗号化は断り、続けて送られる平文のstartupを待つ
+        loop {
+            match pgwire::read_startup(&mut reader)? {
+                Startup::Encryption => {
+                    writer.write_all(b"N")?;
+                    writer.flush()?;
+                }
+                Startup::Cancel => return Ok(()),
+                Startup::Params { version, .. } if version != pgwire::PROTOCOL_VERSION => {
+                    pgwire::error_response(
+                        &mut writer,
+                        pgwire::PROTOCOL_VIOLATION,
+                        &format!(
+                            "unsupported protocol {}.{}",
+                            version >> 16,
+                            version & 0xffff
+                        ),
+                    )?;
+                    return Ok(());
+                }
+                Startup::Params { .. } => break,
+            }
+        }
+
+        if self.options.auth_token.is_some() {
+            pgwire::authentication(&mut writer, pgwire::AUTH_CLEARTEXT_PASSWORD)?;
+            writer.flush()?;
+            let password = match pgwire::read_message(&mut reader, MAX_PASSWORD_LEN)? {
+                Some((b'p', body)) => Some(pgwire::c_string(&body)),
+                _ => None,
+            };
+            session.authorized = self.authorized("", password.as_deref());
+            if !session.authorized {
+                self.metrics.record_error();
+                pgwire::error_response(
+                    &mut writer,
+                    pgwire::INVALID_PASSWORD,
+                    "password authentication failed",
+                )?;
+                return Ok(());
+            }
+        }
+
+        pgwire::authentication(&mut writer, pgwire::AUTH_OK)?;
+        for (name, value) in PG_PARAMETERS {
+            pgwire::parameter_status(&mut writer, name, value)?;
+        }
+        pgwire::ready_for_query(&mut writer, transaction_status(&session))?;
+
+        // extended queryは断った後、Syncが来るまで届いたmessageを捨てる
+        let mut skipping = false;
+        loop {
+            if self.wait_for_input(&stream, &mut reader, SESSION_IDLE_TIMEOUT)? != Wait::Input {
+                return Ok(());
+            }
+            let (tag, body) = match pgwire::read_message(&mut reader, self.options.max_body_bytes) {
+                Ok(Some(m)) => m,
+                Ok(None) => return Ok(()),
+                Err(e) => {
+                    self.metrics.record_error();
+                    let _ = pgwire::error_response(
+                        &mut writer,
+                        pgwire::PROTOCOL_VIOLATION,
+                        &e.to_string(),
+                    );
+                    return Ok(());
+                }
+            };
+
+            match tag {
+                b'Q' => self.run_postgres_query(
+                    &stream,
+                    &mut session,
+                    &mut writer,
+                    &pgwire::c_string(&body),
+                )?,
+                b'X' => return Ok(()),
+                b'S' if skipping => {
+                    skipping = false;
+                    pgwire::ready_for_query(&mut writer, transaction_status(&session))?;
+                    continue;
+                }
+                b'P' | b'B' | b'D' | b'E' | b'C' | b'H' | b'S' => {
+                    if !skipping {
+                        self.metrics.record_error();
+                        pgwire::error_response(
+                            &mut writer,
+                            pgwire::FEATURE_NOT_SUPPORTED,
+                            "extended query protocol is not supported",
+                        )?;
+                        skipping = true;
+                    }
+                    continue;
+                }
+                b'F' => {
+                    self.metrics.record_error();
+                    pgwire::error_response(
+                        &mut writer,
+                        pgwire::FEATURE_NOT_SUPPORTED,
+                        "function call is not supported",
+                    )?;
+                }
+                // COPYは受け付けないので、送られてきたdataは捨てる
+                b'd' | b'c' | b'f' => continue,
+                t => {
+                    self.metrics.record_error();
+                    pgwire::error_response(
+                        &mut writer,
+                        pgwire::PROTOCOL_VIOLATION,
+                        &format!("unexpected message {}", t as char),
+                    )?;
+                    return Ok(());
+                }
+            };
+            pgwire::ready_for_query(&mut writer, transaction_status(&session))?;
+
+            if self.shutdown.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+        }
+    }
+
+    // 1つのQueryに入っているstatementを順に実行する。失敗したら残りは実行しない
+    // postgresと同じく、最後の;は省略できる
+    fn run_postgres_query<S: Connection, W: Write>(
+        &self,
+        stream: &S,
+        session: &mut Session,
+        writer: &mut W,
+        text: &str,
+    ) -> Result<(), anyhow::Error> {
+        let statements = split_statements(text);
+        if statements.is_empty() {
+            pgwire::empty_query_response(writer)?;
+            return Ok(());
+        }
+
+        for (_, query) in statements {
+            let query = if query.ends_with(';') {
+                query
+            } else {
+                format!("{};", query)
+            };
+            if query.to_lowercase().starts_with("copy ") {
+                self.metrics.record_error();
+                pgwire::error_response(
+                    writer,
+                    pgwire::FEATURE_NOT_SUPPORTED,
+                    "COPY is not supported",
+                )?;
+                return Ok(());
+            }
+
+            let started = Instant::now();
+            let mut statement = Statement::default();
+            let result = {
+                let mut rows = PgRows {
+                    writer: &mut *writer,
+                };
+                self.run_query(session, &query, &mut statement, Some(&mut rows))
+            };
+            self.log_statement(stream, &self.log_text(&query), &statement, started, &result);
+
+            let text = match result {
+                Ok(text) => text,
+                Err(e) => {
+                    pgwire::error_response(writer, pg_error_code(&e), &e.to_string())?;
+                    return Ok(());
+                }
+            };
+            let tag = match statement.kind {
+                "select" => format!("SELECT {}", statement.rows.unwrap_or(0)),
+                "insert" => format!("INSERT 0 {}", statement.rows.unwrap_or(0)),
+                "update" => format!("UPDATE {}", statement.rows.unwrap_or(0)),
+                "begin read only" => "BEGIN".to_string(),
+                "show" => {
+                    pgwire::row_description(writer, &[("show".to_string(), pgwire::TEXT_OID)])?;
+                    for l in text.lines() {
+                        pgwire::data_row(writer, &[l.to_string()])?;
+                    }
+                    "SHOW".to_string()
+                }
+                kind => kind.to_uppercase(),
+            };
+            pgwire::command_complete(writer, &tag)?;
+        }
+
+        Ok(())
+    }
+
+    // 次のstatementが届き始めるまで待つ
+    // serverを止めるときに待ち続けないよう、少しずつ区切って待つ
+    fn wait_for_input<S: Connection>(
+        &self,
+        stream: &S,
+        reader: &mut BufReader<&S>,
+        idle_timeout: Duration,
+    ) -> Result<Wait, anyhow::Error>
+    where
+        for<'s> &'s S: Read,
+    {
+        let idle = Instant::now();
+        stream.set_read_timeout(Some(SHUTDOWN_POLL_INTERVAL))?;
+
+        loop {
+            match reader.fill_buf() {
+                Ok(buf) => {
+                    let arrived = !buf.is_empty();
+                    stream.set_read_timeout(Some(idle_timeout))?;
+                    return Ok(if arrived { Wait::Input } else { Wait::Closed });
+                }
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    if self.shutdown.load(Ordering::SeqCst) {
+                        return Ok(Wait::Closed);
+                    }
+                    if idle.elapsed() >= idle_timeout {
+                        return Ok(Wait::Idle);
+                    }
+                }
+                Err(_) => return Ok(Wait::Closed),
+            }
+        }
+    }
+
+    fn log_statement<S: Connection, T>(
+        &self,
+        stream: &S,
+        query: &str,
+        statement: &Statement,
+        started: Instant,
+        result: &Result<T, anyhow::Error>,
+    ) {
+        let elapsed = started.elapsed().as_micros();
+        let peer = stream.peer();
+
+        match result {
+            Ok(_) => {
+                let rows = statement.rows.map_or("-".to_string(), |n| n.to_string());
+                log::info!(
+                    "peer={} kind={} rows={} elapsed_us={} query=\"{}\"",
+                    peer,
+                    statement.kind,
+                    rows,
+                    elapsed,
+                    query
+                );
+            }
+            Err(e) => {
+                self.metrics.record_error();
+                log::warn!(
+                    "peer={} kind={} elapsed_us={} query=\"{}\" error=\"{}\"",
+                    peer,
+                    statement.kind,
+                    elapsed,
+                    query,
+                    e
+                );
+            }
+        }
+    }
+
+    // 読めなかったrequestの後ろは、どこから次のrequestが始まるか分からないので閉じる
+    fn reject_invalid<S: Connection>(
+        &self,
+        stream: &S,
+        e: anyhow::Error,
+    ) -> Result<(), anyhow::Error>
+    where
+        for<'s> &'s S: Read + Write,
+    {
+        self.metrics.record_error();
+        log::warn!("peer={} invalid request: {}", stream.peer(), e);
+        respond(stream, error_status(&e), &format!("{}", e), false)
+    }
+
+    // headerだけを見て401を返し、接続を閉じる
+    fn reject_unauthorized<S: Connection, R: BufRead>(
+        &self,
+        stream: &S,
+        reader: &mut R,
+        request: &Request,
+    ) -> Result<(), anyhow::Error>
+    where
+        for<'s> &'s S: Read + Write,
+    {
+        let started = Instant::now();
+        let mut statement = Statement::default();
+        let result: Result<(), _> = Err(unauthorized(&mut statement));
+        let query = self.log_text(&request.request_line);
+        self.log_statement(stream, &query, &statement, started, &result);
+        if let Err(e) = result {
+            respond(stream, error_status(&e), &format!("{}", e), false)?;
+        }
+        // 読まずに閉じるとRSTで401が届かないことがあるので、送ってから読み捨てる
+        // 届かないbodyを待ち続けないよう、短く区切る
+        stream.set_read_timeout(Some(REJECTED_BODY_TIMEOUT))?;
+        let _ = skip_body(reader, request);
+        Ok(())
+    }
+
+    fn authorized(&self, request_line: &str, token: Option<&str>) -> bool {
+        let expected = match &self.options.auth_token {
+            Some(t) => t,
+            None => return true,
+        };
+        if !self.options.auth_health && request_line.starts_with("GET /health ") {
+            return true;
+        }
+
+        token.is_some_and(|t| constant_time_eq(t.as_bytes(), expected.as_bytes()))
+    }
+
+    // 改行を詰め、長いqueryは切り詰める。redact_literalsなら値を伏せる
+    fn log_text(&self, body: &str) -> String {
+        let query = QueryCache::normalize(body);
+        let query = if self.options.redact_literals {
+            redact_literals(&query)
+        } else {
+            query
+        };
+
+        if query.chars().count() > MAX_LOGGED_QUERY_LEN {
+            let truncated: String = query.chars().take(MAX_LOGGED_QUERY_LEN).collect();
+            format!("{}...", truncated)
+        } else {
+            query
+        }
+    }
+
+    fn execute(
+        &self,
+        request: Request,
+        endpoint: &Endpoint,
+        session: &mut Session,
+        statement: &mut Statement,
+        body: &mut StreamBody,
+    ) -> Result<String, anyhow::Error> {
+        let Request {
+            request_line,
+            body: query,
+            protocol_version,
+            accept,
+            ..
+        } = request;
+
+        // tokenはbodyを読む前にhandleで確かめてある
+        check_protocol_version(protocol_version, statement)?;
+
+        // queryはどのpathにPOSTしてもよい。GETで読めるのはhealth、metrics、config、queryだけ
+        let mut parts = request_line.split_whitespace();
+        let method = parts.next();
+        let (path, query_string) = match parts.next() {
+            Some(target) => match target.split_once('?') {
+                Some((path, query_string)) => (Some(path), query_string),
+                None => (Some(target), ""),
+            },
+            None => (None, ""),
+        };
+        let query = match (method, path) {
+            // 処理中の接続が終わるのを待ってからcheckpointして止める
+            (Some("POST"), Some("/admin/shutdown")) => {
+                statement.kind = "shutdown";
+                self.shutdown_handle(endpoint.clone()).trigger()?;
+                return Ok("shutting down".to_string());
+            }
+            // JSONの1行をinsertする。件数をJSONで返す
+            (Some("POST"), Some(path)) if path.starts_with("/tables/") => {
+                let table_name = json_row_table(path).ok_or_else(|| HttpError {
+                    status: "404 Not Found",
+                    message: format!("{} not found", path),
+                })?;
+                let table_name = url_decode(table_name)?;
+                statement.kind = "insert";
+                let execute_type = self.parser.parse_json_row(&table_name, &query)?;
+                self.run_parsed(session, execute_type, &query, statement, None)?;
+                let inserted = statement.rows.unwrap_or(0);
+                return Ok(serde_json::json!({ "inserted": inserted }).to_string());
+            }
+            (Some("POST"), _) if query.trim().is_empty() => {
+                return Err(HttpError::bad_request("request body is empty".to_string()).into());
+            }
+            (Some("POST"), _) => query,
+            // curlで試せるよう、q=にURL encodeしたstatementを渡す。書き込みは受け付けない
+            (Some("GET"), Some("/query")) => {
+                let query = query_param(query_string, "q")?
+                    .ok_or_else(|| HttpError::bad_request("missing q parameter".to_string()))?;
+                // 読めないstatementは、run_queryで同じerrorになる
+                if let Ok(execute_type) = self.parser.parse(query.trim_end()) {
+                    if !matches!(
+                        execute_type,
+                        ExecuteType::Select(_)
+                            | ExecuteType::ShowTables
+                            | ExecuteType::ShowSchema(_)
+                    ) {
+                        statement.kind = execute_type.kind();
+                        return Err(HttpError {
+                            status: "405 Method Not Allowed",
+                            message: format!(
+                                "{} is not allowed over GET; POST it to /query instead",
+                                execute_type.kind()
+                            ),
+                        }
+                        .into());
+                    }
+                }
+                query
+            }
+            (Some("GET"), Some("/health")) => {
+                statement.kind = "health";
+                return self.health(&mut self.database.lock().unwrap().executor);
+            }
+            (Some("GET"), Some("/metrics")) => {
+                statement.kind = "metrics";
+                let mut database = self.database.lock().unwrap();
+                let executor = &mut database.executor;
+                let mut gauges = StorageGauges {
+                    dirty_pages: executor.dirty_pages(),
+                    ..Default::default()
+                };
+                for name in self.parser.catalog().table_names() {
+                    gauges
+                        .table_rows
+                        .push((name.to_string(), executor.row_estimate(name)?));
+                }
+                let concurrency = ConcurrencyGauges {
+                    connections: self.connections.load(Ordering::SeqCst),
+                    max_connections: self.options.max_connections,
+                    writes: self.writes.in_progress(),
+                    max_concurrent_writes: self.options.max_concurrent_writes,
+                };
+                return Ok(self.metrics.render(
+                    &executor.buffer_pool_stats(),
+                    &gauges,
+                    &concurrency,
+                ));
+            }
+            (Some("GET"), Some("/config")) => {
+                statement.kind = "config";
+                return Ok(self.config_json(&self.database.lock().unwrap().executor));
+            }
+            (Some("GET"), Some(path)) if path.starts_with("/tables/") => {
+                let table_name = json_row_table(path).ok_or_else(|| HttpError {
+                    status: "404 Not Found",
+                    message: format!("{} not found", path),
+                })?;
+                return self.json_rows(&url_decode(table_name)?, query_string, statement);
+            }
+            (Some("GET"), path) => {
+                return Err(HttpError {
+                    status: "404 Not Found",
+                    message: format!("{} not found", path.unwrap_or("")),
+                }
+                .into())
+            }
+            (method, _) => {
+                return Err(HttpError {
+                    status: "405 Method Not Allowed",
+                    message: format!("{} is not allowed", method.unwrap_or("")),
+                }
+                .into())
+            }
+        };
+
+        // ?format=がAcceptより優先する
+        let format = match query_param(query_string, "format")? {
+            Some(name) => Format::parse(&name).ok_or_else(|| {
+                HttpError::bad_request(format!("unknown format {} (expected text or csv)", name))
+            })?,
+            None => accept.as_deref().map_or(Format::Text, Format::from_accept),
+        };
+
+        // clientは末尾に改行をつけて送ってくる
+        // cacheから返すときも同じ形式で、行数のtrailerをつけて返す
+        match format {
+            Format::Csv => {
+                body.set_content_type(format.content_type());
+                self.run_query(
+                    session,
+                    query.trim_end(),
+                    statement,
+                    Some(&mut CsvRows(body)),
+                )
+            }
+            Format::Text => self.run_query(session, query.trim_end(), statement, Some(body)),
+        }
+    }
+
+    fn run_query(
+        &self,
+        session: &mut Session,
+        query: &str,
+        statement: &mut Statement,
+        out: Option<&mut dyn RowWriter>,
+    ) -> Result<String, anyhow::Error> {
+        statement.kind = "unknown";
+        let execute_type = self.parser.parse(query)?;
+        self.run_parsed(session, execute_type, query, statement, out)
+    }
+
+    // queryはselectの結果をcacheするときのkeyにする
+    fn run_parsed(
+        &self,
+        session: &mut Session,
+        execute_type: ExecuteType,
+        query: &str,
+        statement: &mut Statement,
+        out: Option<&mut dyn RowWriter>,
+    ) -> Result<String, anyhow::Error> {
+        // databaseのlockより先に取る。待つのはstatementの実行時間の上限まで
+        let _slot = match execute_type {
+            ExecuteType::Insert(_) | ExecuteType::Update(_) => Some(
+                self.writes
+                    .acquire(self.options.query_timeout)
+                    .ok_or_else(|| DbError::Cancelled("too many concurrent writes".to_string()))?,
+            ),
+            _ => None,
+        };
+
+        let Session {
+            database: shared,
+            transaction,
+            ..
+        } = session;
+        let mut database = shared.lock().unwrap();
+        let Database { executor, cache } = &mut *database;
+
+        statement.kind = execute_type.kind();
+        executor.start_statement();
+        if let Some(kind) = query_kind(&execute_type) {
+            self.metrics.record_query(kind);
+        }
+
+        let response_text = match execute_type {
+            ExecuteType::Select(SelectInput {
+                table_name,
+                columns,
+                updated_at,
+            }) => {
+                let mut text = ResponseText::default();
+                let out: &mut dyn RowWriter = match out {
+                    Some(out) => out,
+                    None => &mut text,
+                };
+                // 期限はこのstatementのものを、lockを放す前に写しておく
+                let cursor = executor.cursor(self.options.max_rows);
+                match cache.as_mut() {
+                    Some(c) => {
+                        let key = QueryCache::normalize(query);
+                        let cached = match c.get(&key) {
+                            Some(cached) => cached,
+                            None => {
+                                let mut cached = CachedRows::default();
+                                statement.rows = Some(self.select(
+                                    ScanLock::Held(executor),
+                                    cursor,
+                                    &table_name,
+                                    &columns,
+                                    updated_at,
+                                    &mut cached,
+                                )?);
+                                c.put(key, &table_name, cached.clone());
+                                cached
+                            }
+                        };
+                        // 出力先への書き込みはclientを待つことがあるので、lockを放してから行う
+                        drop(database);
+                        replay(&cached, out)?;
+                    }
+                    None => {
+                        drop(database);
+                        statement.rows = Some(self.select(
+                            ScanLock::Chunked(shared),
+                            cursor,
+                            &table_name,
+                            &columns,
+                            updated_at,
+                            out,
+                        )?);
+                    }
+                }
+                text.0
+            }
+            ExecuteType::Insert(InsertInput {
+                attributes,
+                table_name,
+            }) => {
+                // 失敗して途中で戻された場合も含め、書き込む前に捨てておく
+                if let Some(c) = cache.as_mut() {
+                    c.invalidate_table(&table_name);
+                }
+                match transaction {
+                    Some(txn) => executor.insert_in(txn, &attributes, &table_name)?,
+                    None => executor.insert(&attributes, &table_name)?,
+                }
+                statement.rows = Some(1);
+                "success".to_string()
+            }
+            ExecuteType::Update(UpdateInput {
+                table_name,
+                attributes,
+                filter,
+            }) => {
+                if let Some(c) = cache.as_mut() {
+                    c.invalidate_table(&table_name);
+                }
+                let filter = filter.as_ref().map(|(c, v)| (c.as_str(), v));
+                let updated = match transaction {
+                    Some(txn) => executor.update_in(txn, &attributes, &table_name, filter)?,
+                    None => executor.update(&attributes, &table_name, filter)?,
+                };
+                statement.rows = Some(updated);
+                "success".to_string()
+            }
+            ExecuteType::Begin => {
+                if transaction.is_some() {
+                    return Err(DbError::Transaction(
+                        "transaction already in progress".to_string(),
+                    )
+                    .into());
+                }
+                *transaction = Some(executor.begin());
+                "begin".to_string()
+            }
+            ExecuteType::BeginReadOnly => {
+                if transaction.is_some() {
+                    return Err(DbError::Transaction(
+                        "transaction already in progress".to_string(),
+                    )
+                    .into());
+                }
+                *transaction = Some(executor.begin_read_only());
+                "begin read only".to_string()
+            }
+            ExecuteType::Commit => {
+                let txn = transaction.take().ok_or_else(|| {
+                    DbError::Transaction("no transaction in progress".to_string())
+                })?;
+                executor.commit(txn)?;
+                "commit".to_string()
+            }
+            ExecuteType::Rollback => {
+                let txn = transaction.take().ok_or_else(|| {
+                    DbError::Transaction("no transaction in progress".to_string())
+                })?;
+                database.rollback(txn)?;
+                "rollback".to_string()
+            }
+            ExecuteType::Checkpoint => {
+                executor.checkpoint()?;
+                "checkpoint".to_string()
+            }
+            ExecuteType::ShowTables => {
+                let names: Vec<&str> = self.parser.catalog().table_names().collect();
+                names.join("\n")
+            }
+            ExecuteType::ShowSchema(table_name) => {
+                let mut s = String::new();
+                for schema in &self.parser.catalog().schemas {
+                    let table = &schema.table;
+                    if table_name.as_ref().is_some_and(|n| *n != table.name) {
+                        continue;
+                    }
+                    s.push_str(format!("{}\n", table.name).as_str());
+                    for c in table.columns() {
+                        s.push_str(format!("  {} {}\n", c.name, c.types).as_str());
+                    }
+                }
+                s.trim_end().to_string()
+            }
+        };
+
+        Ok(response_text)
+    }
+
+    // GET /tables/<table_name>/rowsの応答。`?column=value`があれば一致する行だけを返す
+    // 値はintなら数値、textとnumericなら文字列にする
+    fn json_rows(
+        &self,
+        table_name: &str,
+        query_string: &str,
+        statement: &mut Statement,
+    ) -> Result<String, anyhow::Error> {
+        statement.kind = "select";
+        let table = &self
+            .parser
+            .catalog()
+            .get_schema_by_table_name(table_name)
+            .ok_or_else(|| DbError::TableNotFound(table_name.to_string()))?
+            .table;
+
+        let filter = match &query_params(query_string)?[..] {
+            [] => None,
+            [(column, value)] => {
+                let types = &table
+                    .columns()
+                    .iter()
+                    .find(|c| &c.name == column)
+                    .ok_or_else(|| {
+                        DbError::Parse(format!("{} has no column {}", table_name, column))
+                    })?
+                    .types;
+                let value = AttributeType::from_json_typed(types, &value.as_str().into())
+                    .map_err(|e| DbError::Parse(format!("{}: {}", column, e)))?;
+                Some((column.clone(), value))
+            }
+            _ => {
+                return Err(HttpError::bad_request(
+                    "only one column=value filter is supported".to_string(),
+                )
+                .into())
+            }
+        };
+
+        let mut database = self.database.lock().unwrap();
+        let executor = &mut database.executor;
+        executor.start_statement();
+        self.metrics.record_query(QueryKind::Select);
+
+        let mut rows = Vec::new();
+        let filter = filter.as_ref().map(|(c, v)| (c.as_str(), v));
+        let truncated =
+            executor.scan_each_eq(table_name, self.options.max_rows, filter, &mut |r| {
+                let row: serde_json::Map<String, serde_json::Value> = table
+                    .columns()
+                    .iter()
+                    .map(|c| {
+                        let value = match r.get(&c.name) {
+                            Some(AttributeType::Int(v)) => (*v).into(),
+                            Some(v) => v.to_display().into(),
+                            None => serde_json::Value::Null,
+                        };
+                        (c.name.clone(), value)
+                    })
+                    .collect();
+                rows.push(serde_json::Value::Object(row));
+                Ok(())
+            })?;
+        statement.rows = Some(rows.len());
+
+        Ok(serde_json::json!({
+            "rows": rows,
+            "total": statement.rows,
+            "truncated": truncated,
+        })
+        .to_string())
+    }
+
+    // schemaの列順で、1行目にcolumn名、以降に値を1行ずつoutに渡す
+    // cursorはexecutorのcursorで作り、このstatementの期限とmax_rowsを持たせておく
+    // 列を選んでいればその順で、aliasがあれば列名をそれにする
+    // updated_atなら最後の列に疑似列を加える
+    fn select(
+        &self,
+        mut lock: ScanLock,
+        mut cursor: ScanCursor,
+        table_name: &str,
+        selected: &[SelectColumn],
+        updated_at: bool,
+        out: &mut dyn RowWriter,
+    ) -> Result<usize, anyhow::Error> {
+        let mut columns = self
+            .parser
+            .catalog()
+            .columns_of(table_name)
+            .ok_or_else(|| DbError::TableNotFound(table_name.to_string()))?
+            .to_vec();
+        if updated_at {
+            columns.push(updated_at_column());
+        }
+        // 行から値を引く列名。columnsの列名はaliasに置き換わることがある
+        let mut sources: Vec<String> = columns.iter().map(|c| c.name.clone()).collect();
+        if !selected.is_empty() {
+            (sources, columns) = selected
+                .iter()
+                .filter_map(|s| {
+                    let c = columns.iter().find(|c| c.name == s.name)?;
+                    Some((
+                        s.name.clone(),
+                        Column {
+                            name: s.output_name().to_string(),
+                            types: c.types.clone(),
+                        },
+                    ))
+                })
+                .unzip();
+        }
+        out.columns(&columns)?;
+
+        while !cursor.done() {
+            let mut rows = Vec::new();
+            let mut collect = |r: HashMap<String, AttributeType>| {
+                rows.push(
+                    sources
+                        .iter()
+                        .map(|name| r.get(name).map_or(String::new(), |v| v.to_display()))
+                        .collect::<Vec<String>>(),
+                );
+                Ok(())
+            };
+            match &mut lock {
+                ScanLock::Held(executor) => executor.scan_pages(
+                    &mut cursor,
+                    table_name,
+                    None,
+                    None,
+                    updated_at,
+                    &mut collect,
+                )?,
+                ScanLock::Chunked(database) => database.lock().unwrap().executor.scan_pages(
+                    &mut cursor,
+                    table_name,
+                    Some(SCAN_CHUNK_PAGES),
+                    None,
+                    updated_at,
+                    &mut collect,
+                )?,
+            }
+            // 書き込みに失敗したら(clientが切断したなど)、残りのpageは読まない
+            for row in &rows {
+                out.row(row)?;
+            }
+        }
+        out.end(cursor.emitted(), cursor.truncated())?;
+
+        Ok(cursor.emitted())
+    }
+
+    // 接続を受け付ける前に、fileのstatementを順に実行する
+    // 失敗したstatementは行番号をつけてlogに出し、残りは続けて実行する
+    // 開いたままのtransactionは戻しておく
+    pub fn run_init_file(&self, path: &str) -> Result<(), anyhow::Error> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("cannot read init file {}: {}", path, e))?;
+
+        let mut session = self.session();
+        let statements = split_statements(&text);
+        let mut failed = 0;
+        for (line, query) in &statements {
+            let mut statement = Statement::default();
+            if let Err(e) = self.run_query(&mut session, query, &mut statement, None) {
+                failed += 1;
+                log::warn!("{}:{}: {}", path, line, e);
+            }
+        }
+
+        if let Some(txn) = session.transaction.take() {
+            log::warn!("{}: rolling back a transaction left open", path);
+            self.database.lock().unwrap().rollback(txn)?;
+        }
+        log::info!(
+            "{}: ran {} statements, {} failed",
+            path,
+            statements.len(),
+            failed
+        );
+
+        Ok(())
+    }
+
+    // 起動してからの秒数と、catalogの全tableのfileを読めるかを返す
+    fn health(&self, executor: &mut Executor<LruReplacer>) -> Result<String, anyhow::Error> {
+        let catalog = self.parser.catalog();
+        for schema in &catalog.schemas {
+            executor
+                .row_estimate(&schema.table.name)
+                .map_err(|e| HttpError {
+                    status: "503 Service Unavailable",
+                    message: format!("table {} is not readable: {}", schema.table.name, e),
+                })?;
+        }
+
+        Ok(format!(
+            "ok\nuptime: {}s\ntables: {}",
+            self.started.elapsed().as_secs(),
+            catalog.schemas.len()
+        ))
+    }
+
+    // 実際に動いている設定をJSONで返す。auth tokenは有無だけを出す
+    fn config_json(&self, executor: &Executor<LruReplacer>) -> String {
+        let pool = executor.pool_settings();
+        let config = self.config.as_ref();
+        let options = &self.options;
+
+        serde_json::to_string_pretty(&serde_json::json!({
+            "listen": config.map(|c| &c.listen),
+            "listen_unix": config.and_then(|c| c.listen_unix.as_ref()),
+            "data_dir": config.map(|c| &c.data_dir),
+            "schema": config.map(|c| &c.schema),
+            "init_file": config.and_then(|c| c.init_file.as_ref()),
+            "log_level": config.map(|c| c.log_level.as_str()),
+            "read_only": executor.read_only(),
+            "pool_size": pool.pool_size,
+            "replacer": pool.replacer,
+            "page_size": PAGE_SIZE,
+            "durability": pool.durability.label(),
+            "max_rows": options.max_rows,
+            "max_connections": options.max_connections,
+            "max_concurrent_writes": options.max_concurrent_writes,
+            "query_cache_size": options.query_cache_size,
+            "max_body_bytes": options.max_body_bytes,
+            "query_timeout_ms": options.query_timeout.map(|t| t.as_millis() as u64),
+            "checkpoint_interval_secs": options.checkpoint_interval.map(|t| t.as_secs()),
+            "protocol": options.protocol.label(),
+            "redact_literals": options.redact_literals,
+            "auth_token": options.auth_token.is_some(),
+            "auth_health": options.auth_health,
+        }))
+        .unwrap()
+    }
+
+    fn exit_handler(&self) -> Result<(), anyhow::Error> {
+        let mut database = self.database.lock().unwrap();
+
+        if !database.executor.read_only() {
+            database.executor.checkpoint()?;
+        }
+        Ok(())
+    }
+}
+
+// 一致しないbyteの位置で比較時間が変わらないよう、最後まで比べる
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn query_kind(execute_type: &ExecuteType) -> Option<QueryKind> {
+    match execute_type {
+        ExecuteType::Select(_) => Some(QueryKind::Select),
+        ExecuteType::Insert(_) => Some(QueryKind::Insert),
+        ExecuteType::Update(_) => Some(QueryKind::Update),
+        ExecuteType::Begin | ExecuteType::BeginReadOnly => Some(QueryKind::Begin),
+        ExecuteType::Commit => Some(QueryKind::Commit),
+        ExecuteType::Rollback => Some(QueryKind::Rollback),
+        ExecuteType::Checkpoint => Some(QueryKind::Checkpoint),
+        ExecuteType::ShowTables | ExecuteType::ShowSchema(_) => Some(QueryKind::Show),
+    }
+}
+
+// 'で囲まれた文字列と、=の後ろの値を?に置き換える
+fn redact_literals(query: &str) -> String {
+    let mut redacted = String::with_capacity(query.len());
+    let mut chars = query.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                // ''はtextの中の'なので、そこでは閉じない
+                while let Some(c) = chars.next() {
+                    if c == '\'' && chars.next_if_eq(&'\'').is_none() {
+                        break;
+                    }
+                }
+                redacted.push_str("'?'");
+            }
+            '=' => {
+                redacted.push('=');
+                if chars.peek().is_some_and(|c| *c != '\'') {
+                    while chars
+                        .peek()
+                        .is_some_and(|c| !c.is_whitespace() && *c != ')' && *c != ';')
+                    {
+                        chars.next();
+                    }
+                    redacted.push('?');
+                }
+            }
+            c => redacted.push(c),
+        }
+    }
+
+    redacted
+}
+
+// /tables/<table_name>/rowsのtable_name。GETとPOSTで使う
+fn json_row_table(path: &str) -> Option<&str> {
+    path.strip_prefix("/tables/")?
+        .strip_suffix("/rows")
+        .filter(|t| !t.is_empty() && !t.contains('/'))
+}
+
+// 知らない版のclientには、読み違える前に断る
+fn check_protocol_version(
+    protocol_version: u32,
+    statement: &mut Statement,
+) -> Result<(), anyhow::Error> {
+    if protocol_version == 0 || protocol_version > PROTOCOL_VERSION {
+        statement.kind = "unsupported";
+        return Err(HttpError::bad_request(format!(
+            "unsupported client version {} (server supports {})",
+            protocol_version, PROTOCOL_VERSION
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+fn unauthorized(statement: &mut Statement) -> anyhow::Error {
+    statement.kind = "unauthorized";
+    HttpError {
+        status: "401 Unauthorized",
+        message: "missing or invalid bearer token".to_string(),
+    }
+    .into()
+}
+
+// 書き込み先のclientが接続を閉じた
+fn disconnected(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<io::Error>().is_some_and(|e| {
+        matches!(
+            e.kind(),
+            ErrorKind::BrokenPipe | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted
+        )
+    })
+}
+
+// ReadyForQueryで返す、この接続のtransactionの状態
+fn transaction_status(session: &Session) -> u8 {
+    if session.transaction.is_some() {
+        b'T'
+    } else {
+        b'I'
+    }
+}
+
+// 分類されていないerrorはstorageなどserver側の失敗として扱う
+fn pg_error_code(e: &anyhow::Error) -> &'static str {
+    match e.downcast_ref::<DbError>() {
+        Some(DbError::Parse(_)) => pgwire::SYNTAX_ERROR,
+        Some(DbError::TableNotFound(_)) => pgwire::UNDEFINED_TABLE,
+        Some(DbError::Transaction(_)) => pgwire::INVALID_TRANSACTION_STATE,
+        Some(DbError::ReadOnly(_)) => pgwire::READ_ONLY_TRANSACTION,
+        Some(DbError::Cancelled(_)) => pgwire::QUERY_CANCELED,
+        Some(DbError::Conflict(_)) => pgwire::UNIQUE_VIOLATION,
+        _ => pgwire::INTERNAL_ERROR,
+    }
+}
+
+fn error_status(e: &anyhow::Error) -> &'static str {
+    if let Some(h) = e.downcast_ref::<HttpError>() {
+        return h.status;
+    }
+    if let Some(d) = e.downcast_ref::<DbError>() {
+        return d.status();
+    }
+    "500 Internal Server Error"
+}
+
+fn respond<W: Write>(
+    stream: W,
+    status: &str,
+    body: &str,
+    keep_alive: bool,
+) -> Result<(), anyhow::Error> {
+    let mut writer = BufWriter::new(stream);
+    let authenticate = if status.starts_with("401") {
+        "WWW-Authenticate: Bearer\r\n"
+    } else {
+        ""
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\n{}: {}\r\nConnection: {}\r\n{}\r\n{}",
+        status,
+        body.len(),
+        PROTOCOL_VERSION_HEADER,
+        PROTOCOL_VERSION,
+        if keep_alive { "keep-alive" } else { "close" },
+        authenticate,
+        body
+    );
+    writer.write_all(response.as_bytes())?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+
+    use crate::{
+        catalog::AttributeType, storage::buffer_pool_manager::BufferPoolManager,
+        test_util::temp_dir,
+    };
+
+    use super::*;
+
+    const JSON: &str = r#"{
+        "schemas": [
+            {
+                "table": {
+                    "name": "server_test",
+                    "columns": [
+                        {
+                            "types": "int",
+                            "name": "column_int"
+                        },
+                        {
+                            "types": "text",
+                            "name": "column_text"
+                        }
+                    ]
+                }
+            }
+        ]
+    }"#;
+
+    fn send(addr: SocketAddr, body: &str) -> (String, String) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let request = format!(
+            "POST / HTTP/1.1\r\ncontent-length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes()).unwrap();
+
+        read_response(stream)
+    }
+
+    // statusとbodyに分け、headerが揃っていることを確かめる
+    fn read_response(mut stream: impl Read) -> (String, String) {
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let mut lines = head.split("\r\n");
+        let status = lines
+            .next()
+            .unwrap()
+            .trim_start_matches("HTTP/1.1 ")
+            .to_string();
+        let headers: Vec<&str> = lines.collect();
+
+        assert!(
+            headers.contains(&"Content-Type: text/plain; charset=utf-8"),
+            "{}",
+            head
+        );
+        if headers.contains(&"Transfer-Encoding: chunked") {
+            let (body, trailers) = decode_chunked(body);
+            assert!(!body.contains("\nerror: "), "{}", body);
+            assert!(
+                trailers.contains(&format!("{}: {}", ROW_COUNT_TRAILER, row_count(&body))),
+                "{:?}",
+                trailers
+            );
+            return (status, body);
+        }
+        assert!(
+            headers.contains(&format!("Content-Length: {}", body.len()).as_str()),
+            "{}",
+            head
+        );
+
+        (status, body.to_string())
+    }
+
+    // chunkをつなげたbodyと、trailerの行を返す
+    fn decode_chunked(mut raw: &str) -> (String, Vec<String>) {
+        let mut body = String::new();
+        loop {
+            let (size, rest) = raw.split_once("\r\n").unwrap();
+            let size = usize::from_str_radix(size, 16).unwrap();
+            if size == 0 {
+                let trailers = rest
+                    .split("\r\n")
+                    .filter(|l| !l.is_empty())
+                    .map(|l| l.to_string())
+                    .collect();
+                return (body, trailers);
+            }
+            body.push_str(&rest[..size]);
+            raw = rest[size..].strip_prefix("\r\n").unwrap();
+        }
+    }
+
+    fn row_count(body: &str) -> &str {
+        body.rsplit_once("total: ").unwrap().1
+    }
+
+    fn ok(body: &str) -> (String, String) {
+        ("200 OK".to_string(), body.to_string())
+    }
+
+    // testが失敗してもserverのthreadを待って止まらないよう、serverは'staticにして切り離す
+    fn start(
+        name: &str,
+        options: ServerOptions,
+    ) -> (
+        &'static Server<'static>,
+        SocketAddr,
+        thread::JoinHandle<Result<(), anyhow::Error>>,
+    ) {
+        let temp_dir = temp_dir(name);
+        let catalog: &'static Catalog = Box::leak(Box::new(Catalog::from_json(JSON).unwrap()));
+        let manager =
+            BufferPoolManager::new(4, temp_dir.to_str().unwrap().to_string(), catalog.clone());
+        let server: &'static Server = Box::leak(Box::new(Server::new(
+            catalog,
+            Executor::new(manager),
+            options,
+        )));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || server.run(listener));
+
+        (server, addr, handle)
+    }
+
+    #[test]
+    fn server_concurrent_inserts() {
+        let (_, addr, handle) = start("server_concurrent_inserts", ServerOptions::default());
+
+        let clients = 8;
+        let inserts = 20;
+
+        let workers: Vec<_> = (0..clients)
+            .map(|c| {
+                thread::spawn(move || {
+                    for i in 0..inserts {
+                        let query = format!(
+                            "insert into server_test ( column_int={} column_text='c{}' );\n",
+                            i, c
+                        );
+                        // 他のclientの応答が混ざっていないこと
+                        assert_eq!(send(addr, &query), ok("success"));
+                    }
+                })
+            })
+            .collect();
+        for w in workers {
+            w.join().unwrap();
+        }
+
+        let (status, body) = send(addr, "select * from server_test;\n");
+        assert_eq!(status, "200 OK");
+        assert!(
+            body.ends_with(&format!("total: {}", clients * inserts)),
+            "{}",
+            body
+        );
+
+        shutdown(addr);
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn server_background_checkpoint() {
+        let options = ServerOptions {
+            checkpoint_interval: Some(Duration::from_millis(20)),
+            ..Default::default()
+        };
+        let (server, addr, handle) = start("server_background_checkpoint", options);
+        let dirty_pages = || server.database.lock().unwrap().executor.dirty_pages();
+        let wait_until_clean = || {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while dirty_pages() > 0 {
+                assert!(Instant::now() < deadline, "dirty pages were not written");
+                thread::sleep(Duration::from_millis(10));
+            }
+        };
+
+        let insert = "insert into server_test ( column_int=1 column_text='a' );\n";
+        assert_eq!(send(addr, insert).0, "200 OK");
+        wait_until_clean();
+
+        // transaction中でもpageは書き出し、rollbackはそのままできる
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut reader = BufReader::new(&stream);
+        assert_eq!(
+            send_keep_alive(&stream, &mut reader, "begin;\n").0,
+            "200 OK"
+        );
+        assert_eq!(send_keep_alive(&stream, &mut reader, insert).0, "200 OK");
+        wait_until_clean();
+        assert_eq!(
+            send_keep_alive(&stream, &mut reader, "rollback;\n").0,
+            "200 OK"
+        );
+        drop(reader);
+        drop(stream);
+
+        assert_eq!(
+            send(addr, "select * from server_test;\n"),
+            ok("column_int | column_text\n1 | a\ntotal: 1")
+        );
+        shutdown(addr);
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn server_write_limit() {
+        let options = ServerOptions {
+            max_concurrent_writes: Some(1),
+            query_timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let (server, addr, handle) = start("server_write_limit", options);
+
+        // 書き込みの枠を埋めておくと、insertは待った末に断られる
+        let slot = server.writes.acquire(None).unwrap();
+        let insert = "insert into server_test ( column_int=1 column_text='a' );\n";
+        assert_eq!(
+            send(addr, insert),
+            (
+                "503 Service Unavailable".to_string(),
+                "too many concurrent writes".to_string()
+            )
+        );
+        // 読み込みは待たない
+        assert_eq!(
+            send(addr, "select * from server_test;\n"),
+            ok("column_int | column_text\ntotal: 0")
+        );
+        let (_, body) = send_raw(addr, &[b"GET /metrics HTTP/1.1\r\n\r\n"]);
+        assert!(body.contains("aqua_writes_in_progress 1\n"), "{}", body);
+        assert!(body.contains("aqua_max_concurrent_writes 1\n"), "{}", body);
+
+        // 空くのを待っていたinsertは、解放されると進む
+        thread::scope(|scope| {
+            let waiting = scope.spawn(|| server.writes.acquire(None).is_some());
+            thread::sleep(Duration::from_millis(10));
+            drop(slot);
+            assert!(waiting.join().unwrap());
+        });
+        assert_eq!(send(addr, insert), ok("success"));
+
+        shutdown(addr);
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn server_connection_limit() {
+        let options = ServerOptions {
+            max_connections: 1,
+            ..Default::default()
+        };
+        let (server, addr, handle) = start("server_connection_limit", options);
+
+        // requestを送らずに接続を1つ握っておく
+        let mut held = TcpStream::connect(addr).unwrap();
+
+        // 断られた接続はrequestを読まれずに閉じられるので、送らずに応答だけ読む
+        let rejected = TcpStream::connect(addr).unwrap();
+        assert_eq!(
+            read_response(rejected),
+            (
+                "503 Service Unavailable".to_string(),
+                "too many connections".to_string()
+            )
+        );
+
+        let body = "select * from server_test;\n";
+        let request = format!(
+            "POST / HTTP/1.1\r\ncontent-length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        held.write_all(request.as_bytes()).unwrap();
+        assert_eq!(
+            read_response(held),
+            ok("column_int | column_text\ntotal: 0")
+        );
+
+        // 解放されるまで待ってから止める
+        while server.connections.load(Ordering::SeqCst) > 0 {
+            thread::yield_now();
+        }
+        shutdown(addr);
+        handle.join().unwrap().unwrap();
+    }
+
+    fn send_raw(addr: SocketAddr, chunks: &[&[u8]]) -> (String, String) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        for chunk in chunks {
+            stream.write_all(chunk).unwrap();
+            stream.flush().unwrap();
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        read_response(stream)
+    }
+
+    fn shutdown(addr: SocketAddr) {
+        let response = send_raw(addr, &[b"POST /admin/shutdown HTTP/1.1\r\n\r\n"]);
+        assert_eq!(response, ok("shutting down"));
+    }
+
+    #[test]
+    fn server_raw_requests() {
+        let (_, addr, handle) = start("server_raw_requests", ServerOptions::default());
+
+        // curl形式のheaderで、bodyの末尾に改行がない
+        let response = send_raw(
+            addr,
+            &[b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 26\r\n\r\nselect * from server_test;"],
+        );
+        assert_eq!(response, ok("column_int | column_text\ntotal: 0"));
+
+        // bodyが複数のpacketに分かれて届く
+        let response = send_raw(
+            addr,
+            &[
+                b"POST / HTTP/1.1\r\nContent-Length: 26\r\n\r\nselect * ",
+                b"from server_test;",
+            ],
+        );
+        assert_eq!(response, ok("column_int | column_text\ntotal: 0"));
+
+        let (status, _) = send_raw(addr, &[b"POST / HTTP/1.1\r\nHost: localhost\r\n\r\n"]);
+        assert_eq!(status, "400 Bad Request");
+
+        let request = format!(
+            "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY_SIZE + 1
+        );
+        let (status, _) = send_raw(addr, &[request.as_bytes()]);
+        assert_eq!(status, "413 Payload Too Large");
+
+        shutdown(addr);
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn server_json_row() {
+        let (server, addr, handle) = start("server_json_row", ServerOptions::default());
+        let post = |path: &str, body: &str| {
+            let request = format!(
+                "POST {} HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+                path,
+                body.len(),
+                body
+            );
+            send_raw(addr, &[request.as_bytes()])
+        };
+
+        assert_eq!(
+            post(
+                "/tables/server_test/rows",
+                r#"{"column_int":1,"column_text":"it's"}"#
+            ),
+            ok(r#"{"inserted":1}"#)
+        );
+        let mut records = Vec::new();
+        let mut database = server.database.lock().unwrap();
+        database.executor.scan("server_test", &mut records).unwrap();
+        drop(database);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0]["column_int"], AttributeType::Int(1));
+        assert_eq!(
+            records[0]["column_text"],
+            AttributeType::Text("it's".to_string())
+        );
+
+        let (status, body) = post("/tables/server_test/rows", r#"{"column_int":"x"}"#);
+        assert_eq!(status, "400 Bad Request");
+        assert_eq!(body, "column_int: x is not an int");
+        assert_eq!(post("/tables/nothing/rows", "{}").0, "404 Not Found");
+        assert_eq!(post("/tables/server_test", "{}").0, "404 Not Found");
+
+        shutdown(addr);
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn server_get_json_rows() {
+        let options = ServerOptions {
+            max_rows: Some(2),
+            ..Default::default()
+        };
+        let (_, addr, handle) = start("server_get_json_rows", options);
+        let get = |target: &str| {
+            let request = format!("GET {} HTTP/1.1\r\n\r\n", target);
+            let (status, body) = send_raw(addr, &[request.as_bytes()]);
+            match status.as_str() {
+                "200 OK" => (status, serde_json::from_str(&body).unwrap()),
+                _ => (status, serde_json::Value::String(body)),
+            }
+        };
+
+        for (i, text) in [(5, "a"), (6, "O'Brien"), (5, "c")] {
+            let query = format!(
+                "insert into server_test ( column_int={} column_text='{}' );\n",
+                i,
+                text.replace('\'', "''")
+            );
+            assert_eq!(send(addr, &query), ok("success"));
+        }
+
+        let (status, body) = get("/tables/server_test/rows?column_int=5");
+        assert_eq!(status, "200 OK");
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "rows": [
+                    {"column_int": 5, "column_text": "a"},
+                    {"column_int": 5, "column_text": "c"},
+                ],
+                "total": 2,
+                "truncated": false,
+            })
+        );
+        let (_, body) = get("/tables/server_test/rows?column_text=O%27Brien");
+        assert_eq!(
+            body["rows"],
+            serde_json::json!([{"column_int": 6, "column_text": "O'Brien"}])
+        );
+
+        // filterがなければ全ての行を、max_rowsまで返す
+        let (_, body) = get("/tables/server_test/rows");
+        assert_eq!(body["total"], 2);
+        assert_eq!(body["truncated"], true);
+
+        assert_eq!(
+            get("/tables/server_test/rows?column_int=x").0,
+            "400 Bad Request"
+        );
+        assert_eq!(
+            get("/tables/server_test/rows?nothing=1").0,
+            "400 Bad Request"
+        );
+        assert_eq!(
+            get("/tables/server_test/rows?column_int=5&column_text=a").0,
+            "400 Bad Request"
+        );
+        assert_eq!(get("/tables/nothing/rows").0, "404 Not Found");
+
+        shutdown(addr);
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn server_get_query() {
+        let (_, addr, handle) = start("server_get_query", ServerOptions::default());
+        let get = |target: &str| {
+            let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", target);
+            send_raw(addr, &[request.as_bytes()])
+        };
+
+        send(
+            addr,
+            "insert into server_test ( column_int=1 column_text='O''Brien & co' );\n",
+        );
+        assert_eq!(
+            get("/query?q=select+*+from%20server_test%3B"),
+            (
+                "200 OK".to_string(),
+                "column_int | column_text\n1 | O'Brien & co\ntotal: 1".to_string()
+            )
+        );
+
+        // 書き込みはGETでは受け付けない
+        let (status, body) = get(
+            "/query?q=insert+into+server_test+(+column_int%3D2+column_text%3D%27O%27%27Brien+%26+co%27+)%3B",
+        );
+        assert_eq!(status, "405 Method Not Allowed");
+        assert!(body.contains("insert is not allowed over GET"), "{}", body);
+        assert_eq!(
+            send(addr, "select * from server_test;\n"),
+            ok("column_int | column_text\n1 | O'Brien & co\ntotal: 1")
+        );
+
+        assert_eq!(get("/query?q=select%2").0, "400 Bad Request");
+        assert_eq!(get("/query?x=1").0, "400 Bad Request");
+        assert_eq!(get("/query?q=select+*+from+nothing%3B").0, "404 Not Found");
+
+        shutdown(addr);
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn server_select_display() {
+        let (_, addr, handle) = start("server_select_display", ServerOptions::default());
+
+        send(
+            addr,
+            "insert into server_test ( column_text='hoge' column_int=12 );\n",
+        );
+
+        assert_eq!(
+            send(addr, "select * from server_test;\n"),
+            ok("column_int | column_text\n12 | hoge\ntotal: 1")
+        );
+
+        let (status, body) = send(addr, "select *, updated_at from server_test;\n");
+        assert_eq!(status, "200 OK");
+        let lines: Vec<&str> = body.lines().collect();
+        assert_eq!(lines[0], "column_int | column_text | updated_at");
+        let updated_at: u64 = lines[1].rsplit(" | ").next().unwrap().parse().unwrap();
+        assert!(updated_at > 0);
+
+        assert_eq!(
+            send(
+                addr,
+                "select column_text as t, column_int from server_test;\n"
+            ),
+            ok("t | column_int\nhoge | 12\ntotal: 1")
+        );
+        let (status, body) = send(
+            addr,
+            "select column_int as n, column_text as n from server_test;\n",
+        );
+        assert_eq!(status, "400 Bad Request");
+        assert!(
+            body.contains("column name n is used twice in select"),
+            "{}",
+            body
+        );
+
+        shutdown(addr);
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn server_error_status() {
+        let (_, addr, handle) = start("server_error_status", ServerOptions::default());
+
+        let cases = [
+            ("selec * from server_test;\n", "400 Bad Request"),
+            ("select * from nothing;\n", "404 Not Found"),
+            ("commit;\n", "400 Bad Request"),
+            ("begin read only;\n", "200 OK"),
+            (
+                "insert into server_test ( column_int=1 column_text='a' );\n",
+                "403 Forbidden",
+            ),
+            ("rollback;\n", "200 OK"),
+            ("exit;\n", "400 Bad Request"),
+        ];
+        // transactionは接続ごとなので、1つの接続で続けて送る
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut reader = BufReader::new(&stream);
+        for (query, expected) in cases {
+            let (status, body) = send_keep_alive(&stream, &mut reader, query);
+            assert_eq!(status, expected, "{}: {}", query, body);
+        }
+
+        shutdown(addr);
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn server_query_cache() {
+        let options = ServerOptions {
+            query_cache_size: 4,
+            ..Default::default()
+        };
+        let (server, addr, handle) = start("server_query_cache", options);
+        let hits = || {
+            server
+                .database
+                .lock()
+                .unwrap()
+                .cache
+                .as_ref()
+                .unwrap()
+                .hits()
+        };
+
+        let empty = ok("column_int | column_text\ntotal: 0");
+        assert_eq!(send(addr, "select * from server_test;\n"), empty);
+        assert_eq!(send(addr, "select * from server_test;\n"), empty);
+        assert_eq!(hits(), 1);
+
+        send(
+            addr,
+            "insert into server_test ( column_int=1 column_text='a' );\n",
+        );
+        assert_eq!(
+            send(addr, "select * from server_test;\n"),
+            ok("column_int | column_text\n1 | a\ntotal: 1")
+        );
+        assert_eq!(hits(), 1);
+
+        // CSVでもcacheから返し、行数のtrailerをつける
+        let select = "select * from server_test;";
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let request = format!(
+            "POST / HTTP/1.1\r\nAccept: text/csv\r\nContent-Length: {}\r\n\r\n{}",
+            select.len(),
+            select
+        );
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, raw) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.contains("Content-Type: text/csv"), "{}", head);
+        assert_eq!(
+            decode_chunked(raw),
+            (
+                "column_int,column_text\n1,a\n".to_string(),
+                vec!["X-Row-Count: 1".to_string()]
+            )
+        );
+        assert_eq!(hits(), 2);
+
+        // updateしてもそのtableの結果を捨てる
+        assert_eq!(
+            send(
+                addr,
+                "update server_test set ( column_text='b' ) where column_int=1;\n"
+            ),
+            ok("success")
+        );
+        assert_eq!(
+            send(addr, "select * from server_test;\n"),
+            ok("column_int | column_text\n1 | b\ntotal: 1")
+        );
+        assert_eq!(hits(), 2);
+
+        shutdown(addr);
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn server_shutdown_handle() {
+        let temp_dir = temp_dir("server_shutdown_handle");
+        let catalog: &'static Catalog = Box::leak(Box::new(Catalog::from_json(JSON).unwrap()));
+        let data_dir = temp_dir.to_str().unwrap().to_string();
+
+        let manager = BufferPoolManager::new(4, data_dir.clone(), catalog.clone());
+        let server: &'static Server = Box::leak(Box::new(Server::new(
+            catalog,
+            Executor::new(manager),
+            ServerOptions::default(),
+        )));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let shutdown = server.shutdown_handle(listener.local_addr().unwrap());
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || server.run(listener));
+
+        assert_eq!(
+            send(
+                addr,
+                "insert into server_test ( column_int=1 column_text='a' );\n"
+            ),
+            ok("success")
+        );
+
+        // signal handlerと同じ経路で止める。何度呼んでもよい
+        shutdown.trigger().unwrap();
+        shutdown.trigger().unwrap();
+        handle.join().unwrap().unwrap();
+
+        // checkpoint済みなので、起動し直すとtable fileから読める
+        let manager = BufferPoolManager::new(4, data_dir, catalog.clone());
+        let mut executor = Executor::new(manager);
+        let mut records = Vec::new();
+        executor.scan("server_test", &mut records).unwrap();
+        assert_eq!(records.len(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn server_unix_socket() {
+        use crate::connection::UnixSocketListener;
+        use std::os::unix::net::UnixStream;
+
+        let temp_dir = temp_dir("server_unix_socket");
+        let catalog: &'static Catalog = Box::leak(Box::new(Catalog::from_json(JSON).unwrap()));
+        let manager =
+            BufferPoolManager::new(4, temp_dir.to_str().unwrap().to_string(), catalog.clone());
+        let server: &'static Server = Box::leak(Box::new(Server::new(
+            catalog,
+            Executor::new(manager),
+            ServerOptions::default(),
+        )));
+        let path = temp_dir.join("aqua.sock");
+        let listener = UnixSocketListener::bind(&path).unwrap();
+        let handle = thread::spawn(move || server.run(listener));
+
+        let send = |request: &str| {
+            let mut stream = UnixStream::connect(&path).unwrap();
+            stream.write_all(request.as_bytes()).unwrap();
+            read_response(stream)
+        };
+        let insert = "insert into server_test ( column_int=1 column_text='a' );";
+        assert_eq!(
+            send(&format!(
+                "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+                insert.len(),
+                insert
+            )),
+            ok("success")
+        );
+        assert_eq!(
+            send("GET /query?q=select%20*%20from%20server_test%3B HTTP/1.1\r\n\r\n"),
+            ok("column_int | column_text\n1 | a\ntotal: 1")
+        );
+
+        // 止めるとsocket fileも消える
+        assert_eq!(
+            send("POST /admin/shutdown HTTP/1.1\r\n\r\n"),
+            ok("shutting down")
+        );
+        handle.join().unwrap().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn server_client_disconnect_stops_scan() {
+        let temp_dir = temp_dir("server_client_disconnect_stops_scan");
+        let catalog: &'static Catalog = Box::leak(Box::new(Catalog::from_json(JSON).unwrap()));
+        let mut manager =
+            BufferPoolManager::new(4, temp_dir.to_str().unwrap().to_string(), catalog.clone());
+        manager.set_durability(Durability::Buffered);
+        let mut executor = Executor::new(manager);
+
+        let rows = 6000;
+        let mut txn = executor.begin();
+        for i in 0..rows {
+            let attributes = HashMap::from([
+                ("column_int".to_string(), AttributeType::Int(i)),
+                (
+                    "column_text".to_string(),
+                    AttributeType::Text("a".to_string()),
+                ),
+            ]);
+            executor
+                .insert_in(&mut txn, &attributes, "server_test")
+                .unwrap();
+        }
+        executor.commit(txn).unwrap();
+
+        // 全てを読むときにfetchするpageの数
+        let fetched = |e: &Executor<LruReplacer>| {
+            let stats = e.buffer_pool_stats();
+            stats.hits + stats.misses
+        };
+        let before = fetched(&executor);
+        executor
+            .scan_each("server_test", None, &mut |_| Ok(()))
+            .unwrap();
+        let pages = fetched(&executor) - before;
+
+        let options = ServerOptions {
+            max_rows: None,
+            ..Default::default()
+        };
+        let server: &'static Server = Box::leak(Box::new(Server::new(catalog, executor, options)));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || server.run(listener));
+
+        let before = fetched(&server.database.lock().unwrap().executor);
+
+        // 最初のchunkを読んだところで、残りを読まずに閉じる
+        {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let body = "select * from server_test;";
+            write!(
+                stream,
+                "POST / HTTP/1.1\r\ncontent-length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .unwrap();
+            let mut reader = BufReader::new(&stream);
+            let mut line = String::new();
+            while line != "column_int | column_text\n" {
+                line.clear();
+                reader.read_line(&mut line).unwrap();
+            }
+        }
+
+        while server.connections.load(Ordering::SeqCst) > 0 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let fetched = fetched(&server.database.lock().unwrap().executor) - before;
+        assert!(fetched > 0);
+        assert!(fetched < pages, "fetched {} of {} pages", fetched, pages);
+
+        // pinを残していないので、続けて全ての行を読める
+        let (status, body) = send(addr, "select * from server_test;\n");
+        assert_eq!(status, "200 OK");
+        assert_eq!(row_count(&body), rows.to_string());
+
+        shutdown(addr);
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn server_session_transaction() {
+        let (server, addr, handle) = start("server_session_transaction", ServerOptions::default());
+        let insert = "insert into server_test ( column_int=1 column_text='a' );\n";
+
+        // 1つの接続の中で始めたtransactionを戻す
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut reader = BufReader::new(&stream);
+        let mut send_in = |query: &str| send_keep_alive(&stream, &mut reader, query);
+        assert_eq!(send_in("begin;\n"), ok("begin"));
+        assert_eq!(send_in(insert), ok("success"));
+        // 別の接続からは、このtransactionを終えられない
+        assert_eq!(send(addr, "commit;\n").0, "400 Bad Request");
+        assert_eq!(send_in("rollback;\n"), ok("rollback"));
+        assert_eq!(
+            send_in("select * from server_test;\n"),
+            ok("column_int | column_text\ntotal: 0")
+        );
+
+        // transactionの途中で接続が切れたら、serverが戻す
+        assert_eq!(send_in("begin;\n"), ok("begin"));
+        assert_eq!(send_in(insert), ok("success"));
+        drop(reader);
+        drop(stream);
+
+        while server.connections.load(Ordering::SeqCst) > 0 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(
+            send(addr, "select * from server_test;\n"),
+            ok("column_int | column_text\ntotal: 0")
+        );
+
+        shutdown(addr);
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn server_closed_session_clears_query_cache() {
+        let options = ServerOptions {
+            query_cache_size: 4,
+            ..Default::default()
+        };
+        let (server, addr, handle) = start("server_closed_session_clears_query_cache", options);
+        let select = "select * from server_test;\n";
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut reader = BufReader::new(&stream);
+        let mut send_in = |query: &str| send_keep_alive(&stream, &mut reader, query);
+        assert_eq!(send_in("begin;\n"), ok("begin"));
+        assert_eq!(
+            send_in("insert into server_test ( column_int=1 column_text='a' );\n"),
+            ok("success")
+        );
+        // 別の接続がcommit前の行を読み、cacheに入る
+        assert_eq!(
+            send(addr, select),
+            ok("column_int | column_text\n1 | a\ntotal: 1")
+        );
+
+        // 接続が切れて戻したら、cacheからも消える
+        drop(reader);
+        drop(stream);
+        while server.connections.load(Ordering::SeqCst) > 0 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(send(addr, select), ok("column_int | column_text\ntotal: 0"));
+
+        shutdown(addr);
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn server_protocol_version() {
+        let (_, addr, handle) = start("server_protocol_version", ServerOptions::default());
+
+        let request = |version: u32| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream
+                .write_all(
+                    format!(
+                        "POST / HTTP/1.1\r\nAqua-Protocol-Version: {}\r\nContent-Length: 12\r\n\r\nshow tables;",
+                        version
+                    )
+                    .as_bytes(),
+                )
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        // 応答にはserverの版が載る
+        let response = request(PROTOCOL_VERSION);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("\r\nAqua-Protocol-Version: 1\r\n"));
+        assert!(response.ends_with("\r\n\r\nserver_test"), "{}", response);
+
+        let response = request(PROTOCOL_VERSION + 1);
+        assert!(
+            response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+            "{}",
+            response
+        );
+        assert!(response.ends_with("unsupported client version 2 (server supports 1)"));
+
+        shutdown(addr);
+        handle.join().unwrap().unwrap();
+    }
+
+    // keep-aliveをつけて送り、同じ接続で応答を読む。sessionは接続が閉じるまで続く
+    fn send_keep_alive(
+        stream: &TcpStream,
+        reader: &mut BufReader<&TcpStream>,
+        body: &str,
+    ) -> (String, String) {
+        let request = format!(
+            "POST / HTTP/1.1\r\nConnection: keep-alive\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        (&*stream).write_all(request.as_bytes()).unwrap();
+        let (status, _, body) = read_keep_alive_response(reader);
+        (status, body)
+    }
+
+    // Content-Lengthの分だけ読み、接続は閉じずに次の応答を読めるようにしておく
+    fn read_keep_alive_response(reader: &mut BufReader<&TcpStream>) -> (String, String, String) {
+        let mut status = String::new();
+        reader.read_line(&mut status).unwrap();
+        let mut length = 0;
+        let mut chunked = false;
+        let mut connection = String::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = line.split_once(": ").unwrap();
+            match name {
+                "Content-Length" => length = value.parse().unwrap(),
+                "Transfer-Encoding" => chunked = value == "chunked",
+                "Connection" => connection = value.to_string(),
+                _ => {}
+            }
+        }
+        let mut body = vec![0_u8; length];
+        reader.read_exact(&mut body).unwrap();
+
+        // chunkを読み、0のchunkの後はtrailerの終わりの空行まで読む
+        while chunked {
+            let mut size = String::new();
+            reader.read_line(&mut size).unwrap();
+            let size = usize::from_str_radix(size.trim_end(), 16).unwrap();
+            if size == 0 {
+                let mut line = String::new();
+                while line != "\r\n" {
+                    line.clear();
+                    reader.read_line(&mut line).unwrap();
+                }
+                chunked = false;
+                continue;
+            }
+            let mut chunk = vec![0_u8; size + 2];
+            reader.read_exact(&mut chunk).unwrap();
+            body.extend_from_slice(&chunk[..size]);
+        }
+
+        (
+            status
+                .trim_end()
+                .trim_start_matches("HTTP/1.1 ")
+                .to_string(),
+            connection,
+            String::from_utf8(body).unwrap(),
+        )
+    }
+
+    #[test]
+    fn server_keep_alive() {
+        let (_, addr, handle) = start("server_keep_alive", ServerOptions::default());
+
+        let request = |body: &str, connection: &str| {
+            format!(
+                "POST / HTTP/1.1\r\nConnection: {}\r\nContent-Length: {}\r\n\r\n{}",
+                connection,
+                body.len(),
+                body
+            )
+        };
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        // 2つのrequestを1回で送る
+        let pipelined = request(
+            "insert into server_test ( column_int=1 column_text='a' );",
+            "keep-alive",
+        ) + &request("select * from server_test;", "keep-alive");
+        stream.write_all(pipelined.as_bytes()).unwrap();
+
+        let mut reader = BufReader::new(&stream);
+        assert_eq!(
+            read_keep_alive_response(&mut reader),
+            (
+                "200 OK".to_string(),
+                "keep-alive".to_string(),
+                "success".to_string()
+            )
+        );
+        assert_eq!(
+            read_keep_alive_response(&mut reader),
+            (
+                "200 OK".to_string(),
+                "keep-alive".to_string(),
+                "column_int | column_text\n1 | a\ntotal: 1".to_string()
+            )
+        );
+
+        // 誤ったqueryでも接続は続き、closeを指定すると閉じられる
+        (&stream)
+            .write_all(request("selec;", "keep-alive").as_bytes())
+            .unwrap();
+        assert_eq!(read_keep_alive_response(&mut reader).0, "400 Bad Request");
+        (&stream)
+            .write_all(request("commit;", "close").as_bytes())
+            .unwrap();
+        assert_eq!(read_keep_alive_response(&mut reader).1, "close");
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "");
+
+        shutdown(addr);
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn server_log_text() {
+        assert_eq!(
+            redact_literals("insert into users ( id=1 name='secret value' );"),
+            "insert into users ( id=? name='?' );"
+        );
+        assert_eq!(
+            redact_literals("insert into users ( id=1 name='O''Brien' );"),
+            "insert into users ( id=? name='?' );"
+        );
+        assert_eq!(
+            redact_literals("select * from users;"),
+            "select * from users;"
+        );
+
+        let catalog: &'static Catalog = Box::leak(Box::new(Catalog::from_json(JSON).unwrap()));
+        let temp_dir = temp_dir("server_log_text");
+        let manager =
+            BufferPoolManager::new(1, temp_dir.to_str().unwrap().to_string(), catalog.clone());
+        let options = ServerOptions {
+            redact_literals: true,
+            ..Default::default()
+        };
+        let server = Server::new(catalog, Executor::new(manager), options);
+
+        assert_eq!(
+            server.log_text("insert into server_test ( column_int=1 column_text='a' );\n"),
+            "insert into server_test ( column_int=? column_text='?' );"
+        );
+        let long = server.log_text(&format!("select * from {};", "t".repeat(300)));
+        assert_eq!(long.chars().count(), MAX_LOGGED_QUERY_LEN + 3);
+        assert!(long.ends_with("..."));
+    }
+
+    #[test]
+    fn server_health_and_metrics() {
+        let (_, addr, handle) = start("server_health_and_metrics", ServerOptions::default());
+
+        for i in 0..3 {
+            let query = format!(
+                "insert into server_test ( column_int={} column_text='a' );\n",
+                i
+            );
+            assert_eq!(send(addr, &query), ok("success"));
+        }
+
+        let (status, body) = send_raw(addr, &[b"GET /health HTTP/1.1\r\n\r\n"]);
+        assert_eq!(status, "200 OK");
+        assert!(body.starts_with("ok\nuptime: "), "{}", body);
+        assert!(body.ends_with("tables: 1"), "{}", body);
+
+        let (status, body) = send_raw(addr, &[b"GET /metrics HTTP/1.1\r\n\r\n"]);
+        assert_eq!(status, "200 OK");
+        assert!(body.contains("aqua_queries_total{type=\"insert\"} 3\n"));
+        assert!(
+            body.contains("aqua_buffer_pool_dirty_pages 1\n"),
+            "{}",
+            body
+        );
+        assert!(body.contains("aqua_table_rows{table=\"server_test\"} 3\n"));
+
+        let (status, _) = send_raw(addr, &[b"GET /nothing HTTP/1.1\r\n\r\n"]);
+        assert_eq!(status, "404 Not Found");
+        let (status, _) = send_raw(
+            addr,
+            &[b"PUT / HTTP/1.1\r\nContent-Length: 7\r\n\r\ncommit;"],
+        );
+        assert_eq!(status, "405 Method Not Allowed");
+
+        shutdown(addr);
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn server_quoted_text() {
+        let (_, addr, handle) = start("server_quoted_text", ServerOptions::default());
+
+        assert_eq!(
+            send(
+                addr,
+                "insert into server_test ( column_int=1 column_text='O''Brien' );\n"
+            ),
+            ok("success")
+        );
+        assert_eq!(
+            send(
+                addr,
+                "insert into server_test ( column_int=2 column_text='it''s a=b' );\n"
+            ),
+            ok("success")
+        );
+        assert_eq!(
+            send(addr, "select * from server_test;\n"),
+            ok("column_int | column_text\n1 | O'Brien\n2 | it's a=b\ntotal: 2")
+        );
+
+        let (status, _) = send(
+            addr,
+            "insert into server_test ( column_int=3 column_text='it's' );\n",
+        );
+        assert_eq!(status, "400 Bad Request");
+
+        shutdown(addr);
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn server_config() {
+        let temp_dir = temp_dir("server_config");
+        let catalog: &'static Catalog = Box::leak(Box::new(Catalog::from_json(JSON).unwrap()));
+        let config = Config {
+            data_dir: temp_dir.to_str().unwrap().to_string(),
+            pool_size: 3,
+            server: ServerOptions {
+                auth_token: Some("secret".to_string()),
+                auth_health: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut manager =
+            BufferPoolManager::new(config.pool_size, config.data_dir.clone(), catalog.clone());
+        manager.set_durability(Durability::Buffered);
+        let server: &'static Server = Box::leak(Box::new(Server::with_config(
+            catalog,
+            Executor::new(manager),
+            config.clone(),
+        )));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || server.run(listener));
+
+        // config.serverのtokenが使われる
+        let (status, _) = send_raw(addr, &[b"GET /config HTTP/1.1\r\n\r\n"]);
+        assert_eq!(status, "401 Unauthorized");
+        let (status, body) = send_raw(
+            addr,
+            &[b"GET /config HTTP/1.1\r\nAuthorization: Bearer secret\r\n\r\n"],
+        );
+        assert_eq!(status, "200 OK");
+        let reported: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(reported["pool_size"], 3);
+        assert_eq!(reported["data_dir"], config.data_dir.as_str());
+        assert_eq!(reported["schema"], "schema.json");
+        assert_eq!(reported["replacer"], "lru");
+        assert_eq!(reported["page_size"], PAGE_SIZE);
+        assert_eq!(reported["durability"], "buffered");
+        assert_eq!(reported["max_rows"], DEFAULT_MAX_ROWS);
+        assert_eq!(reported["query_timeout_ms"], 5000);
+        // tokenは有無だけを出し、tokenそのものは出さない
+        assert_eq!(reported["auth_token"], true);
+        assert!(!body.contains("secret"));
+
+        let response = send_raw(
+            addr,
+            &[b"POST /admin/shutdown HTTP/1.1\r\nAuthorization: Bearer secret\r\n\r\n"],
+        );
+        assert_eq!(response, ok("shutting down"));
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn server_csv_format() {
+        let (_, addr, handle) = start("server_csv_format", ServerOptions::default());
+
+        let csv = |path: &str, accept: &str, body: &str| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let request = format!(
+                "POST {} HTTP/1.1\r\n{}Content-Length: {}\r\n\r\n{}",
+                path,
+                accept,
+                body.len(),
+                body
+            );
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            let (head, raw) = response.split_once("\r\n\r\n").unwrap();
+            (head.to_string(), raw.to_string())
+        };
+
+        // 書き込みはCSVを求められてもtextで結果を返す
+        let (head, raw) = csv(
+            "/",
+            "Accept: text/csv\r\n",
+            "insert into server_test ( column_int=1 column_text='a,\"b\"' );",
+        );
+        assert!(
+            head.contains("Content-Type: text/plain; charset=utf-8"),
+            "{}",
+            head
+        );
+        assert_eq!(raw, "success");
+        assert_eq!(
+            send(
+                addr,
+                "insert into server_test ( column_int=2 column_text='x' );"
+            ),
+            ok("success")
+        );
+
+        let select = "select * from server_test;";
+        let (head, raw) = csv("/", "Accept: text/csv\r\n", select);
+        assert!(
+            head.contains("Content-Type: text/csv; charset=utf-8"),
+            "{}",
+            head
+        );
+        let (body, trailers) = decode_chunked(&raw);
+        assert_eq!(body, "column_int,column_text\n1,\"a,\"\"b\"\"\"\n2,x\n");
+        assert_eq!(trailers, vec!["X-Row-Count: 2".to_string()]);
+
+        // ?format=はAcceptより優先する
+        let (head, raw) = csv("/?format=csv", "Accept: text/plain\r\n", select);
+        assert!(
+            head.contains("Content-Type: text/csv; charset=utf-8"),
+            "{}",
+            head
+        );
+        assert_eq!(decode_chunked(&raw).0, body);
+        let (head, _) = csv("/?format=text", "Accept: text/csv\r\n", select);
+        assert!(
+            head.contains("Content-Type: text/plain; charset=utf-8"),
+            "{}",
+            head
+        );
+
+        let (head, raw) = csv("/?format=xml", "", select);
+        assert!(head.starts_with("HTTP/1.1 400 Bad Request"), "{}", head);
+        assert_eq!(raw, "unknown format xml (expected text or csv)");
+
+        shutdown(addr);
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn server_streamed_select() {
+        let (_, addr, handle) = start("server_streamed_select", ServerOptions::default());
+
+        let rows = STREAM_CHUNK_ROWS * 2 + 10;
+        {
+            let stream = TcpStream::connect(addr).unwrap();
+            let mut reader = BufReader::new(&stream);
+            let mut send = |query: &str| send_keep_alive(&stream, &mut reader, query);
+            assert_eq!(send("begin;\n"), ok("begin"));
+            for i in 0..rows {
+                let query = format!(
+                    "insert into server_test ( column_int={} column_text='row' );\n",
+                    i
+                );
+                assert_eq!(send(&query), ok("success"));
+            }
+            assert_eq!(send("commit;\n"), ok("commit"));
+        }
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"POST / HTTP/1.1\r\nContent-Length: 26\r\n\r\nselect * from server_test;")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        let (head, raw) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.contains("\r\nTransfer-Encoding: chunked"), "{}", head);
+        let (body, trailers) = decode_chunked(raw);
+        // 見出しの行を含めて、STREAM_CHUNK_ROWS行で最初のchunkになる
+        let first: usize = body
+            .split_inclusive('\n')
+            .take(STREAM_CHUNK_ROWS)
+            .map(str::len)
+            .sum();
+        assert!(raw.starts_with(&format!("{:x}\r\n", first)), "{}", raw);
+        assert_eq!(body.lines().count(), rows + 2);
+        assert!(body.starts_with("column_int | column_text\n0 | row\n"));
+        assert!(body.ends_with(&format!("{} | row\ntotal: {}", rows - 1, rows)));
+        assert_eq!(trailers, vec![format!("X-Row-Count: {}", rows)]);
+
+        shutdown(addr);
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn server_max_rows() {
+        let options = ServerOptions {
+            max_rows: Some(2),
+            ..Default::default()
+        };
+        let (_, addr, handle) = start("server_max_rows", options);
+
+        for i in 0..3 {
+            let query = format!(
+                "insert into server_test ( column_int={} column_text='row' );\n",
+                i
+            );
+            assert_eq!(send(addr, &query), ok("success"));
+        }
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"POST / HTTP/1.1\r\nContent-Length: 26\r\n\r\nselect * from server_test;")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        // 打ち切ったことは本文の最後とtrailerの両方で分かる
+        let (_, raw) = response.split_once("\r\n\r\n").unwrap();
+        let (body, trailers) = decode_chunked(raw);
+        assert_eq!(
+            body,
+            "column_int | column_text\n0 | row\n1 | row\nresult truncated at 2 rows\ntotal: 2"
+        );
+        assert_eq!(
+            trailers,
+            vec![
+                format!("{}: 2", ROW_COUNT_TRAILER),
+                format!("{}: true", ROW_TRUNCATED_TRAILER),
+            ]
+        );
+
+        shutdown(addr);
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn server_line_protocol() {
+        let options = ServerOptions {
+            protocol: Protocol::Line,
+            auth_token: Some("secret".to_string()),
+            max_rows: Some(2),
+            ..Default::default()
+        };
+        let (server, addr, handle) = start("server_line_protocol", options);
+
+        // tokenを送らなければ断られる
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut reader = BufReader::new(&stream);
+        (&stream).write_all(b"show tables;\n").unwrap();
+        assert_eq!(
+            line::read_reply(&mut reader).unwrap(),
+            line::Reply::Err("send auth <token>; first".to_string())
+        );
+
+        // 1つの接続でstatementを続けて送れる。statementは複数行に分けてもよい
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut reader = BufReader::new(&stream);
+        let mut send = |statement: &str| {
+            (&stream).write_all(statement.as_bytes()).unwrap();
+            line::read_reply(&mut reader).unwrap()
+        };
+        let ok = |lines: &[&str]| line::Reply::Ok(lines.iter().map(|l| l.to_string()).collect());
+
+        assert_eq!(send("auth secret;\n"), ok(&[]));
+        assert_eq!(send("show tables;\n"), ok(&["server_test"]));
+        assert_eq!(
+            send("insert into server_test\n( column_int=1 column_text='a\tb' );\n"),
+            ok(&[])
+        );
+        assert_eq!(
+            send("insert into server_test ( column_int=2 column_text='' );\n"),
+            ok(&[])
+        );
+        assert_eq!(
+            send("select * from server_test;\n"),
+            ok(&["column_int\tcolumn_text", "1\ta\\tb", "2\t"])
+        );
+        assert_eq!(
+            send("select * from nothing;\n"),
+            line::Reply::Err("nothing not exist".to_string())
+        );
+
+        assert_eq!(
+            send("insert into server_test ( column_int=3 column_text='c' );\n"),
+            ok(&[])
+        );
+        assert_eq!(
+            send("select * from server_test;\n"),
+            ok(&[
+                "column_int\tcolumn_text",
+                "1\ta\\tb",
+                "2\t",
+                "result truncated at 2 rows"
+            ])
+        );
+
+        // 途中で切れた接続のtransactionは戻される
+        {
+            let stream = TcpStream::connect(addr).unwrap();
+            let mut reader = BufReader::new(&stream);
+            let mut send = |statement: &str| {
+                (&stream).write_all(statement.as_bytes()).unwrap();
+                line::read_reply(&mut reader).unwrap()
+            };
+            assert_eq!(send("auth secret;\n"), ok(&[]));
+            assert_eq!(send("begin;\n"), ok(&[]));
+            assert_eq!(
+                send("insert into server_test ( column_int=4 column_text='d' );\n"),
+                ok(&[])
+            );
+        }
+        while server.connections.load(Ordering::SeqCst) > 1 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(
+            send("select * from server_test;\n"),
+            ok(&[
+                "column_int\tcolumn_text",
+                "1\ta\\tb",
+                "2\t",
+                "result truncated at 2 rows"
+            ])
+        );
+        let mut records = Vec::new();
+        let mut database = server.database.lock().unwrap();
+        database.executor.scan("server_test", &mut records).unwrap();
+        assert_eq!(records.len(), 3);
+        drop(database);
+
+        server.shutdown_handle(addr).trigger().unwrap();
+        handle.join().unwrap().unwrap();
+    }
+
+    // GET /wsを送り、応答のstatus行とheaderを返す
+    fn websocket_handshake(stream: &TcpStream, headers: &str) -> Vec<String> {
+        let request = format!(
+            "GET /ws HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n{}\r\n",
+            headers
+        );
+        (&mut &*stream).write_all(request.as_bytes()).unwrap();
+        BufReader::new(stream)
+            .lines()
+            .map(|l| l.unwrap())
+            .take_while(|l| !l.is_empty())
+            .collect()
+    }
+
+    fn websocket_send(stream: &TcpStream, text: &str) -> serde_json::Value {
+        let frame = websocket::masked_frame(true, websocket::OPCODE_TEXT, text.as_bytes());
+        (&mut &*stream).write_all(&frame).unwrap();
+        let (opcode, payload) = websocket::read_server_frame(&mut &*stream);
+        assert_eq!(opcode, websocket::OPCODE_TEXT);
+        serde_json::from_slice(&payload).unwrap()
+    }
+
+    #[test]
+    fn server_websocket() {
+        let options = ServerOptions {
+            auth_token: Some("secret".to_string()),
+            max_body_bytes: 100,
+            ..Default::default()
+        };
+        let (server, addr, handle) = start("server_websocket", options);
+
+        // 誤ったtokenでは切り替えない
+        let stream = TcpStream::connect(addr).unwrap();
+        let response = websocket_handshake(&stream, "Authorization: Bearer wrong\r\n");
+        assert_eq!(response[0], "HTTP/1.1 401 Unauthorized");
+
+        // tokenがなければ、最初のmessageで送る
+        let stream = TcpStream::connect(addr).unwrap();
+        let response = websocket_handshake(&stream, "");
+        assert_eq!(response[0], "HTTP/1.1 101 Switching Protocols");
+        assert!(
+            response.contains(&"Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=".to_string())
+        );
+        let frame = websocket::masked_frame(true, websocket::OPCODE_TEXT, b"show tables;");
+        (&stream).write_all(&frame).unwrap();
+        let (opcode, payload) = websocket::read_server_frame(&mut &stream);
+        assert_eq!(opcode, websocket::OPCODE_CLOSE);
+        assert_eq!(
+            payload[..2],
+            websocket::CLOSE_POLICY_VIOLATION.to_be_bytes()
+        );
+
+        let stream = TcpStream::connect(addr).unwrap();
+        websocket_handshake(&stream, "");
+        assert_eq!(websocket_send(&stream, "auth secret;")["result"], "ok");
+        assert_eq!(
+            websocket_send(
+                &stream,
+                "insert into server_test ( column_int=1 column_text='a' );"
+            )["kind"],
+            "insert"
+        );
+        let reply = websocket_send(&stream, "select * from server_test;");
+        assert_eq!(
+            reply,
+            serde_json::json!({
+                "kind": "select",
+                "columns": ["column_int", "column_text"],
+                "rows": [["1", "a"]],
+                "total": 1,
+                "truncated": false,
+            })
+        );
+        let reply = websocket_send(&stream, "select * from nothing;");
+        assert_eq!(reply["status"], 404);
+        assert_eq!(reply["error"], "nothing not exist");
+
+        // pingにはpongを返す
+        let frame = websocket::masked_frame(true, websocket::OPCODE_PING, b"hi");
+        (&stream).write_all(&frame).unwrap();
+        assert_eq!(
+            websocket::read_server_frame(&mut &stream),
+            (websocket::OPCODE_PONG, b"hi".to_vec())
+        );
+
+        // closeで閉じると、開いたままのtransactionは戻される
+        {
+            let stream = TcpStream::connect(addr).unwrap();
+            websocket_handshake(&stream, "Authorization: Bearer secret\r\n");
+            assert_eq!(websocket_send(&stream, "begin;")["kind"], "begin");
+            websocket_send(
+                &stream,
+                "insert into server_test ( column_int=2 column_text='b' );",
+            );
+            let close = websocket::masked_frame(
+                true,
+                websocket::OPCODE_CLOSE,
+                &websocket::CLOSE_NORMAL.to_be_bytes(),
+            );
+            (&stream).write_all(&close).unwrap();
+            let (opcode, _) = websocket::read_server_frame(&mut &stream);
+            assert_eq!(opcode, websocket::OPCODE_CLOSE);
+        }
+        while server.connections.load(Ordering::SeqCst) > 1 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(
+            websocket_send(&stream, "select * from server_test;")["total"],
+            1
+        );
+
+        // max_body_bytesを超えるmessageは受け取らない
+        let long = format!("select * from server_test; -- {}", "a".repeat(100));
+        let frame = websocket::masked_frame(true, websocket::OPCODE_TEXT, long.as_bytes());
+        (&stream).write_all(&frame).unwrap();
+        let (opcode, payload) = websocket::read_server_frame(&mut &stream);
+        assert_eq!(opcode, websocket::OPCODE_CLOSE);
+        assert_eq!(payload[..2], websocket::CLOSE_TOO_BIG.to_be_bytes());
+
+        // 止めるときは、開いている接続にclose frameを送る
+        let stream = TcpStream::connect(addr).unwrap();
+        websocket_handshake(&stream, "Authorization: Bearer secret\r\n");
+        let response = send_raw(
+            addr,
+            &[b"POST /admin/shutdown HTTP/1.1\r\nAuthorization: Bearer secret\r\n\r\n"],
+        );
+        assert_eq!(response, ok("shutting down"));
+        let (opcode, payload) = websocket::read_server_frame(&mut &stream);
+        assert_eq!(opcode, websocket::OPCODE_CLOSE);
+        assert_eq!(payload[..2], websocket::CLOSE_GOING_AWAY.to_be_bytes());
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn server_postgres_protocol() {
+        let options = ServerOptions {
+            protocol: Protocol::Postgres,
+            ..Default::default()
+        };
+        let (server, addr, handle) = start("server_postgres_protocol", options);
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut reader = BufReader::new(&stream);
+        let mut read = || pgwire::read_message(&mut reader, 4096).unwrap().unwrap();
+        let message = |tag: u8, body: &[u8]| {
+            let mut m = vec![tag];
+            m.extend_from_slice(&(body.len() as u32 + 4).to_be_bytes());
+            m.extend_from_slice(body);
+            m
+        };
+
+        // SSLは断られ、平文のstartupを続けて送る
+        (&stream).write_all(&[0, 0, 0, 8, 4, 210, 22, 47]).unwrap();
+        let mut refused = [0_u8; 1];
+        (&stream).read_exact(&mut refused).unwrap();
+        assert_eq!(&refused, b"N");
+
+        let params = b"user\0aqua\0\0";
+        let mut startup = (8 + params.len() as u32).to_be_bytes().to_vec();
+        startup.extend_from_slice(&pgwire::PROTOCOL_VERSION.to_be_bytes());
+        startup.extend_from_slice(params);
+        (&stream).write_all(&startup).unwrap();
+        assert_eq!(read(), (b'R', vec![0, 0, 0, 0]));
+        let mut tags = Vec::new();
+        loop {
+            let (tag, body) = read();
+            tags.push(tag);
+            if tag == b'Z' {
+                assert_eq!(body, b"I");
+                break;
+            }
+        }
+        assert!(tags.iter().all(|&t| t == b'S' || t == b'Z'));
+
+        let query = |q: &str| message(b'Q', format!("{}\0", q).as_bytes());
+        (&stream)
+            .write_all(&query(
+                "insert into server_test ( column_int=1 column_text='a' ); select * from server_test",
+            ))
+            .unwrap();
+        assert_eq!(read(), (b'C', b"INSERT 0 1\0".to_vec()));
+
+        let (tag, body) = read();
+        assert_eq!(tag, b'T');
+        assert_eq!(&body[..2], &[0, 2]);
+        let oid = |name: &str| {
+            let start = body
+                .windows(name.len())
+                .position(|w| w == name.as_bytes())
+                .unwrap()
+                + name.len()
+                + 7;
+            u32::from_be_bytes(body[start..start + 4].try_into().unwrap())
+        };
+        assert_eq!(oid("column_int"), pgwire::INT4_OID);
+        assert_eq!(oid("column_text"), pgwire::TEXT_OID);
+        assert_eq!(
+            read(),
+            (b'D', vec![0, 2, 0, 0, 0, 1, b'1', 0, 0, 0, 1, b'a'])
+        );
+        assert_eq!(read(), (b'C', b"SELECT 1\0".to_vec()));
+        assert_eq!(read(), (b'Z', b"I".to_vec()));
+
+        // 失敗したら残りのstatementは実行しない
+        (&stream)
+            .write_all(&query("select * from nothing; begin;"))
+            .unwrap();
+        let (tag, body) = read();
+        assert_eq!(tag, b'E');
+        assert!(String::from_utf8_lossy(&body).contains(pgwire::UNDEFINED_TABLE));
+        assert_eq!(read(), (b'Z', b"I".to_vec()));
+
+        // extended queryは断り、Syncまで読み飛ばす
+        (&stream)
+            .write_all(&message(b'P', b"\0select\0\0\0"))
+            .unwrap();
+        (&stream)
+            .write_all(&message(b'B', b"\0\0\0\0\0\0\0"))
+            .unwrap();
+        (&stream).write_all(&message(b'S', b"")).unwrap();
+        let (tag, body) = read();
+        assert_eq!(tag, b'E');
+        assert!(String::from_utf8_lossy(&body).contains(pgwire::FEATURE_NOT_SUPPORTED));
+        assert_eq!(read(), (b'Z', b"I".to_vec()));
+
+        (&stream).write_all(&query("")).unwrap();
+        assert_eq!(read(), (b'I', vec![]));
+        assert_eq!(read(), (b'Z', b"I".to_vec()));
+
+        // exitはもうstatementではない
+        (&stream).write_all(&query("exit;")).unwrap();
+        assert_eq!(read().0, b'E');
+        assert_eq!(read(), (b'Z', b"I".to_vec()));
+
+        server.shutdown_handle(addr).trigger().unwrap();
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn server_max_body_bytes() {
+        let options = ServerOptions {
+            max_body_bytes: 16,
+            ..Default::default()
+        };
+        let (_, addr, handle) = start("server_max_body_bytes", options);
+
+        let (status, body) = send(addr, "select * from server_test;\n");
+        assert_eq!(status, "413 Payload Too Large");
+        assert_eq!(body, "request body of 27 bytes exceeds 16 bytes");
+
+        shutdown(addr);
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn server_init_file() {
+        let temp_dir = temp_dir("server_init_file");
+        let catalog: &'static Catalog = Box::leak(Box::new(Catalog::from_json(JSON).unwrap()));
+        let manager =
+            BufferPoolManager::new(4, temp_dir.to_str().unwrap().to_string(), catalog.clone());
+        let server: &'static Server = Box::leak(Box::new(Server::new(
+            catalog,
+            Executor::new(manager),
+            ServerOptions::default(),
+        )));
+
+        let path = temp_dir.join("init.sql");
+        std::fs::write(
+            &path,
+            "-- seed\ninsert into server_test ( column_int=1 column_text='a' );\ninsert into nothing ( column_int=2 );\nbegin;\ninsert into server_test\n  ( column_int=2 column_text='b' );\ncommit;\nbegin;\n",
+        )
+        .unwrap();
+        server.run_init_file(path.to_str().unwrap()).unwrap();
+        assert!(server.run_init_file("no_such_file.sql").is_err());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || server.run(listener));
+
+        // 失敗した行は飛ばされ、最後に開いたtransactionは戻されている
+        assert_eq!(
+            send(addr, "select * from server_test;\n"),
+            ok("column_int | column_text\n1 | a\n2 | b\ntotal: 2")
+        );
+        assert_eq!(send(addr, "begin;\n"), ok("begin"));
+
+        shutdown(addr);
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn server_auth_token() {
+        let options = ServerOptions {
+            auth_token: Some("secret".to_string()),
+            ..Default::default()
+        };
+        let (_, addr, handle) = start("server_auth_token", options);
+
+        let (status, body) = send(addr, "select * from server_test;\n");
+        assert_eq!(status, "401 Unauthorized");
+        assert_eq!(body, "missing or invalid bearer token");
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"POST / HTTP/1.1\r\nAuthorization: Bearer wrong\r\nContent-Length: 7\r\n\r\ncommit;")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 401 Unauthorized\r\n"),
+            "{}",
+            response
+        );
+        assert!(
+            response.contains("\r\nWWW-Authenticate: Bearer\r\n"),
+            "{}",
+            response
+        );
+
+        // bodyが届くのを待たずに、headerだけで断る
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        stream
+            .write_all(b"POST / HTTP/1.1\r\nContent-Length: 1000\r\n\r\nselect")
+            .unwrap();
+        let mut head = [0_u8; 30];
+        stream.read_exact(&mut head).unwrap();
+        assert_eq!(&head, b"HTTP/1.1 401 Unauthorized\r\nCon");
+
+        let response = send_raw(
+            addr,
+            &[b"POST / HTTP/1.1\r\nAuthorization: Bearer secret\r\nContent-Length: 26\r\n\r\nselect * from server_test;"],
+        );
+        assert_eq!(response, ok("column_int | column_text\ntotal: 0"));
+
+        // /healthは既定ではtokenなしで見られる
+        let (status, _) = send_raw(addr, &[b"GET /health HTTP/1.1\r\n\r\n"]);
+        assert_eq!(status, "200 OK");
+        let (status, _) = send_raw(addr, &[b"GET /metrics HTTP/1.1\r\n\r\n"]);
+        assert_eq!(status, "401 Unauthorized");
+
+        let (status, _) = send_raw(addr, &[b"POST /admin/shutdown HTTP/1.1\r\n\r\n"]);
+        assert_eq!(status, "401 Unauthorized");
+        let response = send_raw(
+            addr,
+            &[b"POST /admin/shutdown HTTP/1.1\r\nAuthorization: Bearer secret\r\n\r\n"],
+        );
+        assert_eq!(response, ok("shutting down"));
+        handle.join().unwrap().unwrap();
+
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+    }
+
+    // 出力先に行を渡している間は、databaseのlockを持っていない
+    struct LockProbe<'d> {
+        database: &'d Mutex<Database>,
+        rows: usize,
+    }
+
+    impl RowWriter for LockProbe<'_> {
+        fn row(&mut self, _cells: &[String]) -> Result<(), anyhow::Error> {
+            assert!(self.database.try_lock().is_ok());
+            self.rows += 1;
+            Ok(())
+        }
+
+        fn end(&mut self, _rows: usize, _truncated: bool) -> Result<(), anyhow::Error> {
+            assert!(self.database.try_lock().is_ok());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn server_select_releases_lock() {
+        let temp_dir = temp_dir("server_select_releases_lock");
+        let catalog = Catalog::from_json(JSON).unwrap();
+        let manager =
+            BufferPoolManager::new(4, temp_dir.to_str().unwrap().to_string(), catalog.clone());
+        let server = Server::new(&catalog, Executor::new(manager), ServerOptions::default());
+        let mut session = server.session();
+
+        for i in 0..3 {
+            let insert = format!(
+                "insert into server_test ( column_int={} column_text='a' );",
+                i
+            );
+            let mut statement = Statement::default();
+            server
+                .run_query(&mut session, &insert, &mut statement, None)
+                .unwrap();
+        }
+
+        let mut probe = LockProbe {
+            database: &server.database,
+            rows: 0,
+        };
+        let mut statement = Statement::default();
+        server
+            .run_query(
+                &mut session,
+                "select * from server_test;",
+                &mut statement,
+                Some(&mut probe),
+            )
+            .unwrap();
+        // 1行目は列名
+        assert_eq!(probe.rows, 4);
+        assert_eq!(statement.rows, Some(3));
+    }
+}
@@ -6,7 +6,9 @@ mod descriptors;
 pub mod disk_manager;
 mod hash_table;
 pub mod page;
+pub mod recovery;
 pub mod replacer;
 pub mod tuple;
+pub mod wal;
 
 pub type StorageResult<T> = result::Result<T, anyhow::Error>;
@@ -37,9 +37,10 @@ impl BufferPool {
         Arc::clone(&self.cache[id.value()])
     }
 
+    // 既存のArcを差し替えると古いArcを持っている側が更新を見られないので、中身のpageだけ入れ替える
     pub fn put(&mut self, id: BufferPoolID, page: Page) {
-        let buffer = Buffer::new(id, page);
-        self.cache[id.value()] = Arc::new(RwLock::new(buffer));
+        let mut buffer = self.cache[id.value()].write().unwrap();
+        buffer.page = page;
     }
 }
 
@@ -81,4 +82,25 @@ mod tests {
 
         assert_eq!(buffer.page.id, page_id);
     }
+
+    #[test]
+    fn buffer_pool_put_keeps_arc() {
+        let mut pool = BufferPool::new(1);
+        let id = BufferPoolID(0);
+
+        let old_locked = pool.get(id);
+
+        let page_id = PageID(7);
+        let page = Page {
+            id: page_id,
+            ..Default::default()
+        };
+
+        pool.put(id, page);
+
+        let buffer = old_locked.read().unwrap();
+
+        assert_eq!(buffer.page.id, page_id);
+        assert_eq!(buffer.id, id);
+    }
 }
@@ -1,16 +1,21 @@
-use std::sync::{Arc, RwLock};
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
 
 use anyhow::{anyhow, Ok};
 
-use crate::catalog::Catalog;
+use crate::catalog::{Catalog, Schema};
 
 use super::{
     buffer_pool::{Buffer, BufferPool, BufferPoolID},
     descriptors::{DescriptorID, Descriptors},
-    disk_manager::DiskManager,
+    disk_manager::{DiskManager, Opener},
     hash_table,
     page::*,
     replacer::{LruReplacer, Replacer},
+    tuple::Tuple,
+    wal::{Durability, Lsn, TxnID, Wal, WalOperation},
     StorageResult,
 };
 
@@ -29,6 +34,22 @@ impl Key {
     }
 }
 
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferPoolStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub writes: u64,
+}
+
+// 起動後に変わらない、buffer poolの設定
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PoolSettings {
+    pub pool_size: usize,
+    pub replacer: &'static str,
+    pub durability: Durability,
+}
+
 pub struct BufferPoolManager<R>
 where
     R: Replacer,
@@ -38,12 +59,19 @@ where
     buffer_pool: BufferPool,
     page_table: hash_table::HashTable<Key, DescriptorID>,
     descriptors: Descriptors,
+    wal: Wal,
+    stats: BufferPoolStats,
+    // last_page_idでtableのfileを開き、長さを調べた回数
+    last_page_lookups: u64,
+    write_batching: bool,
+    durability: Durability,
 }
 
 impl BufferPoolManager<LruReplacer> {
     pub fn new(pool_size: usize, base_path: String, catalog: Catalog) -> Self {
         let mut replacer = LruReplacer::new(pool_size);
         let disk_manager = DiskManager::new(base_path, catalog);
+        let wal = Wal::new(disk_manager.wal_path());
         let buffer_pool = BufferPool::new(pool_size);
         let page_table = hash_table::HashTable::new(pool_size);
         let descriptors = Descriptors::new(pool_size);
@@ -59,6 +87,11 @@ impl BufferPoolManager<LruReplacer> {
             buffer_pool,
             page_table,
             descriptors,
+            wal,
+            stats: BufferPoolStats::default(),
+            last_page_lookups: 0,
+            write_batching: false,
+            durability: Durability::Sync,
         }
     }
 }
@@ -67,15 +100,27 @@ impl<R: Replacer> BufferPoolManager<R> {
     fn victim_descriptor(
         &mut self,
         descriptor_id: DescriptorID,
-        table_name: &str,
     ) -> StorageResult<Arc<RwLock<Buffer>>> {
         let descriptor_locker = self.descriptors.get(descriptor_id);
         let mut descriptor = descriptor_locker.write().unwrap();
+
         let buffer_locker = self.buffer_pool.get(descriptor.buffer_pool_id);
 
         if descriptor.dirty {
             let page = &buffer_locker.write().unwrap().page;
-            self.disk_manager.write(page, table_name)?;
+            if self.write_batching {
+                self.write_back_run(page)?;
+            } else {
+                // write-ahead: pageを書く前に、そのpageに反映済みのlogを永続化する
+                self.wal.flush_to(page.header.lsn)?;
+                self.disk_manager.write(page, &page.table_name)?;
+                self.stats.writes += 1;
+            }
+        }
+
+        // 一度もpageを載せていないbufferはevictionに数えない
+        if !buffer_locker.read().unwrap().page.table_name.is_empty() {
+            self.stats.evictions += 1;
         }
 
         descriptor.reset();
@@ -84,37 +129,115 @@ impl<R: Replacer> BufferPoolManager<R> {
         Ok(buffer_locker)
     }
 
-    fn load_page_to_buffer_pool(
+    // 追い出すpageの前後に続く、同じtableのdirtyなpageも1回のwriteで書き出す
+    // 書いた前後のpageはpoolに残したまま、dirtyだけを外す
+    // 他でlockされているbufferは待たずに、そこで区切る
+    fn write_back_run(&mut self, victim: &Page) -> StorageResult<()> {
+        let table_name = victim.table_name.clone();
+        let dirty_neighbor = |id: usize| {
+            let key = Key::new(PageID(id), table_name.clone());
+            let descriptor_id = self
+                .page_table
+                .get_bucket_locker(&key)?
+                .read()
+                .unwrap()
+                .get(key)?;
+            let descriptor = self.descriptors.get(descriptor_id);
+            let buffer = {
+                let d = descriptor.try_read().ok()?;
+                if !d.dirty {
+                    return None;
+                }
+                self.buffer_pool.get(d.buffer_pool_id)
+            };
+            Some((descriptor, buffer))
+        };
+
+        let mut before = Vec::new();
+        for id in (0..victim.id.0).rev() {
+            match dirty_neighbor(id) {
+                Some(n) => before.push(n),
+                None => break,
+            }
+        }
+        let mut after = Vec::new();
+        for id in (victim.id.0 + 1).. {
+            match dirty_neighbor(id) {
+                Some(n) => after.push(n),
+                None => break,
+            }
+        }
+
+        let before_guards: Vec<_> = before
+            .iter()
+            .map_while(|(_, b)| b.try_read().ok())
+            .collect();
+        let after_guards: Vec<_> = after.iter().map_while(|(_, b)| b.try_read().ok()).collect();
+        let pages: Vec<&Page> = before_guards
+            .iter()
+            .rev()
+            .map(|g| &g.page)
+            .chain([victim])
+            .chain(after_guards.iter().map(|g| &g.page))
+            .collect();
+
+        // write-ahead: まとめて書くpageのうち、最も新しいlsnまでを永続化する
+        if let Some(lsn) = pages.iter().map(|p| p.header.lsn).max() {
+            self.wal.flush_to(lsn)?;
+        }
+        self.disk_manager.write_batch(&pages, &table_name)?;
+        self.stats.writes += pages.len() as u64;
+
+        let written = before
+            .iter()
+            .take(before_guards.len())
+            .chain(after.iter().take(after_guards.len()));
+        for (descriptor, _) in written {
+            descriptor.write().unwrap().dirty = false;
+        }
+
+        Ok(())
+    }
+
+    // replacerとpin countが食い違っていても、使用中のpageは追い出さない
+    // pinされたdescriptorはreplacerから外すだけにして次の候補を見る。unpinされれば戻ってくる
+    fn pick_victim(&mut self) -> StorageResult<DescriptorID> {
+        while let Some(descriptor_id) = self.replacer.victim() {
+            if !self.descriptors.get(descriptor_id).read().unwrap().pinned() {
+                return Ok(descriptor_id);
+            }
+        }
+
+        Err(anyhow!("buffer pool exhausted: all buffers are pinned"))
+    }
+
+    fn load_page_from_storage_to_buffer_pool(
         &mut self,
         p_id: PageID,
-        buffer_pool_id: BufferPoolID,
         table_name: &str,
     ) -> StorageResult<Arc<RwLock<Buffer>>> {
+        let buffer_pool_id = self.claim_buffer(p_id, table_name)?;
         let page = self.disk_manager.read(p_id, table_name)?;
         self.buffer_pool.put(buffer_pool_id, page);
         Ok(self.buffer_pool.get(buffer_pool_id))
     }
 
-    fn load_page_from_storage_to_buffer_pool(
-        &mut self,
-        p_id: PageID,
-        table_name: &str,
-    ) -> StorageResult<Arc<RwLock<Buffer>>> {
-        let victim_descriptor_id = self
-            .replacer
-            .victim()
-            .ok_or_else(|| anyhow!("not found victim descriptor id"))?;
+    // victimを追い出し、そのbufferをp_idのpageに割り当てる。pageを載せるのは呼び出し側
+    fn claim_buffer(&mut self, p_id: PageID, table_name: &str) -> StorageResult<BufferPoolID> {
+        let victim_descriptor_id = self.pick_victim()?;
 
-        let buffer_locker = self.victim_descriptor(victim_descriptor_id, table_name)?;
-        let (victim_page_id, buffer_pool_id) = {
+        let buffer_locker = self.victim_descriptor(victim_descriptor_id)?;
+        let (victim_page_id, victim_table_name, buffer_pool_id) = {
             let buffer = buffer_locker.read().unwrap();
-            (buffer.page.id, buffer.id)
+            (buffer.page.id, buffer.page.table_name.clone(), buffer.id)
         };
 
-        let victim_key = Key::new(victim_page_id, table_name.to_string());
+        // victimのkeyは追い出されるpage自身のtable名で作る
+        // 一度も使われていないbufferのpageはtable名が空なので、page tableには何も残っていない
+        let victim_key = Key::new(victim_page_id, victim_table_name);
         let target_key = Key::new(p_id, table_name.to_string());
 
-        let buffer_locker = if self.page_table.same_bucket(&victim_key, &target_key) {
+        if self.page_table.same_bucket(&victim_key, &target_key) {
             let bucket_locker = self
                 .page_table
                 .get_bucket_locker(&victim_key)
@@ -124,8 +247,6 @@ impl<R: Replacer> BufferPoolManager<R> {
 
             bucket.remove(victim_key);
             bucket.put(target_key, victim_descriptor_id);
-
-            self.load_page_to_buffer_pool(p_id, buffer_pool_id, table_name)?
         } else {
             let old_bucket_locker = self
                 .page_table
@@ -143,11 +264,9 @@ impl<R: Replacer> BufferPoolManager<R> {
 
             old_bucket.remove(victim_key);
             new_bucket.put(target_key, victim_descriptor_id);
+        }
 
-            self.load_page_to_buffer_pool(p_id, buffer_pool_id, table_name)?
-        };
-
-        Ok(buffer_locker)
+        Ok(buffer_pool_id)
     }
 
     pub fn mark_dirty(&mut self, buffer_pool_id: BufferPoolID) -> StorageResult<()> {
@@ -160,8 +279,11 @@ impl<R: Replacer> BufferPoolManager<R> {
     }
 
     pub fn new_buffer(&mut self, table_name: &str) -> StorageResult<Arc<RwLock<Buffer>>> {
+        // 書いたばかりの空のpageは、diskから読み直さずにそのまま載せる
         let new_page = self.disk_manager.allocate_page(table_name)?;
-        self.load_page_from_storage_to_buffer_pool(new_page.id, table_name)
+        let buffer_pool_id = self.claim_buffer(new_page.id, table_name)?;
+        self.buffer_pool.put(buffer_pool_id, new_page);
+        Ok(self.buffer_pool.get(buffer_pool_id))
     }
 
     pub fn fetch_buffer(
@@ -179,9 +301,11 @@ impl<R: Replacer> BufferPoolManager<R> {
             let descriptor_arc = self.descriptors.get(d_id);
             let mut descriptor = descriptor_arc.write().unwrap();
             descriptor.pin();
+            self.stats.hits += 1;
             return Ok(self.buffer_pool.get(descriptor.buffer_pool_id));
         };
 
+        self.stats.misses += 1;
         self.load_page_from_storage_to_buffer_pool(p_id, table_name)
     }
 
@@ -192,18 +316,55 @@ impl<R: Replacer> BufferPoolManager<R> {
             .get_bucket_locker(&key)
             .ok_or_else(|| anyhow!("cant get bucket"))?;
 
-        if let Some(descriptor_id) = bucket_locker.read().unwrap().get(key) {
-            let descriptor_arc = self.descriptors.get(descriptor_id);
-            let mut descriptor = descriptor_arc.write().unwrap();
-            descriptor.unpin();
-            if !descriptor.pinned() {
-                self.replacer.unpin(descriptor_id);
+        let descriptor_id = bucket_locker.read().unwrap().get(key);
+        if let Some(descriptor_id) = descriptor_id {
+            self.unpin_descriptor(descriptor_id);
+        }
+
+        Ok(())
+    }
+
+    // unpin_bufferを順に呼ぶのと同じ結果になる
+    // bucketのlockはbucketごとに1回だけ取り、replacerには渡した順で戻す
+    pub fn unpin_buffers(&mut self, pages: &[(PageID, &str)]) -> StorageResult<()> {
+        let key = |i: usize| Key::new(pages[i].0, pages[i].1.to_string());
+
+        let mut by_bucket: HashMap<usize, Vec<usize>> = HashMap::new();
+        for i in 0..pages.len() {
+            by_bucket
+                .entry(self.page_table.bucket_index(&key(i)))
+                .or_default()
+                .push(i);
+        }
+
+        let mut descriptor_ids = vec![None; pages.len()];
+        for indexes in by_bucket.values() {
+            let bucket_locker = self
+                .page_table
+                .get_bucket_locker(&key(indexes[0]))
+                .ok_or_else(|| anyhow!("cant get bucket"))?;
+            let bucket = bucket_locker.read().unwrap();
+            for &i in indexes {
+                descriptor_ids[i] = bucket.get(key(i));
             }
         }
 
+        for descriptor_id in descriptor_ids.into_iter().flatten() {
+            self.unpin_descriptor(descriptor_id);
+        }
+
         Ok(())
     }
 
+    fn unpin_descriptor(&mut self, descriptor_id: DescriptorID) {
+        let descriptor_arc = self.descriptors.get(descriptor_id);
+        let mut descriptor = descriptor_arc.write().unwrap();
+        descriptor.unpin();
+        if !descriptor.pinned() {
+            self.replacer.unpin(descriptor_id);
+        }
+    }
+
     pub fn flush_buffer(&mut self, p_id: PageID, table_name: &str) -> StorageResult<()> {
         let key = Key::new(p_id, table_name.to_string());
         let bucket_locker = self
@@ -213,19 +374,321 @@ impl<R: Replacer> BufferPoolManager<R> {
 
         if let Some(descriptor_id) = bucket_locker.read().unwrap().get(key) {
             let descriptor_arc = self.descriptors.get(descriptor_id);
-            let descriptor = descriptor_arc.write().unwrap();
+            let mut descriptor = descriptor_arc.write().unwrap();
             let buffer = self.buffer_pool.get(descriptor.buffer_pool_id);
             let page = &buffer.write().unwrap().page;
+            self.wal.flush_to(page.header.lsn)?;
             self.disk_manager.write(page, table_name).unwrap();
+            descriptor.dirty = false;
+            self.stats.writes += 1;
+        }
+
+        Ok(())
+    }
+
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.disk_manager.set_read_only(read_only);
+    }
+
+    pub fn set_opener(&mut self, opener: Opener) {
+        self.disk_manager.set_opener(opener);
+    }
+
+    // 有効にすると、flush_allとvictimの書き出しで、同じtableの連続したpageを1回のwriteで書く
+    pub fn set_write_batching(&mut self, enabled: bool) {
+        self.write_batching = enabled;
+    }
+
+    // dirtyなpageを全て書き出す
+    pub fn flush_all(&mut self) -> StorageResult<()> {
+        let mut tables: HashMap<String, Vec<_>> = HashMap::new();
+        for d in &self.descriptors.items {
+            let buffer = {
+                let d_ = d.read().unwrap();
+                if !d_.dirty {
+                    continue;
+                }
+                self.buffer_pool.get(d_.buffer_pool_id)
+            };
+            let (page_id, table_name) = {
+                let b = buffer.read().unwrap();
+                (b.page.id, b.page.table_name.clone())
+            };
+            tables
+                .entry(table_name)
+                .or_default()
+                .push((page_id, Arc::clone(d), buffer));
+        }
+
+        for (table_name, mut entries) in tables {
+            entries.sort_by_key(|(page_id, _, _)| page_id.0);
+
+            let guards: Vec<_> = entries.iter().map(|(_, _, b)| b.read().unwrap()).collect();
+            let pages: Vec<&Page> = guards.iter().map(|g| &g.page).collect();
+
+            // write-ahead: まとめて書くpageのうち、最も新しいlsnまでを永続化する
+            if let Some(lsn) = pages.iter().map(|p| p.header.lsn).max() {
+                self.wal.flush_to(lsn)?;
+            }
+
+            if self.write_batching {
+                self.disk_manager.write_batch(&pages, &table_name)?;
+            } else {
+                for page in &pages {
+                    self.disk_manager.write(page, &table_name)?;
+                }
+            }
+            self.stats.writes += pages.len() as u64;
+
+            for (_, d, _) in &entries {
+                d.write().unwrap().dirty = false;
+            }
+        }
+
+        Ok(())
+    }
+
+    // dirtyなpageを書き出してから、buffer poolを起動直後と同じ空の状態に戻す
+    // statsは残すので、次のfetchがmissとして数えられる
+    pub fn clear(&mut self) -> StorageResult<()> {
+        // pinされたbufferを持っている側の参照が壊れるので、使用中なら何もしない
+        if self
+            .descriptors
+            .items
+            .iter()
+            .any(|d| d.read().unwrap().pinned())
+        {
+            return Err(anyhow!(
+                "cannot clear the buffer pool while a buffer is pinned"
+            ));
+        }
+
+        self.flush_all()?;
+
+        let pool_size = self.descriptors.items.len();
+        self.page_table = hash_table::HashTable::new(pool_size);
+        for d in &self.descriptors.items {
+            let mut descriptor = d.write().unwrap();
+            descriptor.reset();
+            self.buffer_pool
+                .put(descriptor.buffer_pool_id, Page::default());
+            self.replacer.unpin(descriptor.id);
         }
 
         Ok(())
     }
 
-    pub fn last_page_id(&self, table_name: &str) -> StorageResult<Option<PageID>> {
+    // page_count以降のpageをbuffer poolから捨て、table fileも切り詰める
+    // dirtyでも書き出さない。切り落とすpageはcheckpoint済みで、walからも参照されていないこと
+    pub fn truncate_table(&mut self, table_name: &str, page_count: usize) -> StorageResult<()> {
+        let mut targets = Vec::new();
+        for d in &self.descriptors.items {
+            let buffer_pool_id = d.read().unwrap().buffer_pool_id;
+            let buffer = self.buffer_pool.get(buffer_pool_id);
+            let b = buffer.read().unwrap();
+            if b.page.table_name == table_name && b.page.id.0 >= page_count {
+                targets.push((Arc::clone(d), b.page.id));
+            }
+        }
+
+        // 途中まで捨ててから断らないよう、先に全て確かめる
+        if let Some((_, page_id)) = targets.iter().find(|(d, _)| d.read().unwrap().pinned()) {
+            return Err(anyhow!(
+                "cannot truncate {} while page {} is pinned",
+                table_name,
+                page_id.value()
+            ));
+        }
+
+        for (d, page_id) in targets {
+            let key = Key::new(page_id, table_name.to_string());
+            self.page_table
+                .get_bucket_locker(&key)
+                .ok_or_else(|| anyhow!("cant get bucket"))?
+                .write()
+                .unwrap()
+                .remove(key);
+
+            let mut descriptor = d.write().unwrap();
+            descriptor.reset();
+            self.buffer_pool
+                .put(descriptor.buffer_pool_id, Page::default());
+            self.replacer.unpin(descriptor.id);
+        }
+
+        self.disk_manager.truncate_to(table_name, page_count)
+    }
+
+    // pageに追加する前のtupleをwalに記録する
+    pub fn log_insert(
+        &mut self,
+        txn_id: TxnID,
+        page: &Page,
+        tuple: &Tuple,
+        table_name: &str,
+    ) -> StorageResult<Lsn> {
+        let schema = self.disk_manager.schema(table_name)?;
+        let tuple = tuple.raw(schema.table.columns(), schema.table.text_padding)?;
+
+        self.wal.append(WalOperation::Insert {
+            txn_id,
+            table_name: table_name.to_string(),
+            page_id: page.id,
+            slot: page.header.tuple_count,
+            tuple,
+        })
+    }
+
+    pub fn log_delete(
+        &mut self,
+        txn_id: TxnID,
+        page: &Page,
+        slot: u32,
+        table_name: &str,
+    ) -> StorageResult<Lsn> {
+        self.wal.append(WalOperation::Delete {
+            txn_id,
+            table_name: table_name.to_string(),
+            page_id: page.id,
+            slot,
+        })
+    }
+
+    // 書き換える前と後のtupleを記録する
+    pub fn log_update(
+        &mut self,
+        txn_id: TxnID,
+        page: &Page,
+        slot: u32,
+        before: Vec<u8>,
+        after: Vec<u8>,
+        table_name: &str,
+    ) -> StorageResult<Lsn> {
+        self.wal.append(WalOperation::Update {
+            txn_id,
+            table_name: table_name.to_string(),
+            page_id: page.id,
+            slot,
+            before,
+            after,
+        })
+    }
+
+    pub fn set_durability(&mut self, durability: Durability) {
+        self.durability = durability;
+    }
+
+    pub fn commit(&mut self, txn_id: TxnID) -> StorageResult<()> {
+        self.wal.append(WalOperation::Commit { txn_id })?;
+        self.end_txn()
+    }
+
+    pub fn abort(&mut self, txn_id: TxnID) -> StorageResult<()> {
+        self.wal.append(WalOperation::Abort { txn_id })?;
+        self.end_txn()
+    }
+
+    fn end_txn(&mut self) -> StorageResult<()> {
+        match self.durability {
+            Durability::Sync => self.wal.sync(),
+            Durability::Buffered => self.wal.write_out(),
+            Durability::Group(group) => self.wal.group_commit(group),
+        }
+    }
+
+    // group commitで待たせているcommitを、intervalが過ぎていればfsyncする
+    pub fn sync_wal_if_due(&mut self) -> StorageResult<bool> {
+        match self.durability {
+            Durability::Group(group) => self.wal.sync_if_due(group.interval),
+            _ => Ok(false),
+        }
+    }
+
+    pub fn wal_syncs(&self) -> u64 {
+        self.wal.syncs()
+    }
+
+    // 全てのdirtyなpageをflushした後に呼ぶ
+    pub fn truncate_wal(&mut self) -> StorageResult<()> {
+        self.disk_manager.sync_all()?;
+        self.wal.truncate()
+    }
+
+    pub fn wal_size(&mut self) -> StorageResult<u64> {
+        self.wal.size()
+    }
+
+    // pinもreplacerも触らずに、pageがbuffer poolに載っているかだけを見る
+    pub fn is_resident(&self, p_id: PageID, table_name: &str) -> bool {
+        let key = Key::new(p_id, table_name.to_string());
+
+        self.page_table
+            .get_bucket_locker(&key)
+            .is_some_and(|b| b.read().unwrap().get(key).is_some())
+    }
+
+    pub fn catalog(&self) -> &Catalog {
+        self.disk_manager.catalog()
+    }
+
+    pub fn schema(&self, table_name: &str) -> StorageResult<&Schema> {
+        self.disk_manager.schema(table_name)
+    }
+
+    pub fn last_page_id(&mut self, table_name: &str) -> StorageResult<Option<PageID>> {
+        self.last_page_lookups += 1;
         self.disk_manager.last_page_id(table_name)
     }
 
+    pub fn last_page_lookups(&self) -> u64 {
+        self.last_page_lookups
+    }
+
+    pub fn stats(&self) -> BufferPoolStats {
+        self.stats
+    }
+
+    pub fn settings(&self) -> PoolSettings {
+        PoolSettings {
+            pool_size: self.descriptors.items.len(),
+            replacer: self.replacer.name(),
+            durability: self.durability,
+        }
+    }
+
+    // 最後のpage以外は埋まっているものとして、tableの行数を見積もる
+    // rollbackで消されたtupleも数える。statsを変えないよう、載っていないpageはdiskから直接読む
+    pub fn row_estimate(&mut self, table_name: &str) -> StorageResult<usize> {
+        let last = match self.last_page_id(table_name)? {
+            Some(p_id) => p_id,
+            None => return Ok(0),
+        };
+
+        let key = Key::new(last, table_name.to_string());
+        let resident = self
+            .page_table
+            .get_bucket_locker(&key)
+            .and_then(|b| b.read().unwrap().get(key));
+        let last_count = match resident {
+            Some(d_id) => {
+                let buffer_pool_id = self.descriptors.get(d_id).read().unwrap().buffer_pool_id;
+                let count = self
+                    .buffer_pool
+                    .get(buffer_pool_id)
+                    .read()
+                    .unwrap()
+                    .page
+                    .header
+                    .tuple_count;
+                count as usize
+            }
+            None => self.disk_manager.read(last, table_name)?.header.tuple_count as usize,
+        };
+
+        let per_page = tuples_per_page(&self.schema(table_name)?.table);
+        Ok(last.value() * per_page + last_count)
+    }
+
     pub fn dirty_buffers(&self) -> Vec<Arc<RwLock<Buffer>>> {
         let mut v = Vec::new();
         for d in &self.descriptors.items {
@@ -242,11 +705,17 @@ impl<R: Replacer> BufferPoolManager<R> {
 
 #[cfg(test)]
 mod tests {
-    use std::env::temp_dir;
+    use crate::{catalog::Catalog, storage::tuple::Tuple, test_util::temp_dir};
 
-    use crate::{catalog::Catalog, storage::tuple::Tuple};
+    use std::time::Duration;
 
-    use super::BufferPoolManager;
+    use super::{BufferPoolManager, BufferPoolStats};
+    use crate::storage::wal::{Durability, GroupCommit, Wal};
+    use crate::storage::{
+        descriptors::DescriptorID,
+        page::PageID,
+        replacer::{LruReplacer, Replacer},
+    };
 
     const JSON: &str = r#"{
         "schemas": [
@@ -271,14 +740,14 @@ mod tests {
     #[test]
     #[should_panic]
     fn buffer_pool_manager_new_test_no_size() {
-        let c = Catalog::from_json("");
+        let c = Catalog::from_json("").unwrap();
         let _manager = BufferPoolManager::new(0, "dummy".to_string(), c);
     }
 
     #[test]
     fn buffer_pool_manager_write_and_flush() {
-        let temp_dir = temp_dir();
-        let catalog = Catalog::from_json(JSON);
+        let temp_dir = temp_dir("buffer_pool_manager_write_and_flush");
+        let catalog = Catalog::from_json(JSON).unwrap();
         let mut manager =
             BufferPoolManager::new(1, temp_dir.to_str().unwrap().to_string(), catalog);
 
@@ -308,8 +777,8 @@ mod tests {
 
     #[test]
     fn buffer_pool_manager_victim() {
-        let temp_dir = temp_dir();
-        let catalog = Catalog::from_json(JSON);
+        let temp_dir = temp_dir("buffer_pool_manager_victim");
+        let catalog = Catalog::from_json(JSON).unwrap();
         let mut manager =
             BufferPoolManager::new(1, temp_dir.to_str().unwrap().to_string(), catalog);
 
@@ -342,4 +811,438 @@ mod tests {
 
         assert_eq!(buffer.page.header.tuple_count, 1);
     }
+
+    #[test]
+    fn buffer_pool_manager_group_commit() {
+        let temp_dir = temp_dir("buffer_pool_manager_group_commit");
+        let catalog = Catalog::from_json(JSON).unwrap();
+        let mut manager =
+            BufferPoolManager::new(1, temp_dir.to_str().unwrap().to_string(), catalog);
+        manager.set_durability(Durability::Group(GroupCommit {
+            interval: Duration::from_secs(3600),
+            max_commits: 3,
+        }));
+
+        // 3つ目のcommitでまとめて1回だけfsyncする
+        manager.commit(1).unwrap();
+        manager.commit(2).unwrap();
+        assert_eq!(manager.wal_syncs(), 0);
+        manager.commit(3).unwrap();
+        assert_eq!(manager.wal_syncs(), 1);
+        assert_eq!(manager.wal.flushed_lsn(), 3);
+
+        let wal = Wal::new(manager.disk_manager.wal_path());
+        let records = wal.peek_records().unwrap();
+        assert_eq!(records.len(), 3);
+
+        // 数に達しなくても、intervalが過ぎれば定期的な確認でfsyncする
+        manager.set_durability(Durability::Group(GroupCommit {
+            interval: Duration::from_millis(20),
+            max_commits: 100,
+        }));
+        manager.commit(4).unwrap();
+        assert!(!manager.sync_wal_if_due().unwrap());
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(manager.sync_wal_if_due().unwrap());
+        assert_eq!(manager.wal_syncs(), 2);
+        assert_eq!(manager.wal.flushed_lsn(), 4);
+        assert!(!manager.sync_wal_if_due().unwrap());
+    }
+
+    #[test]
+    fn buffer_pool_manager_write_ahead() {
+        let temp_dir = temp_dir("buffer_pool_manager_write_ahead");
+        let catalog = Catalog::from_json(JSON).unwrap();
+        let mut manager =
+            BufferPoolManager::new(1, temp_dir.to_str().unwrap().to_string(), catalog);
+
+        let table_name = "buffer_pool_test";
+
+        let lsn = {
+            let buffer_locker = manager.new_buffer(table_name).unwrap();
+            let mut buffer = buffer_locker.write().unwrap();
+            let mut tuple = Tuple::new();
+            tuple.add_attribute("column_int", crate::catalog::AttributeType::Int(1));
+            tuple.add_attribute(
+                "column_text",
+                crate::catalog::AttributeType::Text("wal".to_string()),
+            );
+            let lsn = manager
+                .log_insert(1, &buffer.page, &tuple, table_name)
+                .unwrap();
+            buffer.page.add_tuple(tuple);
+            buffer.page.header.lsn = lsn;
+            manager.mark_dirty(buffer.id).unwrap();
+            manager.unpin_buffer(buffer.page.id, table_name).unwrap();
+            lsn
+        };
+
+        // commitしていないので、まだlogはdiskに届いていない
+        assert!(manager.wal.flushed_lsn() < lsn);
+
+        // victimでpageが書かれる前にlogがflushされる
+        {
+            let buffer_locker = manager.new_buffer(table_name).unwrap();
+            let buffer = buffer_locker.read().unwrap();
+            manager.unpin_buffer(buffer.page.id, table_name).unwrap();
+        }
+
+        assert!(manager.wal.flushed_lsn() >= lsn);
+    }
+
+    #[test]
+    fn buffer_pool_manager_stats() {
+        let temp_dir = temp_dir("buffer_pool_manager_stats");
+        let catalog = Catalog::from_json(JSON).unwrap();
+        let mut manager =
+            BufferPoolManager::new(1, temp_dir.to_str().unwrap().to_string(), catalog);
+
+        let table_name = "buffer_pool_test";
+
+        let first = {
+            let buffer_locker = manager.new_buffer(table_name).unwrap();
+            let buffer = buffer_locker.read().unwrap();
+            manager.unpin_buffer(buffer.page.id, table_name).unwrap();
+            buffer.page.id
+        };
+
+        // hit
+        {
+            let buffer_locker = manager.fetch_buffer(first, table_name).unwrap();
+            let buffer = buffer_locker.read().unwrap();
+            manager.unpin_buffer(buffer.page.id, table_name).unwrap();
+        }
+
+        // 2つ目のpageでfirstが追い出される
+        {
+            let buffer_locker = manager.new_buffer(table_name).unwrap();
+            let buffer = buffer_locker.read().unwrap();
+            manager.unpin_buffer(buffer.page.id, table_name).unwrap();
+        }
+
+        // miss
+        {
+            let buffer_locker = manager.fetch_buffer(first, table_name).unwrap();
+            let buffer = buffer_locker.read().unwrap();
+            manager.unpin_buffer(buffer.page.id, table_name).unwrap();
+        }
+
+        assert_eq!(
+            manager.stats(),
+            BufferPoolStats {
+                hits: 1,
+                misses: 1,
+                evictions: 2,
+                writes: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn buffer_pool_manager_clear() {
+        let temp_dir = temp_dir("buffer_pool_manager_clear");
+        let catalog = Catalog::from_json(JSON).unwrap();
+        let mut manager =
+            BufferPoolManager::new(2, temp_dir.to_str().unwrap().to_string(), catalog);
+
+        let table_name = "buffer_pool_test";
+
+        let mut page_ids = Vec::new();
+        for _ in 0..2 {
+            let buffer_locker = manager.new_buffer(table_name).unwrap();
+            let buffer = buffer_locker.read().unwrap();
+            manager.mark_dirty(buffer.id).unwrap();
+            manager.unpin_buffer(buffer.page.id, table_name).unwrap();
+            page_ids.push(buffer.page.id);
+        }
+
+        // pinされている間はclearできない
+        let buffer_locker = manager.fetch_buffer(page_ids[0], table_name).unwrap();
+        assert!(manager.clear().is_err());
+        manager.unpin_buffer(page_ids[0], table_name).unwrap();
+        drop(buffer_locker);
+
+        manager.clear().unwrap();
+        assert_eq!(manager.stats().writes, 2);
+        assert!(!manager.is_resident(page_ids[0], table_name));
+        assert!(!manager.is_resident(page_ids[1], table_name));
+
+        // 載っていたpageもdiskから読み直す
+        let buffer_locker = manager.fetch_buffer(page_ids[0], table_name).unwrap();
+        assert_eq!(buffer_locker.read().unwrap().page.id, page_ids[0]);
+        manager.unpin_buffer(page_ids[0], table_name).unwrap();
+        assert_eq!(manager.stats().hits, 1);
+        assert_eq!(manager.stats().misses, 1);
+    }
+
+    #[test]
+    fn buffer_pool_manager_is_resident() {
+        let temp_dir = temp_dir("buffer_pool_manager_is_resident");
+        let catalog = Catalog::from_json(JSON).unwrap();
+        let mut manager =
+            BufferPoolManager::new(2, temp_dir.to_str().unwrap().to_string(), catalog);
+
+        let table_name = "buffer_pool_test";
+
+        let mut page_ids = vec![];
+        for n in 0..3 {
+            let buffer_locker = manager.new_buffer(table_name).unwrap();
+            let buffer = buffer_locker.read().unwrap();
+            manager.unpin_buffer(buffer.page.id, table_name).unwrap();
+            page_ids.push(buffer.page.id);
+
+            // 空いているbufferに載せても、先に載せたpageは残っている
+            if n == 1 {
+                assert!(manager.is_resident(page_ids[0], table_name));
+            }
+        }
+
+        {
+            let buffer_locker = manager.fetch_buffer(page_ids[1], table_name).unwrap();
+            let buffer = buffer_locker.read().unwrap();
+            manager.unpin_buffer(buffer.page.id, table_name).unwrap();
+        }
+
+        // pool sizeが2なので、最初のpageは追い出されている
+        assert!(!manager.is_resident(page_ids[0], table_name));
+        assert!(manager.is_resident(page_ids[1], table_name));
+        assert!(manager.is_resident(page_ids[2], table_name));
+        assert!(!manager.is_resident(page_ids[1], "other_table"));
+
+        // is_residentはhitに数えない
+        assert_eq!(manager.stats().hits, 1);
+
+        // page_ids[2]がLRUなので、次の読み込みで追い出される
+        {
+            let buffer_locker = manager.fetch_buffer(page_ids[0], table_name).unwrap();
+            let buffer = buffer_locker.read().unwrap();
+            manager.unpin_buffer(buffer.page.id, table_name).unwrap();
+        }
+
+        assert!(manager.is_resident(page_ids[0], table_name));
+        assert!(manager.is_resident(page_ids[1], table_name));
+        assert!(!manager.is_resident(page_ids[2], table_name));
+    }
+
+    #[test]
+    fn buffer_pool_manager_refuse_pinned_victim() {
+        let temp_dir = temp_dir("buffer_pool_manager_refuse_pinned_victim");
+        let catalog = Catalog::from_json(JSON).unwrap();
+        let mut manager =
+            BufferPoolManager::new(1, temp_dir.to_str().unwrap().to_string(), catalog);
+
+        let table_name = "buffer_pool_test";
+
+        // pinしたまま
+        let page_id = {
+            let buffer_locker = manager.new_buffer(table_name).unwrap();
+            let buffer = buffer_locker.read().unwrap();
+            buffer.page.id
+        };
+
+        // 誤ってreplacerに戻してしまった状態を作る
+        manager.replacer.unpin(DescriptorID(0));
+
+        assert!(manager.new_buffer(table_name).is_err());
+        assert!(manager.is_resident(page_id, table_name));
+    }
+
+    #[test]
+    fn buffer_pool_manager_skip_pinned_victim() {
+        let temp_dir = temp_dir("buffer_pool_manager_skip_pinned_victim");
+        let catalog = Catalog::from_json(JSON).unwrap();
+        let mut manager =
+            BufferPoolManager::new(2, temp_dir.to_str().unwrap().to_string(), catalog);
+
+        let table_name = "buffer_pool_test";
+
+        let mut page_ids = Vec::new();
+        for _ in 0..2 {
+            let buffer_locker = manager.new_buffer(table_name).unwrap();
+            let buffer = buffer_locker.read().unwrap();
+            page_ids.push(buffer.page.id);
+        }
+
+        // 1つ目はpinしたまま、誤ってreplacerの先頭に戻してしまう
+        manager.replacer.unpin(DescriptorID(0));
+        manager.unpin_buffer(page_ids[1], table_name).unwrap();
+
+        // pinされている1つ目を飛ばして、2つ目が追い出される
+        manager.new_buffer(table_name).unwrap();
+        assert!(manager.is_resident(page_ids[0], table_name));
+        assert!(!manager.is_resident(page_ids[1], table_name));
+    }
+
+    #[test]
+    fn buffer_pool_manager_unpin_buffers() {
+        let catalog = Catalog::from_json(JSON).unwrap();
+        let table_name = "buffer_pool_test";
+
+        // 同じpageを同じ順にpinした2つのmanagerを、1つずつとまとめての両方でunpinする
+        let pinned = |name: &str| {
+            let path = temp_dir(name).to_str().unwrap().to_string();
+            let mut manager = BufferPoolManager::new(4, path, catalog.clone());
+            let mut page_ids = Vec::new();
+            for _ in 0..4 {
+                let buffer_locker = manager.new_buffer(table_name).unwrap();
+                page_ids.push(buffer_locker.read().unwrap().page.id);
+            }
+            // 2つ目は2回pinしておく
+            manager.fetch_buffer(page_ids[1], table_name).unwrap();
+            (manager, page_ids)
+        };
+        let (mut one_by_one, page_ids) = pinned("buffer_pool_manager_unpin_buffer");
+        let (mut batched, _) = pinned("buffer_pool_manager_unpin_buffers");
+
+        let order = [page_ids[2], page_ids[0], page_ids[1], page_ids[3]];
+        for p_id in order {
+            one_by_one.unpin_buffer(p_id, table_name).unwrap();
+        }
+        let pages: Vec<(PageID, &str)> = order.iter().map(|&p| (p, table_name)).collect();
+        batched.unpin_buffers(&pages).unwrap();
+
+        // まだpinされている2つ目はreplacerに入らず、残りは渡した順に追い出される
+        let victims = |m: &mut BufferPoolManager<LruReplacer>| {
+            std::iter::from_fn(|| m.replacer.victim()).collect::<Vec<_>>()
+        };
+        let expected = victims(&mut one_by_one);
+        assert_eq!(expected.len(), 3);
+        assert_eq!(victims(&mut batched), expected);
+
+        // 残っていたpinも同じように外れる
+        one_by_one.unpin_buffer(page_ids[1], table_name).unwrap();
+        batched
+            .unpin_buffers(&[(page_ids[1], table_name), (PageID(99), table_name)])
+            .unwrap();
+        assert_eq!(victims(&mut batched), victims(&mut one_by_one));
+    }
+
+    #[test]
+    fn buffer_pool_manager_flush_all_batched() {
+        let temp_dir = temp_dir("buffer_pool_manager_flush_all_batched");
+        let base_path = temp_dir.to_str().unwrap().to_string();
+        let catalog = Catalog::from_json(JSON).unwrap();
+        let table_name = "buffer_pool_test";
+
+        {
+            let mut manager = BufferPoolManager::new(3, base_path.clone(), catalog.clone());
+            manager.set_write_batching(true);
+
+            for i in 0..3 {
+                let buffer_locker = manager.new_buffer(table_name).unwrap();
+                let mut buffer = buffer_locker.write().unwrap();
+                let mut tuple = Tuple::new();
+                tuple.add_attribute("column_int", crate::catalog::AttributeType::Int(i));
+                tuple.add_attribute(
+                    "column_text",
+                    crate::catalog::AttributeType::Text("test".to_string()),
+                );
+                buffer.page.add_tuple(tuple);
+                manager.mark_dirty(buffer.id).unwrap();
+                manager.unpin_buffer(buffer.page.id, table_name).unwrap();
+            }
+
+            manager.flush_all().unwrap();
+            assert_eq!(manager.stats().writes, 3);
+            // 続いている3つのpageは1回のwriteで書く
+            assert_eq!(manager.disk_manager.writes(), 1);
+            assert!(manager.dirty_buffers().is_empty());
+        }
+
+        let mut manager = BufferPoolManager::new(3, base_path, catalog);
+        for i in 0..3 {
+            let buffer_locker = manager.fetch_buffer(PageID(i), table_name).unwrap();
+            let buffer = buffer_locker.read().unwrap();
+            assert_eq!(buffer.page.header.tuple_count, 1);
+        }
+    }
+
+    #[test]
+    fn buffer_pool_manager_victim_batched() {
+        let temp_dir = temp_dir("buffer_pool_manager_victim_batched");
+        let base_path = temp_dir.to_str().unwrap().to_string();
+        let catalog = Catalog::from_json(JSON).unwrap();
+        let table_name = "buffer_pool_test";
+
+        let mut manager = BufferPoolManager::new(4, base_path.clone(), catalog.clone());
+        manager.set_write_batching(true);
+        let add_page = |manager: &mut BufferPoolManager<LruReplacer>, dirty: bool| {
+            let buffer_locker = manager.new_buffer(table_name).unwrap();
+            let mut buffer = buffer_locker.write().unwrap();
+            if dirty {
+                let mut tuple = Tuple::new();
+                tuple.add_attribute("column_int", crate::catalog::AttributeType::Int(1));
+                tuple.add_attribute(
+                    "column_text",
+                    crate::catalog::AttributeType::Text("test".to_string()),
+                );
+                buffer.page.add_tuple(tuple);
+                manager.mark_dirty(buffer.id).unwrap();
+            }
+            manager.unpin_buffer(buffer.page.id, table_name).unwrap();
+        };
+
+        // page 0, 1, 3がdirtyで、2はdirtyでない
+        for dirty in [true, true, false, true] {
+            add_page(&mut manager, dirty);
+        }
+
+        // page 0を追い出すとき、続くpage 1も一緒に1回で書く
+        add_page(&mut manager, false);
+        assert_eq!(manager.disk_manager.writes(), 1);
+        assert_eq!(manager.stats().writes, 2);
+        assert_eq!(manager.dirty_buffers().len(), 1);
+        assert!(manager.is_resident(PageID(1), table_name));
+
+        // page 1と2はもう書く必要がなく、page 3は1つだけで書く
+        for _ in 0..3 {
+            add_page(&mut manager, false);
+        }
+        assert_eq!(manager.disk_manager.writes(), 2);
+        assert_eq!(manager.stats().writes, 3);
+        assert!(manager.dirty_buffers().is_empty());
+
+        let mut manager = BufferPoolManager::new(4, base_path, catalog);
+        for (i, count) in [1, 1, 0, 1].into_iter().enumerate() {
+            let buffer_locker = manager.fetch_buffer(PageID(i), table_name).unwrap();
+            assert_eq!(buffer_locker.read().unwrap().page.header.tuple_count, count);
+            manager.unpin_buffer(PageID(i), table_name).unwrap();
+        }
+    }
+
+    #[test]
+    fn buffer_pool_manager_new_buffer_without_read() {
+        let temp_dir = temp_dir("buffer_pool_manager_new_buffer_without_read");
+        let catalog = Catalog::from_json(JSON).unwrap();
+        let mut manager =
+            BufferPoolManager::new(1, temp_dir.to_str().unwrap().to_string(), catalog);
+
+        let table_name = "buffer_pool_test";
+
+        // 書いたばかりのpageは読み直さない
+        let first = {
+            let buffer_locker = manager.new_buffer(table_name).unwrap();
+            let buffer = buffer_locker.read().unwrap();
+            assert_eq!(buffer.page.header.tuple_count, 0);
+            assert_eq!(buffer.page.tuples().count(), 0);
+            assert_eq!(buffer.page.table_name, table_name);
+            manager.unpin_buffer(buffer.page.id, table_name).unwrap();
+            buffer.page.id
+        };
+        let tuple_size = {
+            let buffer_locker = manager.new_buffer(table_name).unwrap();
+            let buffer = buffer_locker.read().unwrap();
+            assert_eq!(buffer.page.id, PageID(first.value() + 1));
+            manager.unpin_buffer(buffer.page.id, table_name).unwrap();
+            buffer.page.tuple_size
+        };
+        assert_eq!(manager.disk_manager.reads(), 0);
+
+        // diskにも空のpageとして書かれている
+        let buffer_locker = manager.fetch_buffer(first, table_name).unwrap();
+        let buffer = buffer_locker.read().unwrap();
+        assert_eq!(buffer.page.header.tuple_count, 0);
+        assert_eq!(buffer.page.tuple_size, tuple_size);
+        assert_eq!(manager.disk_manager.reads(), 1);
+    }
 }
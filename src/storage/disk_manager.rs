@@ -1,34 +1,108 @@
 use anyhow::Ok;
 
-use crate::catalog::Catalog;
+use crate::{
+    catalog::{Catalog, Schema},
+    error::DbError,
+};
 
 use super::page::*;
 use super::StorageResult;
 use std::{
     fs::{File, OpenOptions},
-    io::{Read, Seek, SeekFrom, Write},
+    io::{self, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::Path,
 };
 
+// table fileを開く関数。開けないときのerrorを、権限を変えずに試せるよう差し替えられる
+pub type Opener = fn(&OpenOptions, &str) -> io::Result<File>;
+
 pub struct DiskManager {
     catalog: Catalog,
     base_path: String,
+    read_only: bool,
+    opener: Opener,
+    // readでpageをdiskから読んだ回数
+    reads: u64,
+    // pageを書き出したwriteの回数。まとめて書いたpageは1回と数え、allocate_pageは数えない
+    writes: u64,
 }
 
 impl DiskManager {
     pub fn new(base_path: String, catalog: Catalog) -> Self {
-        DiskManager { base_path, catalog }
+        DiskManager {
+            base_path,
+            catalog,
+            read_only: false,
+            opener: |options, path| options.open(path),
+            reads: 0,
+            writes: 0,
+        }
+    }
+
+    pub fn set_opener(&mut self, opener: Opener) {
+        self.opener = opener;
+    }
+
+    // table fileを書き込み権限なしで開く
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    pub fn wal_path(&self) -> String {
+        format!("{}/wal", self.base_path)
+    }
+
+    fn table_path(&self, table_name: &str) -> String {
+        format!("{}/{}", self.base_path, table_name)
     }
 
     fn open(&self, table_name: &str) -> StorageResult<File> {
-        let file = OpenOptions::new()
+        let mut options = OpenOptions::new();
+        options
             .read(true)
-            .write(true)
-            .create(true)
-            .open(format!("{}/{}", self.base_path, table_name))?;
+            .write(!self.read_only)
+            .create(!self.read_only);
+        let file = (self.opener)(&options, &self.table_path(table_name))
+            .map_err(|e| self.open_error(table_name, e))?;
 
         Ok(file)
     }
 
+    // 権限がないときは、どのtableのfileかと考えられる原因を添える
+    // read onlyで開けないなら読み込み権限がないので、--read-onlyは勧めない
+    fn open_error(&self, table_name: &str, e: io::Error) -> anyhow::Error {
+        if e.kind() != ErrorKind::PermissionDenied {
+            return e.into();
+        }
+        let (mode, hint) = if self.read_only {
+            ("reading", "fix the permissions of the file or data dir")
+        } else {
+            (
+                "writing",
+                "the file or data dir may be read-only; fix the permissions or start with --read-only",
+            )
+        };
+        let message = format!(
+            "cannot open table file {} of {} for {}: {} ({})",
+            self.table_path(table_name),
+            table_name,
+            mode,
+            e,
+            hint
+        );
+        DbError::Io(io::Error::new(e.kind(), message)).into()
+    }
+
+    pub fn catalog(&self) -> &Catalog {
+        &self.catalog
+    }
+
+    pub fn schema(&self, table_name: &str) -> StorageResult<&Schema> {
+        self.catalog
+            .get_schema_by_table_name(table_name)
+            .ok_or_else(|| anyhow::anyhow!(format!("{} not found in catalog", table_name)))
+    }
+
     pub fn read(&mut self, page_id: PageID, table_name: &str) -> StorageResult<Page> {
         let mut file = self.open(table_name)?;
 
@@ -42,47 +116,117 @@ impl DiskManager {
         file.seek(SeekFrom::Start(page_id.offset() as u64))?;
         file.read_exact(&mut data)?;
 
-        let schema = self
-            .catalog
-            .get_schema_by_table_name(table_name)
-            .ok_or_else(|| anyhow::anyhow!(format!("{} not found in catalog", table_name)))?;
+        let schema = self.schema(table_name)?;
 
-        page.fill(&data, table_name, schema);
+        page.fill(&data, table_name, schema)?;
+        self.reads += 1;
 
         Ok(page)
     }
 
+    pub fn reads(&self) -> u64 {
+        self.reads
+    }
+
+    pub fn writes(&self) -> u64 {
+        self.writes
+    }
+
     pub fn write(&mut self, page: &Page, table_name: &str) -> StorageResult<()> {
         let mut file = self.open(table_name)?;
 
-        let schema = self
-            .catalog
-            .get_schema_by_table_name(table_name)
-            .ok_or_else(|| anyhow::anyhow!(format!("{} not found in catalog", table_name)))?;
+        let schema = self.schema(table_name)?;
 
         file.seek(SeekFrom::Start(page.id.offset() as u64))?;
-        file.write_all(&page.raw(schema))?;
+        file.write_all(&page.raw(schema)?)?;
+        self.writes += 1;
 
         Ok(())
     }
 
+    // page idが連続しているpageは1回のwriteにまとめる
+    // pagesはpage id順に並んでいること。発行したwriteの回数を返す
+    pub fn write_batch(&mut self, pages: &[&Page], table_name: &str) -> StorageResult<usize> {
+        let mut file = self.open(table_name)?;
+
+        let schema = self.schema(table_name)?;
+
+        let mut calls = 0;
+        let mut i = 0;
+        while i < pages.len() {
+            let mut data = pages[i].raw(schema)?;
+            let mut j = i + 1;
+            while j < pages.len() && pages[j].id.0 == pages[j - 1].id.0 + 1 {
+                data.append(&mut pages[j].raw(schema)?);
+                j += 1;
+            }
+
+            file.seek(SeekFrom::Start(pages[i].id.offset() as u64))?;
+            file.write_all(&data)?;
+            calls += 1;
+            i = j;
+        }
+
+        self.writes += calls as u64;
+        Ok(calls)
+    }
+
+    // 空のpageをfileの末尾に書き、読み直さずにそのまま返す
     pub fn allocate_page(&mut self, table_name: &str) -> StorageResult<Page> {
-        let file = self.open(table_name)?;
+        let mut file = self.open(table_name)?;
 
-        let offset = (file.metadata().unwrap().len() / PAGE_SIZE as u64) as usize;
+        let offset = (file.metadata()?.len() / PAGE_SIZE as u64) as usize;
 
+        let schema = self.schema(table_name)?;
         let page = Page {
             id: PageID(offset),
             table_name: table_name.to_string(),
+            tuple_size: schema.table.tuple_size(),
+            max_tuples: schema.table.max_tuples_per_page,
             ..Default::default()
         };
 
-        self.write(&page, table_name)?;
+        // 長さを調べたのと同じfileに書く
+        file.seek(SeekFrom::Start(page.id.offset() as u64))?;
+        file.write_all(&page.raw(schema)?)?;
 
         Ok(page)
     }
 
+    // walをtruncateする前に、table fileの中身をdiskまで届けておく
+    pub fn sync_all(&self) -> StorageResult<()> {
+        for schema in &self.catalog.schemas {
+            self.open(&schema.table.name)?.sync_all()?;
+        }
+
+        Ok(())
+    }
+
+    // page_count以降のpageをfileから切り落とす。buffer poolに残っている分は呼ぶ側で追い出す
+    pub fn truncate_to(&mut self, table_name: &str, page_count: usize) -> StorageResult<()> {
+        if self.read_only {
+            return Err(anyhow::anyhow!(
+                "cannot truncate {} in read-only mode",
+                table_name
+            ));
+        }
+
+        let file = self.open(table_name)?;
+        let len = (page_count * PAGE_SIZE) as u64;
+        if file.metadata()?.len() > len {
+            file.set_len(len)?;
+            file.sync_all()?;
+        }
+
+        Ok(())
+    }
+
     pub fn last_page_id(&self, table_name: &str) -> StorageResult<Option<PageID>> {
+        // read onlyでは作成しないので、まだ一度も書かれていないtableのfileはない
+        if self.read_only && !Path::new(&self.table_path(table_name)).exists() {
+            return Ok(None);
+        }
+
         let file = self.open(table_name)?;
         let page_num = file.metadata()?.len() as usize / PAGE_SIZE;
 
@@ -96,9 +240,7 @@ impl DiskManager {
 
 #[cfg(test)]
 mod tests {
-    use std::env::temp_dir;
-
-    use crate::{catalog::AttributeType, storage::tuple::Tuple};
+    use crate::{catalog::AttributeType, storage::tuple::Tuple, test_util::temp_dir};
 
     use super::*;
 
@@ -122,10 +264,48 @@ mod tests {
         ]
     }"#;
 
+    #[test]
+    fn disk_permission_denied() {
+        let c = Catalog::from_json(JSON).unwrap();
+        let mut manager = DiskManager::new("/data".to_string(), c);
+
+        let e = manager.open_error("disk_manager", io::Error::from(ErrorKind::PermissionDenied));
+        match e.downcast_ref::<DbError>() {
+            Some(DbError::Io(io)) => assert_eq!(io.kind(), ErrorKind::PermissionDenied),
+            _ => panic!("unexpected {:?}", e),
+        }
+        let message = e.to_string();
+        assert!(
+            message.contains("/data/disk_manager of disk_manager"),
+            "{}",
+            message
+        );
+        assert!(message.contains("for writing"), "{}", message);
+        assert!(message.contains("--read-only"), "{}", message);
+
+        // read onlyで開けないのは読み込み権限がないとき
+        manager.set_read_only(true);
+        let message = manager
+            .open_error("disk_manager", io::Error::from(ErrorKind::PermissionDenied))
+            .to_string();
+        assert!(message.contains("for reading"), "{}", message);
+        assert!(!message.contains("--read-only"), "{}", message);
+        manager.set_read_only(false);
+
+        // それ以外のerrorはそのまま返す
+        let e = manager.open_error("disk_manager", io::Error::from(ErrorKind::NotFound));
+        assert!(e.downcast_ref::<DbError>().is_none());
+    }
+
     #[test]
     fn disk_read_write() {
-        let temp_dir = temp_dir();
-        let c = Catalog::from_json(JSON);
+        let temp_dir = temp_dir("disk_read_write");
+        let c = Catalog::from_json(JSON).unwrap();
+        let table = c
+            .get_schema_by_table_name("disk_manager")
+            .unwrap()
+            .table
+            .clone();
 
         let mut manager = DiskManager::new(temp_dir.to_str().unwrap().to_string(), c);
 
@@ -140,16 +320,78 @@ mod tests {
         let page = manager.read(page.id, "disk_manager").unwrap();
 
         assert_eq!(1, page.header.tuple_count);
-        let tuple = &page.body[0];
+        let attributes = page.body[0]
+            .body
+            .attributes(table.columns(), table.text_padding)
+            .unwrap();
 
-        match &tuple.body.attributes["column_int"] {
+        match &attributes["column_int"] {
             AttributeType::Int(v) => assert_eq!(999, *v),
             _ => panic!("strange column_int"),
         }
 
-        match &tuple.body.attributes["column_text"] {
+        match &attributes["column_text"] {
             AttributeType::Text(v) => assert_eq!(v, "text"),
             _ => panic!("strange column_text"),
         }
     }
+
+    #[test]
+    fn disk_write_batch() {
+        let temp_dir = temp_dir("disk_write_batch");
+        let c = Catalog::from_json(JSON).unwrap();
+        let table = c
+            .get_schema_by_table_name("disk_manager")
+            .unwrap()
+            .table
+            .clone();
+
+        let mut manager = DiskManager::new(temp_dir.to_str().unwrap().to_string(), c);
+
+        let mut pages = Vec::new();
+        for i in 0..4 {
+            let mut page = manager.allocate_page("disk_manager").unwrap();
+            let mut tuple = Tuple::new();
+            tuple.add_attribute("column_int", AttributeType::Int(i));
+            tuple.add_attribute("column_text", AttributeType::Text("text".to_string()));
+            page.add_tuple(tuple);
+            pages.push(page);
+        }
+
+        // 連続したpage idは1回のwriteになる
+        let contiguous: Vec<&Page> = pages[0..3].iter().collect();
+        assert_eq!(1, manager.write_batch(&contiguous, "disk_manager").unwrap());
+
+        // 1が抜けているので、0と2,3の2回に分かれる
+        let gapped = vec![&pages[0], &pages[2], &pages[3]];
+        assert_eq!(2, manager.write_batch(&gapped, "disk_manager").unwrap());
+
+        for (i, p) in pages.iter().enumerate() {
+            let page = manager.read(p.id, "disk_manager").unwrap();
+            assert_eq!(1, page.header.tuple_count);
+            let column_int = page.body[0]
+                .body
+                .get("column_int", table.columns(), table.text_padding)
+                .unwrap();
+            match column_int {
+                Some(AttributeType::Int(v)) => assert_eq!(i as i32, v),
+                _ => panic!("strange column_int"),
+            }
+        }
+    }
+
+    #[test]
+    fn disk_read_only() {
+        let temp_dir = temp_dir("disk_read_only");
+        let c = Catalog::from_json(JSON).unwrap();
+
+        let mut manager = DiskManager::new(temp_dir.to_str().unwrap().to_string(), c.clone());
+        let page = manager.allocate_page("disk_manager").unwrap();
+
+        let mut manager = DiskManager::new(temp_dir.to_str().unwrap().to_string(), c);
+        manager.set_read_only(true);
+
+        assert!(manager.read(page.id, "disk_manager").is_ok());
+        assert!(manager.write(&page, "disk_manager").is_err());
+    }
 }
@@ -59,24 +59,27 @@ where
         assert!(size > 0);
 
         let mut buckets = Vec::with_capacity(size);
-        (0..size)
-            .into_iter()
-            .for_each(|_| buckets.push(Arc::new(RwLock::new(Bucket::new()))));
+        (0..size).for_each(|_| buckets.push(Arc::new(RwLock::new(Bucket::new()))));
 
         Self { size, buckets }
     }
 
-    pub fn same_bucket(&mut self, key1: &K, key2: &K) -> bool {
+    pub fn same_bucket(&self, key1: &K, key2: &K) -> bool {
         self.calculate_bucket(key1) == self.calculate_bucket(key2)
     }
 
-    fn calculate_bucket(&mut self, key: &K) -> usize {
+    // 同じbucketのkeyをまとめて1回のlockで引くときに使う
+    pub fn bucket_index(&self, key: &K) -> usize {
+        self.calculate_bucket(key)
+    }
+
+    fn calculate_bucket(&self, key: &K) -> usize {
         let mut hasher = DefaultHasher::new();
         key.hash(&mut hasher);
         hasher.finish() as usize % self.size
     }
 
-    pub fn get_bucket_locker(&mut self, key: &K) -> Option<BucketLockRef<K, V>> {
+    pub fn get_bucket_locker(&self, key: &K) -> Option<BucketLockRef<K, V>> {
         let index = self.calculate_bucket(key);
         self.buckets.get(index).map(Arc::clone)
     }
@@ -116,7 +119,7 @@ mod tests {
     }
     #[test]
     fn hash_table_1_size() {
-        let mut table = HashTable::new(1);
+        let table = HashTable::new(1);
 
         let key = "test_key";
         let value = "test_value";
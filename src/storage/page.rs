@@ -1,4 +1,8 @@
+use std::ops::Range;
+
 use super::tuple::*;
+use super::wal::Lsn;
+use super::StorageResult;
 use crate::catalog::*;
 
 pub const PAGE_SIZE: usize = 4096;
@@ -10,25 +14,49 @@ pub struct Page {
     pub header: PageHeader,
     pub body: Vec<Tuple>,
     pub tuple_size: usize,
+    // tableのmax_tuples_per_page
+    pub max_tuples: Option<usize>,
     pub table_name: String,
 }
 
 impl Page {
-    pub fn fill(&mut self, raw: &[u8], table_name: &str, schema: &Schema) {
-        assert!(raw.len() == PAGE_SIZE);
+    // tuple_countが壊れていてpageに収まらない数になっていれば、読まずにerrorにする
+    pub fn fill(&mut self, raw: &[u8], table_name: &str, schema: &Schema) -> StorageResult<()> {
+        if raw.len() != PAGE_SIZE {
+            return Err(anyhow::anyhow!(
+                "corrupted page of {}: {} bytes where {} are expected",
+                table_name,
+                raw.len(),
+                PAGE_SIZE
+            ));
+        }
         self.header.fill(&raw[..PAGE_HEADER_SIZE]);
 
         self.table_name = table_name.to_string();
 
-        let mut v: Vec<Tuple> = Vec::with_capacity(self.header.tuple_count as usize);
-
-        let mut offset = PAGE_HEADER_SIZE;
         let table = &schema.table;
         let tuple_size = table.tuple_size();
+        let capacity = (PAGE_SIZE - PAGE_HEADER_SIZE) / tuple_size;
+        if self.header.tuple_count as usize > capacity {
+            return Err(anyhow::anyhow!(
+                "corrupted page of {}: {} tuples where at most {} fit",
+                table_name,
+                self.header.tuple_count,
+                capacity
+            ));
+        }
 
+        let mut v: Vec<Tuple> = Vec::with_capacity(self.header.tuple_count as usize);
+
+        let mut offset = PAGE_HEADER_SIZE;
         for _ in 0..self.header.tuple_count {
             let mut tuple = Tuple::default();
-            tuple.fill(&raw[offset..(offset + tuple_size)], &table.columns);
+            tuple.fill(
+                &raw[offset..(offset + tuple_size)],
+                table.columns(),
+                table.text_padding,
+                tuple_size,
+            )?;
             v.push(tuple);
             offset += tuple_size;
         }
@@ -36,6 +64,18 @@ impl Page {
         self.body = v;
 
         self.tuple_size = schema.table.tuple_size();
+        self.max_tuples = schema.table.max_tuples_per_page;
+
+        Ok(())
+    }
+
+    // slotの順にtupleを返す。削除済みのものも含む
+    pub fn tuples(&self) -> impl Iterator<Item = &Tuple> {
+        self.body.iter()
+    }
+
+    pub fn live_tuples(&self) -> impl Iterator<Item = &Tuple> {
+        self.tuples().filter(|t| t.header.deleted == 0)
     }
 
     pub fn add_tuple(&mut self, tuple: Tuple) {
@@ -43,19 +83,70 @@ impl Page {
         self.body.push(tuple);
     }
 
-    pub fn raw(&self, schema: &Schema) -> Vec<u8> {
+    pub fn raw(&self, schema: &Schema) -> StorageResult<Vec<u8>> {
         let mut b = vec![];
         b.append(&mut self.header.raw());
 
         for t in &self.body {
-            b.append(&mut t.raw(&schema.table.columns));
+            b.append(&mut t.raw(schema.table.columns(), schema.table.text_padding)?);
         }
 
         if PAGE_SIZE > b.len() {
             b.append(&mut vec![0_u8; PAGE_SIZE - b.len()]);
         }
 
-        b
+        Ok(b)
+    }
+
+    // slotのtupleのcolumnだけを書き換え、updated_atを進める
+    // columnは全て固定長なので、tupleを作り直さずにこのcolumnのbyteだけを上書きする
+    // 書き換わるrawの範囲を返す。walへの記録とmark_dirtyは呼び出し側で行う
+    pub fn update_attribute(
+        &mut self,
+        slot: u32,
+        column: &str,
+        value: AttributeType,
+        schema: &Schema,
+    ) -> Result<Range<usize>, anyhow::Error> {
+        let table = &schema.table;
+        let tuple = self
+            .body
+            .get_mut(slot as usize)
+            .filter(|t| t.header.deleted == 0)
+            .ok_or_else(|| anyhow::anyhow!("no live tuple at slot {}", slot))?;
+
+        let c = table
+            .columns()
+            .iter()
+            .find(|c| c.name == column)
+            .ok_or_else(|| anyhow::anyhow!("{} is not a column of {}", column, table.name))?;
+        let column_type = c
+            .column_type()
+            .ok_or_else(|| anyhow::anyhow!("{} is not defined", c.types))?;
+
+        match (column_type, &value) {
+            (ColumnType::Int, AttributeType::Int(_)) => {}
+            (ColumnType::Text, AttributeType::Text(s)) if s.len() <= MAX_TEXT_LEN => {}
+            (ColumnType::Blob, AttributeType::Blob(b)) if b.len() <= MAX_TEXT_LEN => {}
+            (ColumnType::Numeric { precision, scale }, AttributeType::Decimal(d))
+                if d.scale == scale && d.unscaled.unsigned_abs() < 10_u64.pow(precision) => {}
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "{} cannot be stored in {} {}",
+                    value.to_display(),
+                    column,
+                    c.types
+                ))
+            }
+        }
+
+        let range = tuple
+            .body
+            .set(column, value, table.columns(), table.text_padding)?;
+        tuple.header.touch();
+
+        let offset = PAGE_HEADER_SIZE + table.tuple_size() * slot as usize + TUPLE_HEADER_SIZE;
+        Ok((offset + range.start)..(offset + range.end))
     }
 
     pub fn usage_size(&self) -> usize {
@@ -68,15 +159,30 @@ impl Page {
 
     pub fn can_add_tuple(&self) -> bool {
         self.free_size() > self.tuple_size
+            && self
+                .max_tuples
+                .is_none_or(|max| (self.header.tuple_count as usize) < max)
     }
 }
 
+// 1pageに入るtupleの数。can_add_tupleと同じく、空きがtuple 1つ分より大きい間は追加できる
+pub fn tuples_per_page(table: &Table) -> usize {
+    let by_size = (PAGE_SIZE - PAGE_HEADER_SIZE - 1) / table.tuple_size();
+    table
+        .max_tuples_per_page
+        .map_or(by_size, |max| max.min(by_size))
+}
+
 impl Default for Page {
     fn default() -> Self {
         Self {
             id: PageID(0),
             tuple_size: 0,
-            header: PageHeader { tuple_count: 0 },
+            max_tuples: None,
+            header: PageHeader {
+                tuple_count: 0,
+                lsn: 0,
+            },
             body: Vec::new(),
             table_name: String::new(),
         }
@@ -99,9 +205,11 @@ impl PageID {
 #[derive(Default, Debug)]
 // 32byte
 // tuple_count - 4byte
+// lsn - 8byte (このpageに反映済みの最後のwal record)
 // The remaining bytes are reserved space
 pub struct PageHeader {
     pub tuple_count: u32,
+    pub lsn: Lsn,
 }
 
 impl PageHeader {
@@ -109,12 +217,17 @@ impl PageHeader {
         let mut tuple_count_byte = [0_u8; 4];
         tuple_count_byte.clone_from_slice(&raw[..4]);
         self.tuple_count = u32::from_be_bytes(tuple_count_byte);
+
+        let mut lsn_byte = [0_u8; 8];
+        lsn_byte.clone_from_slice(&raw[4..12]);
+        self.lsn = Lsn::from_be_bytes(lsn_byte);
     }
 
     fn raw(&self) -> Vec<u8> {
         let mut b = vec![];
         b.append(&mut self.tuple_count.to_be_bytes().to_vec());
-        b.append(&mut vec![0_u8; 32 - 4]);
+        b.append(&mut self.lsn.to_be_bytes().to_vec());
+        b.append(&mut vec![0_u8; 32 - 12]);
         b
     }
 }
@@ -143,9 +256,53 @@ mod tests {
         ]
     }"#;
 
+    #[test]
+    fn page_live_tuples() {
+        let c = Catalog::from_json(JSON).unwrap();
+        let table = &c.get_schema_by_table_name("table1").unwrap().table;
+
+        let mut page = Page::default();
+        for i in 0..3 {
+            let mut tuple = Tuple::new();
+            tuple.add_attribute("column_int", AttributeType::Int(i));
+            page.add_tuple(tuple);
+        }
+        page.body[1].header.deleted = 1;
+
+        assert_eq!(page.tuples().count(), 3);
+
+        let live: Vec<Option<AttributeType>> = page
+            .live_tuples()
+            .map(|t| {
+                t.body
+                    .get("column_int", table.columns(), table.text_padding)
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(
+            live,
+            vec![Some(AttributeType::Int(0)), Some(AttributeType::Int(2))]
+        );
+    }
+
+    #[test]
+    fn page_corrupted() {
+        let c = Catalog::from_json(JSON).unwrap();
+        let schema = c.get_schema_by_table_name("table1").unwrap();
+
+        let mut page = Page::default();
+        assert!(page.fill(&[0_u8; 100], "table1", schema).is_err());
+
+        // pageに収まらないtuple_countでも、範囲外を読まずにerrorになる
+        let mut raw = Page::default().raw(schema).unwrap();
+        raw[..4].copy_from_slice(&u32::MAX.to_be_bytes());
+        let e = page.fill(&raw, "table1", schema).unwrap_err();
+        assert!(e.to_string().contains("corrupted page"), "{}", e);
+    }
+
     #[test]
     fn page_serde() {
-        let c = Catalog::from_json(JSON);
+        let c = Catalog::from_json(JSON).unwrap();
         let schema = c.get_schema_by_table_name("table1").unwrap();
 
         let mut page = Page::default();
@@ -153,25 +310,117 @@ mod tests {
         tuple.add_attribute("column_int", AttributeType::Int(1));
         tuple.add_attribute("column_text", AttributeType::Text("text".to_string()));
         page.add_tuple(tuple);
+        page.header.lsn = 42;
 
-        let page_raw = page.raw(schema);
+        let page_raw = page.raw(schema).unwrap();
 
         assert_eq!(PAGE_SIZE, page_raw.len());
 
         let mut page = Page::default();
-        page.fill(&page_raw, "", schema);
+        page.fill(&page_raw, "", schema).unwrap();
 
         assert_eq!(1, page.header.tuple_count);
+        assert_eq!(42, page.header.lsn);
         for b in page.body {
             assert_eq!(0, b.header.deleted);
-            match b.body.attributes.get("column_int").unwrap() {
+            let attributes = b
+                .body
+                .attributes(schema.table.columns(), schema.table.text_padding)
+                .unwrap();
+            match attributes.get("column_int").unwrap() {
                 AttributeType::Int(v) => assert_eq!(*v, 1),
                 _ => panic!("expected int, but"),
             }
-            match b.body.attributes.get("column_text").unwrap() {
+            match attributes.get("column_text").unwrap() {
                 AttributeType::Text(v) => assert_eq!(*v, "text"),
                 _ => panic!("expected text, but"),
             }
         }
     }
+
+    #[test]
+    fn page_update_attribute() {
+        let c = Catalog::from_json(JSON).unwrap();
+        let schema = c.get_schema_by_table_name("table1").unwrap();
+        let tuple_size = schema.table.tuple_size();
+
+        let mut built = Page::default();
+        for i in 0..3 {
+            let mut tuple = Tuple::new();
+            tuple.add_attribute("column_int", AttributeType::Int(i));
+            tuple.add_attribute("column_text", AttributeType::Text(format!("text{}", i)));
+            built.add_tuple(tuple);
+        }
+        built.body[2].header.deleted = 1;
+        let before = built.raw(schema).unwrap();
+
+        // diskから読んだpageのtupleは、byte列を持っている
+        let mut page = Page::default();
+        page.fill(&before, "table1", schema).unwrap();
+
+        let range = page
+            .update_attribute(
+                1,
+                "column_text",
+                AttributeType::Text("updated".to_string()),
+                schema,
+            )
+            .unwrap();
+        assert_eq!(range.len(), 256);
+        let after = page.raw(schema).unwrap();
+
+        // 変わるのはこのcolumnのbyteと、tupleのheaderのupdated_atだけ
+        let header =
+            (PAGE_HEADER_SIZE + tuple_size)..(PAGE_HEADER_SIZE + tuple_size + TUPLE_HEADER_SIZE);
+        for i in 0..PAGE_SIZE {
+            if !range.contains(&i) && !header.contains(&i) {
+                assert_eq!(before[i], after[i], "byte {}", i);
+            }
+        }
+        assert_ne!(before[range.clone()], after[range.clone()]);
+        assert!(page.body[1].header.updated_at > 0);
+
+        let mut reread = Page::default();
+        reread.fill(&after, "table1", schema).unwrap();
+        let attributes = reread.body[1]
+            .body
+            .attributes(schema.table.columns(), schema.table.text_padding)
+            .unwrap();
+        assert_eq!(
+            attributes["column_text"],
+            AttributeType::Text("updated".to_string())
+        );
+        assert_eq!(attributes["column_int"], AttributeType::Int(1));
+
+        // mapで持っているtupleはencodeし直すが、書かれるbyteは同じになる
+        built
+            .update_attribute(
+                1,
+                "column_text",
+                AttributeType::Text("updated".to_string()),
+                schema,
+            )
+            .unwrap();
+        assert_eq!(built.raw(schema).unwrap()[range.clone()], after[range]);
+
+        assert!(page
+            .update_attribute(
+                0,
+                "column_int",
+                AttributeType::Text("1".to_string()),
+                schema
+            )
+            .is_err());
+        assert!(page
+            .update_attribute(0, "nothing", AttributeType::Int(1), schema)
+            .is_err());
+        // 削除済みやslotの外のtupleは書き換えない
+        assert!(page
+            .update_attribute(2, "column_int", AttributeType::Int(1), schema)
+            .is_err());
+        assert!(page
+            .update_attribute(3, "column_int", AttributeType::Int(1), schema)
+            .is_err());
+        assert_eq!(page.raw(schema).unwrap(), after);
+    }
 }
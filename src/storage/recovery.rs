@@ -0,0 +1,519 @@
+use std::collections::HashSet;
+
+use crate::catalog::Catalog;
+
+use super::{
+    disk_manager::DiskManager,
+    page::{Page, PageID},
+    tuple::Tuple,
+    wal::{Wal, WalOperation},
+    StorageResult,
+};
+
+// 最後のcheckpointより後に残っているrecordの数
+// read onlyで起動するときは、ここが0でなければ何もせずに止まる
+pub fn pending(disk_manager: &DiskManager) -> StorageResult<usize> {
+    let records = Wal::new(disk_manager.wal_path()).peek_records()?;
+
+    Ok(records
+        .iter()
+        .rev()
+        .take_while(|r| r.operation != WalOperation::Checkpoint)
+        .count())
+}
+
+// 起動時にwalをredoし、commitもabortもされていないtransactionのinsertとupdateを取り消す
+// pageに記録されたlsnより新しいrecordだけを反映するので、何度実行しても結果は同じになる
+pub fn run(disk_manager: &mut DiskManager, catalog: &Catalog) -> StorageResult<usize> {
+    let mut wal = Wal::new(disk_manager.wal_path());
+    let mut records = wal.records()?;
+    let mut replayed = 0;
+
+    // 最後のcheckpointより前のrecordは全てtable fileに反映済み
+    if let Some(i) = records
+        .iter()
+        .rposition(|r| r.operation == WalOperation::Checkpoint)
+    {
+        records.drain(..=i);
+    }
+
+    let finished: HashSet<_> = records
+        .iter()
+        .filter_map(|r| match r.operation {
+            WalOperation::Commit { txn_id } | WalOperation::Abort { txn_id } => Some(txn_id),
+            _ => None,
+        })
+        .collect();
+
+    // redo
+    for record in &records {
+        let (table_name, page_id, slot) = match &record.operation {
+            WalOperation::Insert {
+                table_name,
+                page_id,
+                slot,
+                ..
+            }
+            | WalOperation::Delete {
+                table_name,
+                page_id,
+                slot,
+                ..
+            }
+            | WalOperation::Update {
+                table_name,
+                page_id,
+                slot,
+                ..
+            } => (table_name, *page_id, *slot),
+            _ => continue,
+        };
+
+        let schema = match catalog.get_schema_by_table_name(table_name) {
+            Some(s) => s,
+            // catalogから消えたtableのrecordは捨てる
+            None => continue,
+        };
+
+        let mut page = read_page(disk_manager, table_name, page_id)?;
+
+        if record.lsn <= page.header.lsn {
+            continue;
+        }
+
+        match &record.operation {
+            WalOperation::Insert { tuple: raw, .. } => {
+                if slot != page.header.tuple_count {
+                    return Err(anyhow::anyhow!(
+                        "wal record {} expects slot {} but {} page {} has {} tuples",
+                        record.lsn,
+                        slot,
+                        table_name,
+                        page_id.value(),
+                        page.header.tuple_count
+                    ));
+                }
+
+                let mut tuple = Tuple::default();
+                tuple.fill(
+                    raw,
+                    schema.table.columns(),
+                    schema.table.text_padding,
+                    schema.table.tuple_size(),
+                )?;
+                page.add_tuple(tuple);
+            }
+            _ => {
+                let tuple = page.body.get_mut(slot as usize).ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "wal record {} touches missing slot {} of {} page {}",
+                        record.lsn,
+                        slot,
+                        table_name,
+                        page_id.value()
+                    )
+                })?;
+                match &record.operation {
+                    WalOperation::Update { after, .. } => tuple.fill(
+                        after,
+                        schema.table.columns(),
+                        schema.table.text_padding,
+                        schema.table.tuple_size(),
+                    )?,
+                    _ => tuple.header.deleted = 1,
+                }
+            }
+        }
+
+        page.header.lsn = record.lsn;
+        disk_manager.write(&page, table_name)?;
+        replayed += 1;
+    }
+
+    // undo
+    // 新しいrecordから順に、updateは前の値に戻し、insertは削除済みにする
+    for record in records.iter().rev() {
+        let (txn_id, table_name, page_id, slot, before) = match &record.operation {
+            WalOperation::Insert {
+                txn_id,
+                table_name,
+                page_id,
+                slot,
+                ..
+            } => (txn_id, table_name, page_id, slot, None),
+            WalOperation::Update {
+                txn_id,
+                table_name,
+                page_id,
+                slot,
+                before,
+                ..
+            } => (txn_id, table_name, page_id, slot, Some(before)),
+            _ => continue,
+        };
+        if finished.contains(txn_id) {
+            continue;
+        }
+        let schema = match catalog.get_schema_by_table_name(table_name) {
+            Some(s) => s,
+            None => continue,
+        };
+
+        let mut page = disk_manager.read(*page_id, table_name)?;
+        if let Some(tuple) = page.body.get_mut(*slot as usize) {
+            match before {
+                Some(before) => {
+                    tuple.fill(
+                        before,
+                        schema.table.columns(),
+                        schema.table.text_padding,
+                        schema.table.tuple_size(),
+                    )?;
+                    disk_manager.write(&page, table_name)?;
+                }
+                None if tuple.header.deleted == 0 => {
+                    tuple.header.deleted = 1;
+                    disk_manager.write(&page, table_name)?;
+                }
+                None => {}
+            }
+        }
+    }
+
+    // 反映した内容をdiskに届けてからlogを消す
+    disk_manager.sync_all()?;
+    wal.truncate()?;
+
+    Ok(replayed)
+}
+
+fn read_page(
+    disk_manager: &mut DiskManager,
+    table_name: &str,
+    page_id: PageID,
+) -> StorageResult<Page> {
+    // allocate_pageの書き込みがdiskに届いていないこともある
+    while disk_manager
+        .last_page_id(table_name)?
+        .is_none_or(|PageID(n)| n < page_id.value())
+    {
+        disk_manager.allocate_page(table_name)?;
+    }
+
+    disk_manager.read(page_id, table_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, fs};
+
+    use crate::{
+        catalog::AttributeType, executor::Executor,
+        storage::buffer_pool_manager::BufferPoolManager, test_util::temp_dir,
+    };
+
+    use super::*;
+
+    const JSON: &str = r#"{
+        "schemas": [
+            {
+                "table": {
+                    "name": "recovery_test",
+                    "columns": [
+                        {
+                            "types": "int",
+                            "name": "column_int"
+                        },
+                        {
+                            "types": "text",
+                            "name": "column_text"
+                        }
+                    ]
+                }
+            }
+        ]
+    }"#;
+
+    #[test]
+    fn recovery_redo_after_crash() {
+        let temp_dir = temp_dir("recovery_redo_after_crash");
+        let base_path = temp_dir.to_str().unwrap().to_string();
+        let catalog = Catalog::from_json(JSON).unwrap();
+        let table_name = "recovery_test";
+
+        {
+            let manager = BufferPoolManager::new(10, base_path.clone(), catalog.clone());
+            let mut executor = Executor::new(manager);
+
+            for n in 0..3 {
+                let mut attributes = HashMap::new();
+                attributes.insert("column_int".to_string(), AttributeType::Int(n));
+                attributes.insert(
+                    "column_text".to_string(),
+                    AttributeType::Text(format!("row{}", n)),
+                );
+                executor.insert(&attributes, table_name).unwrap();
+            }
+            // 落ちたときと同じく、dropで書き出させずに捨てる
+            std::mem::forget(executor);
+        }
+
+        let mut disk_manager = DiskManager::new(base_path.clone(), catalog.clone());
+        let wal = fs::read(disk_manager.wal_path()).unwrap();
+        assert_eq!(3, run(&mut disk_manager, &catalog).unwrap());
+
+        // 同じlogをもう一度redoしても、pageのlsnを見て何もしない
+        fs::write(disk_manager.wal_path(), wal).unwrap();
+        assert_eq!(0, run(&mut disk_manager, &catalog).unwrap());
+
+        let manager = BufferPoolManager::new(10, base_path, catalog);
+        let mut executor = Executor::new(manager);
+        let mut records = Vec::new();
+        executor.scan(table_name, &mut records).unwrap();
+
+        assert_eq!(records.len(), 3);
+        for (n, r) in records.iter().enumerate() {
+            assert_eq!(r["column_int"], AttributeType::Int(n as i32));
+        }
+    }
+
+    #[test]
+    fn recovery_undo_unfinished_transaction() {
+        let temp_dir = temp_dir("recovery_undo_unfinished_transaction");
+        let base_path = temp_dir.to_str().unwrap().to_string();
+        let catalog = Catalog::from_json(JSON).unwrap();
+        let table_name = "recovery_test";
+
+        let mut attributes = HashMap::new();
+        attributes.insert("column_int".to_string(), AttributeType::Int(1));
+        attributes.insert(
+            "column_text".to_string(),
+            AttributeType::Text("text".to_string()),
+        );
+
+        {
+            // pool sizeを1にして、commit前のpageがdiskに書き出されるようにする
+            let manager = BufferPoolManager::new(1, base_path.clone(), catalog.clone());
+            let mut executor = Executor::new(manager);
+
+            executor.insert(&attributes, table_name).unwrap();
+
+            let mut rolled_back = executor.begin();
+            executor
+                .insert_in(&mut rolled_back, &attributes, table_name)
+                .unwrap();
+            executor.rollback(rolled_back).unwrap();
+
+            let mut unfinished = executor.begin();
+            for _ in 0..20 {
+                executor
+                    .insert_in(&mut unfinished, &attributes, table_name)
+                    .unwrap();
+            }
+            // commitせずに落ちる。dropで書き出すと未commitのpageが全てdiskに揃い、redoを通らない
+            std::mem::forget(executor);
+        }
+
+        let mut disk_manager = DiskManager::new(base_path.clone(), catalog.clone());
+        run(&mut disk_manager, &catalog).unwrap();
+
+        let manager = BufferPoolManager::new(10, base_path, catalog);
+        let mut executor = Executor::new(manager);
+        let mut records = Vec::new();
+        executor.scan(table_name, &mut records).unwrap();
+
+        assert_eq!(records.len(), 1);
+    }
+
+    #[test]
+    fn recovery_update() {
+        let temp_dir = temp_dir("recovery_update");
+        let base_path = temp_dir.to_str().unwrap().to_string();
+        let catalog = Catalog::from_json(JSON).unwrap();
+        let table_name = "recovery_test";
+        let text = |s: &str| {
+            let mut attributes = HashMap::new();
+            attributes.insert(
+                "column_text".to_string(),
+                AttributeType::Text(s.to_string()),
+            );
+            attributes
+        };
+
+        let texts = |base_path: &str| {
+            let manager = BufferPoolManager::new(10, base_path.to_string(), catalog.clone());
+            let mut executor = Executor::new(manager);
+            let mut records = Vec::new();
+            executor.scan(table_name, &mut records).unwrap();
+            records
+                .iter()
+                .map(|r| r["column_text"].to_display())
+                .collect::<Vec<_>>()
+        };
+        let expected = vec!["inserted", "committed", "inserted"];
+
+        // commitしたupdateは、pageを書き出す前に落ちてもredoする
+        {
+            let manager = BufferPoolManager::new(1, base_path.clone(), catalog.clone());
+            let mut executor = Executor::new(manager);
+
+            for n in 0..3 {
+                let mut attributes = text("inserted");
+                attributes.insert("column_int".to_string(), AttributeType::Int(n));
+                executor.insert(&attributes, table_name).unwrap();
+            }
+            executor.checkpoint().unwrap();
+
+            let committed = AttributeType::Int(1);
+            executor
+                .update(
+                    &text("committed"),
+                    table_name,
+                    Some(("column_int", &committed)),
+                )
+                .unwrap();
+            std::mem::forget(executor);
+        }
+
+        let mut disk_manager = DiskManager::new(base_path.clone(), catalog.clone());
+        assert_eq!(1, run(&mut disk_manager, &catalog).unwrap());
+        assert_eq!(texts(&base_path), expected);
+
+        // commitしていないupdateは、pageに書き出した後でも前の値に戻す
+        {
+            let manager = BufferPoolManager::new(1, base_path.clone(), catalog.clone());
+            let mut executor = Executor::new(manager);
+
+            let mut unfinished = executor.begin();
+            executor
+                .update_in(&mut unfinished, &text("unfinished"), table_name, None)
+                .unwrap();
+            executor.all_flush().unwrap();
+            std::mem::forget(executor);
+        }
+        assert_eq!(texts(&base_path), vec!["unfinished"; 3]);
+
+        run(&mut disk_manager, &catalog).unwrap();
+        assert_eq!(texts(&base_path), expected);
+    }
+
+    #[test]
+    fn recovery_pending() {
+        let temp_dir = temp_dir("recovery_pending");
+        let base_path = temp_dir.to_str().unwrap().to_string();
+        let catalog = Catalog::from_json(JSON).unwrap();
+
+        let mut attributes = HashMap::new();
+        attributes.insert("column_int".to_string(), AttributeType::Int(1));
+        attributes.insert(
+            "column_text".to_string(),
+            AttributeType::Text("text".to_string()),
+        );
+
+        {
+            let manager = BufferPoolManager::new(10, base_path.clone(), catalog.clone());
+            let mut executor = Executor::new(manager);
+            executor.insert(&attributes, "recovery_test").unwrap();
+            // 落ちたときと同じく、insertをlogにだけ残してpageは書き出さない
+            std::mem::forget(executor);
+        }
+
+        let mut disk_manager = DiskManager::new(base_path, catalog.clone());
+        // insertとcommit
+        assert_eq!(2, pending(&disk_manager).unwrap());
+
+        assert_eq!(1, run(&mut disk_manager, &catalog).unwrap());
+        assert_eq!(0, pending(&disk_manager).unwrap());
+    }
+
+    #[test]
+    fn recovery_after_checkpoint() {
+        let temp_dir = temp_dir("recovery_after_checkpoint");
+        let base_path = temp_dir.to_str().unwrap().to_string();
+        let catalog = Catalog::from_json(JSON).unwrap();
+        let table_name = "recovery_test";
+
+        let mut attributes = HashMap::new();
+        attributes.insert("column_int".to_string(), AttributeType::Int(1));
+        attributes.insert(
+            "column_text".to_string(),
+            AttributeType::Text("text".to_string()),
+        );
+
+        {
+            let manager = BufferPoolManager::new(10, base_path.clone(), catalog.clone());
+            let mut executor = Executor::new(manager);
+
+            for _ in 0..3 {
+                executor.insert(&attributes, table_name).unwrap();
+            }
+            executor.checkpoint().unwrap();
+            for _ in 0..2 {
+                executor.insert(&attributes, table_name).unwrap();
+            }
+            std::mem::forget(executor);
+        }
+
+        let mut disk_manager = DiskManager::new(base_path.clone(), catalog.clone());
+        assert_eq!(2, run(&mut disk_manager, &catalog).unwrap());
+
+        let manager = BufferPoolManager::new(10, base_path, catalog);
+        let mut executor = Executor::new(manager);
+        let mut records = Vec::new();
+        executor.scan(table_name, &mut records).unwrap();
+
+        assert_eq!(records.len(), 5);
+    }
+
+    #[test]
+    fn recovery_after_checkpoint_if_due() {
+        let temp_dir = temp_dir("recovery_after_checkpoint_if_due");
+        let base_path = temp_dir.to_str().unwrap().to_string();
+        let catalog = Catalog::from_json(JSON).unwrap();
+        let table_name = "recovery_test";
+        let wal_path = DiskManager::new(base_path.clone(), catalog.clone()).wal_path();
+
+        let mut attributes = HashMap::new();
+        attributes.insert("column_int".to_string(), AttributeType::Int(1));
+        attributes.insert(
+            "column_text".to_string(),
+            AttributeType::Text("text".to_string()),
+        );
+
+        {
+            let manager = BufferPoolManager::new(10, base_path.clone(), catalog.clone());
+            let mut executor = Executor::new(manager);
+            executor.set_checkpoint_threshold(Some(1000));
+
+            // 開いたままのtransactionがあると、commitの後でもcheckpointしない
+            let txn = executor.begin();
+            for _ in 0..5 {
+                executor.insert(&attributes, table_name).unwrap();
+            }
+            assert!(!executor.checkpoint_if_due().unwrap());
+            executor.rollback(txn).unwrap();
+            let before = fs::metadata(&wal_path).unwrap().len();
+            assert!(before > 1000);
+
+            assert!(executor.checkpoint_if_due().unwrap());
+            assert!(fs::metadata(&wal_path).unwrap().len() < before);
+            assert!(!executor.checkpoint_if_due().unwrap());
+
+            for _ in 0..2 {
+                executor.insert(&attributes, table_name).unwrap();
+            }
+            std::mem::forget(executor);
+        }
+
+        // checkpointより後のinsertとcommitだけをredoする
+        let mut disk_manager = DiskManager::new(base_path.clone(), catalog.clone());
+        assert_eq!(2, run(&mut disk_manager, &catalog).unwrap());
+
+        let manager = BufferPoolManager::new(10, base_path, catalog);
+        let mut executor = Executor::new(manager);
+        let mut records = Vec::new();
+        executor.scan(table_name, &mut records).unwrap();
+
+        assert_eq!(records.len(), 7);
+    }
+}
@@ -6,6 +6,8 @@ pub trait Replacer {
     fn victim(&mut self) -> Option<DescriptorID>;
     fn pin(&mut self, descriptor_id: DescriptorID);
     fn unpin(&mut self, descriptor_id: DescriptorID);
+    // 設定の表示に使う名前
+    fn name(&self) -> &'static str;
 }
 
 pub struct LruReplacer {
@@ -36,6 +38,10 @@ impl Replacer for LruReplacer {
     fn unpin(&mut self, descriptor_id: DescriptorID) {
         self.cache.lock().unwrap().put(descriptor_id, true);
     }
+
+    fn name(&self) -> &'static str {
+        "lru"
+    }
 }
 
 #[cfg(test)]
@@ -1,5 +1,10 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    ops::Range,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
+use super::StorageResult;
 use crate::catalog::*;
 
 pub const TUPLE_HEADER_SIZE: usize = 8;
@@ -13,122 +18,610 @@ pub struct Tuple {
 impl Tuple {
     pub fn new() -> Self {
         Self {
-            header: TupleHeader { deleted: 0 },
+            header: TupleHeader {
+                deleted: 0,
+                updated_at: 0,
+            },
             body: Default::default(),
         }
     }
 
-    pub fn fill(&mut self, raw: &[u8], columns: &[Column]) {
+    // diskやwalから読んだbyte列が短くても、sliceの範囲外で止まらないよう先に長さを確かめる
+    // sizeはtupleごとに数え直さないよう、Table::tuple_sizeを渡す
+    pub fn fill(
+        &mut self,
+        raw: &[u8],
+        columns: &[Column],
+        padding: TextPadding,
+        size: usize,
+    ) -> StorageResult<()> {
+        if raw.len() < size {
+            return Err(anyhow::anyhow!(
+                "corrupted tuple: {} bytes where {} are expected",
+                raw.len(),
+                size
+            ));
+        }
+
         self.header.fill(&raw[..TUPLE_HEADER_SIZE]);
-        self.body.fill(&raw[TUPLE_HEADER_SIZE..], columns);
+        self.body.fill(&raw[TUPLE_HEADER_SIZE..], columns, padding)
     }
 
+    // 組み立てているtupleに値を足す。encodeした後やpageから読んだtupleには使わない
     pub fn add_attribute(&mut self, name: &str, types: AttributeType) {
         self.body.attributes.insert(name.to_string(), types);
     }
 
-    pub fn raw(&self, columns: &[Column]) -> Vec<u8> {
+    // 足りないcolumnや型の合わない値があれば、pageに入れる前にここで失敗する
+    pub fn encode(&mut self, columns: &[Column], padding: TextPadding) -> StorageResult<()> {
+        self.body.encode(columns, padding)
+    }
+
+    pub fn raw(&self, columns: &[Column], padding: TextPadding) -> StorageResult<Vec<u8>> {
         let mut b = vec![];
         b.append(&mut self.header.raw());
-        b.append(&mut self.body.raw(columns));
+        b.append(&mut self.body.raw(columns, padding)?);
 
-        b
+        Ok(b)
     }
 }
 
 #[derive(Default, Debug)]
 // 8byte
 // deleted - 1byte
+// 空き - 1byte
+// updated_at - 6byte
 pub struct TupleHeader {
     pub deleted: u8,
+    // 最後にinsertかupdateした時刻。unix epochからのmillisecondで、0は不明
+    pub updated_at: u64,
 }
 
+// 6byteに収まるmillisecond
+const UPDATED_AT_BYTES: usize = 6;
+
 impl TupleHeader {
+    // 書き込んだ時刻にする。時計が戻っても、前の値より必ず大きくする
+    pub fn touch(&mut self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        self.updated_at = now.max(self.updated_at + 1);
+    }
+
     fn fill(&mut self, raw: &[u8]) {
         let mut deleted_byte = [0_u8; 1];
         deleted_byte.clone_from_slice(&raw[..1]);
         self.deleted = u8::from_be_bytes(deleted_byte);
+
+        let mut updated_at_bytes = [0_u8; 8];
+        updated_at_bytes[(8 - UPDATED_AT_BYTES)..].clone_from_slice(&raw[2..8]);
+        self.updated_at = u64::from_be_bytes(updated_at_bytes);
     }
 
     fn raw(&self) -> Vec<u8> {
         let deleted_byte = self.deleted.to_be_bytes().to_vec();
-        let padding = vec![0_u8; 8 - deleted_byte.len()];
+        let padding = vec![0_u8; 8 - deleted_byte.len() - UPDATED_AT_BYTES];
+        let updated_at_bytes = self.updated_at.to_be_bytes()[(8 - UPDATED_AT_BYTES)..].to_vec();
 
-        [deleted_byte, padding].concat()
+        [deleted_byte, padding, updated_at_bytes].concat()
     }
 }
 
 #[derive(Default, Debug)]
 pub struct TupleBody {
-    pub attributes: HashMap<String, AttributeType>,
+    // insertで組み立てた値。encodeするとrawに移り、空になる
+    attributes: HashMap<String, AttributeType>,
+    // columnの並びどおりのbyte列。pageやwalから読んだtupleはmapを作らずにこれだけを持つ
+    raw: Option<Vec<u8>>,
+}
+
+// byte列から読んだままの値。mapに入れるときに初めて所有する値にする
+enum RawValue<'a> {
+    Int(i32),
+    Text(&'a str),
+    Blob(&'a [u8]),
+    Decimal(Decimal),
+}
+
+impl RawValue<'_> {
+    fn to_attribute(&self) -> AttributeType {
+        match self {
+            RawValue::Int(v) => AttributeType::Int(*v),
+            RawValue::Text(s) => AttributeType::Text(s.to_string()),
+            RawValue::Blob(b) => AttributeType::Blob(b.to_vec()),
+            RawValue::Decimal(d) => AttributeType::Decimal(*d),
+        }
+    }
+
+    // AttributeTypeと同じく、intとnumericは数として比べる
+    fn eq_attribute(&self, value: &AttributeType) -> bool {
+        match (self, value) {
+            (RawValue::Text(a), AttributeType::Text(b)) => a == b,
+            (RawValue::Blob(a), AttributeType::Blob(b)) => a == b,
+            (RawValue::Int(a), b) => AttributeType::Int(*a) == *b,
+            (RawValue::Decimal(a), b) => AttributeType::Decimal(*a) == *b,
+            _ => false,
+        }
+    }
+}
+
+// columnの定義と、tupleのbodyの中でその値が始まる位置
+fn find_column<'a>(columns: &'a [Column], column: &str) -> Option<(&'a Column, usize)> {
+    let mut offset = 0;
+    for c in columns {
+        if c.name == column {
+            return Some((c, offset));
+        }
+        offset += c.column_type()?.size();
+    }
+
+    None
+}
+
+// 1つのcolumnのbyte列を読む。textは長さのbyteの内側だけをutf-8として確かめる
+fn read_column<'a>(raw: &'a [u8], c: &Column, padding: TextPadding) -> StorageResult<RawValue<'a>> {
+    let value = match c.column_type() {
+        Some(ColumnType::Int) => {
+            let mut bytes = [0_u8; 4];
+            bytes.clone_from_slice(&raw[..4]);
+            RawValue::Int(i32::from_be_bytes(bytes))
+        }
+        // 先頭1byteの長さが正で、残りの255byteはpaddingで埋めてある
+        Some(ColumnType::Text) => {
+            let length = raw[0] as usize;
+            let s = std::str::from_utf8(&raw[1..(1 + length)])
+                .map_err(|e| anyhow::anyhow!("corrupted tuple: {} is not utf-8: {}", c.name, e))?;
+            // 書いた値は埋め草で終わらないので、落ちるのは長さが壊れて混ざった分だけ
+            RawValue::Text(padding.trim(s))
+        }
+        // textと同じ並びだが、中身を確かめずにそのまま返す
+        Some(ColumnType::Blob) => {
+            let length = raw[0] as usize;
+            RawValue::Blob(&raw[1..(1 + length)])
+        }
+        Some(ColumnType::Numeric { scale, .. }) => {
+            let mut bytes = [0_u8; 8];
+            bytes.clone_from_slice(&raw[..8]);
+            RawValue::Decimal(Decimal {
+                unscaled: i64::from_be_bytes(bytes),
+                scale,
+            })
+        }
+        // catalogで弾いているので、ここに来るのはcatalogとfileが食い違うときだけ
+        None => {
+            return Err(anyhow::anyhow!(
+                "{} of column {} is not a known type",
+                c.types,
+                c.name
+            ))
+        }
+    };
+
+    Ok(value)
+}
+
+// columnに合う値を、columnの大きさのbyte列にする
+fn write_column(
+    c: &Column,
+    value: Option<&AttributeType>,
+    padding: TextPadding,
+) -> StorageResult<Vec<u8>> {
+    let types = value
+        .and_then(|t| match (c.column_type()?, t) {
+            (ColumnType::Int, AttributeType::Int(_)) => Some(t),
+            (ColumnType::Text, AttributeType::Text(_)) => Some(t),
+            (ColumnType::Blob, AttributeType::Blob(_)) => Some(t),
+            // scaleはcatalog側で決まるので、違うscaleの値は書かない
+            (ColumnType::Numeric { scale, .. }, AttributeType::Decimal(d)) if d.scale == scale => {
+                Some(t)
+            }
+            _ => None,
+        })
+        .ok_or_else(|| anyhow::anyhow!("no {} value for column {}", c.types, c.name))?;
+
+    let mut bytes = vec![];
+    match types {
+        AttributeType::Int(v) => {
+            let mut b = v.to_be_bytes().to_vec();
+            bytes.append(&mut b);
+        }
+        AttributeType::Text(v) => {
+            let len = v.len();
+            let mut len_byte = [len as u8].to_vec();
+            bytes.append(&mut len_byte);
+            let mut str_bytes = v.as_bytes().to_vec();
+            bytes.append(&mut str_bytes);
+            bytes.append(&mut vec![padding.byte(); 255 - len]);
+        }
+        AttributeType::Decimal(v) => {
+            let mut b = v.unscaled.to_be_bytes().to_vec();
+            bytes.append(&mut b);
+        }
+        AttributeType::Blob(v) => {
+            bytes.push(v.len() as u8);
+            bytes.extend_from_slice(v);
+            bytes.append(&mut vec![0_u8; 255 - v.len()]);
+        }
+    }
+
+    Ok(bytes)
 }
 
 impl TupleBody {
-    fn fill(&mut self, raw: &[u8], columns: &[Column]) {
-        let mut offset = 0;
-        for c in columns {
-            let t = match c.types.as_str() {
-                "int" => {
-                    let mut bytes = [0_u8; 4];
-                    bytes.clone_from_slice(&raw[offset..(offset + 4)]);
-                    let num = i32::from_be_bytes(bytes);
-                    offset += 4;
-                    AttributeType::Int(num)
-                }
-                "text" => {
-                    let mut length_bytes = [0_u8; 1];
-                    length_bytes.clone_from_slice(&raw[offset..(offset + 1)]);
-                    let length = u8::from_be_bytes(length_bytes);
-                    let mut str_bytes = [0_u8; 255];
-                    str_bytes.copy_from_slice(&raw[(offset + 1)..(offset + 256)]);
-                    let str_bytes = &str_bytes[..(length as usize)];
-                    let str = String::from_utf8(str_bytes.to_vec()).unwrap();
-                    offset += 256;
-                    AttributeType::Text(str)
-                }
-                s => panic!("{} is not defined", s),
-            };
-            self.attributes.insert(c.name.clone(), t);
-        }
-    }
-
-    fn raw(&self, columns: &[Column]) -> Vec<u8> {
-        let mut bytes = vec![];
+    // scanで結果に取り出す前に、columnのbyteだけを読んで比べる。一致しないtupleのmapは作らない
+    pub fn matches(
+        &self,
+        column: &str,
+        value: &AttributeType,
+        columns: &[Column],
+        padding: TextPadding,
+    ) -> bool {
+        let raw = match &self.raw {
+            Some(raw) => raw,
+            None => return self.attributes.get(column) == Some(value),
+        };
 
-        for c in columns {
-            let types = self
-                .attributes
-                .get(&c.name)
-                .and_then(|t| match c.types.as_str() {
-                    "int" => match &t {
-                        AttributeType::Int(_) => Some(t),
-                        _ => None,
-                    },
-                    "text" => match &t {
-                        AttributeType::Text(_) => Some(t),
-                        _ => None,
-                    },
-                    _ => None,
-                })
-                .unwrap();
-
-            match types {
-                AttributeType::Int(v) => {
-                    let mut b = v.to_be_bytes().to_vec();
-                    bytes.append(&mut b);
-                }
-                AttributeType::Text(v) => {
-                    let len = v.len();
-                    let mut len_byte = [len as u8].to_vec();
-                    bytes.append(&mut len_byte);
-                    let mut str_bytes = v.as_bytes().to_vec();
-                    bytes.append(&mut str_bytes);
-                    let mut padding = vec![0_u8; 255 - len];
-                    bytes.append(&mut padding);
-                }
+        find_column(columns, column)
+            .and_then(|(c, offset)| read_column(&raw[offset..], c, padding).ok())
+            .is_some_and(|v| v.eq_attribute(value))
+    }
+
+    // 1つのcolumnの値だけを取り出す。tupleにないcolumnならNone
+    pub fn get(
+        &self,
+        column: &str,
+        columns: &[Column],
+        padding: TextPadding,
+    ) -> StorageResult<Option<AttributeType>> {
+        let raw = match &self.raw {
+            Some(raw) => raw,
+            None => return Ok(self.attributes.get(column).cloned()),
+        };
+
+        match find_column(columns, column) {
+            Some((c, offset)) => Ok(Some(
+                read_column(&raw[offset..], c, padding)?.to_attribute(),
+            )),
+            None => Ok(None),
+        }
+    }
+
+    // 全てのcolumnの値をmapにして返す
+    pub fn attributes(
+        &self,
+        columns: &[Column],
+        padding: TextPadding,
+    ) -> StorageResult<HashMap<String, AttributeType>> {
+        let raw = match &self.raw {
+            Some(raw) => raw,
+            None => return Ok(self.attributes.clone()),
+        };
+
+        let mut attributes = HashMap::with_capacity(columns.len());
+        for (c, offset) in columns.iter().zip(Self::offsets(columns)) {
+            attributes.insert(
+                c.name.clone(),
+                read_column(&raw[offset..], c, padding)?.to_attribute(),
+            );
+        }
+
+        Ok(attributes)
+    }
+
+    // 1つのcolumnの値を書き換え、bodyの中で書き換わるbyteの範囲を返す
+    // byte列を持つtupleは、そのcolumnのbyteだけを上書きする
+    // mapで持っているtupleは値を入れ替えるだけで、pageを書くときにtuple全体をencodeする
+    pub fn set(
+        &mut self,
+        column: &str,
+        value: AttributeType,
+        columns: &[Column],
+        padding: TextPadding,
+    ) -> StorageResult<Range<usize>> {
+        let (c, offset) = find_column(columns, column)
+            .ok_or_else(|| anyhow::anyhow!("{} is not a column", column))?;
+        let bytes = write_column(c, Some(&value), padding)?;
+        let range = offset..(offset + bytes.len());
+
+        match &mut self.raw {
+            Some(raw) => raw[range.clone()].copy_from_slice(&bytes),
+            None => {
+                self.attributes.insert(column.to_string(), value);
             }
         }
 
-        bytes
+        Ok(range)
+    }
+
+    // 組み立てた値をbyte列にし、以後はpageやwalから読んだtupleと同じに扱う
+    fn encode(&mut self, columns: &[Column], padding: TextPadding) -> StorageResult<()> {
+        if self.raw.is_none() {
+            self.raw = Some(self.raw(columns, padding)?);
+            self.attributes.clear();
+        }
+
+        Ok(())
+    }
+
+    // 各columnが始まる位置
+    fn offsets(columns: &[Column]) -> impl Iterator<Item = usize> + '_ {
+        columns.iter().scan(0, |offset, c| {
+            let start = *offset;
+            *offset += c.column_type().map_or(0, |t| t.size());
+            Some(start)
+        })
+    }
+
+    // mapは作らず、長さとutf-8だけを確かめてbyte列を持つ
+    fn fill(&mut self, raw: &[u8], columns: &[Column], padding: TextPadding) -> StorageResult<()> {
+        for (c, offset) in columns.iter().zip(Self::offsets(columns)) {
+            read_column(&raw[offset..], c, padding)?;
+        }
+        let size: usize = columns
+            .iter()
+            .map(|c| c.column_type().map_or(0, |t| t.size()))
+            .sum();
+
+        self.attributes.clear();
+        self.raw = Some(raw[..size].to_vec());
+
+        Ok(())
+    }
+
+    fn raw(&self, columns: &[Column], padding: TextPadding) -> StorageResult<Vec<u8>> {
+        if let Some(raw) = &self.raw {
+            return Ok(raw.clone());
+        }
+
+        let mut bytes = vec![];
+        for c in columns {
+            bytes.append(&mut write_column(c, self.attributes.get(&c.name), padding)?);
+        }
+
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn size(columns: &[Column]) -> usize {
+        TUPLE_HEADER_SIZE
+            + columns
+                .iter()
+                .map(|c| c.column_type().unwrap().size())
+                .sum::<usize>()
+    }
+
+    #[test]
+    fn tuple_text_padding() {
+        let columns = vec![Column {
+            types: "text".to_string(),
+            name: "column_text".to_string(),
+        }];
+        let read = |raw: &[u8], padding| {
+            let mut t = Tuple::default();
+            t.fill(raw, &columns, padding, size(&columns)).unwrap();
+            t.body
+                .get("column_text", &columns, padding)
+                .unwrap()
+                .unwrap()
+        };
+
+        for (padding, byte) in [(TextPadding::Null, 0_u8), (TextPadding::Space, b' ')] {
+            let mut tuple = Tuple::new();
+            tuple.add_attribute("column_text", AttributeType::Text("hoge".to_string()));
+            let mut raw = tuple.raw(&columns, padding).unwrap();
+            assert_eq!(raw[TUPLE_HEADER_SIZE + 1 + 4..], [byte; 251]);
+
+            // 長さのbyteを実際より大きくしても、埋め草は混ざらない
+            raw[TUPLE_HEADER_SIZE] = 6;
+            assert_eq!(read(&raw, padding), AttributeType::Text("hoge".to_string()));
+        }
+
+        // 0で埋めるときは、値の後ろの空白を残す
+        let mut tuple = Tuple::new();
+        tuple.add_attribute("column_text", AttributeType::Text("hoge ".to_string()));
+        let raw = tuple.raw(&columns, TextPadding::Null).unwrap();
+        assert_eq!(
+            read(&raw, TextPadding::Null),
+            AttributeType::Text("hoge ".to_string())
+        );
+    }
+
+    #[test]
+    fn tuple_short_raw() {
+        let columns = vec![
+            Column {
+                types: "int".to_string(),
+                name: "id".to_string(),
+            },
+            Column {
+                types: "text".to_string(),
+                name: "name".to_string(),
+            },
+        ];
+
+        let mut tuple = Tuple::new();
+        tuple.add_attribute("id", AttributeType::Int(1));
+        tuple.add_attribute("name", AttributeType::Text("hoge".to_string()));
+        let raw = tuple.raw(&columns, TextPadding::Null).unwrap();
+
+        // 途中で切れたbyte列でもpanicせずにerrorを返す
+        for len in [
+            0,
+            TUPLE_HEADER_SIZE - 1,
+            TUPLE_HEADER_SIZE + 2,
+            raw.len() - 1,
+        ] {
+            let mut t = Tuple::default();
+            let e = t
+                .fill(&raw[..len], &columns, TextPadding::Null, size(&columns))
+                .unwrap_err();
+            assert!(e.to_string().contains("corrupted tuple"), "{}", e);
+        }
+
+        let mut broken = raw.clone();
+        broken[TUPLE_HEADER_SIZE + 4 + 1] = 0xff;
+        assert!(Tuple::default()
+            .fill(&broken, &columns, TextPadding::Null, size(&columns))
+            .is_err());
+
+        let mut t = Tuple::default();
+        t.fill(&raw, &columns, TextPadding::Null, size(&columns))
+            .unwrap();
+        assert_eq!(
+            t.body.get("id", &columns, TextPadding::Null).unwrap(),
+            Some(AttributeType::Int(1))
+        );
+
+        // 知らない型の列や、列に合う値がないときもpanicしない
+        let mut unknown = columns.clone();
+        unknown[1].types = "float".to_string();
+        assert!(Tuple::default()
+            .fill(&raw, &unknown, TextPadding::Null, raw.len())
+            .is_err());
+        assert!(tuple.raw(&unknown, TextPadding::Null).is_err());
+        let mut missing = Tuple::new();
+        missing.add_attribute("id", AttributeType::Int(1));
+        assert!(missing.raw(&columns, TextPadding::Null).is_err());
+    }
+
+    #[test]
+    fn tuple_matches_raw() {
+        let columns = vec![
+            Column {
+                types: "int".to_string(),
+                name: "id".to_string(),
+            },
+            Column {
+                types: "text".to_string(),
+                name: "name".to_string(),
+            },
+        ];
+
+        let mut tuple = Tuple::new();
+        tuple.add_attribute("id", AttributeType::Int(7));
+        tuple.add_attribute("name", AttributeType::Text("hoge".to_string()));
+        let raw = tuple.raw(&columns, TextPadding::Space).unwrap();
+
+        // pageから読んだtupleはbyte列だけを持ち、そのまま比べられる
+        let mut t = Tuple::default();
+        t.fill(&raw, &columns, TextPadding::Space, size(&columns))
+            .unwrap();
+        let matches = |column, value: AttributeType| {
+            t.body.matches(column, &value, &columns, TextPadding::Space)
+        };
+        assert!(matches("id", AttributeType::Int(7)));
+        assert!(!matches("id", AttributeType::Int(8)));
+        assert!(matches("name", AttributeType::Text("hoge".to_string())));
+        assert!(!matches("name", AttributeType::Text("hoge ".to_string())));
+        // 型の違う値やない列には一致しない
+        assert!(!matches("id", AttributeType::Text("7".to_string())));
+        assert!(!matches("nothing", AttributeType::Int(7)));
+    }
+
+    #[test]
+    fn tuple_updated_at() {
+        let columns = vec![Column {
+            types: "int".to_string(),
+            name: "id".to_string(),
+        }];
+
+        let mut tuple = Tuple::new();
+        tuple.add_attribute("id", AttributeType::Int(1));
+        tuple.header.touch();
+        let inserted_at = tuple.header.updated_at;
+        assert!(inserted_at > 0);
+
+        let mut t = Tuple::default();
+        t.fill(
+            &tuple.raw(&columns, TextPadding::Null).unwrap(),
+            &columns,
+            TextPadding::Null,
+            size(&columns),
+        )
+        .unwrap();
+        assert_eq!(t.header.updated_at, inserted_at);
+        assert_eq!(t.header.deleted, 0);
+
+        // 同じmillisecondのうちに書き直しても変わる
+        t.header.touch();
+        assert!(t.header.updated_at > inserted_at);
+        t.header.deleted = 1;
+        let mut reread = Tuple::default();
+        reread
+            .fill(
+                &t.raw(&columns, TextPadding::Null).unwrap(),
+                &columns,
+                TextPadding::Null,
+                size(&columns),
+            )
+            .unwrap();
+        assert_eq!(reread.header.updated_at, t.header.updated_at);
+        assert_eq!(reread.header.deleted, 1);
+    }
+
+    #[test]
+    fn tuple_numeric_round_trip() {
+        let columns = vec![
+            Column {
+                types: "numeric(10,2)".to_string(),
+                name: "price".to_string(),
+            },
+            Column {
+                types: "int".to_string(),
+                name: "id".to_string(),
+            },
+        ];
+
+        let mut tuple = Tuple::new();
+        let price = AttributeType::from_str_typed("numeric(10,2)", "-19.99").unwrap();
+        tuple.add_attribute("price", price.clone());
+        tuple.add_attribute("id", AttributeType::Int(1));
+        let raw = tuple.raw(&columns, TextPadding::Null).unwrap();
+        assert_eq!(raw.len(), TUPLE_HEADER_SIZE + 8 + 4);
+
+        let mut t = Tuple::default();
+        t.fill(&raw, &columns, TextPadding::Null, size(&columns))
+            .unwrap();
+
+        let attributes = t.body.attributes(&columns, TextPadding::Null).unwrap();
+        assert_eq!(attributes["price"], price);
+        assert_eq!(attributes["price"].to_display(), "-19.99");
+        assert_eq!(attributes["id"], AttributeType::Int(1));
+    }
+
+    #[test]
+    fn tuple_blob_round_trip() {
+        let columns = vec![
+            Column {
+                types: "blob".to_string(),
+                name: "data".to_string(),
+            },
+            Column {
+                types: "blob".to_string(),
+                name: "empty".to_string(),
+            },
+        ];
+
+        // utf-8ではないbyteや、末尾の0もそのまま戻る
+        let data = vec![0xde, 0xad, 0xbe, 0xef, 0xff, 0xc3, 0x28, 0x00];
+        assert!(String::from_utf8(data.clone()).is_err());
+        let mut tuple = Tuple::new();
+        tuple.add_attribute("data", AttributeType::Blob(data.clone()));
+        tuple.add_attribute("empty", AttributeType::Blob(Vec::new()));
+        let raw = tuple.raw(&columns, TextPadding::Null).unwrap();
+        assert_eq!(raw.len(), TUPLE_HEADER_SIZE + 256 * 2);
+
+        let mut t = Tuple::default();
+        t.fill(&raw, &columns, TextPadding::Null, size(&columns))
+            .unwrap();
+        let attributes = t.body.attributes(&columns, TextPadding::Null).unwrap();
+        assert_eq!(attributes["data"], AttributeType::Blob(data));
+        assert_eq!(attributes["empty"], AttributeType::Blob(Vec::new()));
+        assert_eq!(attributes["data"].to_display(), "\\xdeadbeefffc32800");
     }
 }
@@ -0,0 +1,601 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufWriter, ErrorKind, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use super::{page::PageID, StorageResult};
+
+pub type Lsn = u64;
+pub type TxnID = u64;
+
+const KIND_INSERT: u8 = 1;
+const KIND_CHECKPOINT: u8 = 2;
+const KIND_DELETE: u8 = 3;
+const KIND_COMMIT: u8 = 4;
+const KIND_ABORT: u8 = 5;
+const KIND_UPDATE: u8 = 6;
+
+// commit時にwalをどこまで書くか
+// Syncはfsyncまで待つ。BufferedはOSに渡すだけなので、電源断では直前のcommitが失われうる
+// Groupは複数のcommitをまとめて1回fsyncする。失われうるのは最後のfsyncからinterval以内のcommitだけ
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Durability {
+    Sync,
+    Buffered,
+    Group(GroupCommit),
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct GroupCommit {
+    // 最後のfsyncからこれだけ経ったら、次のcommitか定期的な確認でfsyncする
+    pub interval: Duration,
+    // fsyncを待っているcommitがこれだけたまったらfsyncする
+    pub max_commits: usize,
+}
+
+impl Default for GroupCommit {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_millis(10),
+            max_commits: 16,
+        }
+    }
+}
+
+impl Durability {
+    pub fn parse(s: &str) -> Result<Self, anyhow::Error> {
+        match s {
+            "sync" => Ok(Durability::Sync),
+            "buffered" => Ok(Durability::Buffered),
+            "group" => Ok(Durability::Group(GroupCommit::default())),
+            _ => Err(anyhow::anyhow!(
+                "unknown durability {} (expected sync, buffered or group)",
+                s
+            )),
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Durability::Sync => "sync",
+            Durability::Buffered => "buffered",
+            Durability::Group(_) => "group",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum WalOperation {
+    Insert {
+        txn_id: TxnID,
+        table_name: String,
+        page_id: PageID,
+        slot: u32,
+        tuple: Vec<u8>,
+    },
+    // tupleのdeleted flagを立てる
+    Delete {
+        txn_id: TxnID,
+        table_name: String,
+        page_id: PageID,
+        slot: u32,
+    },
+    // slotのtupleを書き換える。beforeとafterはheaderも含めたtuple全体のbyte列
+    Update {
+        txn_id: TxnID,
+        table_name: String,
+        page_id: PageID,
+        slot: u32,
+        before: Vec<u8>,
+        after: Vec<u8>,
+    },
+    Commit {
+        txn_id: TxnID,
+    },
+    // rollbackが最後まで終わったことを表す
+    Abort {
+        txn_id: TxnID,
+    },
+    Checkpoint,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct WalRecord {
+    pub lsn: Lsn,
+    pub operation: WalOperation,
+}
+
+// record
+// length - 4byte (lsn以降のbyte数)
+// lsn - 8byte
+// kind - 1byte
+// checkpoint以外はtxn_id - 8byteが続く
+// insert: table_name length - 2byte, table_name, page_id - 8byte, slot - 4byte, tuple length - 4byte, tuple
+// delete: table_name length - 2byte, table_name, page_id - 8byte, slot - 4byte
+// update: table_name length - 2byte, table_name, page_id - 8byte, slot - 4byte, tuple length - 4byte, before, after
+impl WalRecord {
+    fn raw(&self) -> Vec<u8> {
+        let mut body = vec![];
+        body.append(&mut self.lsn.to_be_bytes().to_vec());
+
+        match &self.operation {
+            WalOperation::Insert {
+                txn_id,
+                table_name,
+                page_id,
+                slot,
+                tuple,
+            } => {
+                body.push(KIND_INSERT);
+                body.append(&mut txn_id.to_be_bytes().to_vec());
+                append_location(&mut body, table_name, *page_id, *slot);
+                body.append(&mut (tuple.len() as u32).to_be_bytes().to_vec());
+                body.append(&mut tuple.clone());
+            }
+            WalOperation::Delete {
+                txn_id,
+                table_name,
+                page_id,
+                slot,
+            } => {
+                body.push(KIND_DELETE);
+                body.append(&mut txn_id.to_be_bytes().to_vec());
+                append_location(&mut body, table_name, *page_id, *slot);
+            }
+            WalOperation::Update {
+                txn_id,
+                table_name,
+                page_id,
+                slot,
+                before,
+                after,
+            } => {
+                body.push(KIND_UPDATE);
+                body.append(&mut txn_id.to_be_bytes().to_vec());
+                append_location(&mut body, table_name, *page_id, *slot);
+                body.append(&mut (before.len() as u32).to_be_bytes().to_vec());
+                body.append(&mut before.clone());
+                body.append(&mut after.clone());
+            }
+            WalOperation::Commit { txn_id } => {
+                body.push(KIND_COMMIT);
+                body.append(&mut txn_id.to_be_bytes().to_vec());
+            }
+            WalOperation::Abort { txn_id } => {
+                body.push(KIND_ABORT);
+                body.append(&mut txn_id.to_be_bytes().to_vec());
+            }
+            WalOperation::Checkpoint => body.push(KIND_CHECKPOINT),
+        }
+
+        let mut b = (body.len() as u32).to_be_bytes().to_vec();
+        b.append(&mut body);
+        b
+    }
+
+    fn fill(raw: &[u8]) -> Option<Self> {
+        let mut reader = ByteReader { raw, offset: 0 };
+
+        let lsn = reader.take_u64()?;
+        let kind = reader.take(1)?[0];
+
+        let operation = match kind {
+            KIND_INSERT => {
+                let txn_id = reader.take_u64()?;
+                let (table_name, page_id, slot) = reader.take_location()?;
+                let len = reader.take_u32()? as usize;
+                let tuple = reader.take(len)?.to_vec();
+                WalOperation::Insert {
+                    txn_id,
+                    table_name,
+                    page_id,
+                    slot,
+                    tuple,
+                }
+            }
+            KIND_DELETE => {
+                let txn_id = reader.take_u64()?;
+                let (table_name, page_id, slot) = reader.take_location()?;
+                WalOperation::Delete {
+                    txn_id,
+                    table_name,
+                    page_id,
+                    slot,
+                }
+            }
+            KIND_UPDATE => {
+                let txn_id = reader.take_u64()?;
+                let (table_name, page_id, slot) = reader.take_location()?;
+                let len = reader.take_u32()? as usize;
+                let before = reader.take(len)?.to_vec();
+                let after = reader.take(len)?.to_vec();
+                WalOperation::Update {
+                    txn_id,
+                    table_name,
+                    page_id,
+                    slot,
+                    before,
+                    after,
+                }
+            }
+            KIND_COMMIT => WalOperation::Commit {
+                txn_id: reader.take_u64()?,
+            },
+            KIND_ABORT => WalOperation::Abort {
+                txn_id: reader.take_u64()?,
+            },
+            KIND_CHECKPOINT => WalOperation::Checkpoint,
+            _ => return None,
+        };
+
+        Some(Self { lsn, operation })
+    }
+}
+
+fn append_location(body: &mut Vec<u8>, table_name: &str, page_id: PageID, slot: u32) {
+    body.append(&mut (table_name.len() as u16).to_be_bytes().to_vec());
+    body.append(&mut table_name.as_bytes().to_vec());
+    body.append(&mut (page_id.value() as u64).to_be_bytes().to_vec());
+    body.append(&mut slot.to_be_bytes().to_vec());
+}
+
+struct ByteReader<'a> {
+    raw: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        let b = self.raw.get(self.offset..(self.offset + n))?;
+        self.offset += n;
+        Some(b)
+    }
+
+    fn take_u32(&mut self) -> Option<u32> {
+        Some(u32::from_be_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn take_u64(&mut self) -> Option<u64> {
+        Some(u64::from_be_bytes(self.take(8)?.try_into().ok()?))
+    }
+
+    fn take_location(&mut self) -> Option<(String, PageID, u32)> {
+        let len = u16::from_be_bytes(self.take(2)?.try_into().ok()?) as usize;
+        let table_name = String::from_utf8(self.take(len)?.to_vec()).ok()?;
+        let page_id = PageID(self.take_u64()? as usize);
+        let slot = self.take_u32()?;
+        Some((table_name, page_id, slot))
+    }
+}
+
+pub struct Wal {
+    path: String,
+    writer: Option<BufWriter<File>>,
+    next_lsn: Lsn,
+    flushed_lsn: Lsn,
+    // fsyncの回数と、最後のfsyncの後に来たcommitの数
+    syncs: u64,
+    unsynced_commits: usize,
+    last_sync: Instant,
+}
+
+impl Wal {
+    pub fn new(path: String) -> Self {
+        Self {
+            path,
+            writer: None,
+            next_lsn: 1,
+            flushed_lsn: 0,
+            syncs: 0,
+            unsynced_commits: 0,
+            last_sync: Instant::now(),
+        }
+    }
+
+    // DiskManagerと同じく、実際に使うまでファイルは開かない
+    fn writer(&mut self) -> StorageResult<&mut BufWriter<File>> {
+        if self.writer.is_none() {
+            let (records, valid_len) = read_records(&self.path)?;
+
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+            // 書きかけで途切れた末尾は捨てる
+            if file.metadata()?.len() > valid_len {
+                file.set_len(valid_len)?;
+            }
+
+            let last_lsn = records.last().map_or(0, |r| r.lsn);
+            self.next_lsn = last_lsn + 1;
+            self.flushed_lsn = last_lsn;
+            self.writer = Some(BufWriter::new(file));
+        }
+
+        Ok(self.writer.as_mut().unwrap())
+    }
+
+    pub fn append(&mut self, operation: WalOperation) -> StorageResult<Lsn> {
+        self.writer()?;
+
+        let record = WalRecord {
+            lsn: self.next_lsn,
+            operation,
+        };
+        self.writer()?.write_all(&record.raw())?;
+        self.next_lsn += 1;
+
+        Ok(record.lsn)
+    }
+
+    pub fn sync(&mut self) -> StorageResult<()> {
+        let writer = self.writer()?;
+        writer.flush()?;
+        writer.get_ref().sync_data()?;
+        self.flushed_lsn = self.next_lsn - 1;
+        self.syncs += 1;
+        self.unsynced_commits = 0;
+        self.last_sync = Instant::now();
+
+        Ok(())
+    }
+
+    // commitをOSへ渡し、まとめる数か時間に達していればfsyncする
+    pub fn group_commit(&mut self, group: GroupCommit) -> StorageResult<()> {
+        self.write_out()?;
+        self.unsynced_commits += 1;
+
+        if self.unsynced_commits >= group.max_commits || self.last_sync.elapsed() >= group.interval
+        {
+            self.sync()?;
+        }
+
+        Ok(())
+    }
+
+    // fsyncを待っているcommitがあり、intervalが過ぎていればfsyncする
+    pub fn sync_if_due(&mut self, interval: Duration) -> StorageResult<bool> {
+        if self.unsynced_commits == 0 || self.last_sync.elapsed() < interval {
+            return Ok(false);
+        }
+
+        self.sync()?;
+        Ok(true)
+    }
+
+    pub fn syncs(&self) -> u64 {
+        self.syncs
+    }
+
+    // fsyncせずにOSへ渡す。flushed_lsnは進めない
+    pub fn write_out(&mut self) -> StorageResult<()> {
+        self.writer()?.flush()?;
+
+        Ok(())
+    }
+
+    // lsnまでのlogがdiskに書かれていることを保証する
+    pub fn flush_to(&mut self, lsn: Lsn) -> StorageResult<()> {
+        self.writer()?;
+
+        if lsn > self.flushed_lsn {
+            self.sync()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn flushed_lsn(&self) -> Lsn {
+        self.flushed_lsn
+    }
+
+    pub fn records(&mut self) -> StorageResult<Vec<WalRecord>> {
+        self.writer()?.flush()?;
+        Ok(read_records(&self.path)?.0)
+    }
+
+    // 末尾を切り詰めず、書き込み用にも開かずにrecordを読む
+    pub fn peek_records(&self) -> StorageResult<Vec<WalRecord>> {
+        Ok(read_records(&self.path)?.0)
+    }
+
+    // 未flushの分も含めたlogの大きさ
+    pub fn size(&mut self) -> StorageResult<u64> {
+        let writer = self.writer()?;
+        Ok(writer.get_ref().metadata()?.len() + writer.buffer().len() as u64)
+    }
+
+    // logを空にする
+    // lsnの採番が巻き戻らないよう、checkpoint recordだけは残す
+    // 途中で落ちても空のlogにならないよう、別fileに書いてからrenameする
+    pub fn truncate(&mut self) -> StorageResult<()> {
+        self.writer()?.flush()?;
+
+        let record = WalRecord {
+            lsn: self.next_lsn,
+            operation: WalOperation::Checkpoint,
+        };
+
+        let tmp_path = format!("{}.tmp", self.path);
+        {
+            let mut tmp = File::create(&tmp_path)?;
+            tmp.write_all(&record.raw())?;
+            tmp.sync_all()?;
+        }
+        fs::rename(&tmp_path, &self.path)?;
+        if let Some(dir) = Path::new(&self.path).parent() {
+            File::open(dir)?.sync_all()?;
+        }
+
+        let file = OpenOptions::new().append(true).open(&self.path)?;
+        self.writer = Some(BufWriter::new(file));
+        self.next_lsn += 1;
+        self.flushed_lsn = record.lsn;
+
+        Ok(())
+    }
+}
+
+fn read_records(path: &str) -> StorageResult<(Vec<WalRecord>, u64)> {
+    let raw = match fs::read(path) {
+        Ok(raw) => raw,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok((vec![], 0)),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut records = vec![];
+    let mut reader = ByteReader {
+        raw: &raw,
+        offset: 0,
+    };
+
+    loop {
+        let start = reader.offset;
+        let record = reader
+            .take(4)
+            .map(|b| u32::from_be_bytes(b.try_into().unwrap()) as usize)
+            .and_then(|len| reader.take(len))
+            .and_then(WalRecord::fill);
+
+        match record {
+            Some(r) => records.push(r),
+            None => return Ok((records, start as u64)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    use crate::test_util::temp_dir;
+
+    use super::*;
+
+    fn insert(slot: u32) -> WalOperation {
+        WalOperation::Insert {
+            txn_id: 1,
+            table_name: "wal_test".to_string(),
+            page_id: PageID(3),
+            slot,
+            tuple: vec![1, 2, 3],
+        }
+    }
+
+    #[test]
+    fn wal_append_and_read() {
+        let path = temp_dir("wal_append_and_read").join("wal");
+        let mut wal = Wal::new(path.to_str().unwrap().to_string());
+
+        assert_eq!(1, wal.append(insert(0)).unwrap());
+        assert_eq!(2, wal.append(insert(1)).unwrap());
+        assert_eq!(0, wal.flushed_lsn());
+
+        let delete = WalOperation::Delete {
+            txn_id: 1,
+            table_name: "wal_test".to_string(),
+            page_id: PageID(3),
+            slot: 1,
+        };
+        assert_eq!(3, wal.append(delete.clone()).unwrap());
+        let update = WalOperation::Update {
+            txn_id: 1,
+            table_name: "wal_test".to_string(),
+            page_id: PageID(3),
+            slot: 0,
+            before: vec![1, 2, 3],
+            after: vec![1, 4, 3],
+        };
+        assert_eq!(4, wal.append(update.clone()).unwrap());
+        assert_eq!(5, wal.append(WalOperation::Abort { txn_id: 1 }).unwrap());
+        assert_eq!(6, wal.append(WalOperation::Commit { txn_id: 2 }).unwrap());
+
+        wal.flush_to(2).unwrap();
+        assert_eq!(6, wal.flushed_lsn());
+
+        let records = wal.records().unwrap();
+        assert_eq!(
+            records,
+            vec![
+                WalRecord {
+                    lsn: 1,
+                    operation: insert(0)
+                },
+                WalRecord {
+                    lsn: 2,
+                    operation: insert(1)
+                },
+                WalRecord {
+                    lsn: 3,
+                    operation: delete
+                },
+                WalRecord {
+                    lsn: 4,
+                    operation: update
+                },
+                WalRecord {
+                    lsn: 5,
+                    operation: WalOperation::Abort { txn_id: 1 }
+                },
+                WalRecord {
+                    lsn: 6,
+                    operation: WalOperation::Commit { txn_id: 2 }
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn wal_reopen_continues_lsn() {
+        let path = temp_dir("wal_reopen_continues_lsn").join("wal");
+        let path = path.to_str().unwrap().to_string();
+
+        {
+            let mut wal = Wal::new(path.clone());
+            wal.append(insert(0)).unwrap();
+            wal.append(insert(1)).unwrap();
+            wal.sync().unwrap();
+        }
+
+        // 途切れたrecordを末尾に足しておく
+        {
+            let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+            file.write_all(&[0, 0, 0, 40, 0, 0]).unwrap();
+        }
+
+        let mut wal = Wal::new(path);
+        assert_eq!(3, wal.append(insert(2)).unwrap());
+        wal.sync().unwrap();
+
+        assert_eq!(3, wal.records().unwrap().len());
+    }
+
+    #[test]
+    fn wal_truncate_keeps_lsn() {
+        let path = temp_dir("wal_truncate_keeps_lsn").join("wal");
+        let mut wal = Wal::new(path.to_str().unwrap().to_string());
+
+        wal.append(insert(0)).unwrap();
+        wal.append(insert(1)).unwrap();
+        wal.truncate().unwrap();
+
+        let records = wal.records().unwrap();
+        assert_eq!(
+            records,
+            vec![WalRecord {
+                lsn: 3,
+                operation: WalOperation::Checkpoint
+            }]
+        );
+        assert_eq!(4, wal.append(insert(2)).unwrap());
+        wal.sync().unwrap();
+
+        // 開き直してもcheckpointの後ろに続く
+        let mut wal = Wal::new(path.to_str().unwrap().to_string());
+        assert_eq!(2, wal.records().unwrap().len());
+        assert_eq!(5, wal.append(insert(3)).unwrap());
+    }
+}
@@ -0,0 +1,10 @@
+use std::{env, fs, path::PathBuf};
+
+// テストごとに空のディレクトリを用意する
+// walなどのファイルがテスト間で混ざらないようにするため
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join("aqua_db_test").join(name);
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
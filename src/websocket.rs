@@ -0,0 +1,339 @@
+use std::{
+    fmt,
+    io::{self, Read, Write},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+// WebSocket(RFC 6455)のうち、text messageのやりとりに要る分だけ
+// frameは先頭2byteにFIN、opcode、MASK、長さが入り、長さが126以上なら続けて2byteか8byteで表す
+// clientからのframeは必ずmaskされ、serverからのframeはmaskしない
+
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+pub const OPCODE_CONTINUATION: u8 = 0x0;
+pub const OPCODE_TEXT: u8 = 0x1;
+pub const OPCODE_BINARY: u8 = 0x2;
+pub const OPCODE_CLOSE: u8 = 0x8;
+pub const OPCODE_PING: u8 = 0x9;
+pub const OPCODE_PONG: u8 = 0xA;
+
+// control frameの本体の上限
+const MAX_CONTROL_LEN: usize = 125;
+
+// close frameのstatus code
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_UNSUPPORTED_DATA: u16 = 1003;
+pub const CLOSE_INVALID_DATA: u16 = 1007;
+pub const CLOSE_POLICY_VIOLATION: u16 = 1008;
+pub const CLOSE_TOO_BIG: u16 = 1009;
+
+// Sec-WebSocket-Keyに対して、Sec-WebSocket-Acceptで返す値
+pub fn accept_key(key: &str) -> String {
+    let digest = sha1_smol::Sha1::from(format!("{}{}", key.trim(), ACCEPT_GUID)).digest();
+    STANDARD.encode(digest.bytes())
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong,
+    // clientが送ってきたstatus code。本体が空ならNone
+    Close(Option<u16>),
+}
+
+// 接続を閉じるべきframeの誤り。codeをclose frameで返す
+#[derive(Debug, PartialEq)]
+pub struct WebSocketError {
+    pub code: u16,
+    pub message: String,
+}
+
+impl WebSocketError {
+    fn protocol(message: &str) -> Self {
+        Self {
+            code: CLOSE_PROTOCOL_ERROR,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for WebSocketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for WebSocketError {}
+
+// 分割されたmessageを、最後のframeが届くまでためておく
+// 分割の途中にcontrol frameが挟まっても、ためた分は捨てない
+pub struct MessageReader {
+    max_len: usize,
+    partial: Option<(u8, Vec<u8>)>,
+}
+
+impl MessageReader {
+    pub fn new(max_len: usize) -> Self {
+        Self {
+            max_len,
+            partial: None,
+        }
+    }
+
+    // messageの前で閉じられたらNone
+    pub fn read<R: Read>(&mut self, reader: &mut R) -> Result<Option<Message>, anyhow::Error> {
+        loop {
+            let mut head = [0_u8; 2];
+            if reader.read(&mut head[..1])? == 0 {
+                return Ok(None);
+            }
+            reader.read_exact(&mut head[1..])?;
+
+            let fin = head[0] & 0x80 != 0;
+            let opcode = head[0] & 0x0F;
+            if head[0] & 0x70 != 0 {
+                return Err(WebSocketError::protocol("reserved bits are set").into());
+            }
+            if head[1] & 0x80 == 0 {
+                return Err(WebSocketError::protocol("client frames must be masked").into());
+            }
+            let len = match head[1] & 0x7F {
+                126 => {
+                    let mut len = [0_u8; 2];
+                    reader.read_exact(&mut len)?;
+                    u16::from_be_bytes(len) as u64
+                }
+                127 => {
+                    let mut len = [0_u8; 8];
+                    reader.read_exact(&mut len)?;
+                    u64::from_be_bytes(len)
+                }
+                n => n as u64,
+            };
+
+            let control = opcode & 0x8 != 0;
+            if control && (!fin || len > MAX_CONTROL_LEN as u64) {
+                return Err(WebSocketError::protocol("invalid control frame").into());
+            }
+            let buffered = self.partial.as_ref().map_or(0, |(_, p)| p.len());
+            if !control && len > (self.max_len - buffered) as u64 {
+                return Err(WebSocketError {
+                    code: CLOSE_TOO_BIG,
+                    message: format!("message exceeds {} bytes", self.max_len),
+                }
+                .into());
+            }
+
+            let mut mask = [0_u8; 4];
+            reader.read_exact(&mut mask)?;
+            let mut payload = vec![0_u8; len as usize];
+            reader.read_exact(&mut payload)?;
+            for (i, b) in payload.iter_mut().enumerate() {
+                *b ^= mask[i % 4];
+            }
+
+            let (opcode, payload) = match opcode {
+                OPCODE_PING => return Ok(Some(Message::Ping(payload))),
+                OPCODE_PONG => return Ok(Some(Message::Pong)),
+                OPCODE_CLOSE => {
+                    let code =
+                        (payload.len() >= 2).then(|| u16::from_be_bytes([payload[0], payload[1]]));
+                    return Ok(Some(Message::Close(code)));
+                }
+                OPCODE_TEXT | OPCODE_BINARY if self.partial.is_none() => (opcode, payload),
+                OPCODE_CONTINUATION => match self.partial.take() {
+                    Some((first, mut data)) => {
+                        data.extend_from_slice(&payload);
+                        (first, data)
+                    }
+                    None => return Err(WebSocketError::protocol("unexpected continuation").into()),
+                },
+                _ => return Err(WebSocketError::protocol("unexpected frame").into()),
+            };
+
+            if !fin {
+                self.partial = Some((opcode, payload));
+                continue;
+            }
+            return match opcode {
+                OPCODE_TEXT => match String::from_utf8(payload) {
+                    Ok(text) => Ok(Some(Message::Text(text))),
+                    Err(_) => Err(WebSocketError {
+                        code: CLOSE_INVALID_DATA,
+                        message: "text frame is not utf-8".to_string(),
+                    }
+                    .into()),
+                },
+                _ => Ok(Some(Message::Binary(payload))),
+            };
+        }
+    }
+}
+
+fn write_frame<W: Write>(writer: &mut W, opcode: u8, payload: &[u8]) -> io::Result<()> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        n if n < 126 => frame.push(n as u8),
+        n if n <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(n as u16).to_be_bytes());
+        }
+        n => {
+            frame.push(127);
+            frame.extend_from_slice(&(n as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    writer.write_all(&frame)?;
+    writer.flush()
+}
+
+pub fn text<W: Write>(writer: &mut W, text: &str) -> io::Result<()> {
+    write_frame(writer, OPCODE_TEXT, text.as_bytes())
+}
+
+pub fn ping<W: Write>(writer: &mut W) -> io::Result<()> {
+    write_frame(writer, OPCODE_PING, &[])
+}
+
+pub fn pong<W: Write>(writer: &mut W, payload: &[u8]) -> io::Result<()> {
+    write_frame(writer, OPCODE_PONG, payload)
+}
+
+// reasonはcontrol frameに収まるよう切り詰める
+pub fn close<W: Write>(writer: &mut W, code: u16, reason: &str) -> io::Result<()> {
+    let mut payload = code.to_be_bytes().to_vec();
+    let mut end = reason.len().min(MAX_CONTROL_LEN - 2);
+    while !reason.is_char_boundary(end) {
+        end -= 1;
+    }
+    payload.extend_from_slice(&reason.as_bytes()[..end]);
+    write_frame(writer, OPCODE_CLOSE, &payload)
+}
+
+// clientと同じようにmaskしたframe。serverのtestで使う
+#[cfg(test)]
+pub fn masked_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mask = [0x12_u8, 0x34, 0x56, 0x78];
+    let mut frame = vec![if fin { 0x80 | opcode } else { opcode }];
+    match payload.len() {
+        n if n < 126 => frame.push(0x80 | n as u8),
+        n if n <= u16::MAX as usize => {
+            frame.push(0x80 | 126);
+            frame.extend_from_slice(&(n as u16).to_be_bytes());
+        }
+        n => {
+            frame.push(0x80 | 127);
+            frame.extend_from_slice(&(n as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(&mask);
+    frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+    frame
+}
+
+// serverが送ったframeを読む。serverのtestで使う
+#[cfg(test)]
+pub fn read_server_frame<R: Read>(reader: &mut R) -> (u8, Vec<u8>) {
+    let mut head = [0_u8; 2];
+    reader.read_exact(&mut head).unwrap();
+    let len = match head[1] {
+        126 => {
+            let mut len = [0_u8; 2];
+            reader.read_exact(&mut len).unwrap();
+            u16::from_be_bytes(len) as usize
+        }
+        127 => {
+            let mut len = [0_u8; 8];
+            reader.read_exact(&mut len).unwrap();
+            u64::from_be_bytes(len) as usize
+        }
+        n => n as usize,
+    };
+    let mut payload = vec![0_u8; len];
+    reader.read_exact(&mut payload).unwrap();
+    (head[0] & 0x0F, payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn websocket_frames() {
+        // RFC 6455の例
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+
+        let long = "a".repeat(300);
+        let mut input = masked_frame(true, OPCODE_TEXT, b"select * from t;");
+        input.extend(masked_frame(false, OPCODE_TEXT, b"sel"));
+        // 分割の途中のping
+        input.extend(masked_frame(true, OPCODE_PING, b"hi"));
+        input.extend(masked_frame(true, OPCODE_CONTINUATION, b"ect;"));
+        input.extend(masked_frame(true, OPCODE_TEXT, long.as_bytes()));
+        input.extend(masked_frame(
+            true,
+            OPCODE_CLOSE,
+            &CLOSE_NORMAL.to_be_bytes(),
+        ));
+
+        let mut reader = MessageReader::new(1024);
+        let mut input = input.as_slice();
+        let mut messages = Vec::new();
+        while let Some(m) = reader.read(&mut input).unwrap() {
+            messages.push(m);
+        }
+        assert_eq!(
+            messages,
+            vec![
+                Message::Text("select * from t;".to_string()),
+                Message::Ping(b"hi".to_vec()),
+                Message::Text("select;".to_string()),
+                Message::Text(long.clone()),
+                Message::Close(Some(CLOSE_NORMAL)),
+            ]
+        );
+
+        let error = |input: Vec<u8>| {
+            MessageReader::new(100)
+                .read(&mut input.as_slice())
+                .unwrap_err()
+                .downcast::<WebSocketError>()
+                .unwrap()
+                .code
+        };
+        assert_eq!(
+            error(masked_frame(true, OPCODE_TEXT, long.as_bytes())),
+            CLOSE_TOO_BIG
+        );
+        assert_eq!(
+            error(masked_frame(true, OPCODE_TEXT, &[0xFF])),
+            CLOSE_INVALID_DATA
+        );
+        assert_eq!(error(vec![0x81, 0x01, b'a']), CLOSE_PROTOCOL_ERROR);
+
+        let mut out = Vec::new();
+        text(&mut out, &long).unwrap();
+        close(&mut out, CLOSE_NORMAL, "bye").unwrap();
+        let mut out = out.as_slice();
+        assert_eq!(
+            read_server_frame(&mut out),
+            (OPCODE_TEXT, long.into_bytes())
+        );
+        assert_eq!(
+            read_server_frame(&mut out),
+            (
+                OPCODE_CLOSE,
+                [&CLOSE_NORMAL.to_be_bytes()[..], b"bye"].concat()
+            )
+        );
+    }
+}